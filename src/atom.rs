@@ -17,7 +17,7 @@ pub fn register_atom(atom: String) {
 
 pub fn get_atom(only_if_exists: bool, name: String) -> u32 {
     let mut atoms = ATOMS.lock().unwrap();
-    match atoms.iter().filter(|(_, v)| **v == name).next() {
+    match atoms.iter().find(|(_, v)| **v == name) {
         Some((k, _)) => *k,
         None if only_if_exists => 0,
         _ => {
@@ -27,4 +27,10 @@ pub fn get_atom(only_if_exists: bool, name: String) -> u32 {
             *next_atom - 1
         }
     }
-}
\ No newline at end of file
+}
+/// The last of the atoms predefined by the core protocol.
+pub const LAST_PREDEFINED_ATOM: u32 = 68;
+
+pub fn atom_exists(atom: u32) -> bool {
+    (1..=LAST_PREDEFINED_ATOM).contains(&atom) || ATOMS.lock().unwrap().contains_key(&atom)
+}
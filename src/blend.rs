@@ -0,0 +1,285 @@
+//! RENDER compositing operators. Colors are premultiplied `[alpha, red, green, blue]`.
+//! The Porter-Duff, disjoint and conjoint operators use the same 8-bit arithmetic as
+//! pixman; the PDF blend modes are computed in floating point, as pixman does.
+
+pub const PICT_OP_CLEAR: u8 = 0x00;
+pub const PICT_OP_SRC: u8 = 0x01;
+pub const PICT_OP_DST: u8 = 0x02;
+pub const PICT_OP_OVER: u8 = 0x03;
+pub const PICT_OP_OVER_REVERSE: u8 = 0x04;
+pub const PICT_OP_IN: u8 = 0x05;
+pub const PICT_OP_IN_REVERSE: u8 = 0x06;
+pub const PICT_OP_OUT: u8 = 0x07;
+pub const PICT_OP_OUT_REVERSE: u8 = 0x08;
+pub const PICT_OP_ATOP: u8 = 0x09;
+pub const PICT_OP_ATOP_REVERSE: u8 = 0x0a;
+pub const PICT_OP_XOR: u8 = 0x0b;
+pub const PICT_OP_ADD: u8 = 0x0c;
+pub const PICT_OP_SATURATE: u8 = 0x0d;
+pub const PICT_OP_DISJOINT_CLEAR: u8 = 0x10;
+pub const PICT_OP_DISJOINT_XOR: u8 = 0x1b;
+pub const PICT_OP_CONJOINT_CLEAR: u8 = 0x20;
+pub const PICT_OP_CONJOINT_XOR: u8 = 0x2b;
+pub const PICT_OP_MULTIPLY: u8 = 0x30;
+pub const PICT_OP_SCREEN: u8 = 0x31;
+pub const PICT_OP_OVERLAY: u8 = 0x32;
+pub const PICT_OP_DARKEN: u8 = 0x33;
+pub const PICT_OP_LIGHTEN: u8 = 0x34;
+pub const PICT_OP_COLOR_DODGE: u8 = 0x35;
+pub const PICT_OP_COLOR_BURN: u8 = 0x36;
+pub const PICT_OP_HARD_LIGHT: u8 = 0x37;
+pub const PICT_OP_SOFT_LIGHT: u8 = 0x38;
+pub const PICT_OP_DIFFERENCE: u8 = 0x39;
+pub const PICT_OP_EXCLUSION: u8 = 0x3a;
+pub const PICT_OP_HSL_HUE: u8 = 0x3b;
+pub const PICT_OP_HSL_SATURATION: u8 = 0x3c;
+pub const PICT_OP_HSL_COLOR: u8 = 0x3d;
+pub const PICT_OP_HSL_LUMINOSITY: u8 = 0x3e;
+
+pub fn is_valid_op(op: u8) -> bool {
+    matches!(
+        op,
+        PICT_OP_CLEAR..=PICT_OP_SATURATE
+            | PICT_OP_DISJOINT_CLEAR..=PICT_OP_DISJOINT_XOR
+            | PICT_OP_CONJOINT_CLEAR..=PICT_OP_CONJOINT_XOR
+            | PICT_OP_MULTIPLY..=PICT_OP_HSL_LUMINOSITY
+    )
+}
+
+/// `a * b / 255`, rounded.
+pub fn mul(a: u8, b: u8) -> u8 {
+    let t = a as u32 * b as u32 + 0x80;
+    (((t >> 8) + t) >> 8) as u8
+}
+
+/// `a * 255 / b`, rounded.
+fn div(a: u8, b: u8) -> u8 {
+    ((a as u32 * 255 + b as u32 / 2) / b as u32) as u8
+}
+
+/// How much of the source or destination an operator keeps.
+#[derive(Clone, Copy)]
+enum Factor {
+    Zero,
+    One,
+    /// The other operand's alpha.
+    In,
+    /// One minus the other operand's alpha.
+    Out,
+}
+
+/// The source and destination factors of the Porter-Duff operators, in the order of
+/// their opcodes within each group.
+const PORTER_DUFF: [(Factor, Factor); 12] = {
+    use Factor::*;
+    [
+        (Zero, Zero),
+        (One, Zero),
+        (Zero, One),
+        (One, Out),
+        (Out, One),
+        (In, Zero),
+        (Zero, In),
+        (Out, Zero),
+        (Zero, Out),
+        (In, Out),
+        (Out, In),
+        (Out, Out),
+    ]
+};
+
+/// The factor for an operand with alpha `a` when the other operand has alpha `b`.
+fn factor(factor: Factor, group: u8, a: u8, b: u8) -> u8 {
+    match (factor, group) {
+        (Factor::Zero, _) => 0,
+        (Factor::One, _) => 255,
+        (Factor::In, PICT_OP_DISJOINT_CLEAR) if 255 - b >= a => 0,
+        (Factor::In, PICT_OP_DISJOINT_CLEAR) => !div(255 - b, a),
+        (Factor::Out, PICT_OP_DISJOINT_CLEAR) if 255 - b >= a => 255,
+        (Factor::Out, PICT_OP_DISJOINT_CLEAR) => div(255 - b, a),
+        (Factor::In, PICT_OP_CONJOINT_CLEAR) if b >= a => 255,
+        (Factor::In, PICT_OP_CONJOINT_CLEAR) => div(b, a),
+        (Factor::Out, PICT_OP_CONJOINT_CLEAR) if b >= a => 0,
+        (Factor::Out, PICT_OP_CONJOINT_CLEAR) => !div(b, a),
+        (Factor::In, _) => b,
+        (Factor::Out, _) => 255 - b,
+    }
+}
+
+/// Combines `source`, masked by `mask`, into `destination` with operator `op`. For
+/// masks without component alpha all four entries of `mask` are the mask's alpha.
+pub fn combine(op: u8, source: [u8; 4], mask: [u8; 4], destination: [u8; 4]) -> [u8; 4] {
+    // The source in the mask, and the source alpha seen by each channel.
+    let s: [u8; 4] = std::array::from_fn(|c| mul(source[c], mask[c]));
+    let sa: [u8; 4] = std::array::from_fn(|c| mul(source[0], mask[c]));
+    let d = destination;
+    let da = d[0];
+    match op {
+        PICT_OP_ADD => std::array::from_fn(|c| s[c].saturating_add(d[c])),
+        PICT_OP_SATURATE => std::array::from_fn(|c| {
+            if sa[c] > 255 - da {
+                mul(s[c], div(255 - da, sa[c])).saturating_add(d[c])
+            } else {
+                s[c].saturating_add(d[c])
+            }
+        }),
+        PICT_OP_MULTIPLY..=PICT_OP_EXCLUSION => blend_separable(op, s, sa, d),
+        PICT_OP_HSL_HUE..=PICT_OP_HSL_LUMINOSITY => blend_hsl(op, s, d),
+        _ => {
+            let group = match op {
+                PICT_OP_DISJOINT_CLEAR..=PICT_OP_DISJOINT_XOR => PICT_OP_DISJOINT_CLEAR,
+                PICT_OP_CONJOINT_CLEAR..=PICT_OP_CONJOINT_XOR => PICT_OP_CONJOINT_CLEAR,
+                _ => PICT_OP_CLEAR,
+            };
+            let index = op - group;
+            let (fa, fb) = PORTER_DUFF[index as usize];
+            std::array::from_fn(|c| {
+                let fa = factor(fa, group, sa[c], da);
+                let fb = factor(fb, group, da, sa[c]);
+                mul(s[c], fa).saturating_add(mul(d[c], fb))
+            })
+        }
+    }
+}
+
+fn to_float(value: u8) -> f32 {
+    value as f32 / 255.0
+}
+
+fn to_byte(value: f32) -> u8 {
+    (value.clamp(0.0, 1.0) * 255.0 + 0.5) as u8
+}
+
+/// The PDF separable blend modes: each color channel is `(1 - sa) * d + (1 - da) * s`
+/// plus the mode's blend function.
+fn blend_separable(op: u8, s: [u8; 4], sa: [u8; 4], d: [u8; 4]) -> [u8; 4] {
+    let da = to_float(d[0]);
+    let mut result = [0; 4];
+    for c in 0..4 {
+        let (s, sa, d) = (to_float(s[c]), to_float(sa[c]), to_float(d[c]));
+        result[c] = if c == 0 {
+            to_byte(s + d - s * d)
+        } else {
+            let blended = match op {
+                PICT_OP_MULTIPLY => s * d,
+                PICT_OP_SCREEN => s * da + d * sa - s * d,
+                PICT_OP_OVERLAY => hard_light(sa, d, da, s),
+                PICT_OP_DARKEN => (s * da).min(d * sa),
+                PICT_OP_LIGHTEN => (s * da).max(d * sa),
+                PICT_OP_COLOR_DODGE => color_dodge(sa, s, da, d),
+                PICT_OP_COLOR_BURN => color_burn(sa, s, da, d),
+                PICT_OP_HARD_LIGHT => hard_light(da, s, sa, d),
+                PICT_OP_SOFT_LIGHT => soft_light(sa, s, da, d),
+                PICT_OP_DIFFERENCE => (s * da - d * sa).abs(),
+                _ => s * da + d * sa - 2.0 * d * s,
+            };
+            to_byte((1.0 - sa) * d + (1.0 - da) * s + blended)
+        };
+    }
+    result
+}
+
+/// Hard light with `s` as the source; overlay is hard light with the operands swapped.
+fn hard_light(da: f32, s: f32, sa: f32, d: f32) -> f32 {
+    if 2.0 * s < sa {
+        2.0 * s * d
+    } else {
+        sa * da - 2.0 * (da - d) * (sa - s)
+    }
+}
+
+fn color_dodge(sa: f32, s: f32, da: f32, d: f32) -> f32 {
+    if d == 0.0 {
+        0.0
+    } else if d * sa >= sa * da - s * da || sa - s == 0.0 {
+        sa * da
+    } else {
+        sa * sa * d / (sa - s)
+    }
+}
+
+fn color_burn(sa: f32, s: f32, da: f32, d: f32) -> f32 {
+    if d >= da {
+        sa * da
+    } else if sa * (da - d) >= s * da || s == 0.0 {
+        0.0
+    } else {
+        sa * (da - sa * (da - d) / s)
+    }
+}
+
+fn soft_light(sa: f32, s: f32, da: f32, d: f32) -> f32 {
+    if da == 0.0 {
+        d * sa
+    } else if 2.0 * s < sa {
+        d * sa - d * (da - d) * (sa - 2.0 * s) / da
+    } else if 4.0 * d <= da {
+        d * sa + (2.0 * s - sa) * d * ((16.0 * d / da - 12.0) * d / da + 3.0)
+    } else {
+        d * sa + ((d * da).sqrt() - d) * (2.0 * s - sa)
+    }
+}
+
+fn luminosity(c: [f32; 3]) -> f32 {
+    c[0] * 0.3 + c[1] * 0.59 + c[2] * 0.11
+}
+
+fn saturation(c: [f32; 3]) -> f32 {
+    c[0].max(c[1]).max(c[2]) - c[0].min(c[1]).min(c[2])
+}
+
+fn set_luminosity(mut c: [f32; 3], alpha: f32, l: f32) -> [f32; 3] {
+    let delta = l - luminosity(c);
+    for channel in &mut c {
+        *channel += delta;
+    }
+    let l = luminosity(c);
+    let (min, max) = (c[0].min(c[1]).min(c[2]), c[0].max(c[1]).max(c[2]));
+    if min < 0.0 {
+        for channel in &mut c {
+            *channel = if l - min == 0.0 { 0.0 } else { l + (*channel - l) * l / (l - min) };
+        }
+    }
+    if max > alpha {
+        for channel in &mut c {
+            *channel = if max - l == 0.0 { alpha } else { l + (*channel - l) * (alpha - l) / (max - l) };
+        }
+    }
+    c
+}
+
+fn set_saturation(c: [f32; 3], s: f32) -> [f32; 3] {
+    let (min, max) = (c[0].min(c[1]).min(c[2]), c[0].max(c[1]).max(c[2]));
+    if max - min == 0.0 {
+        return [0.0; 3];
+    }
+    c.map(|channel| (channel - min) * s / (max - min))
+}
+
+/// The non-separable HSL blend modes. Component alpha doesn't apply to them, so only
+/// the masked source's own alpha is used.
+fn blend_hsl(op: u8, s: [u8; 4], d: [u8; 4]) -> [u8; 4] {
+    let (sa, da) = (to_float(s[0]), to_float(d[0]));
+    let src = [to_float(s[1]), to_float(s[2]), to_float(s[3])];
+    let dst = [to_float(d[1]), to_float(d[2]), to_float(d[3])];
+    let scaled = |c: [f32; 3], a: f32| c.map(|channel| channel * a);
+    let blended = match op {
+        PICT_OP_HSL_HUE => set_luminosity(
+            set_saturation(scaled(src, da), saturation(dst) * sa),
+            sa * da,
+            luminosity(dst) * sa,
+        ),
+        PICT_OP_HSL_SATURATION => set_luminosity(
+            set_saturation(scaled(dst, sa), saturation(src) * da),
+            sa * da,
+            luminosity(dst) * sa,
+        ),
+        PICT_OP_HSL_COLOR => set_luminosity(scaled(src, da), sa * da, luminosity(dst) * sa),
+        _ => set_luminosity(scaled(dst, sa), sa * da, luminosity(src) * da),
+    };
+    let mut result = [to_byte(sa + da - sa * da), 0, 0, 0];
+    for c in 0..3 {
+        result[c + 1] = to_byte((1.0 - sa) * dst[c] + (1.0 - da) * src[c] + blended[c]);
+    }
+    result
+}
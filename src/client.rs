@@ -0,0 +1,344 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    io::{Read, Write},
+    os::fd::OwnedFd,
+    sync::{Condvar, Mutex},
+};
+
+use crate::{
+    composite::free_client_composite,
+    connection::Connection,
+    cursor::{free_client_cursors, CURSORS},
+    damage::{free_client_damages, DAMAGES},
+    error::{ErrorCode, XError},
+    event::Event,
+    glyph::GLYPH_SETS,
+    grab,
+    input::DeviceEvent,
+    picture::PICTURES,
+    pixmap::PIXMAPS,
+    selection::release_client_selections,
+    shm::SEGMENTS,
+    unix::Credentials,
+    window::{destroy_client_windows, get_window, restore_save_set, Window, WINDOWS},
+    xfixes::{free_client_xfixes_resources, BARRIERS, REGIONS},
+    xkb::PCF_DETECTABLE_AUTO_REPEAT,
+};
+
+pub const DESTROY_ALL: u8 = 0;
+pub const RETAIN_PERMANENT: u8 = 1;
+pub const RETAIN_TEMPORARY: u8 = 2;
+
+pub const SET_MODE_INSERT: u8 = 0;
+pub const SET_MODE_DELETE: u8 = 1;
+
+/// How a save-set window is rescued, as chosen through XFIXES ChangeSaveSet. Core
+/// ChangeSaveSet uses the default: the nearest ancestor, mapped.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SaveSetMode {
+    /// Reparent the window to the root instead of its nearest ancestor outside the
+    /// client's windows.
+    pub to_root: bool,
+    /// Leave the window unmapped instead of mapping it.
+    pub unmap: bool,
+}
+
+/// The AllTemporary resource argument to KillClient.
+pub const ALL_TEMPORARY: u32 = 0;
+
+pub static CLIENTS: Mutex<BTreeMap<u32, Client>> = Mutex::new(BTreeMap::new());
+pub static NEXT_CLIENT: Mutex<u32> = Mutex::new(1);
+
+/// Disconnected clients whose resources were kept according to their close-down mode.
+pub static RETAINED_CLIENTS: Mutex<BTreeMap<u32, Client>> = Mutex::new(BTreeMap::new());
+
+/// The client holding the server grab, if any. Lock before `CLIENTS`.
+pub static SERVER_GRAB: Mutex<Option<u32>> = Mutex::new(None);
+static SERVER_UNGRABBED: Condvar = Condvar::new();
+
+/// Server-side state for a connected client. Client 0 is the server itself and owns
+/// the root window.
+#[derive(Debug)]
+pub struct Client {
+    pub events: VecDeque<Event>,
+    /// Set through XTEST GrabControl to keep processing requests during server grabs.
+    pub impervious: bool,
+    /// What happens to the client's resources when it disconnects, set by SetCloseDownMode.
+    pub close_down_mode: u8,
+    /// Set by KillClient; the connection is closed the next time it reads.
+    pub killed: bool,
+    /// Other clients' windows to rescue when this client's windows are destroyed.
+    pub save_set: BTreeMap<u32, SaveSetMode>,
+    /// Peer credentials of clients connected through the Unix socket; `None` over TCP.
+    pub credentials: Option<Credentials>,
+    /// File descriptors received with the client's requests, oldest first.
+    pub received_fds: VecDeque<OwnedFd>,
+    /// File descriptors to send along with the next bytes written to the client.
+    pub outgoing_fds: Vec<OwnedFd>,
+    /// Set once the client has agreed on an XKB version with UseExtension.
+    pub xkb_initialized: bool,
+    /// The details selected with XKB SelectEvents, indexed by XKB event type.
+    pub xkb_events: [u32; 12],
+    /// XKB per-client flags, such as detectable auto-repeat.
+    pub xkb_flags: u32,
+}
+
+pub fn register_client() -> u32 {
+    let mut next_client = NEXT_CLIENT.lock().unwrap();
+    let mut clients = CLIENTS.lock().unwrap();
+    let client = *next_client;
+    clients.insert(
+        client,
+        Client {
+            events: VecDeque::new(),
+            impervious: false,
+            close_down_mode: DESTROY_ALL,
+            killed: false,
+            save_set: BTreeMap::new(),
+            credentials: None,
+            received_fds: VecDeque::new(),
+            outgoing_fds: Vec::new(),
+            xkb_initialized: false,
+            xkb_events: [0; 12],
+            xkb_flags: 0,
+        },
+    );
+    *next_client += 1;
+    client
+}
+
+/// Returns the client that created the resource `id`.
+pub fn resource_owner(id: u32) -> u32 {
+    id >> 21
+}
+
+pub fn set_credentials(client: u32, credentials: Option<Credentials>) {
+    if let Some(client) = CLIENTS.lock().unwrap().get_mut(&client) {
+        client.credentials = credentials;
+    }
+}
+
+pub fn client_credentials(client: u32) -> Option<Credentials> {
+    CLIENTS.lock().unwrap().get(&client).and_then(|client| client.credentials)
+}
+
+/// Takes the oldest file descriptor the client sent that no request has used yet.
+pub fn take_received_fd(client: u32) -> Option<OwnedFd> {
+    CLIENTS.lock().unwrap().get_mut(&client)?.received_fds.pop_front()
+}
+
+/// Queues `fd` to be passed to the client with the next reply. Returns false if the
+/// client is not connected through the Unix socket and can't receive it.
+pub fn send_fd(client: u32, fd: OwnedFd) -> bool {
+    match CLIENTS.lock().unwrap().get_mut(&client) {
+        Some(client) if client.credentials.is_some() => {
+            client.outgoing_fds.push(fd);
+            true
+        }
+        _ => false,
+    }
+}
+
+pub fn queue_event(client: u32, event: Event) {
+    if let Some(client) = CLIENTS.lock().unwrap().get_mut(&client) {
+        // With detectable auto-repeat, a held key repeats its KeyPress without releases.
+        let repeat_release = matches!(event, Event::KeyRelease(DeviceEvent { auto_repeat: true, .. }));
+        if repeat_release && client.xkb_flags & PCF_DETECTABLE_AUTO_REPEAT != 0 {
+            return;
+        }
+        client.events.push_back(event);
+    }
+}
+
+/// Queues `event` for every connected client.
+pub fn broadcast_event(event: Event) {
+    for client in CLIENTS.lock().unwrap().values_mut() {
+        client.events.push_back(event.clone());
+    }
+}
+
+/// Implements GrabServer.
+pub fn grab_server(client: u32) {
+    *SERVER_GRAB.lock().unwrap() = Some(client);
+}
+
+/// Implements UngrabServer, and releases the server grab of a disconnecting client.
+pub fn ungrab_server(client: u32) {
+    let mut server_grab = SERVER_GRAB.lock().unwrap();
+    if *server_grab == Some(client) {
+        *server_grab = None;
+        SERVER_UNGRABBED.notify_all();
+    }
+}
+
+/// Blocks while another client holds the server grab, unless `client` was made
+/// impervious through XTEST. Events for the client keep queueing in the meantime.
+pub fn wait_for_server_grab(client: u32) {
+    let mut server_grab = SERVER_GRAB.lock().unwrap();
+    while server_grab.is_some_and(|grabber| grabber != client)
+        && !CLIENTS.lock().unwrap().get(&client).is_some_and(|client| client.impervious)
+    {
+        server_grab = SERVER_UNGRABBED.wait(server_grab).unwrap();
+    }
+}
+
+/// Implements SetCloseDownMode.
+pub fn set_close_down_mode(client: u32, mode: u8) -> Result<(), XError> {
+    if mode > RETAIN_TEMPORARY {
+        return Err(XError::new(ErrorCode::Value, mode as u32));
+    }
+    if let Some(client) = CLIENTS.lock().unwrap().get_mut(&client) {
+        client.close_down_mode = mode;
+    }
+    Ok(())
+}
+
+pub fn is_killed(client: u32) -> bool {
+    CLIENTS.lock().unwrap().get(&client).is_some_and(|client| client.killed)
+}
+
+/// Implements ChangeSaveSet and its XFIXES variant. Inserting a window already in the
+/// save-set changes how it is rescued.
+pub fn change_save_set(
+    windows: &BTreeMap<u32, Window>,
+    client: u32,
+    mode: u8,
+    window: u32,
+    save_set_mode: SaveSetMode,
+) -> Result<(), XError> {
+    get_window(windows, window)?;
+    if resource_owner(window) == client {
+        return Err(XError::new(ErrorCode::Match, window));
+    }
+    let mut clients = CLIENTS.lock().unwrap();
+    let Some(client) = clients.get_mut(&client) else {
+        return Ok(());
+    };
+    match mode {
+        SET_MODE_INSERT => {
+            client.save_set.insert(window, save_set_mode);
+        }
+        SET_MODE_DELETE => {
+            client.save_set.remove(&window);
+        }
+        mode => return Err(XError::new(ErrorCode::Value, mode as u32)),
+    };
+    Ok(())
+}
+
+/// Forgets a destroyed window in every save-set.
+pub fn remove_from_save_sets(window: u32) {
+    for client in CLIENTS.lock().unwrap().values_mut() {
+        client.save_set.remove(&window);
+    }
+    for client in RETAINED_CLIENTS.lock().unwrap().values_mut() {
+        client.save_set.remove(&window);
+    }
+}
+
+fn free_client_resources(client: u32, save_set: &BTreeMap<u32, SaveSetMode>) {
+    let mut windows = WINDOWS.lock().unwrap();
+    restore_save_set(&mut windows, client, save_set);
+    destroy_client_windows(&mut windows, client);
+    free_client_composite(&mut windows, client);
+    free_client_cursors(&windows, client);
+    drop(windows);
+    free_client_xfixes_resources(client);
+    PICTURES.lock().unwrap().retain(|id, _| resource_owner(*id) != client);
+    GLYPH_SETS.lock().unwrap().retain(|id, _| resource_owner(*id) != client);
+    PIXMAPS.lock().unwrap().retain(|id, _| resource_owner(*id) != client);
+    SEGMENTS.lock().unwrap().retain(|id, _| resource_owner(*id) != client);
+    free_client_damages(client);
+}
+
+/// Undoes everything a disconnecting client set up, then frees its resources or keeps
+/// them according to its close-down mode.
+pub fn close_down_client(client: u32) {
+    ungrab_server(client);
+    grab::release_client_grabs(client);
+    let mut windows = WINDOWS.lock().unwrap();
+    release_client_selections(&windows, client);
+    for window in windows.values_mut() {
+        window.event_masks.remove(&client);
+        window.shape.selected.remove(&client);
+        window.randr_masks.remove(&client);
+        window.xi_masks.retain(|(selecting, _), _| *selecting != client);
+        window.selection_masks.retain(|(selecting, _), _| *selecting != client);
+        window.cursor_masks.remove(&client);
+    }
+    drop(windows);
+    let Some(state) = CLIENTS.lock().unwrap().remove(&client) else {
+        return;
+    };
+    match state.close_down_mode {
+        DESTROY_ALL => free_client_resources(client, &state.save_set),
+        _ => {
+            RETAINED_CLIENTS.lock().unwrap().insert(client, state);
+        }
+    }
+}
+
+/// Returns whether `id` names a resource in any resource table. Each table is locked
+/// on its own, so this can be called with none of them held.
+fn resource_exists(id: u32) -> bool {
+    let tables: [fn(u32) -> bool; 9] = [
+        |id| WINDOWS.lock().unwrap().contains_key(&id),
+        |id| PICTURES.lock().unwrap().contains_key(&id),
+        |id| PIXMAPS.lock().unwrap().contains_key(&id),
+        |id| CURSORS.lock().unwrap().get(&id).is_some_and(|cursor| !cursor.freed),
+        |id| GLYPH_SETS.lock().unwrap().contains_key(&id),
+        |id| REGIONS.lock().unwrap().contains_key(&id),
+        |id| BARRIERS.lock().unwrap().contains_key(&id),
+        |id| DAMAGES.lock().unwrap().contains_key(&id),
+        |id| SEGMENTS.lock().unwrap().contains_key(&id),
+    ];
+    tables.iter().any(|exists| exists(id))
+}
+
+/// Implements KillClient: closes the connection of the client owning `resource`, or
+/// frees the resources it left behind. AllTemporary frees the resources of every client
+/// that disconnected in RetainTemporary mode.
+pub fn kill_client(resource: u32) -> Result<(), XError> {
+    if resource == ALL_TEMPORARY {
+        let temporary: Vec<u32> = RETAINED_CLIENTS
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, state)| state.close_down_mode == RETAIN_TEMPORARY)
+            .map(|(client, _)| *client)
+            .collect();
+        for client in temporary {
+            let state = RETAINED_CLIENTS.lock().unwrap().remove(&client).unwrap();
+            free_client_resources(client, &state.save_set);
+        }
+        return Ok(());
+    }
+    let client = resource_owner(resource);
+    if client == 0 || !resource_exists(resource) {
+        return Err(XError::new(ErrorCode::Value, resource));
+    }
+    if let Some(state) = CLIENTS.lock().unwrap().get_mut(&client) {
+        state.killed = true;
+        return Ok(());
+    }
+    let retained = RETAINED_CLIENTS.lock().unwrap().remove(&client);
+    if let Some(state) = retained {
+        free_client_resources(client, &state.save_set);
+    }
+    Ok(())
+}
+
+impl<T: Read + Write> Connection<T> {
+    pub fn flush_events(&mut self) {
+        let events: Vec<Event> = match CLIENTS.lock().unwrap().get_mut(&self.client) {
+            Some(client) => client.events.drain(..).collect(),
+            None => return,
+        };
+        for event in events {
+            let bytes = self.event_bytes(&event);
+            if self.stream.write_all(&bytes).is_err() {
+                return;
+            }
+        }
+    }
+}
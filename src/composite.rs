@@ -0,0 +1,401 @@
+//! The COMPOSITE extension, version 0.4. A redirected window and the inferiors that
+//! aren't redirected themselves draw to the window's backing pixmap instead of the
+//! screen. The server paints automatically redirected windows into their parent's
+//! surface whenever either changes; manually redirected windows are left to a
+//! compositing manager, which names their pixmaps and draws on the overlay window.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    error::{ErrorCode, XError},
+    extension::ExtensionRequest,
+    picture::Drawables,
+    pixmap::{Pixmap, PIXMAPS},
+    region::Region,
+    screen::DEFAULT_SCREEN,
+    shm::Mapping,
+    window::{
+        destroy_window, get_window, is_viewable, surface_origin, validate, Background, Window, INPUT_ONLY,
+        WINDOWS,
+    },
+    xfixes::create_region,
+};
+
+pub static COMPOSITE_NAME: &str = "Composite";
+pub const COMPOSITE_MAJOR_VERSION: u32 = 0;
+pub const COMPOSITE_MINOR_VERSION: u32 = 4;
+
+pub const REDIRECT_AUTOMATIC: u8 = 0;
+pub const REDIRECT_MANUAL: u8 = 1;
+
+/// The backing pixmaps of viewable redirected windows, by window. Lock after `PIXMAPS`
+/// and before the framebuffer.
+pub static BACKING_PIXMAPS: Mutex<BTreeMap<u32, Pixmap>> = Mutex::new(BTreeMap::new());
+
+/// The overlay window, which the server creates for the first client that asks for it.
+pub const OVERLAY_WINDOW: u32 = 0x101;
+
+/// The clients that got the overlay window and haven't released it. Lock after
+/// `WINDOWS`.
+static OVERLAY_CLIENTS: Mutex<BTreeSet<u32>> = Mutex::new(BTreeSet::new());
+
+/// How `id` is redirected, if it is: manually if any client asked for that, else
+/// automatically. The root, InputOnly windows and the overlay window aren't redirected.
+pub fn redirect_mode(windows: &BTreeMap<u32, Window>, id: u32) -> Option<u8> {
+    let window = windows.get(&id)?;
+    if window.class == INPUT_ONLY || id == OVERLAY_WINDOW {
+        return None;
+    }
+    let parent = windows.get(&window.parent)?;
+    window.redirects.values().chain(parent.subwindow_redirects.values()).max().copied()
+}
+
+/// The surface the server paints an automatically redirected window into, and where it
+/// shows there in screen coordinates.
+pub fn composited_into(windows: &BTreeMap<u32, Window>, id: u32) -> Option<(u32, &Region)> {
+    let window = windows.get(&id).filter(|window| window.composited && window.surface == id)?;
+    Some((windows[&window.parent].surface, &window.outer_clip))
+}
+
+/// Lists the automatically redirected windows composited into `surface` under `id`,
+/// bottom-most first.
+fn composited_children(windows: &BTreeMap<u32, Window>, id: u32, surface: u32, composited: &mut Vec<u32>) {
+    for child in &windows[&id].children {
+        if windows[child].surface == surface {
+            composited_children(windows, *child, surface, composited);
+        } else if composited_into(windows, *child).is_some() {
+            composited.push(*child);
+        }
+    }
+}
+
+/// Paints the automatically redirected windows composited into `surface` over
+/// `region` of it, in screen coordinates.
+pub fn composite_children(drawables: &mut Drawables, surface: u32, region: &Region) {
+    let windows = drawables.windows;
+    let top = match surface {
+        0 => DEFAULT_SCREEN.root_window,
+        surface => surface,
+    };
+    let mut composited = vec![];
+    composited_children(windows, top, surface, &mut composited);
+    for id in composited {
+        let clip = windows[&id].outer_clip.intersect(region);
+        if clip.is_empty() {
+            continue;
+        }
+        let (origin_x, origin_y) = surface_origin(windows, id);
+        let Some(backing) = drawables.backing_pixmaps.get(&id) else {
+            continue;
+        };
+        let mut pixels = vec![];
+        for r in &clip.rectangles {
+            for y in r.y..r.y + r.height {
+                for x in r.x..r.x + r.width {
+                    pixels.push((x, y, backing.get(x - origin_x, y - origin_y)));
+                }
+            }
+        }
+        let Some((destination, surface_x, surface_y)) = drawables.surface(surface) else {
+            return;
+        };
+        for (x, y, pixel) in pixels {
+            destination.set(x - surface_x, y - surface_y, pixel);
+        }
+    }
+}
+
+/// A zeroed backing pixmap. Its pixels are in memory pixmaps naming it can share, so
+/// they keep showing what the window draws.
+fn backing_pixmap(width: u16, height: u16, depth: u8) -> Pixmap {
+    match Mapping::anonymous(width as usize * height as usize * 4) {
+        Some(mapping) => Pixmap::shared(width, height, depth, Arc::new(mapping), 0),
+        None => Pixmap::new(width, height, depth),
+    }
+}
+
+/// Gives each viewable redirected window a backing pixmap the size of the window and
+/// its border, and drops the others. A window that changes size gets a new pixmap with
+/// the old contents at its top-left corner; pixmaps naming the old one keep it. Returns
+/// the windows whose pixmaps are new and need painting.
+pub fn update_backing_pixmaps(windows: &BTreeMap<u32, Window>) -> BTreeSet<u32> {
+    let redirected = |id: u32| windows.get(&id).is_some_and(|w| w.surface == id && is_viewable(windows, id));
+    let mut backing_pixmaps = BACKING_PIXMAPS.lock().unwrap();
+    backing_pixmaps.retain(|id, _| redirected(*id));
+    let mut fresh = BTreeSet::new();
+    for window in windows.values().filter(|window| redirected(window.id)) {
+        let outer = window.outer_rectangle();
+        let (width, height) = (outer.width as u16, outer.height as u16);
+        let old = backing_pixmaps.get(&window.id);
+        if old.is_some_and(|old| (old.width, old.height, old.depth) == (width, height, window.depth)) {
+            continue;
+        }
+        let mut pixmap = backing_pixmap(width, height, window.depth);
+        match old {
+            Some(old) => {
+                if let Some(kept) = old.bounds().intersect(&pixmap.bounds()) {
+                    for y in 0..kept.height {
+                        for x in 0..kept.width {
+                            pixmap.set(x, y, old.get(x, y));
+                        }
+                    }
+                }
+            }
+            None => {
+                fresh.insert(window.id);
+            }
+        }
+        backing_pixmaps.insert(window.id, pixmap);
+    }
+    fresh
+}
+
+/// Keeps the overlay window the size of the root window.
+pub fn resize_overlay(windows: &mut BTreeMap<u32, Window>, width: u16, height: u16) {
+    if let Some(overlay) = windows.get_mut(&OVERLAY_WINDOW) {
+        overlay.width = width;
+        overlay.height = height;
+    }
+}
+
+/// Releases `client`'s hold on the overlay window, destroying the window once nobody
+/// holds it. Returns false if the client didn't hold it.
+fn release_overlay(windows: &mut BTreeMap<u32, Window>, client: u32) -> bool {
+    let mut clients = OVERLAY_CLIENTS.lock().unwrap();
+    if !clients.remove(&client) {
+        return false;
+    }
+    if clients.is_empty() {
+        drop(clients);
+        let _ = destroy_window(windows, OVERLAY_WINDOW);
+    }
+    true
+}
+
+/// Drops the redirections of a client whose resources are destroyed, and its hold on
+/// the overlay window.
+pub fn free_client_composite(windows: &mut BTreeMap<u32, Window>, client: u32) {
+    let mut changed = false;
+    for window in windows.values_mut() {
+        changed |= window.redirects.remove(&client).is_some();
+        changed |= window.subwindow_redirects.remove(&client).is_some();
+    }
+    release_overlay(windows, client);
+    if changed {
+        validate(windows);
+    }
+}
+
+/// Checks that `client` may redirect `window` with `update`. Only one client may redirect
+/// a window manually, and no client may redirect the same window twice.
+fn check_redirect(window: &Window, parent: &Window, client: u32, update: u8) -> Result<(), XError> {
+    let redirects = window.redirects.iter().chain(&parent.subwindow_redirects);
+    for (redirecting, mode) in redirects {
+        if *redirecting == client || (update == REDIRECT_MANUAL && *mode == REDIRECT_MANUAL) {
+            return Err(XError::new(ErrorCode::Access, window.id));
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+pub enum CompositeRequest {
+    QueryVersion {
+        major_version: u32,
+        minor_version: u32,
+    },
+    RedirectWindow {
+        window: u32,
+        update: u8,
+    },
+    RedirectSubwindows {
+        window: u32,
+        update: u8,
+    },
+    UnredirectWindow {
+        window: u32,
+        update: u8,
+    },
+    UnredirectSubwindows {
+        window: u32,
+        update: u8,
+    },
+    CreateRegionFromBorderClip {
+        region: u32,
+        window: u32,
+    },
+    NameWindowPixmap {
+        window: u32,
+        pixmap: u32,
+    },
+    GetOverlayWindow {
+        window: u32,
+    },
+    ReleaseOverlayWindow {
+        window: u32,
+    },
+}
+
+fn read_composite_request(request: &ExtensionRequest) -> Result<CompositeRequest, XError> {
+    let data = request.data;
+    let minimum_length = match request.minor_opcode {
+        0..=6 => 8,
+        7 | 8 => 4,
+        minor_opcode => return Err(XError::new(ErrorCode::Request, minor_opcode as u32)),
+    };
+    if data.len() < minimum_length {
+        return Err(XError::new(ErrorCode::Length, 0));
+    }
+    Ok(match request.minor_opcode {
+        0 => CompositeRequest::QueryVersion {
+            major_version: request.card32(data),
+            minor_version: request.card32(&data[4..]),
+        },
+        1 => CompositeRequest::RedirectWindow {
+            window: request.card32(data),
+            update: data[4],
+        },
+        2 => CompositeRequest::RedirectSubwindows {
+            window: request.card32(data),
+            update: data[4],
+        },
+        3 => CompositeRequest::UnredirectWindow {
+            window: request.card32(data),
+            update: data[4],
+        },
+        4 => CompositeRequest::UnredirectSubwindows {
+            window: request.card32(data),
+            update: data[4],
+        },
+        5 => CompositeRequest::CreateRegionFromBorderClip {
+            region: request.card32(data),
+            window: request.card32(&data[4..]),
+        },
+        6 => CompositeRequest::NameWindowPixmap {
+            window: request.card32(data),
+            pixmap: request.card32(&data[4..]),
+        },
+        7 => CompositeRequest::GetOverlayWindow {
+            window: request.card32(data),
+        },
+        _ => CompositeRequest::ReleaseOverlayWindow {
+            window: request.card32(data),
+        },
+    })
+}
+
+pub fn handle_composite_request(request: &ExtensionRequest) -> Result<Option<Vec<u8>>, XError> {
+    let composite_request = read_composite_request(request)?;
+    let client = request.client;
+    match composite_request {
+        CompositeRequest::QueryVersion { .. } => {
+            let mut body = request.to_bytes_32(COMPOSITE_MAJOR_VERSION).to_vec();
+            body.extend(request.to_bytes_32(COMPOSITE_MINOR_VERSION));
+            Ok(Some(request.reply(0, body)))
+        }
+        CompositeRequest::RedirectWindow { window, update } => {
+            let mut windows = WINDOWS.lock().unwrap();
+            let w = get_window(&windows, window)?;
+            if update > REDIRECT_MANUAL {
+                return Err(XError::new(ErrorCode::Value, update as u32));
+            }
+            if w.parent == 0 || w.class == INPUT_ONLY || window == OVERLAY_WINDOW {
+                return Err(XError::new(ErrorCode::Match, window));
+            }
+            check_redirect(w, &windows[&w.parent], client, update)?;
+            windows.get_mut(&window).unwrap().redirects.insert(client, update);
+            validate(&mut windows);
+            Ok(None)
+        }
+        CompositeRequest::RedirectSubwindows { window, update } => {
+            let mut windows = WINDOWS.lock().unwrap();
+            let w = get_window(&windows, window)?;
+            if update > REDIRECT_MANUAL {
+                return Err(XError::new(ErrorCode::Value, update as u32));
+            }
+            for child in &w.children {
+                check_redirect(&windows[child], w, client, update)?;
+            }
+            // A parent without children still can't be redirected twice by a client.
+            if w.subwindow_redirects.contains_key(&client) {
+                return Err(XError::new(ErrorCode::Access, window));
+            }
+            windows.get_mut(&window).unwrap().subwindow_redirects.insert(client, update);
+            validate(&mut windows);
+            Ok(None)
+        }
+        CompositeRequest::UnredirectWindow { window, update } => {
+            let mut windows = WINDOWS.lock().unwrap();
+            let w = get_window(&windows, window)?;
+            if w.redirects.get(&client) != Some(&update) {
+                return Err(XError::new(ErrorCode::Value, window));
+            }
+            windows.get_mut(&window).unwrap().redirects.remove(&client);
+            validate(&mut windows);
+            Ok(None)
+        }
+        CompositeRequest::UnredirectSubwindows { window, update } => {
+            let mut windows = WINDOWS.lock().unwrap();
+            let w = get_window(&windows, window)?;
+            if w.subwindow_redirects.get(&client) != Some(&update) {
+                return Err(XError::new(ErrorCode::Value, window));
+            }
+            windows.get_mut(&window).unwrap().subwindow_redirects.remove(&client);
+            validate(&mut windows);
+            Ok(None)
+        }
+        CompositeRequest::CreateRegionFromBorderClip { region, window } => {
+            let windows = WINDOWS.lock().unwrap();
+            let w = get_window(&windows, window)?;
+            let border_clip = w.outer_clip.translate(-w.screen_rectangle.x, -w.screen_rectangle.y);
+            drop(windows);
+            create_region(region, border_clip)?;
+            Ok(None)
+        }
+        CompositeRequest::NameWindowPixmap { window, pixmap } => {
+            let windows = WINDOWS.lock().unwrap();
+            get_window(&windows, window)?;
+            let mut pixmaps = PIXMAPS.lock().unwrap();
+            let backing_pixmaps = BACKING_PIXMAPS.lock().unwrap();
+            let backing = backing_pixmaps.get(&window).ok_or(XError::new(ErrorCode::Match, window))?;
+            if pixmaps.contains_key(&pixmap) || windows.contains_key(&pixmap) {
+                return Err(XError::new(ErrorCode::IDChoice, pixmap));
+            }
+            pixmaps.insert(pixmap, backing.share());
+            Ok(None)
+        }
+        CompositeRequest::GetOverlayWindow { window } => {
+            let mut windows = WINDOWS.lock().unwrap();
+            get_window(&windows, window)?;
+            if !windows.contains_key(&OVERLAY_WINDOW) {
+                let root = &windows[&DEFAULT_SCREEN.root_window];
+                let mut overlay = Window::new(OVERLAY_WINDOW, root.id);
+                overlay.width = root.width;
+                overlay.height = root.height;
+                overlay.depth = root.depth;
+                overlay.visual = root.visual;
+                overlay.attributes.colormap = root.attributes.colormap;
+                overlay.background = Background::None;
+                overlay.mapped = true;
+                // Input goes to the windows underneath.
+                overlay.shape.input = Some(Region::new());
+                windows.insert(OVERLAY_WINDOW, overlay);
+                windows.get_mut(&DEFAULT_SCREEN.root_window).unwrap().children.push(OVERLAY_WINDOW);
+                validate(&mut windows);
+            }
+            OVERLAY_CLIENTS.lock().unwrap().insert(client);
+            Ok(Some(request.reply(0, request.to_bytes_32(OVERLAY_WINDOW).to_vec())))
+        }
+        CompositeRequest::ReleaseOverlayWindow { window } => {
+            let mut windows = WINDOWS.lock().unwrap();
+            get_window(&windows, window)?;
+            if !release_overlay(&mut windows, client) {
+                return Err(XError::new(ErrorCode::Match, window));
+            }
+            Ok(None)
+        }
+    }
+}
@@ -1,8 +1,11 @@
-use std::io::{Read, Write};
+use std::io::{ErrorKind, Read, Write};
 
 use crate::{
+    client::{is_killed, register_client},
+    input::MOTION_BUFFER_SIZE,
+    keyboard::{MAX_KEYCODE, MIN_KEYCODE},
     pixmap::DEFAULT_PIXMAP_FORMATS,
-    screen::{Depth, Screen, Visual},
+    screen::{Depth, Screen, DEFAULT_SCREEN, DEFAULT_VISUAL, SCREEN_SIZE},
     VENDOR,
 };
 
@@ -11,6 +14,7 @@ pub struct Connection<T: Read + Write> {
     pub stream: T,
     pub endianness: Endianness,
     pub sequence_number: u16,
+    pub client: u32,
 }
 
 #[derive(Clone, Debug)]
@@ -120,7 +124,7 @@ pub struct ConnSetup {
 
 pub fn establish_connection<T: Read + Write>(mut stream: T) -> Option<Connection<T>> {
     let mut client_prefix_bytes = [0u8; 12];
-    stream.read(&mut client_prefix_bytes).ok()?;
+    stream.read_exact(&mut client_prefix_bytes).ok()?;
     let endianness = match client_prefix_bytes[0] {
         b'B' => Endianness::Big,
         b'l' => Endianness::Little,
@@ -137,22 +141,24 @@ pub fn establish_connection<T: Read + Write>(mut stream: T) -> Option<Connection
 
     //let _auth_bytes = stream.read(&mut vec![0u8;(client_prefix.n_auth_name+pad(client_prefix.n_auth_name as usize) as u16+client_prefix.d_auth_data+pad(client_prefix.d_auth_data as usize) as u16) as usize]);
 
+    let client = register_client();
+
     /* Append Connection Setup */
     let conn_setup = ConnSetup {
         release: 1,
-        rid_base: 0x4600000,
+        rid_base: client << 21,
         rid_mask: 0x01fffff,
-        motion_buffer_size: 256,
+        motion_buffer_size: MOTION_BUFFER_SIZE as u32,
         v_bytes_vendor: VENDOR.len() as u16,
         max_request_size: 65535,
         num_roots: 1,
-        num_formats: 1,
+        num_formats: DEFAULT_PIXMAP_FORMATS.len() as u8,
         image_byte_order: 0,
         bitmap_bit_order: 0,
         bitmap_scanline_unit: 32,
         bitmap_scanline_pad: 32,
-        min_keycode: 8,
-        max_keycode: 255,
+        min_keycode: MIN_KEYCODE,
+        max_keycode: MAX_KEYCODE,
         pad2: 0,
     };
 
@@ -194,23 +200,13 @@ pub fn establish_connection<T: Read + Write>(mut stream: T) -> Option<Connection
 
     /* Define some defaults */
 
+    let size = *SCREEN_SIZE.lock().unwrap();
     let screen = Screen {
-        root_window: 1,
-        default_colormap: 1,
-        white_pixel: 1,
-        black_pixel: 0,
-        current_input_masks: 0,
-        width_px: 1920,
-        height_px: 1080,
-        width_mm: 192,
-        height_mm: 108,
-        min_installed_maps: 1,
-        max_installed_maps: 1,
-        root_visual: 1,
-        backing_stores: 0,
-        save_unders: 0,
-        root_depth: 1,
-        num_depths: 1,
+        width_px: size.width,
+        height_px: size.height,
+        width_mm: size.mm_width,
+        height_mm: size.mm_height,
+        ..DEFAULT_SCREEN
     };
     let depth = Depth {
         depth: 1,
@@ -218,16 +214,7 @@ pub fn establish_connection<T: Read + Write>(mut stream: T) -> Option<Connection
         number_of_visuals: 1,
         pad1: 0,
     };
-    let visual = Visual {
-        visual_id: 1,
-        class: 4,
-        bits_per_rgb_val: 32,
-        colormap_entries: 256,
-        red_mask: 0xFF,
-        green_mask: 0xFF00,
-        blue_mask: 0xFF0000,
-        pad0: 0,
-    };
+    let visual = DEFAULT_VISUAL;
 
     /* Append Screens */
 
@@ -284,17 +271,42 @@ pub fn establish_connection<T: Read + Write>(mut stream: T) -> Option<Connection
             .to_vec(),
     );
 
-    stream.write(&prefix_data).ok();
-    stream.write(&additional_data).ok()?;
+    stream.write_all(&prefix_data).ok();
+    stream.write_all(&additional_data).ok()?;
 
     Some(Connection {
         stream,
         endianness,
         sequence_number: 0,
+        client,
     })
 }
 
 impl<T: Read + Write> Connection<T> {
+    /// Fills `buffer` from the stream, flushing queued events whenever the stream is idle.
+    pub fn read_bytes(&mut self, buffer: &mut [u8]) -> Option<()> {
+        let mut offset = 0;
+        while offset < buffer.len() {
+            if is_killed(self.client) {
+                return None;
+            }
+            match self.stream.read(&mut buffer[offset..]) {
+                Ok(0) => return None,
+                Ok(n) => offset += n,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted
+                    ) =>
+                {
+                    self.flush_events();
+                }
+                Err(_) => return None,
+            }
+        }
+        Some(())
+    }
+
     pub fn card32(&self, bytes: &[u8]) -> u32 {
         self.endianness.card32(bytes)
     }
@@ -0,0 +1,154 @@
+//! A line-based control socket for driving the server from test harnesses.
+//!
+//! Each line is one command, answered with `ok` or `error: <reason>`:
+//!
+//! ```text
+//! key <keycode> press|release
+//! keysym <keysym> press|release    (0x-prefixed hex, decimal, or a single character)
+//! button <button> press|release
+//! motion <x> <y>                   (absolute root coordinates)
+//! move <dx> <dy>                   (relative to the current position)
+//! scroll <dx> <dy>                 (smooth scrolling by whole steps, positive down and right)
+//! touch <slot> begin|update|end <x> <y>
+//! advance <milliseconds>           (moves the clock when started with --virtual-clock)
+//! keymap <path>                    (replaces the keymap with an XKB keymap file)
+//! ```
+
+use std::{
+    io::{BufRead, BufReader, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    thread,
+};
+
+use crate::{
+    input::{
+        inject_button, inject_key, inject_keysym, inject_motion, inject_relative_motion, inject_scroll,
+        inject_touch, is_valid_button, is_valid_keycode,
+    },
+    time,
+    touch::TouchPhase,
+    xkb,
+};
+
+pub static CONTROL_SOCKET: &str = "/tmp/xaugh-control";
+
+/// Binds the control socket and serves it on a background thread.
+pub fn spawn_control_socket(path: &str) -> std::io::Result<()> {
+    let _ = std::fs::remove_file(path);
+    let listener = UnixListener::bind(path)?;
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            thread::spawn(|| handle_control_stream(stream));
+        }
+    });
+    Ok(())
+}
+
+fn handle_control_stream(stream: UnixStream) -> Option<()> {
+    let mut writer = stream.try_clone().ok()?;
+    for line in BufReader::new(stream).lines() {
+        let reply = match run_command(&line.ok()?) {
+            Ok(()) => "ok\n".to_string(),
+            Err(reason) => format!("error: {reason}\n"),
+        };
+        writer.write_all(reply.as_bytes()).ok()?;
+    }
+    Some(())
+}
+
+fn parse_number(word: Option<&str>) -> Result<i64, String> {
+    let word = word.ok_or("missing argument")?;
+    let parsed = match word.strip_prefix("0x") {
+        Some(hex) => i64::from_str_radix(hex, 16),
+        None => word.parse(),
+    };
+    parsed.map_err(|_| format!("invalid number {word}"))
+}
+
+fn parse_pressed(word: Option<&str>) -> Result<bool, String> {
+    match word {
+        Some("press") => Ok(true),
+        Some("release") => Ok(false),
+        other => Err(format!("expected press or release, got {other:?}")),
+    }
+}
+
+fn parse_keysym(word: Option<&str>) -> Result<u32, String> {
+    let word = word.ok_or("missing argument")?;
+    let mut chars = word.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) if c.is_ascii_graphic() => Ok(c as u32),
+        _ => Ok(parse_number(Some(word))? as u32),
+    }
+}
+
+pub fn run_command(line: &str) -> Result<(), String> {
+    let mut words = line.split_whitespace();
+    match words.next() {
+        Some("key") => {
+            let keycode = parse_number(words.next())?;
+            let keycode = match u8::try_from(keycode) {
+                Ok(keycode) if is_valid_keycode(keycode) => keycode,
+                _ => return Err(format!("keycode {keycode} out of range")),
+            };
+            inject_key(keycode, parse_pressed(words.next())?);
+        }
+        Some("keysym") => {
+            let keysym = parse_keysym(words.next())?;
+            if !inject_keysym(keysym, parse_pressed(words.next())?) {
+                return Err(format!("no keycode produces keysym {keysym:#x}"));
+            }
+        }
+        Some("button") => {
+            let button = parse_number(words.next())?;
+            let button = match u8::try_from(button) {
+                Ok(button) if is_valid_button(button) => button,
+                _ => return Err(format!("button {button} out of range")),
+            };
+            inject_button(button, parse_pressed(words.next())?);
+        }
+        Some("motion") => {
+            let x = parse_number(words.next())?;
+            let y = parse_number(words.next())?;
+            inject_motion(x as i32, y as i32);
+        }
+        Some("move") => {
+            let dx = parse_number(words.next())?;
+            let dy = parse_number(words.next())?;
+            inject_relative_motion(dx as i32, dy as i32);
+        }
+        Some("scroll") => {
+            let dx = parse_number(words.next())?;
+            let dy = parse_number(words.next())?;
+            inject_scroll(dx as i32, dy as i32);
+        }
+        Some("touch") => {
+            let slot = parse_number(words.next())?;
+            let slot = u32::try_from(slot).map_err(|_| "slot out of range")?;
+            let phase = match words.next() {
+                Some("begin") => TouchPhase::Begin,
+                Some("update") => TouchPhase::Update,
+                Some("end") => TouchPhase::End,
+                other => return Err(format!("expected begin, update or end, got {other:?}")),
+            };
+            let x = parse_number(words.next())?;
+            let y = parse_number(words.next())?;
+            inject_touch(slot, phase, x as i32, y as i32);
+        }
+        Some("advance") => {
+            let milliseconds = parse_number(words.next())?;
+            let milliseconds = u32::try_from(milliseconds).map_err(|_| "milliseconds out of range")?;
+            if !time::advance(milliseconds) {
+                return Err("the server is not running on a virtual clock".to_string());
+            }
+        }
+        Some("keymap") => {
+            let path = words.next().ok_or("missing argument")?;
+            let text = std::fs::read_to_string(path).map_err(|error| format!("can't read {path}: {error}"))?;
+            xkb::replace_keymap(&text)?;
+        }
+        Some(command) => return Err(format!("unknown command {command}")),
+        None => return Err("empty command".to_string()),
+    }
+    Ok(())
+}
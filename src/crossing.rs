@@ -0,0 +1,164 @@
+use std::collections::BTreeMap;
+
+use crate::{
+    client::queue_event,
+    event::{deliver_event, Event, ENTER_WINDOW_MASK, LEAVE_WINDOW_MASK},
+    focus::{
+        Focus, NOTIFY_ANCESTOR, NOTIFY_INFERIOR, NOTIFY_NONLINEAR, NOTIFY_NONLINEAR_VIRTUAL,
+        NOTIFY_NORMAL, NOTIFY_VIRTUAL,
+    },
+    input::{window_at, DeviceEvent, InputState},
+    screen::DEFAULT_SCREEN,
+    time,
+    window::{child_towards, common_ancestor, is_inferior_or_self, is_parent, Window},
+    xinput::{XICrossingEvent, XIModifiers, VIRTUAL_CORE_POINTER, XI_ENTER, XI_LEAVE},
+};
+
+/// Returns the windows strictly between `id` and its ancestor `ancestor`, bottom-most first.
+fn windows_between(windows: &BTreeMap<u32, Window>, id: u32, ancestor: u32) -> Vec<u32> {
+    let mut between = vec![];
+    let mut window = windows[&id].parent;
+    while window != ancestor && window != 0 {
+        between.push(window);
+        window = windows[&window].parent;
+    }
+    between
+}
+
+impl InputState {
+    fn crossing_event(
+        &self,
+        windows: &BTreeMap<u32, Window>,
+        enter: bool,
+        window: u32,
+        detail: u8,
+        mode: u8,
+        (from, to): (u32, u32),
+    ) {
+        let w = &windows[&window];
+        // The child on the way to the pointer's old position for LeaveNotify, or its new
+        // position for EnterNotify, falling back to the other end for Inferior crossings.
+        let (near, far) = if enter { (to, from) } else { (from, to) };
+        let child = match child_towards(windows, window, near) {
+            0 => child_towards(windows, window, far),
+            child => child,
+        };
+        let focus = match self.focus {
+            Focus::None => false,
+            Focus::PointerRoot => true,
+            Focus::Window(focus) => is_inferior_or_self(windows, window, focus),
+        };
+        let device_event = DeviceEvent {
+            detail,
+            time: time::now(),
+            root: DEFAULT_SCREEN.root_window,
+            event: window,
+            child,
+            root_x: self.pointer_x,
+            root_y: self.pointer_y,
+            event_x: self.pointer_x - w.screen_rectangle.x as i16,
+            event_y: self.pointer_y - w.screen_rectangle.y as i16,
+            state: self.state(),
+            same_screen: true,
+            auto_repeat: false,
+        };
+        let xi_type = if enter { XI_ENTER } else { XI_LEAVE };
+        let xi_event = Event::XICrossing(XICrossingEvent {
+            evtype: xi_type,
+            mode,
+            focus,
+            device_event,
+            modifiers: XIModifiers::current(device_event.state),
+        });
+        let (mask, event) = match enter {
+            true => (
+                ENTER_WINDOW_MASK,
+                Event::EnterNotify {
+                    device_event,
+                    mode,
+                    focus,
+                },
+            ),
+            false => (
+                LEAVE_WINDOW_MASK,
+                Event::LeaveNotify {
+                    device_event,
+                    mode,
+                    focus,
+                },
+            ),
+        };
+        match &self.pointer_grab.grab {
+            Some(grab) => {
+                let mut selected = if window == grab.window { grab.event_mask } else { 0 };
+                if grab.owner_events {
+                    selected |= w.event_masks.get(&grab.client).copied().unwrap_or(0);
+                }
+                if selected & mask != 0 {
+                    queue_event(grab.client, event);
+                }
+                if let Some(xi2_mask) = grab.xi2_mask {
+                    let mut selected = if window == grab.window { xi2_mask } else { 0 };
+                    if grab.owner_events {
+                        selected |= w.xi_mask(grab.client, VIRTUAL_CORE_POINTER);
+                    }
+                    if selected & 1 << xi_type != 0 {
+                        queue_event(grab.client, xi_event);
+                    }
+                }
+            }
+            None => {
+                deliver_event(windows, window, mask, event);
+                for client in w.xi_clients(VIRTUAL_CORE_POINTER, xi_type) {
+                    queue_event(client, xi_event.clone());
+                }
+            }
+        }
+    }
+
+    /// Generates the LeaveNotify and EnterNotify events for the pointer moving from the
+    /// window `from` to the window `to`.
+    pub fn crossing_events(&self, windows: &BTreeMap<u32, Window>, from: u32, to: u32, mode: u8) {
+        if from == to || !windows.contains_key(&from) || !windows.contains_key(&to) {
+            return;
+        }
+        let leave = |window, detail| self.crossing_event(windows, false, window, detail, mode, (from, to));
+        let enter = |window, detail| self.crossing_event(windows, true, window, detail, mode, (from, to));
+        if is_parent(windows, from, to) {
+            leave(from, NOTIFY_INFERIOR);
+            for window in windows_between(windows, to, from).into_iter().rev() {
+                enter(window, NOTIFY_VIRTUAL);
+            }
+            enter(to, NOTIFY_ANCESTOR);
+        } else if is_parent(windows, to, from) {
+            leave(from, NOTIFY_ANCESTOR);
+            for window in windows_between(windows, from, to) {
+                leave(window, NOTIFY_VIRTUAL);
+            }
+            enter(to, NOTIFY_INFERIOR);
+        } else {
+            let common = common_ancestor(windows, from, to);
+            leave(from, NOTIFY_NONLINEAR);
+            for window in windows_between(windows, from, common) {
+                leave(window, NOTIFY_NONLINEAR_VIRTUAL);
+            }
+            for window in windows_between(windows, to, common).into_iter().rev() {
+                enter(window, NOTIFY_NONLINEAR_VIRTUAL);
+            }
+            enter(to, NOTIFY_NONLINEAR);
+        }
+    }
+
+    /// Recomputes the window under the pointer after the pointer or the window tree
+    /// moved, sending crossing events if it changed.
+    pub fn update_pointer_window(&mut self, windows: &BTreeMap<u32, Window>) {
+        let window = window_at(windows, self.pointer_x as i32, self.pointer_y as i32);
+        let old = self.pointer_window;
+        if window != old {
+            self.motion_hint_window = 0;
+        }
+        self.pointer_window = window;
+        self.crossing_events(windows, old, window, NOTIFY_NORMAL);
+        self.update_cursor(windows);
+    }
+}
@@ -0,0 +1,263 @@
+//! Cursors and the cursor the pointer shows: the cursor of the pointer grab, or else of
+//! the window under the pointer or its closest ancestor that defines one. Cursors are
+//! never drawn, but clients can read the image through XFIXES.
+
+use std::{collections::BTreeMap, sync::Mutex};
+
+use crate::{
+    client::resource_owner,
+    error::{ErrorCode, XError},
+    input::{InputState, INPUT},
+    pixmap::PIXMAPS,
+    window::{is_inferior_or_self, Window},
+    xfixes::cursor_notify,
+};
+
+/// Cursors by id. Lock after `WINDOWS`, `INPUT`, `PICTURES` and `PIXMAPS`.
+pub static CURSORS: Mutex<BTreeMap<u32, Cursor>> = Mutex::new(BTreeMap::new());
+
+/// The serial number given to the next cursor created. The default cursor has serial 1.
+static NEXT_SERIAL: Mutex<u32> = Mutex::new(2);
+
+const DEFAULT_SERIAL: u32 = 1;
+
+/// The pixel values of bitmap cursors, which RecolorCursor replaces.
+const TRANSPARENT: u8 = 0;
+const BACKGROUND: u8 = 1;
+const FOREGROUND: u8 = 2;
+
+#[derive(Clone, Debug)]
+pub struct Cursor {
+    pub width: u16,
+    pub height: u16,
+    pub x_hot: u16,
+    pub y_hot: u16,
+    /// Premultiplied ARGB pixels, row by row.
+    pub pixels: Vec<u32>,
+    /// Which pixels of a cursor made from bitmaps are transparent, background or
+    /// foreground, so RecolorCursor can repaint them.
+    pub bits: Option<Vec<u8>>,
+    pub serial: u32,
+    /// The name atom set through XFIXES, or 0.
+    pub name: u32,
+    /// Set by FreeCursor. A freed cursor stays around while windows or grabs use it.
+    pub freed: bool,
+}
+
+impl Cursor {
+    pub fn new(width: u16, height: u16, x_hot: u16, y_hot: u16, pixels: Vec<u32>) -> Cursor {
+        let mut next_serial = NEXT_SERIAL.lock().unwrap();
+        let serial = *next_serial;
+        *next_serial = next_serial.wrapping_add(1).max(DEFAULT_SERIAL + 1);
+        Cursor {
+            width,
+            height,
+            x_hot,
+            y_hot,
+            pixels,
+            bits: None,
+            serial,
+            name: 0,
+            freed: false,
+        }
+    }
+
+    /// The cursor shown where no window defines one: a black X outlined in white.
+    pub fn default_cursor() -> Cursor {
+        let pixels = (0..16 * 16i32)
+            .map(|i| {
+                let (x, y) = (i % 16, i / 16);
+                let distance = (x - y).abs().min((x + y - 14).abs());
+                match distance {
+                    _ if x == 15 || y == 15 => 0,
+                    0 | 1 => 0xff000000,
+                    2 => 0xffffffff,
+                    _ => 0,
+                }
+            })
+            .collect();
+        Cursor {
+            width: 16,
+            height: 16,
+            x_hot: 7,
+            y_hot: 7,
+            pixels,
+            bits: None,
+            serial: DEFAULT_SERIAL,
+            name: 0,
+            freed: false,
+        }
+    }
+
+    fn paint(&mut self, foreground: [u16; 3], background: [u16; 3]) {
+        let argb = |[red, green, blue]: [u16; 3]| {
+            0xff000000 | (red as u32 >> 8) << 16 | (green as u32 >> 8) << 8 | blue as u32 >> 8
+        };
+        let Some(bits) = &self.bits else {
+            return;
+        };
+        self.pixels = bits
+            .iter()
+            .map(|bit| match *bit {
+                FOREGROUND => argb(foreground),
+                BACKGROUND => argb(background),
+                _ => 0,
+            })
+            .collect();
+    }
+}
+
+fn bad_cursor(cursor: u32) -> XError {
+    XError::new(ErrorCode::Cursor, cursor)
+}
+
+/// Looks up a cursor that hasn't been freed.
+pub fn get_cursor(cursors: &BTreeMap<u32, Cursor>, id: u32) -> Result<&Cursor, XError> {
+    cursors.get(&id).filter(|cursor| !cursor.freed).ok_or(bad_cursor(id))
+}
+
+/// Checks a cursor argument, where 0 stands for None.
+pub fn check_cursor(id: u32) -> Result<(), XError> {
+    if id != 0 {
+        get_cursor(&CURSORS.lock().unwrap(), id)?;
+    }
+    Ok(())
+}
+
+/// Adds a cursor, which may replace a freed cursor with the same id.
+pub fn add_cursor(id: u32, cursor: Cursor) -> Result<(), XError> {
+    let mut cursors = CURSORS.lock().unwrap();
+    if cursors.get(&id).is_some_and(|cursor| !cursor.freed) {
+        return Err(XError::new(ErrorCode::IDChoice, id));
+    }
+    cursors.insert(id, cursor);
+    Ok(())
+}
+
+/// Implements CreateCursor. Pixels set in `mask`, or every pixel without a mask, show
+/// the foreground where `source` is set and the background elsewhere.
+pub fn create_cursor(
+    id: u32,
+    source: u32,
+    mask: u32,
+    foreground: [u16; 3],
+    background: [u16; 3],
+    x_hot: u16,
+    y_hot: u16,
+) -> Result<(), XError> {
+    let pixmaps = PIXMAPS.lock().unwrap();
+    let source_pixmap = pixmaps.get(&source).ok_or(XError::new(ErrorCode::Pixmap, source))?;
+    if source_pixmap.depth != 1 {
+        return Err(XError::new(ErrorCode::Match, source));
+    }
+    let mask_pixmap = match mask {
+        0 => None,
+        mask => Some(pixmaps.get(&mask).ok_or(XError::new(ErrorCode::Pixmap, mask))?),
+    };
+    if mask_pixmap.is_some_and(|mask_pixmap| {
+        mask_pixmap.depth != 1
+            || (mask_pixmap.width, mask_pixmap.height) != (source_pixmap.width, source_pixmap.height)
+    }) {
+        return Err(XError::new(ErrorCode::Match, mask));
+    }
+    let (width, height) = (source_pixmap.width, source_pixmap.height);
+    if x_hot >= width || y_hot >= height {
+        return Err(XError::new(ErrorCode::Match, source));
+    }
+    let mut bits = vec![];
+    for y in 0..height as i32 {
+        for x in 0..width as i32 {
+            bits.push(match mask_pixmap.map_or(1, |mask_pixmap| mask_pixmap.get(x, y) & 1) {
+                0 => TRANSPARENT,
+                _ if source_pixmap.get(x, y) & 1 != 0 => FOREGROUND,
+                _ => BACKGROUND,
+            });
+        }
+    }
+    drop(pixmaps);
+    let mut cursor = Cursor {
+        bits: Some(bits),
+        ..Cursor::new(width, height, x_hot, y_hot, vec![])
+    };
+    cursor.paint(foreground, background);
+    add_cursor(id, cursor)
+}
+
+/// Implements RecolorCursor. Cursors made from an image keep their colors.
+pub fn recolor_cursor(id: u32, foreground: [u16; 3], background: [u16; 3]) -> Result<(), XError> {
+    let mut cursors = CURSORS.lock().unwrap();
+    get_cursor(&cursors, id)?;
+    cursors.get_mut(&id).unwrap().paint(foreground, background);
+    Ok(())
+}
+
+/// Implements FreeCursor.
+pub fn free_cursor(windows: &BTreeMap<u32, Window>, input: &InputState, id: u32) -> Result<(), XError> {
+    let mut cursors = CURSORS.lock().unwrap();
+    get_cursor(&cursors, id)?;
+    cursors.get_mut(&id).unwrap().freed = true;
+    prune_cursors(&mut cursors, windows, input);
+    Ok(())
+}
+
+/// Frees the cursors of a client whose resources are destroyed.
+pub fn free_client_cursors(windows: &BTreeMap<u32, Window>, client: u32) {
+    let input = INPUT.lock().unwrap();
+    let mut cursors = CURSORS.lock().unwrap();
+    for (id, cursor) in cursors.iter_mut() {
+        if resource_owner(*id) == client {
+            cursor.freed = true;
+        }
+    }
+    prune_cursors(&mut cursors, windows, &input);
+}
+
+/// Forgets the freed cursors that no window or grab uses any more.
+pub fn prune_cursors(
+    cursors: &mut BTreeMap<u32, Cursor>,
+    windows: &BTreeMap<u32, Window>,
+    input: &InputState,
+) {
+    let grab_cursor = input.pointer_grab.grab.as_ref().map_or(0, |grab| grab.cursor);
+    cursors.retain(|id, cursor| {
+        !cursor.freed || *id == grab_cursor || windows.values().any(|w| w.attributes.cursor == *id)
+    });
+}
+
+impl InputState {
+    /// The id of the cursor the pointer shows, or 0 for the default cursor.
+    pub fn displayed_cursor(&self, windows: &BTreeMap<u32, Window>, cursors: &BTreeMap<u32, Cursor>) -> u32 {
+        let mut window = self.pointer_window;
+        if let Some(grab) = &self.pointer_grab.grab {
+            if cursors.contains_key(&grab.cursor) {
+                return grab.cursor;
+            }
+            // Outside the grab window, the pointer shows the grab window's cursor.
+            if !is_inferior_or_self(windows, window, grab.window) {
+                window = grab.window;
+            }
+        }
+        while let Some(w) = windows.get(&window) {
+            if cursors.contains_key(&w.attributes.cursor) {
+                return w.attributes.cursor;
+            }
+            window = w.parent;
+        }
+        0
+    }
+
+    /// Notes which cursor the pointer shows after anything that may have changed it,
+    /// telling XFIXES clients if it did.
+    pub fn update_cursor(&mut self, windows: &BTreeMap<u32, Window>) {
+        let cursors = CURSORS.lock().unwrap();
+        let (serial, name) = match self.displayed_cursor(windows, &cursors) {
+            0 => (DEFAULT_SERIAL, 0),
+            id => (cursors[&id].serial, cursors[&id].name),
+        };
+        drop(cursors);
+        if serial != self.cursor_serial {
+            self.cursor_serial = serial;
+            cursor_notify(windows, serial, name);
+        }
+    }
+}
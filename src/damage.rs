@@ -0,0 +1,375 @@
+//! The DAMAGE extension, version 1.1, and the damage tracking behind it. Everything that
+//! draws reports the pixels it changed: drawing to a window damages each window in the
+//! same surface whose visible part, border and inferiors included, it touched, and
+//! drawing to a pixmap damages the pixmap. Damage objects collect the damage to their
+//! drawable and tell their client about it at their report level; objects the server
+//! creates for itself, such as for an output backend, are polled with `take_damage`
+//! instead.
+
+use std::{collections::BTreeMap, sync::Mutex};
+
+use crate::{
+    client::{queue_event, resource_owner},
+    composite::{composite_children, composited_into},
+    error::{ErrorCode, XError},
+    event::Event,
+    extension::{find_extension, ExtensionRequest},
+    picture::Drawables,
+    pixmap::PIXMAPS,
+    region::{Rectangle, Region},
+    time,
+    window::{visible_region, Window, WINDOWS},
+    xfixes::{get_region, REGIONS},
+};
+
+pub static DAMAGE_NAME: &str = "DAMAGE";
+pub const DAMAGE_MAJOR_VERSION: u32 = 1;
+pub const DAMAGE_MINOR_VERSION: u32 = 1;
+
+/// Errors relative to the extension's first error.
+const BAD_DAMAGE: u8 = 0;
+
+/// Events relative to the extension's first event.
+pub const DAMAGE_NOTIFY: u8 = 0;
+
+pub const REPORT_RAW_RECTANGLES: u8 = 0;
+pub const REPORT_DELTA_RECTANGLES: u8 = 1;
+pub const REPORT_BOUNDING_BOX: u8 = 2;
+pub const REPORT_NON_EMPTY: u8 = 3;
+
+/// Set in the level of a DamageNotify event when more events for the same change follow.
+pub const DAMAGE_NOTIFY_MORE: u8 = 0x80;
+
+/// Damage objects by id. Lock after `WINDOWS`, `PICTURES`, `PIXMAPS`, the framebuffer
+/// and `REGIONS`.
+pub static DAMAGES: Mutex<BTreeMap<u32, Damage>> = Mutex::new(BTreeMap::new());
+
+/// The id of the next damage object the server creates for itself. These come from the
+/// server's own part of the id space, so they never clash with a client's.
+static NEXT_INTERNAL_DAMAGE: Mutex<u32> = Mutex::new(1);
+
+#[derive(Clone, Debug)]
+pub struct Damage {
+    pub drawable: u32,
+    pub level: u8,
+    /// The size of a pixmap drawable, which never changes, for DamageNotify events.
+    pub pixmap_size: (u16, u16),
+    /// The damage since it was last subtracted, in drawable coordinates. Objects that
+    /// report raw rectangles don't collect it.
+    pub damage: Region,
+}
+
+impl Damage {
+    /// Adds `region` to the damage, returning the areas to report.
+    fn add(&mut self, region: &Region) -> Vec<Rectangle> {
+        if self.level == REPORT_RAW_RECTANGLES {
+            return region.rectangles.clone();
+        }
+        let was_empty = self.damage.is_empty();
+        let old_extents = self.damage.extents();
+        let added = region.subtract(&self.damage);
+        self.damage = self.damage.union(&added);
+        match self.level {
+            REPORT_DELTA_RECTANGLES => added.rectangles,
+            REPORT_BOUNDING_BOX if was_empty || self.damage.extents() != old_extents => {
+                vec![self.damage.extents()]
+            }
+            REPORT_NON_EMPTY if was_empty && !self.damage.is_empty() => vec![Rectangle::default()],
+            _ => vec![],
+        }
+    }
+
+    /// The areas to report for the whole of the current damage.
+    fn current(&self) -> Vec<Rectangle> {
+        match self.level {
+            REPORT_RAW_RECTANGLES | REPORT_DELTA_RECTANGLES => self.damage.rectangles.clone(),
+            REPORT_BOUNDING_BOX => vec![self.damage.extents()],
+            _ => vec![Rectangle::default()],
+        }
+    }
+
+    /// The drawable's position on the screen and size.
+    fn geometry(&self, windows: &BTreeMap<u32, Window>) -> Rectangle {
+        match windows.get(&self.drawable) {
+            Some(window) => window.screen_rectangle,
+            None => Rectangle::new(0, 0, self.pixmap_size.0 as i32, self.pixmap_size.1 as i32),
+        }
+    }
+}
+
+fn bad_damage(damage: u32) -> XError {
+    let first_error = find_extension(DAMAGE_NAME).map_or(0, |e| e.first_error);
+    XError::extension(first_error + BAD_DAMAGE, damage)
+}
+
+/// Sends DamageNotify events for `areas` of damage object `id`. Objects the server
+/// created for itself have no client to tell.
+fn report(windows: &BTreeMap<u32, Window>, id: u32, damage: &Damage, areas: Vec<Rectangle>) {
+    let client = resource_owner(id);
+    if client == 0 || areas.is_empty() {
+        return;
+    }
+    let geometry = damage.geometry(windows);
+    let timestamp = time::now();
+    let count = areas.len();
+    for (index, mut area) in areas.into_iter().enumerate() {
+        // NonEmpty reports the whole drawable.
+        if damage.level == REPORT_NON_EMPTY {
+            area = Rectangle::new(0, 0, geometry.width, geometry.height);
+        }
+        let more = if index + 1 < count { DAMAGE_NOTIFY_MORE } else { 0 };
+        let event = Event::DamageNotify {
+            level: damage.level | more,
+            drawable: damage.drawable,
+            damage: id,
+            timestamp,
+            area,
+            geometry,
+        };
+        queue_event(client, event);
+    }
+}
+
+/// Records that `region` of `surface`, in screen coordinates, changed. The
+/// automatically redirected windows composited into the surface are painted back over
+/// the change, and a change to such a window's backing pixmap shows in its parent's
+/// surface.
+pub fn damage_surface(drawables: &mut Drawables, surface: u32, region: &Region) {
+    if region.is_empty() {
+        return;
+    }
+    composite_children(drawables, surface, region);
+    let windows = drawables.windows;
+    let mut damages = DAMAGES.lock().unwrap();
+    for (id, damage) in damages.iter_mut() {
+        let Some(window) = windows.get(&damage.drawable).filter(|window| window.surface == surface) else {
+            continue;
+        };
+        let changed = visible_region(windows, window).intersect(region);
+        if changed.is_empty() {
+            continue;
+        }
+        let (origin_x, origin_y) = (window.screen_rectangle.x, window.screen_rectangle.y);
+        let areas = damage.add(&changed.translate(-origin_x, -origin_y));
+        report(windows, *id, damage, areas);
+    }
+    drop(damages);
+    if let Some((parent_surface, clip)) = composited_into(windows, surface) {
+        damage_surface(drawables, parent_surface, &region.intersect(clip));
+    }
+}
+
+/// Records that `region` of `drawable` changed, in the drawable's coordinates. The
+/// region must already be clipped to what drawing to the drawable can change.
+pub fn damage_drawable(drawables: &mut Drawables, drawable: u32, region: &Region) {
+    let windows = drawables.windows;
+    if let Some(window) = windows.get(&drawable) {
+        let (origin_x, origin_y) = (window.screen_rectangle.x, window.screen_rectangle.y);
+        damage_surface(drawables, window.surface, &region.translate(origin_x, origin_y));
+        return;
+    }
+    if region.is_empty() {
+        return;
+    }
+    let mut damages = DAMAGES.lock().unwrap();
+    for (id, damage) in damages.iter_mut().filter(|(_, damage)| damage.drawable == drawable) {
+        let areas = damage.add(region);
+        report(windows, *id, damage, areas);
+    }
+}
+
+/// Starts collecting the damage to `drawable` for the server itself, returning the id
+/// of the damage object to pass to `take_damage`.
+pub fn track_damage(drawable: u32) -> u32 {
+    let mut next_id = NEXT_INTERNAL_DAMAGE.lock().unwrap();
+    let id = *next_id;
+    *next_id += 1;
+    let damage = Damage {
+        drawable,
+        level: REPORT_DELTA_RECTANGLES,
+        pixmap_size: (0, 0),
+        damage: Region::new(),
+    };
+    DAMAGES.lock().unwrap().insert(id, damage);
+    id
+}
+
+/// Returns the damage collected by damage object `id` since the last call, and clears
+/// it. Damage objects go away with their drawable, leaving nothing to take.
+pub fn take_damage(id: u32) -> Region {
+    let mut damages = DAMAGES.lock().unwrap();
+    damages.get_mut(&id).map_or(Region::new(), |damage| std::mem::take(&mut damage.damage))
+}
+
+pub fn untrack_damage(id: u32) {
+    DAMAGES.lock().unwrap().remove(&id);
+}
+
+/// Destroys the damage objects on a drawable that is destroyed.
+pub fn free_drawable_damages(drawable: u32) {
+    DAMAGES.lock().unwrap().retain(|_, damage| damage.drawable != drawable);
+}
+
+/// Destroys the damage objects of a client whose resources are destroyed, and those on
+/// drawables that went with them.
+pub fn free_client_damages(client: u32) {
+    let windows = WINDOWS.lock().unwrap();
+    let pixmaps = PIXMAPS.lock().unwrap();
+    DAMAGES.lock().unwrap().retain(|id, damage| {
+        resource_owner(*id) != client
+            && (windows.contains_key(&damage.drawable) || pixmaps.contains_key(&damage.drawable))
+    });
+}
+
+#[derive(Debug)]
+pub enum DamageRequest {
+    QueryVersion {
+        major_version: u32,
+        minor_version: u32,
+    },
+    Create {
+        damage: u32,
+        drawable: u32,
+        level: u8,
+    },
+    Destroy {
+        damage: u32,
+    },
+    Subtract {
+        damage: u32,
+        repair: u32,
+        parts: u32,
+    },
+    Add {
+        drawable: u32,
+        region: u32,
+    },
+}
+
+fn read_damage_request(request: &ExtensionRequest) -> Result<DamageRequest, XError> {
+    let data = request.data;
+    let minimum_length = match request.minor_opcode {
+        0 => 8,
+        1 => 12,
+        2 => 4,
+        3 => 12,
+        4 => 8,
+        minor_opcode => return Err(XError::new(ErrorCode::Request, minor_opcode as u32)),
+    };
+    if data.len() < minimum_length {
+        return Err(XError::new(ErrorCode::Length, 0));
+    }
+    Ok(match request.minor_opcode {
+        0 => DamageRequest::QueryVersion {
+            major_version: request.card32(data),
+            minor_version: request.card32(&data[4..]),
+        },
+        1 => DamageRequest::Create {
+            damage: request.card32(data),
+            drawable: request.card32(&data[4..]),
+            level: data[8],
+        },
+        2 => DamageRequest::Destroy {
+            damage: request.card32(data),
+        },
+        3 => DamageRequest::Subtract {
+            damage: request.card32(data),
+            repair: request.card32(&data[4..]),
+            parts: request.card32(&data[8..]),
+        },
+        _ => DamageRequest::Add {
+            drawable: request.card32(data),
+            region: request.card32(&data[4..]),
+        },
+    })
+}
+
+pub fn handle_damage_request(request: &ExtensionRequest) -> Result<Option<Vec<u8>>, XError> {
+    let damage_request = read_damage_request(request)?;
+    match damage_request {
+        DamageRequest::QueryVersion { .. } => {
+            let mut body = request.to_bytes_32(DAMAGE_MAJOR_VERSION).to_vec();
+            body.extend(request.to_bytes_32(DAMAGE_MINOR_VERSION));
+            Ok(Some(request.reply(0, body)))
+        }
+        DamageRequest::Create {
+            damage,
+            drawable,
+            level,
+        } => {
+            let windows = WINDOWS.lock().unwrap();
+            let pixmaps = PIXMAPS.lock().unwrap();
+            let pixmap_size = match (windows.contains_key(&drawable), pixmaps.get(&drawable)) {
+                (true, _) => (0, 0),
+                (false, Some(pixmap)) => (pixmap.width, pixmap.height),
+                (false, None) => return Err(XError::new(ErrorCode::Drawable, drawable)),
+            };
+            if level > REPORT_NON_EMPTY {
+                return Err(XError::new(ErrorCode::Value, level as u32));
+            }
+            let mut damages = DAMAGES.lock().unwrap();
+            if damages.contains_key(&damage) {
+                return Err(XError::new(ErrorCode::IDChoice, damage));
+            }
+            let new_damage = Damage {
+                drawable,
+                level,
+                pixmap_size,
+                damage: Region::new(),
+            };
+            damages.insert(damage, new_damage);
+            Ok(None)
+        }
+        DamageRequest::Destroy { damage } => {
+            DAMAGES.lock().unwrap().remove(&damage).ok_or(bad_damage(damage))?;
+            Ok(None)
+        }
+        DamageRequest::Subtract {
+            damage,
+            repair,
+            parts,
+        } => {
+            let windows = WINDOWS.lock().unwrap();
+            let mut regions = REGIONS.lock().unwrap();
+            let mut damages = DAMAGES.lock().unwrap();
+            let damage_object = damages.get_mut(&damage).ok_or(bad_damage(damage))?;
+            let repair_region = match repair {
+                0 => None,
+                repair => Some(get_region(&regions, repair)?.clone()),
+            };
+            if parts != 0 {
+                get_region(&regions, parts)?;
+            }
+            let repaired = match &repair_region {
+                Some(repair_region) => damage_object.damage.intersect(repair_region),
+                None => damage_object.damage.clone(),
+            };
+            if parts != 0 {
+                regions.insert(parts, repaired.clone());
+            }
+            damage_object.damage = damage_object.damage.subtract(&repaired);
+            // Whatever damage a repair leaves is reported again.
+            if repair_region.is_some() && !damage_object.damage.is_empty() {
+                let areas = damage_object.current();
+                report(&windows, damage, damage_object, areas);
+            }
+            Ok(None)
+        }
+        DamageRequest::Add { drawable, region } => {
+            let windows = WINDOWS.lock().unwrap();
+            let bounds = match windows.get(&drawable) {
+                Some(window) => {
+                    let (origin_x, origin_y) = (window.screen_rectangle.x, window.screen_rectangle.y);
+                    visible_region(&windows, window).translate(-origin_x, -origin_y)
+                }
+                None => match PIXMAPS.lock().unwrap().get(&drawable) {
+                    Some(pixmap) => Region::from_rectangle(pixmap.bounds()),
+                    None => return Err(XError::new(ErrorCode::Drawable, drawable)),
+                },
+            };
+            let region = get_region(&REGIONS.lock().unwrap(), region)?.intersect(&bounds);
+            damage_drawable(&mut Drawables::lock(&windows), drawable, &region);
+            Ok(None)
+        }
+    }
+}
@@ -0,0 +1,67 @@
+use std::io::{Read, Write};
+
+use crate::connection::Connection;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ErrorCode {
+    Request = 1,
+    Value = 2,
+    Window = 3,
+    Pixmap = 4,
+    Atom = 5,
+    Cursor = 6,
+    Font = 7,
+    Match = 8,
+    Drawable = 9,
+    Access = 10,
+    Alloc = 11,
+    Colormap = 12,
+    GContext = 13,
+    IDChoice = 14,
+    Name = 15,
+    Length = 16,
+    Implementation = 17,
+}
+
+/// An error to report back to the client, along with the offending value.
+#[derive(Clone, Copy, Debug)]
+pub struct XError {
+    /// A core `ErrorCode`, or an error code allocated to an extension.
+    pub code: u8,
+    pub bad_value: u32,
+}
+
+impl XError {
+    pub fn new(code: ErrorCode, bad_value: u32) -> XError {
+        XError {
+            code: code as u8,
+            bad_value,
+        }
+    }
+
+    /// An error defined by an extension, `code` being its first error plus the offset.
+    pub fn extension(code: u8, bad_value: u32) -> XError {
+        XError { code, bad_value }
+    }
+}
+
+impl<T: Read + Write> Connection<T> {
+    pub fn write_error(&mut self, error: XError, major_opcode: u8) {
+        self.write_extension_error(error, major_opcode, 0);
+    }
+
+    pub fn write_extension_error(&mut self, error: XError, major_opcode: u8, minor_opcode: u16) {
+        let mut bytes_to_write = vec![
+            0,
+            error.code,
+            self.to_bytes_16(self.sequence_number)[0],
+            self.to_bytes_16(self.sequence_number)[1],
+        ];
+        bytes_to_write.append(&mut self.to_bytes_32(error.bad_value).to_vec());
+        bytes_to_write.append(&mut self.to_bytes_16(minor_opcode).to_vec());
+        bytes_to_write.push(major_opcode);
+        bytes_to_write.append(&mut vec![0; 21]);
+        self.stream.write_all(&bytes_to_write).unwrap();
+    }
+}
@@ -1,48 +1,373 @@
-use std::io::{Read, Write};
+use std::{
+    collections::BTreeMap,
+    io::{Read, Write},
+};
 
-use crate::connection::Connection;
+use crate::{
+    client::queue_event,
+    connection::Connection,
+    damage::{DAMAGE_NAME, DAMAGE_NOTIFY},
+    extension::find_extension,
+    ge::GENERIC_EVENT,
+    input::DeviceEvent,
+    randr::RANDR_NAME,
+    region::Rectangle,
+    shape::SHAPE_NAME,
+    shm::SHM_NAME,
+    window::Window,
+    xfixes::{CURSOR_NOTIFY, SELECTION_NOTIFY, XFIXES_NAME},
+    xinput::{
+        fp3232, XICrossingEvent, XIDeviceEvent, XIModifiers, XIRawEvent, VIRTUAL_CORE_POINTER,
+        VIRTUAL_POINTER, XINPUT_NAME,
+    },
+    xkb::{
+        CORE_KEYBOARD_ID, MAP_EXPLICIT_COMPONENTS, MAP_KEY_ACTIONS, MAP_KEY_BEHAVIORS, MAP_KEY_SYMS,
+        MAP_MODIFIER_MAP, MAP_NOTIFY, MAP_VIRTUAL_MOD_MAP, NEW_KEYBOARD_NOTIFY, STATE_NOTIFY, XKB_NAME,
+    },
+};
+
+pub const KEY_PRESS_MASK: u32 = 1 << 0;
+pub const KEY_RELEASE_MASK: u32 = 1 << 1;
+pub const BUTTON_PRESS_MASK: u32 = 1 << 2;
+pub const BUTTON_RELEASE_MASK: u32 = 1 << 3;
+pub const ENTER_WINDOW_MASK: u32 = 1 << 4;
+pub const LEAVE_WINDOW_MASK: u32 = 1 << 5;
+pub const POINTER_MOTION_MASK: u32 = 1 << 6;
+pub const POINTER_MOTION_HINT_MASK: u32 = 1 << 7;
+pub const BUTTON1_MOTION_MASK: u32 = 1 << 8;
+pub const BUTTON_MOTION_MASK: u32 = 1 << 13;
+pub const KEYMAP_STATE_MASK: u32 = 1 << 14;
+pub const EXPOSURE_MASK: u32 = 1 << 15;
+pub const VISIBILITY_CHANGE_MASK: u32 = 1 << 16;
+pub const STRUCTURE_NOTIFY_MASK: u32 = 1 << 17;
+pub const RESIZE_REDIRECT_MASK: u32 = 1 << 18;
+pub const SUBSTRUCTURE_NOTIFY_MASK: u32 = 1 << 19;
+pub const SUBSTRUCTURE_REDIRECT_MASK: u32 = 1 << 20;
+pub const FOCUS_CHANGE_MASK: u32 = 1 << 21;
+pub const PROPERTY_CHANGE_MASK: u32 = 1 << 22;
+pub const COLORMAP_CHANGE_MASK: u32 = 1 << 23;
+pub const OWNER_GRAB_BUTTON_MASK: u32 = 1 << 24;
 
 #[repr(C)]
 #[derive(Clone, Debug)]
 pub enum Event {
-    KeyPress,
-    KeyRelease,
-    ButtonPress,
-    ButtonRelease,
-    MotionNotify,
-    EnterNotify,
-    LeaveNotify,
-    FocusIn,
-    FocusOut,
-    KeymapNotify,
-    Expose,
-    GraphicsExposure,
-    NoExposure,
-    VisibilityNotify,
-    CreateNotify,
-    DestroyNotify,
-    UnmapNotify,
-    MapNotify,
-    MapRequest,
-    ReparentNotify,
-    ConfigureNotify,
-    ConfigureRequest,
-    GravityNotify,
-    ResizeRequest,
-    CirculateNotify,
-    CirculateRequest,
-    PropertyNotify,
-    SelectionClear,
-    SelectionRequest,
-    SelectionNotify,
-    ColormapNotify,
+    KeyPress(DeviceEvent),
+    KeyRelease(DeviceEvent),
+    ButtonPress(DeviceEvent),
+    ButtonRelease(DeviceEvent),
+    MotionNotify(DeviceEvent),
+    EnterNotify {
+        device_event: DeviceEvent,
+        mode: u8,
+        focus: bool,
+    },
+    LeaveNotify {
+        device_event: DeviceEvent,
+        mode: u8,
+        focus: bool,
+    },
+    FocusIn {
+        detail: u8,
+        event: u32,
+        mode: u8,
+    },
+    FocusOut {
+        detail: u8,
+        event: u32,
+        mode: u8,
+    },
+    /// The keys pressed, one bit per keycode from 8.
+    KeymapNotify {
+        keys: [u8; 31],
+    },
+    Expose {
+        window: u32,
+        x: u16,
+        y: u16,
+        width: u16,
+        height: u16,
+        count: u16,
+    },
+    GraphicsExposure {
+        drawable: u32,
+        x: u16,
+        y: u16,
+        width: u16,
+        height: u16,
+        minor_opcode: u16,
+        count: u16,
+        major_opcode: u8,
+    },
+    NoExposure {
+        drawable: u32,
+        minor_opcode: u16,
+        major_opcode: u8,
+    },
+    VisibilityNotify {
+        window: u32,
+        state: u8,
+    },
+    CreateNotify {
+        parent: u32,
+        window: u32,
+        x: i16,
+        y: i16,
+        width: u16,
+        height: u16,
+        border_width: u16,
+        override_redirect: bool,
+    },
+    DestroyNotify {
+        event: u32,
+        window: u32,
+    },
+    UnmapNotify {
+        event: u32,
+        window: u32,
+        from_configure: bool,
+    },
+    MapNotify {
+        event: u32,
+        window: u32,
+        override_redirect: bool,
+    },
+    MapRequest {
+        parent: u32,
+        window: u32,
+    },
+    ReparentNotify {
+        event: u32,
+        window: u32,
+        parent: u32,
+        x: i16,
+        y: i16,
+        override_redirect: bool,
+    },
+    ConfigureNotify {
+        event: u32,
+        window: u32,
+        above_sibling: u32,
+        x: i16,
+        y: i16,
+        width: u16,
+        height: u16,
+        border_width: u16,
+        override_redirect: bool,
+    },
+    ConfigureRequest {
+        stack_mode: u8,
+        parent: u32,
+        window: u32,
+        sibling: u32,
+        x: i16,
+        y: i16,
+        width: u16,
+        height: u16,
+        border_width: u16,
+        value_mask: u16,
+    },
+    GravityNotify {
+        event: u32,
+        window: u32,
+        x: i16,
+        y: i16,
+    },
+    ResizeRequest {
+        window: u32,
+        width: u16,
+        height: u16,
+    },
+    CirculateNotify {
+        event: u32,
+        window: u32,
+        place: u8,
+    },
+    CirculateRequest {
+        parent: u32,
+        window: u32,
+        place: u8,
+    },
+    PropertyNotify {
+        window: u32,
+        atom: u32,
+        time: u32,
+        state: u8,
+    },
+    SelectionClear {
+        time: u32,
+        owner: u32,
+        selection: u32,
+    },
+    SelectionRequest {
+        time: u32,
+        owner: u32,
+        requestor: u32,
+        selection: u32,
+        target: u32,
+        property: u32,
+    },
+    SelectionNotify {
+        time: u32,
+        requestor: u32,
+        selection: u32,
+        target: u32,
+        property: u32,
+    },
+    ColormapNotify {
+        window: u32,
+        colormap: u32,
+        new: bool,
+        state: u8,
+    },
     ClientMessage {
         sequence_number: u16,
         window: u32,
         atom_type: u32,
         data: ClientMessageData,
     },
-    MappingNotify,
+    MappingNotify {
+        request: u8,
+        first_keycode: u8,
+        count: u8,
+    },
+    /// SHAPE: a shape of `window` changed.
+    ShapeNotify {
+        kind: u8,
+        window: u32,
+        x: i16,
+        y: i16,
+        width: u16,
+        height: u16,
+        time: u32,
+        shaped: bool,
+    },
+    /// MIT-SHM: a ShmPutImage that asked for it has finished with the segment.
+    ShmCompletion {
+        drawable: u32,
+        minor_event: u16,
+        major_event: u8,
+        shmseg: u32,
+        offset: u32,
+    },
+    /// RANDR: the screen's size or configuration changed.
+    ScreenChangeNotify {
+        rotation: u8,
+        timestamp: u32,
+        config_timestamp: u32,
+        root: u32,
+        window: u32,
+        size_id: u16,
+        width: u16,
+        height: u16,
+        mm_width: u16,
+        mm_height: u16,
+    },
+    /// RANDR: a CRTC was configured.
+    CrtcChangeNotify {
+        timestamp: u32,
+        window: u32,
+        crtc: u32,
+        mode: u32,
+        rotation: u16,
+        x: i16,
+        y: i16,
+        width: u16,
+        height: u16,
+    },
+    /// RANDR: an output was connected to a different CRTC or mode.
+    OutputChangeNotify {
+        timestamp: u32,
+        config_timestamp: u32,
+        window: u32,
+        output: u32,
+        crtc: u32,
+        mode: u32,
+        rotation: u16,
+        connection: u8,
+    },
+    /// RANDR: an output property changed or was deleted.
+    OutputPropertyNotify {
+        window: u32,
+        output: u32,
+        atom: u32,
+        timestamp: u32,
+        deleted: bool,
+    },
+    /// RANDR: modes were created or destroyed.
+    ResourceChangeNotify {
+        timestamp: u32,
+        window: u32,
+    },
+    /// XKEYBOARD: the keymap was replaced.
+    XkbNewKeyboardNotify {
+        time: u32,
+        min_key_code: u8,
+        max_key_code: u8,
+        old_min_key_code: u8,
+        old_max_key_code: u8,
+        changed: u16,
+    },
+    /// XKEYBOARD: parts of the keymap changed, for the types and keys given.
+    XkbMapNotify {
+        time: u32,
+        changed: u16,
+        min_key_code: u8,
+        max_key_code: u8,
+        first_type: u8,
+        n_types: u8,
+        first_key: u8,
+        n_keys: u8,
+        virtual_mods: u16,
+    },
+    /// XKEYBOARD: the keyboard state changed. The compatibility, grab and lookup states
+    /// are the effective modifiers.
+    XkbStateNotify {
+        time: u32,
+        mods: u8,
+        base_mods: u8,
+        latched_mods: u8,
+        locked_mods: u8,
+        group: u8,
+        base_group: i16,
+        latched_group: i16,
+        locked_group: u8,
+        ptr_btn_state: u16,
+        changed: u16,
+        keycode: u8,
+        event_type: u8,
+    },
+    /// XInputExtension: a key, button, motion or touch event.
+    XIDevice(XIDeviceEvent),
+    /// XInputExtension: a raw event, reported to root window selections.
+    XIRaw(XIRawEvent),
+    /// XInputExtension: the master pointer entered or left a window.
+    XICrossing(XICrossingEvent),
+    /// XFIXES: a selection changed owner, or its owner went away.
+    XFixesSelectionNotify {
+        subtype: u8,
+        window: u32,
+        owner: u32,
+        selection: u32,
+        timestamp: u32,
+        selection_timestamp: u32,
+    },
+    /// XFIXES: the pointer shows another cursor.
+    XFixesCursorNotify {
+        subtype: u8,
+        window: u32,
+        cursor_serial: u32,
+        timestamp: u32,
+        name: u32,
+    },
+    /// DAMAGE: a drawable was damaged.
+    DamageNotify {
+        level: u8,
+        drawable: u32,
+        damage: u32,
+        timestamp: u32,
+        area: Rectangle,
+        geometry: Rectangle,
+    },
 }
 
 impl<T: Read + Write> Connection<T> {
@@ -62,6 +387,707 @@ impl<T: Read + Write> Connection<T> {
             _ => panic!("unknown event type")
         }
     }
+
+    fn device_event_bytes(&self, device_event: &DeviceEvent) -> Vec<u8> {
+        let mut body = self.to_bytes_32(device_event.time).to_vec();
+        body.append(&mut self.to_bytes_32(device_event.root).to_vec());
+        body.append(&mut self.to_bytes_32(device_event.event).to_vec());
+        body.append(&mut self.to_bytes_32(device_event.child).to_vec());
+        for value in [
+            device_event.root_x as u16,
+            device_event.root_y as u16,
+            device_event.event_x as u16,
+            device_event.event_y as u16,
+            device_event.state,
+        ] {
+            body.append(&mut self.to_bytes_16(value).to_vec());
+        }
+        body.push(device_event.same_screen as u8);
+        body
+    }
+
+    /// Encodes EnterNotify and LeaveNotify, which end with the mode and a byte holding
+    /// the focus and same-screen flags where device events have same-screen.
+    fn crossing_event_bytes(&self, device_event: &DeviceEvent, mode: u8, focus: bool) -> Vec<u8> {
+        let mut body = self.device_event_bytes(device_event);
+        body.pop();
+        body.push(mode);
+        body.push(focus as u8 | (device_event.same_screen as u8) << 1);
+        body
+    }
+
+    /// Frames a Generic Event Extension event of `extension`: the event type, then
+    /// `data`, which may take the event past 32 bytes.
+    fn generic_event(&self, extension: &str, evtype: u16, mut data: Vec<u8>) -> (u8, u8, Vec<u8>) {
+        let length = (10 + data.len()).max(32).next_multiple_of(4);
+        let mut body = self.to_bytes_32((length as u32 - 32) / 4).to_vec();
+        body.extend(self.to_bytes_16(evtype));
+        body.append(&mut data);
+        body.resize(length - 4, 0);
+        (GENERIC_EVENT, find_extension(extension).map_or(0, |e| e.major_opcode), body)
+    }
+
+    fn fp3232_bytes(&self, value: f64) -> Vec<u8> {
+        let (integral, fraction) = fp3232(value);
+        let mut bytes = self.to_bytes_32(integral as u32).to_vec();
+        bytes.extend(self.to_bytes_32(fraction));
+        bytes
+    }
+
+    /// Encodes the valuator mask, then the values, of an XInput event.
+    fn valuator_bytes(&self, valuators: &[(u16, f64)]) -> (u16, Vec<u8>) {
+        let Some((last, _)) = valuators.last() else {
+            return (0, vec![]);
+        };
+        let mut words = vec![0u32; *last as usize / 32 + 1];
+        for (number, _) in valuators {
+            words[*number as usize / 32] |= 1 << (number % 32);
+        }
+        let length = words.len() as u16;
+        let mut mask: Vec<u8> = words.into_iter().flat_map(|word| self.to_bytes_32(word)).collect();
+        for (_, value) in valuators {
+            mask.extend(self.fp3232_bytes(*value));
+        }
+        (length, mask)
+    }
+
+    /// Encodes the root, event and child windows and the root and event coordinates of
+    /// an XInput event, in 16.16 fixed point.
+    fn xi_position_bytes(&self, device_event: &DeviceEvent) -> Vec<u8> {
+        let mut bytes = vec![];
+        for window in [device_event.root, device_event.event, device_event.child] {
+            bytes.extend(self.to_bytes_32(window));
+        }
+        for value in [device_event.root_x, device_event.root_y, device_event.event_x, device_event.event_y] {
+            bytes.extend(self.to_bytes_32(((value as i32) << 16) as u32));
+        }
+        bytes
+    }
+
+    fn xi_modifier_bytes(&self, modifiers: &XIModifiers) -> Vec<u8> {
+        let mut bytes: Vec<u8> = modifiers.mods.iter().flat_map(|mods| self.to_bytes_32(*mods)).collect();
+        bytes.extend(modifiers.group);
+        bytes
+    }
+
+    /// Encodes `event` in this client's byte order, stamped with its current sequence number.
+    pub fn event_bytes(&self, event: &Event) -> Vec<u8> {
+        let (code, detail, body): (u8, u8, Vec<u8>) = match event {
+            Event::KeyPress(device_event) => (2, device_event.detail, self.device_event_bytes(device_event)),
+            Event::KeyRelease(device_event) => (3, device_event.detail, self.device_event_bytes(device_event)),
+            Event::ButtonPress(device_event) => (4, device_event.detail, self.device_event_bytes(device_event)),
+            Event::ButtonRelease(device_event) => (5, device_event.detail, self.device_event_bytes(device_event)),
+            Event::MotionNotify(device_event) => (6, device_event.detail, self.device_event_bytes(device_event)),
+            Event::EnterNotify {
+                device_event,
+                mode,
+                focus,
+            } => (7, device_event.detail, self.crossing_event_bytes(device_event, *mode, *focus)),
+            Event::LeaveNotify {
+                device_event,
+                mode,
+                focus,
+            } => (8, device_event.detail, self.crossing_event_bytes(device_event, *mode, *focus)),
+            Event::FocusIn { detail, event, mode } => {
+                let mut body = self.to_bytes_32(*event).to_vec();
+                body.push(*mode);
+                (9, *detail, body)
+            }
+            Event::FocusOut { detail, event, mode } => {
+                let mut body = self.to_bytes_32(*event).to_vec();
+                body.push(*mode);
+                (10, *detail, body)
+            }
+            // KeymapNotify has no sequence number.
+            Event::KeymapNotify { keys } => {
+                let mut bytes = vec![11];
+                bytes.extend(keys);
+                return bytes;
+            }
+            Event::Expose {
+                window,
+                x,
+                y,
+                width,
+                height,
+                count,
+            } => {
+                let mut body = self.to_bytes_32(*window).to_vec();
+                for value in [*x, *y, *width, *height, *count] {
+                    body.append(&mut self.to_bytes_16(value).to_vec());
+                }
+                (12, 0, body)
+            }
+            Event::GraphicsExposure {
+                drawable,
+                x,
+                y,
+                width,
+                height,
+                minor_opcode,
+                count,
+                major_opcode,
+            } => {
+                let mut body = self.to_bytes_32(*drawable).to_vec();
+                for value in [*x, *y, *width, *height, *minor_opcode, *count] {
+                    body.append(&mut self.to_bytes_16(value).to_vec());
+                }
+                body.push(*major_opcode);
+                (13, 0, body)
+            }
+            Event::NoExposure {
+                drawable,
+                minor_opcode,
+                major_opcode,
+            } => {
+                let mut body = self.to_bytes_32(*drawable).to_vec();
+                body.append(&mut self.to_bytes_16(*minor_opcode).to_vec());
+                body.push(*major_opcode);
+                (14, 0, body)
+            }
+            Event::VisibilityNotify { window, state } => {
+                let mut body = self.to_bytes_32(*window).to_vec();
+                body.push(*state);
+                (15, 0, body)
+            }
+            Event::CreateNotify {
+                parent,
+                window,
+                x,
+                y,
+                width,
+                height,
+                border_width,
+                override_redirect,
+            } => {
+                let mut body = self.to_bytes_32(*parent).to_vec();
+                body.append(&mut self.to_bytes_32(*window).to_vec());
+                for value in [*x as u16, *y as u16, *width, *height, *border_width] {
+                    body.append(&mut self.to_bytes_16(value).to_vec());
+                }
+                body.push(*override_redirect as u8);
+                (16, 0, body)
+            }
+            Event::DestroyNotify { event, window } => {
+                let mut body = self.to_bytes_32(*event).to_vec();
+                body.append(&mut self.to_bytes_32(*window).to_vec());
+                (17, 0, body)
+            }
+            Event::UnmapNotify {
+                event,
+                window,
+                from_configure,
+            } => {
+                let mut body = self.to_bytes_32(*event).to_vec();
+                body.append(&mut self.to_bytes_32(*window).to_vec());
+                body.push(*from_configure as u8);
+                (18, 0, body)
+            }
+            Event::MapNotify {
+                event,
+                window,
+                override_redirect,
+            } => {
+                let mut body = self.to_bytes_32(*event).to_vec();
+                body.append(&mut self.to_bytes_32(*window).to_vec());
+                body.push(*override_redirect as u8);
+                (19, 0, body)
+            }
+            Event::ReparentNotify {
+                event,
+                window,
+                parent,
+                x,
+                y,
+                override_redirect,
+            } => {
+                let mut body = self.to_bytes_32(*event).to_vec();
+                body.append(&mut self.to_bytes_32(*window).to_vec());
+                body.append(&mut self.to_bytes_32(*parent).to_vec());
+                body.append(&mut self.to_bytes_16(*x as u16).to_vec());
+                body.append(&mut self.to_bytes_16(*y as u16).to_vec());
+                body.push(*override_redirect as u8);
+                (21, 0, body)
+            }
+            Event::ConfigureNotify {
+                event,
+                window,
+                above_sibling,
+                x,
+                y,
+                width,
+                height,
+                border_width,
+                override_redirect,
+            } => {
+                let mut body = self.to_bytes_32(*event).to_vec();
+                body.append(&mut self.to_bytes_32(*window).to_vec());
+                body.append(&mut self.to_bytes_32(*above_sibling).to_vec());
+                for value in [*x as u16, *y as u16, *width, *height, *border_width] {
+                    body.append(&mut self.to_bytes_16(value).to_vec());
+                }
+                body.push(*override_redirect as u8);
+                (22, 0, body)
+            }
+            Event::MapRequest { parent, window } => {
+                let body = [*parent, *window]
+                    .iter()
+                    .flat_map(|value| self.to_bytes_32(*value))
+                    .collect();
+                (20, 0, body)
+            }
+            Event::ConfigureRequest {
+                stack_mode,
+                parent,
+                window,
+                sibling,
+                x,
+                y,
+                width,
+                height,
+                border_width,
+                value_mask,
+            } => {
+                let mut body: Vec<u8> = [*parent, *window, *sibling]
+                    .iter()
+                    .flat_map(|value| self.to_bytes_32(*value))
+                    .collect();
+                for value in [*x as u16, *y as u16, *width, *height, *border_width, *value_mask] {
+                    body.append(&mut self.to_bytes_16(value).to_vec());
+                }
+                (23, *stack_mode, body)
+            }
+            Event::GravityNotify { event, window, x, y } => {
+                let mut body = self.to_bytes_32(*event).to_vec();
+                body.append(&mut self.to_bytes_32(*window).to_vec());
+                for value in [*x as u16, *y as u16] {
+                    body.append(&mut self.to_bytes_16(value).to_vec());
+                }
+                (24, 0, body)
+            }
+            Event::ResizeRequest { window, width, height } => {
+                let mut body = self.to_bytes_32(*window).to_vec();
+                for value in [*width, *height] {
+                    body.append(&mut self.to_bytes_16(value).to_vec());
+                }
+                (25, 0, body)
+            }
+            Event::CirculateNotify { event, window, place } => {
+                let mut body: Vec<u8> = [*event, *window, 0]
+                    .iter()
+                    .flat_map(|value| self.to_bytes_32(*value))
+                    .collect();
+                body.push(*place);
+                (26, 0, body)
+            }
+            Event::CirculateRequest { parent, window, place } => {
+                let mut body: Vec<u8> = [*parent, *window, 0]
+                    .iter()
+                    .flat_map(|value| self.to_bytes_32(*value))
+                    .collect();
+                body.push(*place);
+                (27, 0, body)
+            }
+            Event::PropertyNotify {
+                window,
+                atom,
+                time,
+                state,
+            } => {
+                let mut body: Vec<u8> = [*window, *atom, *time]
+                    .iter()
+                    .flat_map(|value| self.to_bytes_32(*value))
+                    .collect();
+                body.push(*state);
+                (28, 0, body)
+            }
+            Event::SelectionClear { time, owner, selection } => {
+                let body = [*time, *owner, *selection]
+                    .iter()
+                    .flat_map(|value| self.to_bytes_32(*value))
+                    .collect();
+                (29, 0, body)
+            }
+            Event::SelectionRequest {
+                time,
+                owner,
+                requestor,
+                selection,
+                target,
+                property,
+            } => {
+                let body = [*time, *owner, *requestor, *selection, *target, *property]
+                    .iter()
+                    .flat_map(|value| self.to_bytes_32(*value))
+                    .collect();
+                (30, 0, body)
+            }
+            Event::SelectionNotify {
+                time,
+                requestor,
+                selection,
+                target,
+                property,
+            } => {
+                let body = [*time, *requestor, *selection, *target, *property]
+                    .iter()
+                    .flat_map(|value| self.to_bytes_32(*value))
+                    .collect();
+                (31, 0, body)
+            }
+            Event::ColormapNotify {
+                window,
+                colormap,
+                new,
+                state,
+            } => {
+                let mut body: Vec<u8> = [*window, *colormap]
+                    .iter()
+                    .flat_map(|value| self.to_bytes_32(*value))
+                    .collect();
+                body.extend([*new as u8, *state]);
+                (32, 0, body)
+            }
+            Event::ClientMessage {
+                window,
+                atom_type,
+                data,
+                ..
+            } => {
+                let mut body = self.to_bytes_32(*window).to_vec();
+                body.append(&mut self.to_bytes_32(*atom_type).to_vec());
+                let format = match data {
+                    ClientMessageData::Bytes(bytes) => {
+                        body.extend_from_slice(bytes);
+                        8
+                    }
+                    ClientMessageData::Shorts(shorts) => {
+                        for value in shorts {
+                            body.append(&mut self.to_bytes_16(*value).to_vec());
+                        }
+                        16
+                    }
+                    ClientMessageData::Longs(longs) => {
+                        for value in longs {
+                            body.append(&mut self.to_bytes_32(*value).to_vec());
+                        }
+                        32
+                    }
+                };
+                (33, format, body)
+            }
+            Event::MappingNotify {
+                request,
+                first_keycode,
+                count,
+            } => (34, 0, vec![*request, *first_keycode, *count]),
+            Event::ShapeNotify {
+                kind,
+                window,
+                x,
+                y,
+                width,
+                height,
+                time,
+                shaped,
+            } => {
+                let mut body = self.to_bytes_32(*window).to_vec();
+                for value in [*x as u16, *y as u16, *width, *height] {
+                    body.append(&mut self.to_bytes_16(value).to_vec());
+                }
+                body.append(&mut self.to_bytes_32(*time).to_vec());
+                body.push(*shaped as u8);
+                (find_extension(SHAPE_NAME).map_or(0, |e| e.first_event), *kind, body)
+            }
+            Event::ShmCompletion {
+                drawable,
+                minor_event,
+                major_event,
+                shmseg,
+                offset,
+            } => {
+                let mut body = self.to_bytes_32(*drawable).to_vec();
+                body.append(&mut self.to_bytes_16(*minor_event).to_vec());
+                body.extend([*major_event, 0]);
+                body.append(&mut self.to_bytes_32(*shmseg).to_vec());
+                body.append(&mut self.to_bytes_32(*offset).to_vec());
+                (find_extension(SHM_NAME).map_or(0, |e| e.first_event), 0, body)
+            }
+            Event::ScreenChangeNotify {
+                rotation,
+                timestamp,
+                config_timestamp,
+                root,
+                window,
+                size_id,
+                width,
+                height,
+                mm_width,
+                mm_height,
+            } => {
+                let mut body: Vec<u8> = [*timestamp, *config_timestamp, *root, *window]
+                    .iter()
+                    .flat_map(|value| self.to_bytes_32(*value))
+                    .collect();
+                // The subpixel order is always unknown.
+                for value in [*size_id, 0, *width, *height, *mm_width, *mm_height] {
+                    body.append(&mut self.to_bytes_16(value).to_vec());
+                }
+                (find_extension(RANDR_NAME).map_or(0, |e| e.first_event), *rotation, body)
+            }
+            Event::CrtcChangeNotify {
+                timestamp,
+                window,
+                crtc,
+                mode,
+                rotation,
+                x,
+                y,
+                width,
+                height,
+            } => {
+                let mut body: Vec<u8> = [*timestamp, *window, *crtc, *mode]
+                    .iter()
+                    .flat_map(|value| self.to_bytes_32(*value))
+                    .collect();
+                for value in [*rotation, 0, *x as u16, *y as u16, *width, *height] {
+                    body.append(&mut self.to_bytes_16(value).to_vec());
+                }
+                (find_extension(RANDR_NAME).map_or(0, |e| e.first_event + 1), 0, body)
+            }
+            Event::OutputChangeNotify {
+                timestamp,
+                config_timestamp,
+                window,
+                output,
+                crtc,
+                mode,
+                rotation,
+                connection,
+            } => {
+                let mut body: Vec<u8> = [*timestamp, *config_timestamp, *window, *output, *crtc, *mode]
+                    .iter()
+                    .flat_map(|value| self.to_bytes_32(*value))
+                    .collect();
+                body.append(&mut self.to_bytes_16(*rotation).to_vec());
+                body.extend([*connection, 0]);
+                (find_extension(RANDR_NAME).map_or(0, |e| e.first_event + 1), 1, body)
+            }
+            Event::OutputPropertyNotify {
+                window,
+                output,
+                atom,
+                timestamp,
+                deleted,
+            } => {
+                let mut body: Vec<u8> = [*window, *output, *atom, *timestamp]
+                    .iter()
+                    .flat_map(|value| self.to_bytes_32(*value))
+                    .collect();
+                body.push(*deleted as u8);
+                (find_extension(RANDR_NAME).map_or(0, |e| e.first_event + 1), 2, body)
+            }
+            Event::ResourceChangeNotify { timestamp, window } => {
+                let mut body = self.to_bytes_32(*timestamp).to_vec();
+                body.append(&mut self.to_bytes_32(*window).to_vec());
+                (find_extension(RANDR_NAME).map_or(0, |e| e.first_event + 1), 5, body)
+            }
+            Event::XkbNewKeyboardNotify {
+                time,
+                min_key_code,
+                max_key_code,
+                old_min_key_code,
+                old_max_key_code,
+                changed,
+            } => {
+                let mut body = self.to_bytes_32(*time).to_vec();
+                body.extend([CORE_KEYBOARD_ID, CORE_KEYBOARD_ID, *min_key_code, *max_key_code]);
+                body.extend([*old_min_key_code, *old_max_key_code, 0, 0]);
+                body.append(&mut self.to_bytes_16(*changed).to_vec());
+                (find_extension(XKB_NAME).map_or(0, |e| e.first_event), NEW_KEYBOARD_NOTIFY, body)
+            }
+            Event::XkbMapNotify {
+                time,
+                changed,
+                min_key_code,
+                max_key_code,
+                first_type,
+                n_types,
+                first_key,
+                n_keys,
+                virtual_mods,
+            } => {
+                let mut body = self.to_bytes_32(*time).to_vec();
+                // No pointer button actions.
+                body.extend([CORE_KEYBOARD_ID, 0]);
+                body.append(&mut self.to_bytes_16(*changed).to_vec());
+                body.extend([*min_key_code, *max_key_code, *first_type, *n_types]);
+                // Key symbols, actions, behaviors, explicit components, modifier map and
+                // virtual modifier map each get the key range if they changed.
+                for part in [MAP_KEY_SYMS, MAP_KEY_ACTIONS, MAP_KEY_BEHAVIORS] {
+                    body.extend(if changed & part != 0 { [*first_key, *n_keys] } else { [0, 0] });
+                }
+                for part in [MAP_EXPLICIT_COMPONENTS, MAP_MODIFIER_MAP, MAP_VIRTUAL_MOD_MAP] {
+                    body.extend(if changed & part != 0 { [*first_key, *n_keys] } else { [0, 0] });
+                }
+                body.append(&mut self.to_bytes_16(*virtual_mods).to_vec());
+                (find_extension(XKB_NAME).map_or(0, |e| e.first_event), MAP_NOTIFY, body)
+            }
+            Event::XkbStateNotify {
+                time,
+                mods,
+                base_mods,
+                latched_mods,
+                locked_mods,
+                group,
+                base_group,
+                latched_group,
+                locked_group,
+                ptr_btn_state,
+                changed,
+                keycode,
+                event_type,
+            } => {
+                let mut body = self.to_bytes_32(*time).to_vec();
+                body.extend([CORE_KEYBOARD_ID, *mods, *base_mods, *latched_mods, *locked_mods, *group]);
+                body.append(&mut self.to_bytes_16(*base_group as u16).to_vec());
+                body.append(&mut self.to_bytes_16(*latched_group as u16).to_vec());
+                body.extend([*locked_group, *mods, *mods, *mods, *mods, *mods]);
+                body.append(&mut self.to_bytes_16(*ptr_btn_state).to_vec());
+                body.append(&mut self.to_bytes_16(*changed).to_vec());
+                // Not caused by a request.
+                body.extend([*keycode, *event_type, 0, 0]);
+                (find_extension(XKB_NAME).map_or(0, |e| e.first_event), STATE_NOTIFY, body)
+            }
+            Event::XIDevice(event) => {
+                let mut data = self.to_bytes_16(event.deviceid).to_vec();
+                data.extend(self.to_bytes_32(event.device_event.time));
+                data.extend(self.to_bytes_32(event.detail));
+                data.extend(self.xi_position_bytes(&event.device_event));
+                let (valuators_len, mut valuators) = self.valuator_bytes(&event.valuators);
+                // One unit of buttons.
+                data.extend(self.to_bytes_16(1));
+                data.extend(self.to_bytes_16(valuators_len));
+                data.extend(self.to_bytes_16(event.sourceid));
+                data.extend([0, 0]);
+                data.extend(self.to_bytes_32(event.flags));
+                data.extend(self.xi_modifier_bytes(&event.modifiers));
+                data.extend(self.to_bytes_32((event.device_event.state as u32 >> 8 & 0x1f) << 1));
+                data.append(&mut valuators);
+                self.generic_event(XINPUT_NAME, event.evtype, data)
+            }
+            Event::XIRaw(event) => {
+                let mut data = self.to_bytes_16(event.deviceid).to_vec();
+                data.extend(self.to_bytes_32(event.time));
+                data.extend(self.to_bytes_32(event.detail));
+                data.extend(self.to_bytes_16(event.sourceid));
+                let (valuators_len, mut valuators) = self.valuator_bytes(&event.valuators);
+                data.extend(self.to_bytes_16(valuators_len));
+                data.extend(self.to_bytes_32(event.flags));
+                data.extend([0; 4]);
+                data.append(&mut valuators);
+                // The raw values, which are the same as the values without acceleration.
+                for (_, value) in &event.valuators {
+                    data.extend(self.fp3232_bytes(*value));
+                }
+                self.generic_event(XINPUT_NAME, event.evtype, data)
+            }
+            Event::XICrossing(event) => {
+                let device_event = &event.device_event;
+                let mut data = self.to_bytes_16(VIRTUAL_CORE_POINTER).to_vec();
+                data.extend(self.to_bytes_32(device_event.time));
+                data.extend(self.to_bytes_16(VIRTUAL_POINTER));
+                data.extend([event.mode, device_event.detail]);
+                data.extend(self.xi_position_bytes(device_event));
+                data.extend([device_event.same_screen as u8, event.focus as u8]);
+                // One unit of buttons.
+                data.extend(self.to_bytes_16(1));
+                data.extend(self.xi_modifier_bytes(&event.modifiers));
+                data.extend(self.to_bytes_32((device_event.state as u32 >> 8 & 0x1f) << 1));
+                self.generic_event(XINPUT_NAME, event.evtype, data)
+            }
+            Event::XFixesSelectionNotify {
+                subtype,
+                window,
+                owner,
+                selection,
+                timestamp,
+                selection_timestamp,
+            } => {
+                let mut body = vec![];
+                for value in [*window, *owner, *selection, *timestamp, *selection_timestamp] {
+                    body.extend(self.to_bytes_32(value));
+                }
+                let code = find_extension(XFIXES_NAME).map_or(0, |e| e.first_event + SELECTION_NOTIFY);
+                (code, *subtype, body)
+            }
+            Event::XFixesCursorNotify {
+                subtype,
+                window,
+                cursor_serial,
+                timestamp,
+                name,
+            } => {
+                let mut body = vec![];
+                for value in [*window, *cursor_serial, *timestamp, *name] {
+                    body.extend(self.to_bytes_32(value));
+                }
+                let code = find_extension(XFIXES_NAME).map_or(0, |e| e.first_event + CURSOR_NOTIFY);
+                (code, *subtype, body)
+            }
+            Event::DamageNotify {
+                level,
+                drawable,
+                damage,
+                timestamp,
+                area,
+                geometry,
+            } => {
+                let mut body = vec![];
+                for value in [*drawable, *damage, *timestamp] {
+                    body.extend(self.to_bytes_32(value));
+                }
+                for rectangle in [area, geometry] {
+                    body.extend(self.to_bytes_16(rectangle.x as u16));
+                    body.extend(self.to_bytes_16(rectangle.y as u16));
+                    body.extend(self.to_bytes_16(rectangle.width as u16));
+                    body.extend(self.to_bytes_16(rectangle.height as u16));
+                }
+                let code = find_extension(DAMAGE_NAME).map_or(0, |e| e.first_event + DAMAGE_NOTIFY);
+                (code, *level, body)
+            }
+        };
+        let mut bytes = vec![code, detail];
+        bytes.append(&mut self.to_bytes_16(self.sequence_number).to_vec());
+        bytes.extend(body);
+        bytes.resize(bytes.len().max(32), 0);
+        bytes
+    }
+}
+
+/// Queues `event` for every client that selected `mask` on `window`.
+pub fn deliver_event(windows: &BTreeMap<u32, Window>, window: u32, mask: u32, event: Event) {
+    let Some(window) = windows.get(&window) else {
+        return;
+    };
+    for (client, selected) in &window.event_masks {
+        if selected & mask != 0 {
+            queue_event(*client, event.clone());
+        }
+    }
+}
+
+/// Sends a structure event to the window itself (StructureNotify) and to its parent
+/// (SubstructureNotify), filling in the `event` field for each recipient.
+pub fn deliver_structure_event(
+    windows: &BTreeMap<u32, Window>,
+    window: u32,
+    make_event: impl Fn(u32) -> Event,
+) {
+    deliver_event(windows, window, STRUCTURE_NOTIFY_MASK, make_event(window));
+    if let Some(parent) = windows.get(&window).map(|w| w.parent) {
+        deliver_event(windows, parent, SUBSTRUCTURE_NOTIFY_MASK, make_event(parent));
+    }
 }
 
 #[repr(C)]
@@ -0,0 +1,212 @@
+//! The registry of protocol extensions. Each extension declares its name, how many
+//! events and errors it defines and a handler for its requests; major opcodes and
+//! event and error bases are allocated in registration order when the server starts.
+
+use std::{collections::BTreeMap, sync::Mutex};
+
+use crate::{
+    composite::{handle_composite_request, COMPOSITE_NAME},
+    connection::Endianness,
+    damage::{handle_damage_request, DAMAGE_NAME},
+    error::XError,
+    ge::{handle_ge_request, GE_NAME},
+    randr::{handle_randr_request, RANDR_NAME},
+    render::{handle_render_request, RENDER_NAME},
+    shape::{handle_shape_request, SHAPE_NAME},
+    shm::{handle_shm_request, SHM_NAME},
+    xfixes::{handle_xfixes_request, XFIXES_NAME},
+    xinerama::{handle_xinerama_request, XINERAMA_NAME},
+    xinput::{handle_xi_request, XINPUT_NAME},
+    xkb::{handle_xkb_request, XKB_NAME},
+    xtest::{handle_xtest_request, XTEST_NAME},
+};
+
+pub const FIRST_EXTENSION_OPCODE: u8 = 128;
+pub const FIRST_EXTENSION_EVENT: u8 = 64;
+pub const FIRST_EXTENSION_ERROR: u8 = 128;
+
+/// Handles one request of an extension, returning the reply if the request has one.
+pub type Handler = fn(&ExtensionRequest) -> Result<Option<Vec<u8>>, XError>;
+
+/// What an extension needs from the registry.
+pub struct ExtensionSpec {
+    pub name: &'static str,
+    pub events: u8,
+    pub errors: u8,
+    pub handler: Handler,
+}
+
+/// Extensions built into the server, in the order their opcodes are allocated.
+const BUILTIN_EXTENSIONS: &[ExtensionSpec] = &[
+    ExtensionSpec {
+        name: XTEST_NAME,
+        events: 0,
+        errors: 0,
+        handler: handle_xtest_request,
+    },
+    ExtensionSpec {
+        name: SHM_NAME,
+        events: 1,
+        errors: 1,
+        handler: handle_shm_request,
+    },
+    ExtensionSpec {
+        name: SHAPE_NAME,
+        events: 1,
+        errors: 0,
+        handler: handle_shape_request,
+    },
+    ExtensionSpec {
+        name: RENDER_NAME,
+        events: 0,
+        errors: 5,
+        handler: handle_render_request,
+    },
+    ExtensionSpec {
+        name: RANDR_NAME,
+        events: 2,
+        errors: 4,
+        handler: handle_randr_request,
+    },
+    ExtensionSpec {
+        name: XINERAMA_NAME,
+        events: 0,
+        errors: 0,
+        handler: handle_xinerama_request,
+    },
+    ExtensionSpec {
+        name: XKB_NAME,
+        events: 1,
+        errors: 1,
+        handler: handle_xkb_request,
+    },
+    ExtensionSpec {
+        name: GE_NAME,
+        events: 0,
+        errors: 0,
+        handler: handle_ge_request,
+    },
+    // Version 1 events are never sent, but libXi assumes seventeen event codes.
+    ExtensionSpec {
+        name: XINPUT_NAME,
+        events: 17,
+        errors: 5,
+        handler: handle_xi_request,
+    },
+    ExtensionSpec {
+        name: XFIXES_NAME,
+        events: 2,
+        errors: 1,
+        handler: handle_xfixes_request,
+    },
+    ExtensionSpec {
+        name: DAMAGE_NAME,
+        events: 1,
+        errors: 1,
+        handler: handle_damage_request,
+    },
+    ExtensionSpec {
+        name: COMPOSITE_NAME,
+        events: 0,
+        errors: 0,
+        handler: handle_composite_request,
+    },
+];
+
+/// An enabled extension and the numbers it was allocated.
+#[derive(Clone, Copy)]
+pub struct Extension {
+    pub name: &'static str,
+    pub major_opcode: u8,
+    /// The first event code, or 0 if the extension defines no events.
+    pub first_event: u8,
+    /// The first error code, or 0 if the extension defines no errors.
+    pub first_error: u8,
+    pub handler: Handler,
+}
+
+pub static EXTENSIONS: Mutex<Vec<Extension>> = Mutex::new(Vec::new());
+
+/// Registers the built-in extensions. `overrides` maps extension names to whether they
+/// were switched on or off on the command line; extensions are on by default.
+pub fn init_extensions(overrides: &BTreeMap<String, bool>) {
+    let mut extensions = EXTENSIONS.lock().unwrap();
+    extensions.clear();
+    let (mut major_opcode, mut first_event, mut first_error) =
+        (FIRST_EXTENSION_OPCODE, FIRST_EXTENSION_EVENT, FIRST_EXTENSION_ERROR);
+    for spec in BUILTIN_EXTENSIONS {
+        if !overrides.get(spec.name).copied().unwrap_or(true) {
+            continue;
+        }
+        extensions.push(Extension {
+            name: spec.name,
+            major_opcode,
+            first_event: if spec.events == 0 { 0 } else { first_event },
+            first_error: if spec.errors == 0 { 0 } else { first_error },
+            handler: spec.handler,
+        });
+        major_opcode += 1;
+        first_event += spec.events;
+        first_error += spec.errors;
+    }
+}
+
+pub fn find_extension(name: &str) -> Option<Extension> {
+    EXTENSIONS.lock().unwrap().iter().find(|e| e.name == name).copied()
+}
+
+pub fn extension_by_opcode(major_opcode: u8) -> Option<Extension> {
+    EXTENSIONS
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|e| e.major_opcode == major_opcode)
+        .copied()
+}
+
+pub fn extension_names() -> Vec<&'static str> {
+    EXTENSIONS.lock().unwrap().iter().map(|e| e.name).collect()
+}
+
+/// A request to an extension, with what its handler needs to know about the client.
+pub struct ExtensionRequest<'a> {
+    pub client: u32,
+    pub endianness: Endianness,
+    pub sequence_number: u16,
+    pub minor_opcode: u8,
+    /// The request after its four-byte header.
+    pub data: &'a [u8],
+}
+
+impl ExtensionRequest<'_> {
+    pub fn card32(&self, bytes: &[u8]) -> u32 {
+        self.endianness.card32(bytes)
+    }
+
+    pub fn card16(&self, bytes: &[u8]) -> u16 {
+        self.endianness.card16(bytes)
+    }
+
+    pub fn int16(&self, bytes: &[u8]) -> i16 {
+        self.endianness.int16(bytes)
+    }
+
+    pub fn to_bytes_32(&self, val: u32) -> [u8; 4] {
+        self.endianness.to_bytes_32(val)
+    }
+
+    pub fn to_bytes_16(&self, val: u16) -> [u8; 2] {
+        self.endianness.to_bytes_16(val)
+    }
+
+    /// Builds a reply from the bytes following the length field, padding them to the
+    /// minimum reply size and a multiple of four bytes.
+    pub fn reply(&self, extra: u8, mut body: Vec<u8>) -> Vec<u8> {
+        body.resize(body.len().max(24).next_multiple_of(4), 0);
+        let mut bytes = vec![1, extra];
+        bytes.extend(self.to_bytes_16(self.sequence_number));
+        bytes.extend(self.to_bytes_32((body.len() as u32 - 24) / 4));
+        bytes.append(&mut body);
+        bytes
+    }
+}
@@ -0,0 +1,276 @@
+use std::collections::BTreeMap;
+
+use crate::{
+    error::{ErrorCode, XError},
+    event::{deliver_event, Event, FOCUS_CHANGE_MASK},
+    input::{window_at, InputState},
+    screen::DEFAULT_SCREEN,
+    time,
+    window::{common_ancestor, get_window, is_inferior_or_self, is_parent, is_viewable, Window},
+};
+
+pub const NOTIFY_ANCESTOR: u8 = 0;
+pub const NOTIFY_VIRTUAL: u8 = 1;
+pub const NOTIFY_INFERIOR: u8 = 2;
+pub const NOTIFY_NONLINEAR: u8 = 3;
+pub const NOTIFY_NONLINEAR_VIRTUAL: u8 = 4;
+pub const NOTIFY_POINTER: u8 = 5;
+pub const NOTIFY_POINTER_ROOT: u8 = 6;
+pub const NOTIFY_DETAIL_NONE: u8 = 7;
+
+pub const NOTIFY_NORMAL: u8 = 0;
+pub const NOTIFY_GRAB: u8 = 1;
+pub const NOTIFY_UNGRAB: u8 = 2;
+pub const NOTIFY_WHILE_GRABBED: u8 = 3;
+
+pub const REVERT_TO_NONE: u8 = 0;
+pub const REVERT_TO_POINTER_ROOT: u8 = 1;
+pub const REVERT_TO_PARENT: u8 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Focus {
+    None,
+    PointerRoot,
+    Window(u32),
+}
+
+impl Focus {
+    pub fn from_id(id: u32) -> Focus {
+        match id {
+            0 => Focus::None,
+            1 => Focus::PointerRoot,
+            window => Focus::Window(window),
+        }
+    }
+
+    pub fn id(self) -> u32 {
+        match self {
+            Focus::None => 0,
+            Focus::PointerRoot => 1,
+            Focus::Window(window) => window,
+        }
+    }
+}
+
+fn focus_event(windows: &BTreeMap<u32, Window>, focus_in: bool, window: u32, detail: u8, mode: u8) {
+    let event = match focus_in {
+        true => Event::FocusIn {
+            detail,
+            event: window,
+            mode,
+        },
+        false => Event::FocusOut {
+            detail,
+            event: window,
+            mode,
+        },
+    };
+    deliver_event(windows, window, FOCUS_CHANGE_MASK, event);
+}
+
+/// Sends FocusOut from `child` up to, but not including, `ancestor` (0 to include the
+/// root).
+fn focus_out_events(windows: &BTreeMap<u32, Window>, child: u32, ancestor: u32, detail: u8, mode: u8) {
+    let mut window = child;
+    while window != ancestor && window != 0 {
+        focus_event(windows, false, window, detail, mode);
+        window = windows[&window].parent;
+    }
+}
+
+/// Sends FocusIn from `ancestor` down to `child`, leaving out `skip`. The ancestor
+/// itself is included if `include_ancestor` is set.
+fn focus_in_events(
+    windows: &BTreeMap<u32, Window>,
+    ancestor: u32,
+    child: u32,
+    skip: u32,
+    detail: u8,
+    mode: u8,
+    include_ancestor: bool,
+) {
+    let mut path = vec![];
+    let mut window = child;
+    while window != ancestor {
+        if window == 0 {
+            return;
+        }
+        path.push(window);
+        window = windows[&window].parent;
+    }
+    if include_ancestor {
+        focus_event(windows, true, ancestor, detail, mode);
+    }
+    for window in path.into_iter().rev().filter(|window| *window != skip) {
+        focus_event(windows, true, window, detail, mode);
+    }
+}
+
+impl InputState {
+    /// Generates the FocusOut and FocusIn events for focus moving from `from` to `to`.
+    pub fn focus_events(&self, windows: &BTreeMap<u32, Window>, from: Focus, to: Focus, mode: u8) {
+        let exists = |focus| match focus {
+            Focus::Window(window) => windows.contains_key(&window),
+            _ => true,
+        };
+        if from == to || !exists(from) || !exists(to) {
+            return;
+        }
+        let root = DEFAULT_SCREEN.root_window;
+        let pointer = window_at(windows, self.pointer_x as i32, self.pointer_y as i32);
+        let root_detail = |focus| match focus {
+            Focus::None => NOTIFY_DETAIL_NONE,
+            _ => NOTIFY_POINTER_ROOT,
+        };
+        match (from, to) {
+            (Focus::Window(from), Focus::Window(to)) => {
+                if is_parent(windows, to, from) {
+                    focus_event(windows, false, from, NOTIFY_ANCESTOR, mode);
+                    focus_out_events(windows, windows[&from].parent, to, NOTIFY_VIRTUAL, mode);
+                    focus_event(windows, true, to, NOTIFY_INFERIOR, mode);
+                    if is_parent(windows, to, pointer)
+                        && pointer != from
+                        && !is_parent(windows, from, pointer)
+                        && !is_parent(windows, pointer, from)
+                    {
+                        focus_in_events(windows, to, pointer, 0, NOTIFY_POINTER, mode, false);
+                    }
+                } else if is_parent(windows, from, to) {
+                    if is_parent(windows, from, pointer)
+                        && pointer != to
+                        && !is_parent(windows, to, pointer)
+                        && !is_parent(windows, pointer, to)
+                    {
+                        focus_out_events(windows, pointer, from, NOTIFY_POINTER, mode);
+                    }
+                    focus_event(windows, false, from, NOTIFY_INFERIOR, mode);
+                    focus_in_events(windows, from, to, to, NOTIFY_VIRTUAL, mode, false);
+                    focus_event(windows, true, to, NOTIFY_ANCESTOR, mode);
+                } else {
+                    let common = common_ancestor(windows, from, to);
+                    if is_parent(windows, from, pointer) {
+                        focus_out_events(windows, pointer, from, NOTIFY_POINTER, mode);
+                    }
+                    focus_event(windows, false, from, NOTIFY_NONLINEAR, mode);
+                    focus_out_events(windows, windows[&from].parent, common, NOTIFY_NONLINEAR_VIRTUAL, mode);
+                    focus_in_events(windows, common, to, to, NOTIFY_NONLINEAR_VIRTUAL, mode, false);
+                    focus_event(windows, true, to, NOTIFY_NONLINEAR, mode);
+                    if is_parent(windows, to, pointer) {
+                        focus_in_events(windows, to, pointer, 0, NOTIFY_POINTER, mode, false);
+                    }
+                }
+            }
+            (Focus::Window(from), to) => {
+                if is_parent(windows, from, pointer) {
+                    focus_out_events(windows, pointer, from, NOTIFY_POINTER, mode);
+                }
+                focus_event(windows, false, from, NOTIFY_NONLINEAR, mode);
+                focus_out_events(windows, windows[&from].parent, 0, NOTIFY_NONLINEAR_VIRTUAL, mode);
+                focus_event(windows, true, root, root_detail(to), mode);
+                if to == Focus::PointerRoot {
+                    focus_in_events(windows, root, pointer, 0, NOTIFY_POINTER, mode, false);
+                }
+            }
+            (from, to) => {
+                if from == Focus::PointerRoot {
+                    focus_out_events(windows, pointer, root, NOTIFY_POINTER, mode);
+                }
+                focus_event(windows, false, root, root_detail(from), mode);
+                match to {
+                    Focus::Window(to) => {
+                        if to != root {
+                            focus_in_events(windows, root, to, to, NOTIFY_NONLINEAR_VIRTUAL, mode, true);
+                        }
+                        focus_event(windows, true, to, NOTIFY_NONLINEAR, mode);
+                        if is_parent(windows, to, pointer) {
+                            focus_in_events(windows, to, pointer, 0, NOTIFY_POINTER, mode, false);
+                        }
+                    }
+                    _ => {
+                        focus_event(windows, true, root, root_detail(to), mode);
+                        if to == Focus::PointerRoot {
+                            focus_in_events(windows, root, pointer, 0, NOTIFY_POINTER, mode, false);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// The window keyboard events start from: the pointer window if it is inside the
+    /// focus window (or the focus is PointerRoot), otherwise the focus window itself.
+    pub fn focus_source(&self, windows: &BTreeMap<u32, Window>) -> Option<u32> {
+        let pointer = window_at(windows, self.pointer_x as i32, self.pointer_y as i32);
+        match self.focus {
+            Focus::None => None,
+            Focus::PointerRoot => Some(pointer),
+            Focus::Window(focus) if is_inferior_or_self(windows, pointer, focus) => Some(pointer),
+            Focus::Window(focus) => Some(focus),
+        }
+    }
+
+    fn focus_mode(&self) -> u8 {
+        match self.keyboard_grab.grab {
+            Some(_) => NOTIFY_WHILE_GRABBED,
+            None => NOTIFY_NORMAL,
+        }
+    }
+
+    /// Moves the focus without checking the request time.
+    fn change_focus(&mut self, windows: &BTreeMap<u32, Window>, focus: Focus) {
+        let old = self.focus;
+        self.focus = focus;
+        self.focus_events(windows, old, focus, self.focus_mode());
+    }
+
+    /// Implements SetInputFocus.
+    pub fn set_focus(
+        &mut self,
+        windows: &BTreeMap<u32, Window>,
+        focus: u32,
+        revert_to: u8,
+        time: u32,
+    ) -> Result<(), XError> {
+        if revert_to > REVERT_TO_PARENT {
+            return Err(XError::new(ErrorCode::Value, revert_to as u32));
+        }
+        let focus = Focus::from_id(focus);
+        if let Focus::Window(window) = focus {
+            get_window(windows, window)?;
+            if !is_viewable(windows, window) {
+                return Err(XError::new(ErrorCode::Match, window));
+            }
+        }
+        let Some(time) = time::check(time, self.focus_time) else {
+            return Ok(());
+        };
+        self.focus_time = time;
+        self.revert_to = revert_to;
+        self.change_focus(windows, focus);
+        Ok(())
+    }
+
+    /// Applies the revert-to rule if the focus window is no longer viewable.
+    pub fn revert_unviewable_focus(&mut self, windows: &BTreeMap<u32, Window>) {
+        let Focus::Window(window) = self.focus else {
+            return;
+        };
+        if is_viewable(windows, window) {
+            return;
+        }
+        let focus = match self.revert_to {
+            REVERT_TO_POINTER_ROOT => Focus::PointerRoot,
+            REVERT_TO_PARENT => {
+                let mut parent = windows[&window].parent;
+                while !is_viewable(windows, parent) {
+                    parent = windows[&parent].parent;
+                }
+                self.revert_to = REVERT_TO_NONE;
+                Focus::Window(parent)
+            }
+            _ => Focus::None,
+        };
+        self.focus_time = time::now();
+        self.change_focus(windows, focus);
+    }
+}
@@ -0,0 +1,47 @@
+//! The Generic Event Extension, which lets other extensions send events longer than 32
+//! bytes. Generic events all share event code 35 and carry the sending extension's
+//! major opcode, the extension's own event type and the length of the data past the
+//! usual 32 bytes.
+
+use crate::{
+    error::{ErrorCode, XError},
+    extension::ExtensionRequest,
+};
+
+pub static GE_NAME: &str = "Generic Event Extension";
+pub const GE_MAJOR_VERSION: u16 = 1;
+pub const GE_MINOR_VERSION: u16 = 0;
+
+/// The event code of every generic event.
+pub const GENERIC_EVENT: u8 = 35;
+
+#[derive(Debug)]
+pub enum GeRequest {
+    QueryVersion { major_version: u16, minor_version: u16 },
+}
+
+fn read_ge_request(request: &ExtensionRequest) -> Result<GeRequest, XError> {
+    let data = request.data;
+    let minimum_length = match request.minor_opcode {
+        0 => 4,
+        minor_opcode => return Err(XError::new(ErrorCode::Request, minor_opcode as u32)),
+    };
+    if data.len() < minimum_length {
+        return Err(XError::new(ErrorCode::Length, 0));
+    }
+    Ok(GeRequest::QueryVersion {
+        major_version: request.card16(data),
+        minor_version: request.card16(&data[2..]),
+    })
+}
+
+pub fn handle_ge_request(request: &ExtensionRequest) -> Result<Option<Vec<u8>>, XError> {
+    let ge_request = read_ge_request(request)?;
+    match ge_request {
+        GeRequest::QueryVersion { .. } => {
+            let mut body = request.to_bytes_16(GE_MAJOR_VERSION).to_vec();
+            body.extend(request.to_bytes_16(GE_MINOR_VERSION));
+            Ok(Some(request.reply(0, body)))
+        }
+    }
+}
@@ -0,0 +1,185 @@
+//! RENDER glyph sets: glyph images uploaded by clients doing their own font
+//! rasterization, and drawing runs of them with CompositeGlyphs.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    error::{ErrorCode, XError},
+    picture::{PictFormat, A1_FORMAT, A8R8G8B8_FORMAT, A8_FORMAT},
+    region::Rectangle,
+    render::bad_glyph,
+};
+
+/// The formats glyph sets may use.
+pub const GLYPH_FORMATS: [u32; 3] = [A1_FORMAT, A8_FORMAT, A8R8G8B8_FORMAT];
+
+/// The size and placement of a glyph: (`x`, `y`) is the glyph origin within the image,
+/// and the offsets advance the pen to the next glyph.
+#[derive(Clone, Copy, Debug)]
+pub struct GlyphInfo {
+    pub width: u16,
+    pub height: u16,
+    pub x: i16,
+    pub y: i16,
+    pub x_off: i16,
+    pub y_off: i16,
+}
+
+pub struct Glyph {
+    pub info: GlyphInfo,
+    /// Premultiplied `[alpha, red, green, blue]`, row by row.
+    pixels: Vec<[u8; 4]>,
+    /// Set for glyphs with color channels, which mask each channel separately.
+    component_alpha: bool,
+}
+
+impl fmt::Debug for Glyph {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Glyph").field("info", &self.info).finish()
+    }
+}
+
+impl Glyph {
+    /// The destination rectangle the glyph covers when its origin is at (`x`, `y`).
+    pub fn rectangle(&self, x: i32, y: i32) -> Rectangle {
+        let info = &self.info;
+        Rectangle::new(
+            x - info.x as i32,
+            y - info.y as i32,
+            info.width as i32,
+            info.height as i32,
+        )
+    }
+
+    /// The glyph as a mask at (`x`, `y`) within its image.
+    pub fn mask(&self, x: i32, y: i32) -> [u8; 4] {
+        let color = self.pixels[y as usize * self.info.width as usize + x as usize];
+        if self.component_alpha {
+            color
+        } else {
+            [color[0]; 4]
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct GlyphSet {
+    pub format: &'static PictFormat,
+    pub glyphs: BTreeMap<u32, Arc<Glyph>>,
+}
+
+/// Glyph sets by id. ReferenceGlyphSet gives a set another id, so ids share sets.
+pub static GLYPH_SETS: Mutex<BTreeMap<u32, Arc<Mutex<GlyphSet>>>> = Mutex::new(BTreeMap::new());
+
+/// Bytes in one row of a glyph image. Rows are padded to 32 bits, and pixels take 1, 8
+/// or 32 bits according to the depth, as clients lay them out.
+fn glyph_stride(depth: u8, width: u16) -> usize {
+    let bits_per_pixel = match depth {
+        1 => 1,
+        8 => 8,
+        _ => 32,
+    };
+    (width as usize * bits_per_pixel).next_multiple_of(32) / 8
+}
+
+/// Decodes the images of AddGlyphs, which follow each other in `data`, and adds the
+/// glyphs to `set`, replacing any with the same ids.
+pub fn add_glyphs(set: &mut GlyphSet, ids: &[u32], infos: &[GlyphInfo], data: &[u8]) -> Result<(), XError> {
+    let format = set.format;
+    let mut offset = 0;
+    let mut glyphs = vec![];
+    for (id, info) in ids.iter().zip(infos) {
+        let stride = glyph_stride(format.depth, info.width);
+        let image = data
+            .get(offset..offset + stride * info.height as usize)
+            .ok_or(XError::new(ErrorCode::Length, 0))?;
+        offset += image.len();
+        let mut pixels = Vec::with_capacity(info.width as usize * info.height as usize);
+        for row in image.chunks_exact(stride.max(1)).take(info.height as usize) {
+            for x in 0..info.width as usize {
+                let pixel = match format.depth {
+                    1 => (row[x / 8] >> (x % 8)) as u32 & 1,
+                    8 => row[x] as u32,
+                    _ => u32::from_le_bytes(row[4 * x..4 * x + 4].try_into().unwrap()),
+                };
+                pixels.push(format.fetch(pixel));
+            }
+        }
+        let glyph = Glyph {
+            info: *info,
+            pixels,
+            component_alpha: format.red.mask != 0,
+        };
+        glyphs.push((*id, Arc::new(glyph)));
+    }
+    set.glyphs.extend(glyphs);
+    Ok(())
+}
+
+/// An element of a CompositeGlyphs request.
+#[derive(Debug)]
+pub enum GlyphItem {
+    GlyphSet(u32),
+    Glyphs { dx: i16, dy: i16, glyphs: Vec<u32> },
+}
+
+/// Looks up the glyphs of a CompositeGlyphs request, returning each glyph with the
+/// position of its origin. Items either switch glyph set or move the pen and draw.
+pub fn layout_glyphs(
+    mut set: Arc<Mutex<GlyphSet>>,
+    items: &[GlyphItem],
+    lookup_set: impl Fn(u32) -> Result<Arc<Mutex<GlyphSet>>, XError>,
+) -> Result<Vec<(Arc<Glyph>, i32, i32)>, XError> {
+    let (mut x, mut y) = (0, 0);
+    let mut placed = vec![];
+    for item in items {
+        match item {
+            GlyphItem::GlyphSet(id) => set = lookup_set(*id)?,
+            GlyphItem::Glyphs { dx, dy, glyphs } => {
+                x += *dx as i32;
+                y += *dy as i32;
+                let set = set.lock().unwrap();
+                for id in glyphs {
+                    let glyph = set.glyphs.get(id).ok_or(bad_glyph(*id))?;
+                    placed.push((glyph.clone(), x, y));
+                    x += glyph.info.x_off as i32;
+                    y += glyph.info.y_off as i32;
+                }
+            }
+        }
+    }
+    Ok(placed)
+}
+
+/// Adds `glyphs` together into a mask of `format` covering all of them, returning the
+/// mask's rectangle and pixels.
+pub fn glyph_mask(glyphs: &[(Arc<Glyph>, i32, i32)], format: &PictFormat) -> (Rectangle, Vec<[u8; 4]>) {
+    let extents = glyphs
+        .iter()
+        .map(|(glyph, x, y)| glyph.rectangle(*x, *y))
+        .filter(|r| !r.is_empty())
+        .reduce(|a, b| {
+            let (x1, y1) = (a.x.min(b.x), a.y.min(b.y));
+            let (x2, y2) = ((a.x + a.width).max(b.x + b.width), (a.y + a.height).max(b.y + b.height));
+            Rectangle::new(x1, y1, x2 - x1, y2 - y1)
+        })
+        .unwrap_or_default();
+    let mut mask = vec![[0u8; 4]; extents.width as usize * extents.height as usize];
+    for (glyph, x, y) in glyphs {
+        let r = glyph.rectangle(*x, *y);
+        for gy in 0..r.height {
+            let row = (r.y + gy - extents.y) as usize * extents.width as usize;
+            for gx in 0..r.width {
+                let index = row + (r.x + gx - extents.x) as usize;
+                let color = glyph.pixels[gy as usize * r.width as usize + gx as usize];
+                let sum: [u8; 4] = std::array::from_fn(|c| mask[index][c].saturating_add(color[c]));
+                mask[index] = format.fetch(format.store(sum));
+            }
+        }
+    }
+    (extents, mask)
+}
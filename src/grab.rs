@@ -0,0 +1,713 @@
+use std::collections::BTreeMap;
+
+use crate::{
+    error::{ErrorCode, XError},
+    focus::{Focus, NOTIFY_GRAB, NOTIFY_UNGRAB},
+    input::{process_pending, DeviceEvent, InputState, RawInput, INPUT},
+    keyboard::check_keycode_range,
+    time,
+    window::{get_window, is_viewable, Window, WINDOWS},
+};
+
+pub const GRAB_MODE_SYNC: u8 = 0;
+pub const GRAB_MODE_ASYNC: u8 = 1;
+
+pub const GRAB_SUCCESS: u8 = 0;
+pub const ALREADY_GRABBED: u8 = 1;
+pub const GRAB_INVALID_TIME: u8 = 2;
+pub const GRAB_NOT_VIEWABLE: u8 = 3;
+pub const GRAB_FROZEN: u8 = 4;
+
+pub const ANY_MODIFIER: u16 = 1 << 15;
+pub const ANY_BUTTON: u8 = 0;
+pub const ANY_KEY: u8 = 0;
+
+pub const ASYNC_POINTER: u8 = 0;
+pub const SYNC_POINTER: u8 = 1;
+pub const REPLAY_POINTER: u8 = 2;
+pub const ASYNC_KEYBOARD: u8 = 3;
+pub const SYNC_KEYBOARD: u8 = 4;
+pub const REPLAY_KEYBOARD: u8 = 5;
+pub const ASYNC_BOTH: u8 = 6;
+pub const SYNC_BOTH: u8 = 7;
+
+/// Event mask bits that may be selected by a pointer grab.
+pub const POINTER_GRAB_EVENTS: u32 = 0x7ffc;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Device {
+    Pointer,
+    Keyboard,
+}
+
+impl Device {
+    pub fn other(self) -> Device {
+        match self {
+            Device::Pointer => Device::Keyboard,
+            Device::Keyboard => Device::Pointer,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GrabKind {
+    /// GrabPointer or GrabKeyboard.
+    Active,
+    /// The automatic pointer grab started by a ButtonPress.
+    Implicit,
+    /// Activated from a GrabButton or GrabKey by the given button or keycode.
+    Passive(u8),
+}
+
+#[derive(Clone, Debug)]
+pub struct Grab {
+    pub client: u32,
+    pub window: u32,
+    pub owner_events: bool,
+    pub event_mask: u32,
+    pub pointer_mode: u8,
+    pub keyboard_mode: u8,
+    pub confine_to: u32,
+    pub cursor: u32,
+    pub time: u32,
+    pub kind: GrabKind,
+    /// For grabs made through XInputExtension, the XInput events reported in place of
+    /// core events.
+    pub xi2_mask: Option<u32>,
+}
+
+/// A GrabButton or GrabKey waiting for its button or key to be pressed.
+#[derive(Clone, Debug)]
+pub struct PassiveGrab {
+    pub device: Device,
+    pub client: u32,
+    pub window: u32,
+    /// Button or keycode, or AnyButton/AnyKey.
+    pub detail: u8,
+    pub modifiers: u16,
+    pub owner_events: bool,
+    pub event_mask: u32,
+    pub pointer_mode: u8,
+    pub keyboard_mode: u8,
+    pub confine_to: u32,
+    pub cursor: u32,
+    pub xi2_mask: Option<u32>,
+}
+
+impl PassiveGrab {
+    fn matches(&self, detail: u8, modifiers: u16) -> bool {
+        (self.detail == ANY_BUTTON || self.detail == detail)
+            && (self.modifiers == ANY_MODIFIER || self.modifiers == modifiers)
+    }
+
+    fn overlaps(&self, other: &PassiveGrab) -> bool {
+        self.device == other.device
+            && self.window == other.window
+            && (self.detail == ANY_BUTTON || other.detail == ANY_BUTTON || self.detail == other.detail)
+            && (self.modifiers == ANY_MODIFIER
+                || other.modifiers == ANY_MODIFIER
+                || self.modifiers == other.modifiers)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum SyncState {
+    Thawed,
+    FreezeNextEvent,
+    FreezeBothNextEvent,
+    FrozenNoEvent,
+    /// Frozen after reporting an event, which ReplayPointer/ReplayKeyboard can replay.
+    FrozenWithEvent(RawInput, DeviceEvent),
+}
+
+#[derive(Clone, Debug)]
+pub struct DeviceGrab {
+    pub grab: Option<Grab>,
+    pub state: SyncState,
+    /// Frozen by a synchronous grab on the other device.
+    pub other: bool,
+    pub last_grab_time: u32,
+}
+
+impl DeviceGrab {
+    pub const fn new() -> DeviceGrab {
+        DeviceGrab {
+            grab: None,
+            state: SyncState::Thawed,
+            other: false,
+            last_grab_time: 0,
+        }
+    }
+
+    fn grabbed_by(&self, client: u32) -> bool {
+        self.grab.as_ref().is_some_and(|grab| grab.client == client)
+    }
+
+    fn own_frozen(&self) -> bool {
+        matches!(
+            self.state,
+            SyncState::FrozenNoEvent | SyncState::FrozenWithEvent(..)
+        )
+    }
+}
+
+impl Default for DeviceGrab {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl InputState {
+    pub fn device_grab(&self, device: Device) -> &DeviceGrab {
+        match device {
+            Device::Pointer => &self.pointer_grab,
+            Device::Keyboard => &self.keyboard_grab,
+        }
+    }
+
+    pub fn device_grab_mut(&mut self, device: Device) -> &mut DeviceGrab {
+        match device {
+            Device::Pointer => &mut self.pointer_grab,
+            Device::Keyboard => &mut self.keyboard_grab,
+        }
+    }
+
+    pub fn is_frozen(&self, device: Device) -> bool {
+        let device_grab = self.device_grab(device);
+        device_grab.own_frozen() || device_grab.other
+    }
+
+    /// Returns true if `device` is frozen by a grab belonging to `client`.
+    pub fn frozen_by(&self, device: Device, client: u32) -> bool {
+        let device_grab = self.device_grab(device);
+        (device_grab.own_frozen() && device_grab.grabbed_by(client))
+            || (device_grab.other && self.device_grab(device.other()).grabbed_by(client))
+    }
+
+    fn frozen_by_other_client(&self, device: Device, client: u32) -> bool {
+        let device_grab = self.device_grab(device);
+        (device_grab.own_frozen() && !device_grab.grabbed_by(client))
+            || (device_grab.other && !self.device_grab(device.other()).grabbed_by(client))
+    }
+
+    pub fn activate_grab(&mut self, windows: &BTreeMap<u32, Window>, device: Device, grab: Grab) {
+        if device == Device::Keyboard {
+            let from = match &self.keyboard_grab.grab {
+                Some(old) => Focus::Window(old.window),
+                None => self.focus,
+            };
+            self.focus_events(windows, from, Focus::Window(grab.window), NOTIFY_GRAB);
+        } else {
+            let from = match &self.pointer_grab.grab {
+                Some(old) => old.window,
+                None => self.pointer_window,
+            };
+            self.crossing_events(windows, from, grab.window, NOTIFY_GRAB);
+        }
+        let (this_mode, other_mode) = match device {
+            Device::Pointer => (grab.pointer_mode, grab.keyboard_mode),
+            Device::Keyboard => (grab.keyboard_mode, grab.pointer_mode),
+        };
+        let client = grab.client;
+        let other_grabbed_by_client = self.device_grab(device.other()).grabbed_by(client);
+        let this = self.device_grab_mut(device);
+        this.last_grab_time = grab.time;
+        this.grab = Some(grab);
+        if this_mode == GRAB_MODE_SYNC {
+            this.state = SyncState::FrozenNoEvent;
+        } else {
+            this.state = SyncState::Thawed;
+            if other_grabbed_by_client {
+                this.other = false;
+            }
+        }
+        self.device_grab_mut(device.other()).other = other_mode == GRAB_MODE_SYNC;
+        self.update_cursor(windows);
+    }
+
+    pub fn deactivate_grab(&mut self, windows: &BTreeMap<u32, Window>, device: Device) {
+        let this = self.device_grab_mut(device);
+        let grab = this.grab.take();
+        match (device, grab) {
+            (Device::Keyboard, Some(grab)) => {
+                self.focus_events(windows, Focus::Window(grab.window), self.focus, NOTIFY_UNGRAB);
+            }
+            (Device::Pointer, Some(grab)) => {
+                self.crossing_events(windows, grab.window, self.pointer_window, NOTIFY_UNGRAB);
+            }
+            _ => {}
+        }
+        let this = self.device_grab_mut(device);
+        this.state = SyncState::Thawed;
+        self.device_grab_mut(device.other()).other = false;
+        self.update_cursor(windows);
+    }
+
+    /// Applies the freeze requested by SyncPointer, SyncKeyboard, SyncBoth or a
+    /// synchronous passive grab once a grabbed event has been reported.
+    pub fn freeze_after_delivery(&mut self, device: Device, raw: RawInput, event: DeviceEvent) {
+        match self.device_grab(device).state {
+            SyncState::FreezeBothNextEvent => {
+                let client = self.device_grab(device).grab.as_ref().map(|grab| grab.client);
+                let other = self.device_grab_mut(device.other());
+                if client.is_some() && other.grab.as_ref().map(|grab| grab.client) == client {
+                    other.state = SyncState::FrozenNoEvent;
+                } else {
+                    other.other = true;
+                }
+                self.device_grab_mut(device).state = SyncState::FrozenWithEvent(raw, event);
+            }
+            SyncState::FreezeNextEvent | SyncState::FrozenNoEvent => {
+                self.device_grab_mut(device).state = SyncState::FrozenWithEvent(raw, event);
+            }
+            _ => {}
+        }
+    }
+
+    fn thaw(&mut self, device: Device, client: u32) {
+        if self.device_grab(device).grabbed_by(client) {
+            self.device_grab_mut(device).state = SyncState::Thawed;
+        }
+        if self.device_grab(device.other()).grabbed_by(client) {
+            self.device_grab_mut(device).other = false;
+        }
+    }
+
+    fn sync(&mut self, device: Device, client: u32, state: SyncState) {
+        let other_grabbed_by_client = self.device_grab(device.other()).grabbed_by(client);
+        let this = self.device_grab_mut(device);
+        if this.grabbed_by(client) {
+            this.state = state;
+        }
+        if other_grabbed_by_client {
+            this.other = false;
+        }
+    }
+
+    /// Finds the outermost passive grab that `detail` and `modifiers` activate on the
+    /// path from the root to `source`. When replaying, only windows below
+    /// `replay_window` are considered.
+    pub fn find_passive_grab(
+        &self,
+        windows: &BTreeMap<u32, Window>,
+        device: Device,
+        source: u32,
+        detail: u8,
+        modifiers: u16,
+        replay_window: Option<u32>,
+    ) -> Option<PassiveGrab> {
+        let mut path = vec![];
+        let mut window = source;
+        while window != 0 {
+            if Some(window) == replay_window {
+                break;
+            }
+            path.push(window);
+            window = windows[&window].parent;
+        }
+        for window in path.into_iter().rev() {
+            for passive in &self.passive_grabs {
+                if passive.device == device
+                    && passive.window == window
+                    && passive.matches(detail, modifiers & 0xff)
+                    && (passive.confine_to == 0 || is_viewable(windows, passive.confine_to))
+                {
+                    return Some(passive.clone());
+                }
+            }
+        }
+        None
+    }
+
+    /// Implements GrabPointer and GrabKeyboard, returning the reply status.
+    pub fn grab_device(&mut self, windows: &BTreeMap<u32, Window>, device: Device, mut grab: Grab) -> u8 {
+        if !is_viewable(windows, grab.window)
+            || (grab.confine_to != 0 && !is_viewable(windows, grab.confine_to))
+        {
+            return GRAB_NOT_VIEWABLE;
+        }
+        let device_grab = self.device_grab(device);
+        let Some(time) = time::check(grab.time, device_grab.last_grab_time) else {
+            return GRAB_INVALID_TIME;
+        };
+        grab.time = time;
+        if device_grab.grab.as_ref().is_some_and(|g| g.client != grab.client) {
+            return ALREADY_GRABBED;
+        }
+        if self.frozen_by_other_client(device, grab.client) {
+            return GRAB_FROZEN;
+        }
+        self.activate_grab(windows, device, grab);
+        GRAB_SUCCESS
+    }
+
+    /// Implements UngrabPointer and UngrabKeyboard.
+    pub fn ungrab_device(&mut self, windows: &BTreeMap<u32, Window>, device: Device, client: u32, time: u32) {
+        let device_grab = self.device_grab(device);
+        if !device_grab.grabbed_by(client) || time::check(time, device_grab.last_grab_time).is_none() {
+            return;
+        }
+        self.deactivate_grab(windows, device);
+    }
+
+    pub fn change_active_pointer_grab(
+        &mut self,
+        windows: &BTreeMap<u32, Window>,
+        client: u32,
+        cursor: u32,
+        time: u32,
+        event_mask: u32,
+    ) {
+        let device_grab = &mut self.pointer_grab;
+        if !device_grab.grabbed_by(client) || time::check(time, device_grab.last_grab_time).is_none() {
+            return;
+        }
+        let grab = device_grab.grab.as_mut().unwrap();
+        grab.cursor = cursor;
+        grab.event_mask = event_mask;
+        self.update_cursor(windows);
+    }
+
+    /// Implements GrabButton and GrabKey, replacing this client's own conflicting grabs
+    /// made through the same protocol.
+    pub fn grab_passive(&mut self, passive: PassiveGrab) -> Result<(), XError> {
+        if self
+            .passive_grabs
+            .iter()
+            .any(|other| other.client != passive.client && other.overlaps(&passive))
+        {
+            return Err(XError::new(ErrorCode::Access, passive.window));
+        }
+        self.passive_grabs.retain(|other| {
+            !(other.client == passive.client
+                && other.xi2_mask.is_some() == passive.xi2_mask.is_some()
+                && other.device == passive.device
+                && other.window == passive.window
+                && other.detail == passive.detail
+                && other.modifiers == passive.modifiers)
+        });
+        self.passive_grabs.push(passive);
+        Ok(())
+    }
+
+    /// Implements UngrabButton and UngrabKey, or with `xi2` set, XIPassiveUngrabDevice.
+    pub fn ungrab_passive(
+        &mut self,
+        device: Device,
+        client: u32,
+        window: u32,
+        detail: u8,
+        modifiers: u16,
+        xi2: bool,
+    ) {
+        self.passive_grabs.retain(|passive| {
+            !(passive.device == device
+                && passive.xi2_mask.is_some() == xi2
+                && passive.client == client
+                && passive.window == window
+                && (detail == ANY_BUTTON || passive.detail == detail)
+                && (modifiers == ANY_MODIFIER || passive.modifiers == modifiers))
+        });
+    }
+
+    /// Implements AllowEvents. Replayed events are returned for the caller to dispatch
+    /// once the grab they were frozen under has been released.
+    pub fn allow_events(
+        &mut self,
+        windows: &BTreeMap<u32, Window>,
+        client: u32,
+        mode: u8,
+        time: u32,
+    ) -> Result<Option<(RawInput, DeviceEvent, u32)>, XError> {
+        if mode > SYNC_BOTH {
+            return Err(XError::new(ErrorCode::Value, mode as u32));
+        }
+        let last_grab_time = [Device::Pointer, Device::Keyboard]
+            .into_iter()
+            .filter(|device| self.device_grab(*device).grabbed_by(client))
+            .map(|device| self.device_grab(device).last_grab_time)
+            .max_by(|a, b| time::compare(*a, *b))
+            .unwrap_or(0);
+        if time::check(time, last_grab_time).is_none() {
+            return Ok(None);
+        }
+        let device = match mode {
+            ASYNC_POINTER | SYNC_POINTER | REPLAY_POINTER => Device::Pointer,
+            _ => Device::Keyboard,
+        };
+        let both_frozen = self.frozen_by(Device::Pointer, client) && self.frozen_by(Device::Keyboard, client);
+        match mode {
+            ASYNC_POINTER | ASYNC_KEYBOARD => self.thaw(device, client),
+            SYNC_POINTER | SYNC_KEYBOARD => {
+                let device_grab = self.device_grab(device);
+                if device_grab.grabbed_by(client) && self.is_frozen(device) {
+                    self.sync(device, client, SyncState::FreezeNextEvent);
+                }
+            }
+            REPLAY_POINTER | REPLAY_KEYBOARD => {
+                let device_grab = self.device_grab(device);
+                if let (true, SyncState::FrozenWithEvent(raw, event)) =
+                    (device_grab.grabbed_by(client), &device_grab.state)
+                {
+                    // Only events frozen under a passive grab can be replayed; the
+                    // freeze of a GrabPointer or GrabKeyboard is just released.
+                    let grab = device_grab.grab.as_ref().unwrap();
+                    if grab.kind == GrabKind::Active {
+                        self.thaw(device, client);
+                        return Ok(None);
+                    }
+                    let replay = (*raw, *event, grab.window);
+                    self.deactivate_grab(windows, device);
+                    return Ok(Some(replay));
+                }
+            }
+            ASYNC_BOTH if both_frozen => {
+                self.thaw(Device::Pointer, client);
+                self.thaw(Device::Keyboard, client);
+            }
+            SYNC_BOTH if both_frozen => {
+                self.sync(Device::Pointer, client, SyncState::FreezeBothNextEvent);
+                self.sync(Device::Keyboard, client, SyncState::FreezeBothNextEvent);
+            }
+            _ => {}
+        }
+        Ok(None)
+    }
+
+    /// Releases active grabs whose window (or confine-to window) is no longer viewable,
+    /// and drops passive grabs on windows that no longer exist.
+    pub fn release_unviewable_grabs(&mut self, windows: &BTreeMap<u32, Window>) {
+        for device in [Device::Pointer, Device::Keyboard] {
+            if let Some(grab) = &self.device_grab(device).grab {
+                if !is_viewable(windows, grab.window)
+                    || (grab.confine_to != 0 && !is_viewable(windows, grab.confine_to))
+                {
+                    self.deactivate_grab(windows, device);
+                }
+            }
+        }
+        self.passive_grabs.retain(|passive| windows.contains_key(&passive.window));
+    }
+
+    /// Releases every active and passive grab belonging to `client`.
+    pub fn release_client_grabs(&mut self, windows: &BTreeMap<u32, Window>, client: u32) {
+        for device in [Device::Pointer, Device::Keyboard] {
+            if self.device_grab(device).grabbed_by(client) {
+                self.deactivate_grab(windows, device);
+            }
+        }
+        self.passive_grabs.retain(|passive| passive.client != client);
+    }
+}
+
+/// Releases the grabs of a client that has disconnected, thawing any devices they froze.
+pub fn release_client_grabs(client: u32) {
+    let windows = WINDOWS.lock().unwrap();
+    let mut input = INPUT.lock().unwrap();
+    input.release_client_grabs(&windows, client);
+    process_pending(&windows, &mut input);
+}
+
+/// Checks the window, confine-to window and modes shared by the grab requests.
+pub fn check_grab_arguments(
+    windows: &BTreeMap<u32, Window>,
+    grab_window: u32,
+    confine_to: u32,
+    pointer_mode: u8,
+    keyboard_mode: u8,
+) -> Result<(), XError> {
+    get_window(windows, grab_window)?;
+    if confine_to != 0 {
+        get_window(windows, confine_to)?;
+    }
+    for mode in [pointer_mode, keyboard_mode] {
+        if mode > GRAB_MODE_ASYNC {
+            return Err(XError::new(ErrorCode::Value, mode as u32));
+        }
+    }
+    Ok(())
+}
+
+pub fn check_pointer_event_mask(event_mask: u32) -> Result<(), XError> {
+    if event_mask & !POINTER_GRAB_EVENTS != 0 {
+        return Err(XError::new(ErrorCode::Value, event_mask));
+    }
+    Ok(())
+}
+
+pub fn check_modifiers(modifiers: u16) -> Result<(), XError> {
+    if modifiers != ANY_MODIFIER && modifiers & !0xff != 0 {
+        return Err(XError::new(ErrorCode::Value, modifiers as u32));
+    }
+    Ok(())
+}
+
+pub fn check_grab_key(key: u8) -> Result<(), XError> {
+    match key {
+        ANY_KEY => Ok(()),
+        key => check_keycode_range(key, 1),
+    }
+}
+
+impl Grab {
+    pub fn from_passive(passive: &PassiveGrab, time: u32, detail: u8) -> Grab {
+        Grab {
+            client: passive.client,
+            window: passive.window,
+            owner_events: passive.owner_events,
+            event_mask: passive.event_mask,
+            pointer_mode: passive.pointer_mode,
+            keyboard_mode: passive.keyboard_mode,
+            confine_to: passive.confine_to,
+            cursor: passive.cursor,
+            time,
+            kind: GrabKind::Passive(detail),
+            xi2_mask: passive.xi2_mask,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{input::DeviceEvent, screen::DEFAULT_SCREEN, time::CURRENT_TIME};
+
+    const CLIENT: u32 = 1;
+    const ROOT: u32 = DEFAULT_SCREEN.root_window;
+
+    fn screen() -> BTreeMap<u32, Window> {
+        let mut root = Window::new(ROOT, 0);
+        root.mapped = true;
+        BTreeMap::from([(ROOT, root)])
+    }
+
+    fn input() -> InputState {
+        let mut input = INPUT.lock().unwrap().clone();
+        input.pointer_window = ROOT;
+        input
+    }
+
+    fn keyboard_grab(kind: GrabKind, keyboard_mode: u8, pointer_mode: u8) -> Grab {
+        Grab {
+            client: CLIENT,
+            window: ROOT,
+            owner_events: false,
+            event_mask: 0,
+            pointer_mode,
+            keyboard_mode,
+            confine_to: 0,
+            cursor: 0,
+            time: CURRENT_TIME,
+            kind,
+            xi2_mask: None,
+        }
+    }
+
+    /// Reports a key press to the keyboard grab, as dispatching it would.
+    fn deliver_key(input: &mut InputState) {
+        let raw = RawInput::Key {
+            keycode: 38,
+            pressed: true,
+        };
+        let event = DeviceEvent {
+            detail: 38,
+            time: time::now(),
+            root: ROOT,
+            event: ROOT,
+            child: 0,
+            root_x: 0,
+            root_y: 0,
+            event_x: 0,
+            event_y: 0,
+            state: 0,
+            same_screen: true,
+            auto_repeat: false,
+        };
+        input.freeze_after_delivery(Device::Keyboard, raw, event);
+    }
+
+    #[test]
+    fn sync_grab_freezes_until_async_events() {
+        let (windows, mut input) = (screen(), input());
+        let grab = keyboard_grab(GrabKind::Active, GRAB_MODE_SYNC, GRAB_MODE_ASYNC);
+        let status = input.grab_device(&windows, Device::Keyboard, grab);
+        assert_eq!(status, GRAB_SUCCESS);
+        assert!(input.is_frozen(Device::Keyboard));
+        assert!(!input.is_frozen(Device::Pointer));
+        // Only the grabbing client can thaw the keyboard.
+        input.allow_events(&windows, CLIENT + 1, ASYNC_KEYBOARD, CURRENT_TIME).unwrap();
+        assert!(input.is_frozen(Device::Keyboard));
+        input.allow_events(&windows, CLIENT, ASYNC_KEYBOARD, CURRENT_TIME).unwrap();
+        assert!(!input.is_frozen(Device::Keyboard));
+    }
+
+    #[test]
+    fn sync_keyboard_freezes_after_the_next_event() {
+        let (windows, mut input) = (screen(), input());
+        let grab = keyboard_grab(GrabKind::Active, GRAB_MODE_SYNC, GRAB_MODE_ASYNC);
+        input.grab_device(&windows, Device::Keyboard, grab);
+        input.allow_events(&windows, CLIENT, SYNC_KEYBOARD, CURRENT_TIME).unwrap();
+        assert!(!input.is_frozen(Device::Keyboard));
+        deliver_key(&mut input);
+        assert!(matches!(input.keyboard_grab.state, SyncState::FrozenWithEvent(..)));
+    }
+
+    #[test]
+    fn grab_freezes_the_other_device() {
+        let (windows, mut input) = (screen(), input());
+        let grab = keyboard_grab(GrabKind::Active, GRAB_MODE_ASYNC, GRAB_MODE_SYNC);
+        input.grab_device(&windows, Device::Keyboard, grab);
+        assert!(!input.is_frozen(Device::Keyboard));
+        assert!(input.frozen_by(Device::Pointer, CLIENT));
+        input.allow_events(&windows, CLIENT, ASYNC_POINTER, CURRENT_TIME).unwrap();
+        assert!(!input.is_frozen(Device::Pointer));
+        assert!(input.keyboard_grab.grab.is_some());
+    }
+
+    #[test]
+    fn replay_releases_a_passive_grab() {
+        let (windows, mut input) = (screen(), input());
+        let grab = keyboard_grab(GrabKind::Passive(38), GRAB_MODE_SYNC, GRAB_MODE_ASYNC);
+        input.activate_grab(&windows, Device::Keyboard, grab);
+        deliver_key(&mut input);
+        let replay = input.allow_events(&windows, CLIENT, REPLAY_KEYBOARD, CURRENT_TIME).unwrap();
+        assert!(matches!(replay, Some((RawInput::Key { keycode: 38, .. }, _, ROOT))));
+        assert!(input.keyboard_grab.grab.is_none());
+        assert!(!input.is_frozen(Device::Keyboard));
+    }
+
+    #[test]
+    fn replay_only_thaws_an_active_grab() {
+        let (windows, mut input) = (screen(), input());
+        let grab = keyboard_grab(GrabKind::Active, GRAB_MODE_SYNC, GRAB_MODE_ASYNC);
+        input.grab_device(&windows, Device::Keyboard, grab);
+        deliver_key(&mut input);
+        let replay = input.allow_events(&windows, CLIENT, REPLAY_KEYBOARD, CURRENT_TIME).unwrap();
+        assert!(replay.is_none());
+        assert!(input.keyboard_grab.grab.is_some());
+        assert!(!input.is_frozen(Device::Keyboard));
+    }
+
+    #[test]
+    fn allow_events_before_the_grab_is_ignored() {
+        time::use_virtual_clock();
+        time::advance(100);
+        let (windows, mut input) = (screen(), input());
+        let grab = keyboard_grab(GrabKind::Active, GRAB_MODE_SYNC, GRAB_MODE_ASYNC);
+        input.grab_device(&windows, Device::Keyboard, grab);
+        let grab_time = input.keyboard_grab.last_grab_time;
+        input.allow_events(&windows, CLIENT, ASYNC_KEYBOARD, grab_time - 1).unwrap();
+        assert!(input.is_frozen(Device::Keyboard));
+        input.allow_events(&windows, CLIENT, ASYNC_KEYBOARD, grab_time.wrapping_add(100_000)).unwrap();
+        assert!(input.is_frozen(Device::Keyboard));
+        time::advance(10);
+        input.allow_events(&windows, CLIENT, ASYNC_KEYBOARD, grab_time).unwrap();
+        assert!(!input.is_frozen(Device::Keyboard));
+    }
+
+    #[test]
+    fn allow_events_rejects_unknown_modes() {
+        let (windows, mut input) = (screen(), input());
+        assert!(input.allow_events(&windows, CLIENT, SYNC_BOTH + 1, CURRENT_TIME).is_err());
+    }
+}
@@ -0,0 +1,147 @@
+//! RENDER source pictures without a drawable: solid fills and linear, radial and
+//! conical gradients. Gradients are evaluated at pixel centers the way pixman does,
+//! interpolating stop colors before premultiplying them.
+
+use std::f64::consts::PI;
+
+use crate::{
+    error::{ErrorCode, XError},
+    picture::{REPEAT_NORMAL, REPEAT_PAD, REPEAT_REFLECT},
+};
+
+/// A gradient stop: its position along the gradient and its color as non-premultiplied
+/// `[alpha, red, green, blue]` between 0 and 1.
+#[derive(Clone, Copy, Debug)]
+pub struct Stop {
+    pub offset: f64,
+    pub color: [f64; 4],
+}
+
+#[derive(Clone, Debug)]
+pub enum Fill {
+    /// A premultiplied `[alpha, red, green, blue]` color.
+    Solid([u8; 4]),
+    Linear {
+        p1: (f64, f64),
+        p2: (f64, f64),
+        stops: Vec<Stop>,
+    },
+    /// Circles given as (x, y, radius).
+    Radial {
+        inner: (f64, f64, f64),
+        outer: (f64, f64, f64),
+        stops: Vec<Stop>,
+    },
+    /// `angle` in degrees.
+    Conical {
+        center: (f64, f64),
+        angle: f64,
+        stops: Vec<Stop>,
+    },
+}
+
+/// Builds the stops of a gradient request from their 16.16 fixed point offsets and
+/// 16-bit red, green, blue and alpha colors. Offsets must be between 0 and 1 and must
+/// not decrease.
+pub fn make_stops(offsets: &[i32], colors: &[[u16; 4]]) -> Result<Vec<Stop>, XError> {
+    if offsets.is_empty() {
+        return Err(XError::new(ErrorCode::Value, 0));
+    }
+    let mut previous = 0;
+    let mut stops = vec![];
+    for (offset, [red, green, blue, alpha]) in offsets.iter().zip(colors) {
+        if *offset < previous || *offset > 1 << 16 {
+            return Err(XError::new(ErrorCode::Value, *offset as u32));
+        }
+        previous = *offset;
+        stops.push(Stop {
+            offset: *offset as f64 / 65536.0,
+            color: [*alpha, *red, *green, *blue].map(|channel| channel as f64 / 65535.0),
+        });
+    }
+    Ok(stops)
+}
+
+/// The color at position `t` along the stops, after applying `repeat` to `t`.
+fn stop_color(stops: &[Stop], t: f64, repeat: u8) -> [u8; 4] {
+    let t = match repeat {
+        REPEAT_NORMAL => t - t.floor(),
+        REPEAT_PAD => t.clamp(0.0, 1.0),
+        REPEAT_REFLECT => {
+            let t = t.rem_euclid(2.0);
+            if t > 1.0 {
+                2.0 - t
+            } else {
+                t
+            }
+        }
+        _ if !(0.0..=1.0).contains(&t) => return [0; 4],
+        _ => t,
+    };
+    let color = match stops.iter().position(|stop| stop.offset > t) {
+        Some(0) => stops[0].color,
+        None => stops[stops.len() - 1].color,
+        Some(i) => {
+            let (left, right) = (&stops[i - 1], &stops[i]);
+            let f = (t - left.offset) / (right.offset - left.offset);
+            std::array::from_fn(|c| left.color[c] + (right.color[c] - left.color[c]) * f)
+        }
+    };
+    let alpha = color[0];
+    let byte = |value: f64| (value.clamp(0.0, 1.0) * 255.0 + 0.5) as u8;
+    [byte(alpha), byte(color[1] * alpha), byte(color[2] * alpha), byte(color[3] * alpha)]
+}
+
+impl Fill {
+    /// The premultiplied color at (`x`, `y`), a point in gradient space.
+    pub fn color_at(&self, x: f64, y: f64, repeat: u8) -> [u8; 4] {
+        match self {
+            Fill::Solid(color) => *color,
+            Fill::Linear { p1, p2, stops } => {
+                let (dx, dy) = (p2.0 - p1.0, p2.1 - p1.1);
+                let length = dx * dx + dy * dy;
+                let t = if length == 0.0 {
+                    0.0
+                } else {
+                    ((x - p1.0) * dx + (y - p1.1) * dy) / length
+                };
+                stop_color(stops, t, repeat)
+            }
+            Fill::Radial { inner, outer, stops } => match radial_parameter(*inner, *outer, x, y) {
+                Some(t) => stop_color(stops, t, repeat),
+                None => [0; 4],
+            },
+            Fill::Conical { center, angle, stops } => {
+                let mut t = (y - center.1).atan2(x - center.0) + angle / 180.0 * PI;
+                t = t.rem_euclid(2.0 * PI);
+                stop_color(stops, 1.0 - t / (2.0 * PI), repeat)
+            }
+        }
+    }
+}
+
+/// Finds `t` for which the point lies on the circle interpolated between `inner`
+/// (t = 0) and `outer` (t = 1) with a radius that isn't negative, preferring the root
+/// pixman tries first.
+fn radial_parameter(inner: (f64, f64, f64), outer: (f64, f64, f64), x: f64, y: f64) -> Option<f64> {
+    let (cdx, cdy, dr) = (outer.0 - inner.0, outer.1 - inner.1, outer.2 - inner.2);
+    let (pdx, pdy) = (x - inner.0, y - inner.1);
+    let a = cdx * cdx + cdy * cdy - dr * dr;
+    let b = pdx * cdx + pdy * cdy + inner.2 * dr;
+    let c = pdx * pdx + pdy * pdy - inner.2 * inner.2;
+    let valid = |t: f64| t * dr >= -inner.2;
+    if a == 0.0 {
+        if b == 0.0 {
+            return None;
+        }
+        let t = c / (2.0 * b);
+        return valid(t).then_some(t);
+    }
+    let discriminant = b * b - a * c;
+    if discriminant < 0.0 {
+        return None;
+    }
+    let root = discriminant.sqrt();
+    [(b + root) / a, (b - root) / a].into_iter().find(|t| valid(*t))
+}
+
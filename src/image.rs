@@ -0,0 +1,242 @@
+//! Moving images between clients and drawables, and between drawables, for PutImage,
+//! CopyArea and MIT-SHM. Images use the byte and bit order announced in the connection
+//! setup (both LSBFirst), 32 bits per pixel in ZPixmap format, and scanlines padded to
+//! 32 bits.
+
+use std::collections::BTreeMap;
+
+use crate::{
+    damage::damage_drawable,
+    error::{ErrorCode, XError},
+    picture::{Drawables, CLIP_BY_CHILDREN},
+    pixmap::PIXMAPS,
+    region::{Rectangle, Region},
+    screen::DEFAULT_SCREEN,
+    window::{is_viewable, Window, INPUT_ONLY},
+};
+
+pub const XY_BITMAP: u8 = 0;
+pub const XY_PIXMAP: u8 = 1;
+pub const Z_PIXMAP: u8 = 2;
+
+/// Bytes in one scanline of an image `width` pixels wide. XY formats store each plane
+/// as a bitmap.
+pub fn scanline_bytes(format: u8, width: usize) -> usize {
+    let bits = if format == Z_PIXMAP { width * 32 } else { width };
+    bits.next_multiple_of(32) / 8
+}
+
+pub fn image_size(format: u8, depth: u8, width: usize, height: usize) -> usize {
+    let planes = if format == XY_PIXMAP { depth as usize } else { 1 };
+    scanline_bytes(format, width) * height * planes
+}
+
+/// An image in client format. In the XY formats, `left_pad` bits at the start of each
+/// scanline are skipped.
+pub struct Image<'a> {
+    pub format: u8,
+    pub depth: u8,
+    pub width: u16,
+    pub height: u16,
+    pub left_pad: u8,
+    pub data: &'a [u8],
+}
+
+fn bit(data: &[u8], row: usize, x: usize) -> u32 {
+    (data[row + x / 8] >> (x % 8)) as u32 & 1
+}
+
+impl Image<'_> {
+    pub fn size(&self) -> usize {
+        let width = self.width as usize + self.left_pad as usize;
+        image_size(self.format, self.depth, width, self.height as usize)
+    }
+
+    /// Returns the pixel at (`x`, `y`). GC state isn't tracked, so XYBitmap images draw
+    /// their set bits with pixel 1 and clear bits with pixel 0.
+    fn pixel(&self, x: usize, y: usize) -> u32 {
+        let stride = scanline_bytes(self.format, self.width as usize + self.left_pad as usize);
+        let x = x + self.left_pad as usize;
+        match self.format {
+            Z_PIXMAP => {
+                let start = y * stride + x * 4;
+                u32::from_le_bytes(self.data[start..start + 4].try_into().unwrap())
+            }
+            XY_BITMAP => bit(self.data, y * stride, x),
+            _ => {
+                let plane_size = stride * self.height as usize;
+                (0..self.depth as usize).fold(0, |pixel, plane| {
+                    pixel << 1 | bit(self.data, plane * plane_size + y * stride, x)
+                })
+            }
+        }
+    }
+}
+
+/// Returns the depth of `drawable`, which must be a window that can be drawn to or a
+/// pixmap.
+fn drawable_depth(windows: &BTreeMap<u32, Window>, drawable: u32) -> Result<u8, XError> {
+    match windows.get(&drawable) {
+        Some(window) if window.class == INPUT_ONLY => Err(XError::new(ErrorCode::Match, drawable)),
+        Some(window) => Ok(window.depth),
+        None => PIXMAPS
+            .lock()
+            .unwrap()
+            .get(&drawable)
+            .map(|pixmap| pixmap.depth)
+            .ok_or(XError::new(ErrorCode::Drawable, drawable)),
+    }
+}
+
+/// Draws the `source` part of `image` with its top-left corner at (`x`, `y`) in
+/// `drawable`, clipped to the visible part of windows.
+pub fn put_image(
+    windows: &BTreeMap<u32, Window>,
+    drawable: u32,
+    image: &Image,
+    source: Rectangle,
+    x: i16,
+    y: i16,
+) -> Result<(), XError> {
+    let depth = drawable_depth(windows, drawable)?;
+    match image.format {
+        XY_BITMAP if image.depth != 1 => return Err(XError::new(ErrorCode::Match, drawable)),
+        XY_PIXMAP | Z_PIXMAP if image.depth != depth => return Err(XError::new(ErrorCode::Match, drawable)),
+        Z_PIXMAP if image.left_pad != 0 => return Err(XError::new(ErrorCode::Match, drawable)),
+        XY_BITMAP | XY_PIXMAP | Z_PIXMAP => {}
+        format => return Err(XError::new(ErrorCode::Value, format as u32)),
+    }
+    if image.data.len() < image.size() {
+        return Err(XError::new(ErrorCode::Length, 0));
+    }
+    let Some(source) = source.intersect(&Rectangle::new(0, 0, image.width as i32, image.height as i32)) else {
+        return Ok(());
+    };
+    // Maps destination coordinates back into the image.
+    let (dx, dy) = (x as i32 - source.x, y as i32 - source.y);
+    let mut drawables = Drawables::lock(windows);
+    let destination = source.translate(dx, dy);
+    let region = drawables.drawable_clip(drawable, CLIP_BY_CHILDREN).intersect_rectangle(&destination);
+    for r in &region.rectangles {
+        for destination_y in r.y..r.y + r.height {
+            for destination_x in r.x..r.x + r.width {
+                let pixel = image.pixel((destination_x - dx) as usize, (destination_y - dy) as usize);
+                drawables.set(drawable, destination_x, destination_y, pixel);
+            }
+        }
+    }
+    damage_drawable(&mut drawables, drawable, &region);
+    Ok(())
+}
+
+/// Implements CopyArea with the default GC: copies `source_rectangle` of `source` to
+/// (`x`, `y`) in `destination`, clipped to the visible part of windows. Returns the
+/// parts of the destination, in its coordinates, that the source couldn't fill
+/// because they were obscured or outside it, which get GraphicsExpose events.
+pub fn copy_area(
+    windows: &BTreeMap<u32, Window>,
+    source: u32,
+    destination: u32,
+    source_rectangle: Rectangle,
+    x: i16,
+    y: i16,
+) -> Result<Region, XError> {
+    if drawable_depth(windows, source)? != drawable_depth(windows, destination)? {
+        return Err(XError::new(ErrorCode::Match, destination));
+    }
+    let mut drawables = Drawables::lock(windows);
+    let (dx, dy) = (x as i32 - source_rectangle.x, y as i32 - source_rectangle.y);
+    let wanted = Region::from_rectangle(source_rectangle);
+    let readable = wanted.intersect(&drawables.drawable_clip(source, CLIP_BY_CHILDREN));
+    let writable = drawables.drawable_clip(destination, CLIP_BY_CHILDREN);
+    let copied = readable.translate(dx, dy).intersect(&writable);
+    let mut pixels = vec![];
+    for r in &copied.rectangles {
+        for destination_y in r.y..r.y + r.height {
+            for destination_x in r.x..r.x + r.width {
+                let pixel = drawables.get(source, destination_x - dx, destination_y - dy);
+                pixels.push((destination_x, destination_y, pixel));
+            }
+        }
+    }
+    for (destination_x, destination_y, pixel) in pixels {
+        drawables.set(destination, destination_x, destination_y, pixel);
+    }
+    damage_drawable(&mut drawables, destination, &copied);
+    Ok(wanted.subtract(&readable).translate(dx, dy).intersect(&writable))
+}
+
+/// The result of GetImage: the drawable's depth and visual, and the image data.
+pub struct ImageReply {
+    pub depth: u8,
+    pub visual: u32,
+    pub data: Vec<u8>,
+}
+
+/// Reads `rectangle` of `drawable` in `format`, keeping only the planes in
+/// `plane_mask`. Windows must be viewable and the rectangle must lie within the window's
+/// border and the screen; pixmaps must contain it.
+pub fn get_image(
+    windows: &BTreeMap<u32, Window>,
+    drawable: u32,
+    rectangle: Rectangle,
+    format: u8,
+    plane_mask: u32,
+) -> Result<ImageReply, XError> {
+    if format != XY_PIXMAP && format != Z_PIXMAP {
+        return Err(XError::new(ErrorCode::Value, format as u32));
+    }
+    let depth = drawable_depth(windows, drawable)?;
+    if let Some(window) = windows.get(&drawable) {
+        let (origin_x, origin_y) = (window.screen_rectangle.x, window.screen_rectangle.y);
+        let border_width = window.border_width as i32;
+        let outside = Rectangle::new(
+            origin_x - border_width,
+            origin_y - border_width,
+            window.width as i32 + 2 * border_width,
+            window.height as i32 + 2 * border_width,
+        );
+        let screen = windows[&DEFAULT_SCREEN.root_window].screen_rectangle;
+        let area = rectangle.translate(origin_x, origin_y);
+        let inside = |bounds: &Rectangle| area.is_empty() || area.intersect(bounds) == Some(area);
+        // Redirected windows keep all their contents offscreen.
+        let on_screen = window.surface != 0 || inside(&screen);
+        if !is_viewable(windows, drawable) || !inside(&outside) || !on_screen {
+            return Err(XError::new(ErrorCode::Match, drawable));
+        }
+    } else {
+        let pixmaps = PIXMAPS.lock().unwrap();
+        let pixmap = &pixmaps[&drawable];
+        if !rectangle.is_empty() && rectangle.intersect(&pixmap.bounds()) != Some(rectangle) {
+            return Err(XError::new(ErrorCode::Match, drawable));
+        }
+    }
+    let drawables = Drawables::lock(windows);
+    let pixels: Vec<u32> = (rectangle.y..rectangle.y + rectangle.height)
+        .flat_map(|y| (rectangle.x..rectangle.x + rectangle.width).map(move |x| (x, y)))
+        .map(|(x, y)| drawables.get(drawable, x, y))
+        .collect();
+    drop(drawables);
+    let width = rectangle.width as usize;
+    let mut data = vec![];
+    if format == Z_PIXMAP {
+        for row in pixels.chunks(width.max(1)) {
+            for pixel in row {
+                data.extend((pixel & plane_mask).to_le_bytes());
+            }
+        }
+    } else {
+        let stride = scanline_bytes(XY_PIXMAP, width);
+        for plane in (0..depth as u32).rev().filter(|plane| plane_mask & 1 << plane != 0) {
+            for row in pixels.chunks(width.max(1)) {
+                let mut scanline = vec![0u8; stride];
+                for (x, pixel) in row.iter().enumerate() {
+                    scanline[x / 8] |= ((pixel >> plane & 1) as u8) << (x % 8);
+                }
+                data.append(&mut scanline);
+            }
+        }
+    }
+    let visual = windows.get(&drawable).map_or(0, |window| window.visual);
+    Ok(ImageReply { depth, visual, data })
+}
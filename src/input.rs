@@ -0,0 +1,1042 @@
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, VecDeque},
+    sync::Mutex,
+};
+
+use crate::{
+    client::queue_event,
+    error::XError,
+    event::{
+        Event, BUTTON_MOTION_MASK, BUTTON_PRESS_MASK, BUTTON_RELEASE_MASK,
+        KEY_PRESS_MASK, KEY_RELEASE_MASK, OWNER_GRAB_BUTTON_MASK, POINTER_MOTION_HINT_MASK,
+        POINTER_MOTION_MASK,
+    },
+    focus::{Focus, REVERT_TO_NONE},
+    grab::{Device, DeviceGrab, Grab, GrabKind, PassiveGrab, GRAB_MODE_ASYNC},
+    keyboard::{KEYMAP, MAX_KEYCODE, MIN_KEYCODE, SHIFT_MASK},
+    region::Rectangle,
+    screen::DEFAULT_SCREEN,
+    time::{self, CURRENT_TIME},
+    touch::{Touch, TouchPhase},
+    window::{child_towards, get_window, is_inferior_or_self, Window, WINDOWS},
+    xfixes::apply_barriers,
+    xinput::{
+        deliver_raw_event, XIDeviceEvent, XIModifiers, XIRawEvent, VALUATOR_HORIZONTAL_SCROLL,
+        VALUATOR_VERTICAL_SCROLL, VALUATOR_X, VALUATOR_Y, VIRTUAL_CORE_KEYBOARD, VIRTUAL_CORE_POINTER,
+        VIRTUAL_KEYBOARD, VIRTUAL_POINTER, VIRTUAL_TOUCHSCREEN, XI_BUTTON_PRESS, XI_BUTTON_RELEASE,
+        XI_KEY_PRESS, XI_KEY_RELEASE, XI_KEY_REPEAT, XI_MOTION, XI_POINTER_EMULATED, XI_RAW_KEY_PRESS,
+        XI_TOUCH_BEGIN, XI_TOUCH_END, XI_TOUCH_UPDATE,
+    },
+    xkb::{key_repeats, process_key, XKB},
+};
+
+pub const BUTTON1_MASK: u16 = 1 << 8;
+
+/// Number of buttons on the core pointer.
+pub const POINTER_BUTTONS: u8 = 5;
+
+/// Number of pointer positions kept for GetMotionEvents.
+pub const MOTION_BUFFER_SIZE: usize = 256;
+
+/// MotionNotify detail for events sent in place of a stream to PointerMotionHint clients.
+const NOTIFY_HINT: u8 = 1;
+
+/// Keysym of the key pressed alongside keysyms that sit in the shifted column.
+const SHIFT_L: u32 = 0xffe1;
+
+/// State of the core pointer and keyboard. Lock after `WINDOWS` and before `KEYMAP`
+/// and `XKB`.
+pub static INPUT: Mutex<InputState> = Mutex::new(InputState {
+    pointer_x: 0,
+    pointer_y: 0,
+    pointer_window: 0,
+    buttons: 0,
+    keys: [0; 32],
+    pointer_grab: DeviceGrab::new(),
+    keyboard_grab: DeviceGrab::new(),
+    passive_grabs: Vec::new(),
+    pending: VecDeque::new(),
+    focus: Focus::PointerRoot,
+    revert_to: REVERT_TO_NONE,
+    focus_time: 0,
+    motion_history: VecDeque::new(),
+    motion_hint_window: 0,
+    scroll: (0, 0),
+    touches: Vec::new(),
+    next_touch_id: 1,
+    cursor_serial: 1,
+});
+
+#[derive(Clone, Debug)]
+pub struct InputState {
+    pub pointer_x: i16,
+    pub pointer_y: i16,
+    /// The deepest viewable window containing the pointer, as last reported by crossing
+    /// events.
+    pub pointer_window: u32,
+    /// Button1Mask through Button5Mask for the buttons currently held.
+    pub buttons: u16,
+    /// Bit vector of keycodes currently held, as reported by QueryKeymap.
+    pub keys: [u8; 32],
+    pub pointer_grab: DeviceGrab,
+    pub keyboard_grab: DeviceGrab,
+    pub passive_grabs: Vec<PassiveGrab>,
+    /// Input waiting for its device to be thawed.
+    pub pending: VecDeque<RawInput>,
+    pub focus: Focus,
+    pub revert_to: u8,
+    /// When the focus last changed, for SetInputFocus time checks.
+    pub focus_time: u32,
+    /// Timestamped root positions of the pointer, oldest first.
+    pub motion_history: VecDeque<(u32, i16, i16)>,
+    /// The window a PointerMotionHint event was last sent on, until the hint is reset.
+    pub motion_hint_window: u32,
+    /// Horizontal and vertical scroll valuators, which count scroll steps.
+    pub scroll: (i32, i32),
+    /// Touches on the touchscreen that have begun and not yet ended.
+    pub touches: Vec<Touch>,
+    /// The touch ID given to the next touch.
+    pub next_touch_id: u32,
+    /// The serial number of the cursor the pointer shows, as last reported to XFIXES
+    /// clients.
+    pub cursor_serial: u32,
+}
+
+impl InputState {
+    fn record_motion(&mut self) {
+        if self.motion_history.len() == MOTION_BUFFER_SIZE {
+            self.motion_history.pop_front();
+        }
+        self.motion_history.push_back((time::now(), self.pointer_x, self.pointer_y));
+    }
+
+    /// Implements GetMotionEvents, returning the recorded positions between `start` and
+    /// `stop` that fall inside `window`, relative to its origin.
+    pub fn motion_events(
+        &self,
+        windows: &BTreeMap<u32, Window>,
+        window: u32,
+        start: u32,
+        stop: u32,
+    ) -> Result<Vec<(u32, i16, i16)>, XError> {
+        let w = get_window(windows, window)?;
+        let now = time::now();
+        let start = if start == CURRENT_TIME { now } else { start };
+        let stop = match stop {
+            CURRENT_TIME => now,
+            stop if time::compare(stop, now) == Ordering::Greater => now,
+            stop => stop,
+        };
+        if time::compare(start, stop) == Ordering::Greater || time::compare(start, now) == Ordering::Greater {
+            return Ok(vec![]);
+        }
+        let area = w.screen_rectangle;
+        let border = w.border_width as i32;
+        Ok(self
+            .motion_history
+            .iter()
+            .filter(|(time, _, _)| {
+                time::compare(*time, start) != Ordering::Less
+                    && time::compare(*time, stop) != Ordering::Greater
+            })
+            .filter(|(_, x, y)| {
+                let (x, y) = (*x as i32, *y as i32);
+                x >= area.x - border
+                    && x < area.x + area.width + border
+                    && y >= area.y - border
+                    && y < area.y + area.height + border
+            })
+            .map(|(time, x, y)| (*time, x - area.x as i16, y - area.y as i16))
+            .collect())
+    }
+
+    /// Lets further PointerMotionHint events through once `client` has asked for the
+    /// pointer position with QueryPointer or GetMotionEvents.
+    pub fn stop_motion_hint(&mut self, windows: &BTreeMap<u32, Window>, client: u32) {
+        let Some(window) = windows.get(&self.motion_hint_window) else {
+            return;
+        };
+        let selected = window.event_masks.get(&client).copied().unwrap_or(0);
+        let grabbed = self
+            .pointer_grab
+            .grab
+            .as_ref()
+            .is_some_and(|grab| grab.client == client && grab.event_mask & POINTER_MOTION_HINT_MASK != 0);
+        if selected & POINTER_MOTION_HINT_MASK != 0 || grabbed {
+            self.motion_hint_window = 0;
+        }
+    }
+
+    pub fn is_key_down(&self, keycode: u8) -> bool {
+        self.keys[keycode as usize / 8] & (1 << (keycode % 8)) != 0
+    }
+
+    fn set_key(&mut self, keycode: u8, down: bool) {
+        if down {
+            self.keys[keycode as usize / 8] |= 1 << (keycode % 8);
+        } else {
+            self.keys[keycode as usize / 8] &= !(1 << (keycode % 8));
+        }
+    }
+
+    /// The modifier, group and button mask reported in the `state` field of device
+    /// events.
+    pub fn state(&self) -> u16 {
+        self.buttons | XKB.lock().unwrap().core_state()
+    }
+}
+
+/// A change to the pointer or keyboard, queued until its device is not frozen.
+#[derive(Clone, Copy, Debug)]
+pub enum RawInput {
+    Key { keycode: u8, pressed: bool },
+    /// `emulated` is set on the wheel buttons that stand in for scrolling.
+    Button { button: u8, pressed: bool, emulated: bool },
+    Motion { x: i32, y: i32, relative: bool },
+    /// Smooth scrolling by whole steps, positive down and right.
+    Scroll { dx: i32, dy: i32 },
+    /// A touch on the touchscreen, told apart from other touches by its slot.
+    Touch { slot: u32, phase: TouchPhase, x: i32, y: i32 },
+}
+
+impl RawInput {
+    pub fn device(&self) -> Device {
+        match self {
+            RawInput::Key { .. } => Device::Keyboard,
+            _ => Device::Pointer,
+        }
+    }
+
+    /// The master device and the slave device that XInput reports the input from.
+    pub fn xi_devices(&self) -> (u16, u16) {
+        match self {
+            RawInput::Key { .. } => (VIRTUAL_CORE_KEYBOARD, VIRTUAL_KEYBOARD),
+            RawInput::Touch { .. } => (VIRTUAL_CORE_POINTER, VIRTUAL_TOUCHSCREEN),
+            _ => (VIRTUAL_CORE_POINTER, VIRTUAL_POINTER),
+        }
+    }
+
+    /// The XInput event type reporting the input.
+    pub fn xi_type(&self) -> u16 {
+        match *self {
+            RawInput::Key { pressed: true, .. } => XI_KEY_PRESS,
+            RawInput::Key { pressed: false, .. } => XI_KEY_RELEASE,
+            RawInput::Button { pressed: true, .. } => XI_BUTTON_PRESS,
+            RawInput::Button { pressed: false, .. } => XI_BUTTON_RELEASE,
+            RawInput::Motion { .. } | RawInput::Scroll { .. } => XI_MOTION,
+            RawInput::Touch { phase, .. } => match phase {
+                TouchPhase::Begin => XI_TOUCH_BEGIN,
+                TouchPhase::Update => XI_TOUCH_UPDATE,
+                TouchPhase::End => XI_TOUCH_END,
+            },
+        }
+    }
+}
+
+/// Pointer and keyboard state captured for one device event.
+#[derive(Clone, Copy, Debug)]
+pub struct DeviceEvent {
+    pub detail: u8,
+    pub time: u32,
+    pub root: u32,
+    pub event: u32,
+    pub child: u32,
+    pub root_x: i16,
+    pub root_y: i16,
+    pub event_x: i16,
+    pub event_y: i16,
+    pub state: u16,
+    pub same_screen: bool,
+    /// Set on the KeyRelease and KeyPress sent each time a held key repeats.
+    pub auto_repeat: bool,
+}
+
+pub fn button_mask(button: u8) -> u16 {
+    match button {
+        1..=5 => BUTTON1_MASK << (button - 1),
+        _ => 0,
+    }
+}
+
+/// Returns the deepest viewable window whose input shape contains the given root
+/// coordinates.
+pub fn window_at(windows: &BTreeMap<u32, Window>, x: i32, y: i32) -> u32 {
+    let mut id = DEFAULT_SCREEN.root_window;
+    'descend: loop {
+        for child in windows[&id].children.iter().rev() {
+            let window = &windows[child];
+            if window.mapped && window.accepts_input_at(x, y) {
+                id = *child;
+                continue 'descend;
+            }
+        }
+        return id;
+    }
+}
+
+/// The window a device event is reported relative to, and the clients it is reported
+/// to along with the event mask each of them selected.
+struct Target {
+    window: u32,
+    recipients: Vec<(u32, u32)>,
+}
+
+/// Finds the first window from `source` towards the root with a client interested in
+/// `mask`, going no further than `stop_at` (0 for the root). If `client` is given, only
+/// that client's selections are considered.
+fn propagate(
+    windows: &BTreeMap<u32, Window>,
+    source: u32,
+    stop_at: u32,
+    mask: u32,
+    client: Option<u32>,
+) -> Option<Target> {
+    let mut window = source;
+    loop {
+        let w = &windows[&window];
+        let recipients: Vec<(u32, u32)> = w
+            .event_masks
+            .iter()
+            .filter(|(selecting, selected)| {
+                *selected & mask != 0 && client.is_none_or(|client| client == **selecting)
+            })
+            .map(|(selecting, selected)| (*selecting, *selected))
+            .collect();
+        if !recipients.is_empty() {
+            return Some(Target { window, recipients });
+        }
+        if w.attributes.do_not_propogate_mask & mask != 0 || w.parent == 0 || window == stop_at {
+            return None;
+        }
+        window = w.parent;
+    }
+}
+
+/// Finds where a device event goes while `grab` is active, or None if the grab's event
+/// mask filters it out.
+fn grab_target(
+    windows: &BTreeMap<u32, Window>,
+    grab: &Grab,
+    source: Option<u32>,
+    stop_at: u32,
+    mask: u32,
+) -> Option<Target> {
+    if let (true, Some(source)) = (grab.owner_events, source) {
+        if let Some(target) = propagate(windows, source, stop_at, mask, Some(grab.client)) {
+            return Some(target);
+        }
+    }
+    (grab.event_mask & mask != 0).then(|| Target {
+        window: grab.window,
+        recipients: vec![(grab.client, grab.event_mask)],
+    })
+}
+
+/// Fills in the window-relative fields of `template` for reporting on `window`.
+pub fn relative_to(
+    windows: &BTreeMap<u32, Window>,
+    window: u32,
+    source: Option<u32>,
+    template: DeviceEvent,
+) -> DeviceEvent {
+    let w = &windows[&window];
+    DeviceEvent {
+        event: window,
+        child: source.map_or(0, |source| child_towards(windows, window, source)),
+        event_x: template.root_x - w.screen_rectangle.x as i16,
+        event_y: template.root_y - w.screen_rectangle.y as i16,
+        ..template
+    }
+}
+
+/// Delivers a device event starting at `source` and propagating towards the root until
+/// a window with an interested client is found, going no further than `stop_at` (0 for
+/// the root). If `client` is given, only that client's selections are considered.
+/// Returns the window it was delivered to.
+pub fn deliver_device_event(
+    windows: &BTreeMap<u32, Window>,
+    source: u32,
+    stop_at: u32,
+    mask: u32,
+    template: DeviceEvent,
+    client: Option<u32>,
+    make_event: impl Fn(DeviceEvent) -> Event,
+) -> Option<u32> {
+    let target = propagate(windows, source, stop_at, mask, client)?;
+    let event = make_event(relative_to(windows, target.window, Some(source), template));
+    for (client, _) in &target.recipients {
+        queue_event(*client, event.clone());
+    }
+    Some(target.window)
+}
+
+/// How a device event is reported: the core event mask and event, and the XInput
+/// event type, flags, devices and valuators.
+struct EventKind {
+    mask: u32,
+    make_event: fn(DeviceEvent) -> Event,
+    /// None for the KeyRelease before a repeated KeyPress, which XInput doesn't report.
+    xi_type: Option<u16>,
+    xi_flags: u32,
+    master: u16,
+    slave: u16,
+    valuators: Vec<(u16, f64)>,
+}
+
+impl EventKind {
+    fn new(
+        raw: RawInput,
+        mask: u32,
+        make_event: fn(DeviceEvent) -> Event,
+        template: &DeviceEvent,
+        valuators: Vec<(u16, f64)>,
+    ) -> EventKind {
+        let (master, slave) = raw.xi_devices();
+        let (xi_type, xi_flags) = match raw {
+            RawInput::Key { pressed: false, .. } if template.auto_repeat => (None, 0),
+            RawInput::Key { .. } if template.auto_repeat => (Some(raw.xi_type()), XI_KEY_REPEAT),
+            RawInput::Button { emulated: true, .. } => (Some(raw.xi_type()), XI_POINTER_EMULATED),
+            _ => (Some(raw.xi_type()), 0),
+        };
+        EventKind {
+            mask,
+            make_event,
+            xi_type,
+            xi_flags,
+            master,
+            slave,
+            valuators,
+        }
+    }
+
+    fn xi_event(&self, deviceid: u16, device_event: DeviceEvent) -> Event {
+        Event::XIDevice(XIDeviceEvent {
+            evtype: self.xi_type.unwrap_or_default(),
+            deviceid,
+            sourceid: self.slave,
+            detail: device_event.detail as u32,
+            flags: self.xi_flags,
+            device_event,
+            modifiers: XIModifiers::current(device_event.state),
+            valuators: self.valuators.clone(),
+        })
+    }
+}
+
+/// Delivers the XInput form of a device event to the first window from `source`
+/// towards the root, going no further than `stop_at` (0 for the root), where a client
+/// selected it through XInput or the core protocol. Core delivery stops at the same
+/// window. The recipients returned are the clients that selected the event for the
+/// master device, with the events they selected.
+fn deliver_xi2(
+    windows: &BTreeMap<u32, Window>,
+    source: u32,
+    stop_at: u32,
+    kind: &EventKind,
+    template: DeviceEvent,
+) -> Option<Target> {
+    let xi_type = kind.xi_type?;
+    let mut window = source;
+    loop {
+        let w = &windows[&window];
+        let masters = w.xi_clients(kind.master, xi_type);
+        let slaves = w.xi_clients(kind.slave, xi_type);
+        if !masters.is_empty() || !slaves.is_empty() || w.event_masks.values().any(|s| s & kind.mask != 0) {
+            let event = relative_to(windows, window, Some(source), template);
+            for client in &slaves {
+                queue_event(*client, kind.xi_event(kind.slave, event));
+            }
+            for client in &masters {
+                queue_event(*client, kind.xi_event(kind.master, event));
+            }
+            let recipients = masters
+                .into_iter()
+                .map(|client| (client, w.xi_mask(client, kind.master)))
+                .collect();
+            return Some(Target { window, recipients });
+        }
+        if w.attributes.do_not_propogate_mask & kind.mask != 0 || w.parent == 0 || window == stop_at {
+            return None;
+        }
+        window = w.parent;
+    }
+}
+
+/// Delivers a device event to the client holding `grab`, as an XInput event if the
+/// grab was made through XInput. Returns false if the grab's event mask filtered it
+/// out.
+fn deliver_grabbed(
+    windows: &BTreeMap<u32, Window>,
+    grab: &Grab,
+    source: Option<u32>,
+    stop_at: u32,
+    kind: &EventKind,
+    template: DeviceEvent,
+) -> bool {
+    let Some(xi2_mask) = grab.xi2_mask else {
+        let Some(target) = grab_target(windows, grab, source, stop_at, kind.mask) else {
+            return false;
+        };
+        let event = (kind.make_event)(relative_to(windows, target.window, source, template));
+        for (client, _) in &target.recipients {
+            queue_event(*client, event.clone());
+        }
+        return true;
+    };
+    let Some(xi_type) = kind.xi_type else {
+        return false;
+    };
+    let mut owner = None;
+    if let (true, Some(mut window)) = (grab.owner_events, source) {
+        loop {
+            let w = &windows[&window];
+            if w.xi_mask(grab.client, kind.master) & 1 << xi_type != 0 {
+                owner = Some(window);
+                break;
+            }
+            if w.parent == 0 || window == stop_at {
+                break;
+            }
+            window = w.parent;
+        }
+    }
+    let Some(window) = owner.or((xi2_mask & 1 << xi_type != 0).then_some(grab.window)) else {
+        return false;
+    };
+    let event = relative_to(windows, window, source, template);
+    queue_event(grab.client, kind.xi_event(kind.master, event));
+    true
+}
+
+/// Delivers MotionNotify to `target`. Clients that selected PointerMotionHint get a
+/// single hint event, then nothing more on that window until the hint is reset.
+fn deliver_motion(
+    windows: &BTreeMap<u32, Window>,
+    input: &mut InputState,
+    target: Target,
+    source: u32,
+    template: DeviceEvent,
+) {
+    let event = relative_to(windows, target.window, Some(source), template);
+    let mut hinted = false;
+    for (client, selected) in target.recipients {
+        if selected & POINTER_MOTION_HINT_MASK == 0 {
+            queue_event(client, Event::MotionNotify(event));
+        } else if input.motion_hint_window != target.window {
+            let hint = DeviceEvent {
+                detail: NOTIFY_HINT,
+                ..event
+            };
+            queue_event(client, Event::MotionNotify(hint));
+            hinted = true;
+        }
+    }
+    if hinted {
+        input.motion_hint_window = target.window;
+    }
+}
+
+pub fn template(input: &InputState, detail: u8, state: u16) -> DeviceEvent {
+    DeviceEvent {
+        detail,
+        time: time::now(),
+        root: DEFAULT_SCREEN.root_window,
+        event: 0,
+        child: 0,
+        root_x: input.pointer_x,
+        root_y: input.pointer_y,
+        event_x: 0,
+        event_y: 0,
+        state,
+        same_screen: true,
+        auto_repeat: false,
+    }
+}
+
+/// Processes queued input in order, skipping input for frozen devices.
+pub fn process_pending(windows: &BTreeMap<u32, Window>, input: &mut InputState) {
+    while let Some(index) = input
+        .pending
+        .iter()
+        .position(|raw| !input.is_frozen(raw.device()))
+    {
+        let raw = input.pending.remove(index).unwrap();
+        process_raw(windows, input, raw);
+    }
+}
+
+fn process_raw(windows: &BTreeMap<u32, Window>, input: &mut InputState, raw: RawInput) {
+    let state = input.state();
+    if !matches!(raw, RawInput::Motion { .. }) {
+        input.motion_hint_window = 0;
+    }
+    let (detail, valuators) = match raw {
+        RawInput::Key { keycode, pressed } => {
+            if !pressed && !input.is_key_down(keycode) {
+                return;
+            }
+            // Pressing a key that is already down repeats it, as if the key had been
+            // released and pressed again.
+            if pressed && input.is_key_down(keycode) {
+                if !key_repeats(keycode) {
+                    return;
+                }
+                let release = RawInput::Key {
+                    keycode,
+                    pressed: false,
+                };
+                let repeat = DeviceEvent {
+                    auto_repeat: true,
+                    ..template(input, keycode, state)
+                };
+                dispatch(windows, input, release, repeat, None);
+                report_raw(windows, raw, repeat, vec![]);
+                dispatch(windows, input, raw, repeat, None);
+                return;
+            }
+            input.set_key(keycode, pressed);
+            process_key(keycode, pressed, input.buttons);
+            (keycode, vec![])
+        }
+        RawInput::Button { button, pressed, .. } => {
+            if pressed {
+                input.buttons |= button_mask(button);
+            } else {
+                input.buttons &= !button_mask(button);
+            }
+            (button, vec![])
+        }
+        RawInput::Motion { x, y, relative } => {
+            let (x, y) = match relative {
+                true => (input.pointer_x as i32 + x, input.pointer_y as i32 + y),
+                false => (x, y),
+            };
+            let (x, y) = confine(windows, input, x, y);
+            if (x, y) == (input.pointer_x, input.pointer_y) {
+                return;
+            }
+            let valuators = vec![
+                (VALUATOR_X, (x - input.pointer_x) as f64),
+                (VALUATOR_Y, (y - input.pointer_y) as f64),
+            ];
+            input.pointer_x = x;
+            input.pointer_y = y;
+            input.record_motion();
+            input.update_pointer_window(windows);
+            (0, valuators)
+        }
+        RawInput::Scroll { dx, dy } => {
+            if (dx, dy) == (0, 0) {
+                return;
+            }
+            input.scroll.0 += dx;
+            input.scroll.1 += dy;
+            // Each step is also a click of the wheel button for it, reported after the
+            // scroll itself for clients that don't know the scroll valuators.
+            let mut clicks = vec![];
+            for (delta, back, forward) in [(dy, 4, 5), (dx, 6, 7)] {
+                let button = if delta < 0 { back } else { forward };
+                for _ in 0..delta.unsigned_abs() {
+                    for pressed in [true, false] {
+                        clicks.push(RawInput::Button {
+                            button,
+                            pressed,
+                            emulated: true,
+                        });
+                    }
+                }
+            }
+            for click in clicks.into_iter().rev() {
+                input.pending.push_front(click);
+            }
+            let valuators = [(VALUATOR_HORIZONTAL_SCROLL, dx), (VALUATOR_VERTICAL_SCROLL, dy)]
+                .into_iter()
+                .filter(|(_, delta)| *delta != 0)
+                .map(|(number, delta)| (number, delta as f64))
+                .collect();
+            (0, valuators)
+        }
+        RawInput::Touch { slot, phase, x, y } => {
+            input.process_touch(windows, slot, phase, x, y);
+            return;
+        }
+    };
+    let template = template(input, detail, state);
+    if !matches!(raw, RawInput::Button { emulated: true, .. }) {
+        report_raw(windows, raw, template, valuators);
+    }
+    dispatch(windows, input, raw, template, None);
+}
+
+/// Reports input as an XInput raw event, with the valuators the device moved by.
+fn report_raw(
+    windows: &BTreeMap<u32, Window>,
+    raw: RawInput,
+    template: DeviceEvent,
+    valuators: Vec<(u16, f64)>,
+) {
+    let (master, sourceid) = raw.xi_devices();
+    let event = XIRawEvent {
+        evtype: raw.xi_type() - XI_KEY_PRESS + XI_RAW_KEY_PRESS,
+        deviceid: sourceid,
+        sourceid,
+        time: template.time,
+        detail: template.detail as u32,
+        flags: if template.auto_repeat { XI_KEY_REPEAT } else { 0 },
+        valuators,
+    };
+    deliver_raw_event(windows, master, event);
+}
+
+/// Clamps root coordinates to the screen, or to the confine-to window of the pointer grab,
+/// then stops the pointer at any pointer barriers on the way there.
+fn confine(windows: &BTreeMap<u32, Window>, input: &InputState, x: i32, y: i32) -> (i16, i16) {
+    let confine_to = match &input.pointer_grab.grab {
+        Some(grab) if windows.contains_key(&grab.confine_to) => grab.confine_to,
+        _ => DEFAULT_SCREEN.root_window,
+    };
+    let bounds = windows[&confine_to].screen_rectangle;
+    let x = x.clamp(bounds.x, bounds.x + bounds.width.max(1) - 1);
+    let y = y.clamp(bounds.y, bounds.y + bounds.height.max(1) - 1);
+    let (x, y) = apply_barriers((input.pointer_x as i32, input.pointer_y as i32), (x, y));
+    (x as i16, y as i16)
+}
+
+/// Reports input whose effect on the device state has already been applied. When
+/// replaying a frozen event, passive grabs at or above `replay_window` are ignored.
+pub fn dispatch(
+    windows: &BTreeMap<u32, Window>,
+    input: &mut InputState,
+    raw: RawInput,
+    template: DeviceEvent,
+    replay_window: Option<u32>,
+) {
+    let pointer = window_at(windows, template.root_x as i32, template.root_y as i32);
+    match raw {
+        RawInput::Key { keycode, pressed } => {
+            let (mask, make_event): (u32, fn(DeviceEvent) -> Event) = match pressed {
+                true => (KEY_PRESS_MASK, Event::KeyPress),
+                false => (KEY_RELEASE_MASK, Event::KeyRelease),
+            };
+            let kind = EventKind::new(raw, mask, make_event, &template, vec![]);
+            let source = input.focus_source(windows);
+            let stop_at = match input.focus {
+                Focus::Window(focus) => focus,
+                _ => 0,
+            };
+            if let (true, None, Some(source)) = (pressed, &input.keyboard_grab.grab, source) {
+                if let Some(passive) = input.find_passive_grab(
+                    windows,
+                    Device::Keyboard,
+                    source,
+                    keycode,
+                    template.state,
+                    replay_window,
+                ) {
+                    let grab = Grab::from_passive(&passive, template.time, keycode);
+                    input.activate_grab(windows, Device::Keyboard, grab);
+                }
+            }
+            match input.keyboard_grab.grab.clone() {
+                Some(grab) => {
+                    if deliver_grabbed(windows, &grab, source, stop_at, &kind, template) {
+                        input.freeze_after_delivery(Device::Keyboard, raw, template);
+                    }
+                    if !pressed && !template.auto_repeat && grab.kind == GrabKind::Passive(keycode) {
+                        input.deactivate_grab(windows, Device::Keyboard);
+                    }
+                }
+                None => {
+                    if let Some(source) = source {
+                        let stop_at = deliver_xi2(windows, source, stop_at, &kind, template)
+                            .map_or(stop_at, |target| target.window);
+                        deliver_device_event(windows, source, stop_at, mask, template, None, make_event);
+                    }
+                }
+            }
+        }
+        RawInput::Button { button, pressed, .. } => {
+            let (mask, make_event): (u32, fn(DeviceEvent) -> Event) = match pressed {
+                true => (BUTTON_PRESS_MASK, Event::ButtonPress),
+                false => (BUTTON_RELEASE_MASK, Event::ButtonRelease),
+            };
+            let kind = EventKind::new(raw, mask, make_event, &template, vec![]);
+            if pressed && input.pointer_grab.grab.is_none() {
+                match input.find_passive_grab(
+                    windows,
+                    Device::Pointer,
+                    pointer,
+                    button,
+                    template.state,
+                    replay_window,
+                ) {
+                    Some(passive) => {
+                        let grab = Grab::from_passive(&passive, template.time, button);
+                        input.activate_grab(windows, Device::Pointer, grab);
+                    }
+                    None => {
+                        let xi_target = deliver_xi2(windows, pointer, 0, &kind, template);
+                        let stop_at = xi_target.as_ref().map_or(0, |target| target.window);
+                        let core_window =
+                            deliver_device_event(windows, pointer, stop_at, mask, template, None, make_event);
+                        // The client selecting ButtonPress gets an implicit grab until
+                        // every button is released, XInput clients first.
+                        let grab = match (xi_target, core_window) {
+                            (Some(Target { window, recipients }), _) if !recipients.is_empty() => {
+                                let (client, selected) = recipients[0];
+                                Grab {
+                                    client,
+                                    window,
+                                    owner_events: false,
+                                    event_mask: 0,
+                                    pointer_mode: GRAB_MODE_ASYNC,
+                                    keyboard_mode: GRAB_MODE_ASYNC,
+                                    confine_to: 0,
+                                    cursor: 0,
+                                    time: template.time,
+                                    kind: GrabKind::Implicit,
+                                    xi2_mask: Some(selected),
+                                }
+                            }
+                            (_, Some(window)) => {
+                                let (client, selected) = windows[&window]
+                                    .event_masks
+                                    .iter()
+                                    .find(|(_, selected)| *selected & BUTTON_PRESS_MASK != 0)
+                                    .map(|(client, selected)| (*client, *selected))
+                                    .unwrap();
+                                Grab {
+                                    client,
+                                    window,
+                                    owner_events: selected & OWNER_GRAB_BUTTON_MASK != 0,
+                                    event_mask: selected,
+                                    pointer_mode: GRAB_MODE_ASYNC,
+                                    keyboard_mode: GRAB_MODE_ASYNC,
+                                    confine_to: 0,
+                                    cursor: 0,
+                                    time: template.time,
+                                    kind: GrabKind::Implicit,
+                                    xi2_mask: None,
+                                }
+                            }
+                            _ => return,
+                        };
+                        input.activate_grab(windows, Device::Pointer, grab);
+                        return;
+                    }
+                }
+            }
+            match input.pointer_grab.grab.clone() {
+                Some(grab) => {
+                    if deliver_grabbed(windows, &grab, Some(pointer), 0, &kind, template) {
+                        input.freeze_after_delivery(Device::Pointer, raw, template);
+                    }
+                    if !pressed && input.buttons == 0 && grab.kind != GrabKind::Active {
+                        input.deactivate_grab(windows, Device::Pointer);
+                    }
+                }
+                None => {
+                    let stop_at =
+                        deliver_xi2(windows, pointer, 0, &kind, template).map_or(0, |target| target.window);
+                    deliver_device_event(windows, pointer, stop_at, mask, template, None, make_event);
+                }
+            }
+        }
+        RawInput::Motion { .. } | RawInput::Scroll { .. } => {
+            let mut mask = POINTER_MOTION_MASK;
+            if input.buttons != 0 {
+                // Button1MotionMask through Button5MotionMask share bits with Button1Mask onwards.
+                mask |= BUTTON_MOTION_MASK | input.buttons as u32;
+            }
+            // Scrolling moves only the scroll valuators, which the core protocol can't
+            // report.
+            let (core, valuators) = match raw {
+                RawInput::Scroll { dx, dy } => {
+                    let (scroll_x, scroll_y) = input.scroll;
+                    let axes = [
+                        (VALUATOR_HORIZONTAL_SCROLL, dx, scroll_x),
+                        (VALUATOR_VERTICAL_SCROLL, dy, scroll_y),
+                    ];
+                    let valuators = axes
+                        .into_iter()
+                        .filter(|(_, delta, _)| *delta != 0)
+                        .map(|(number, _, value)| (number, value as f64))
+                        .collect();
+                    (false, valuators)
+                }
+                _ => (true, vec![(VALUATOR_X, template.root_x as f64), (VALUATOR_Y, template.root_y as f64)]),
+            };
+            let kind = EventKind::new(raw, mask, Event::MotionNotify, &template, valuators);
+            match input.pointer_grab.grab.clone() {
+                Some(grab) if grab.xi2_mask.is_some() => {
+                    deliver_grabbed(windows, &grab, Some(pointer), 0, &kind, template);
+                }
+                Some(grab) => {
+                    let target = grab_target(windows, &grab, Some(pointer), 0, mask);
+                    if let (true, Some(target)) = (core, target) {
+                        deliver_motion(windows, input, target, pointer, template);
+                    }
+                }
+                None => {
+                    let stop_at =
+                        deliver_xi2(windows, pointer, 0, &kind, template).map_or(0, |target| target.window);
+                    if let (true, Some(target)) = (core, propagate(windows, pointer, stop_at, mask, None)) {
+                        deliver_motion(windows, input, target, pointer, template);
+                    }
+                }
+            }
+        }
+        // Touches are delivered by `process_touch` instead.
+        RawInput::Touch { .. } => {}
+    }
+}
+
+impl InputState {
+    /// Implements WarpPointer. The move is queued like any other pointer input, so it
+    /// produces the usual motion and crossing events.
+    pub fn warp_pointer(
+        &mut self,
+        windows: &BTreeMap<u32, Window>,
+        src_window: u32,
+        src: Rectangle,
+        dst_window: u32,
+        dst_x: i16,
+        dst_y: i16,
+    ) -> Result<(), XError> {
+        for window in [src_window, dst_window] {
+            if window != 0 {
+                get_window(windows, window)?;
+            }
+        }
+        let (x, y) = (self.pointer_x as i32, self.pointer_y as i32);
+        if src_window != 0 {
+            let window = &windows[&src_window];
+            let mut area = src;
+            if area.width == 0 {
+                area.width = window.width as i32 - area.x;
+            }
+            if area.height == 0 {
+                area.height = window.height as i32 - area.y;
+            }
+            let area = area.translate(window.screen_rectangle.x, window.screen_rectangle.y);
+            if !is_inferior_or_self(windows, self.pointer_window, src_window) || !area.contains(x, y) {
+                return Ok(());
+            }
+        }
+        let (x, y) = match dst_window {
+            0 => (x + dst_x as i32, y + dst_y as i32),
+            dst_window => {
+                let origin = windows[&dst_window].screen_rectangle;
+                (origin.x + dst_x as i32, origin.y + dst_y as i32)
+            }
+        };
+        self.pending.push_back(RawInput::Motion {
+            x,
+            y,
+            relative: false,
+        });
+        process_pending(windows, self);
+        Ok(())
+    }
+
+    /// Moves the pointer into the confine-to window of a newly activated pointer grab.
+    /// The device itself didn't move, so this reports crossing and motion events but no
+    /// raw event or motion history.
+    pub fn confine_pointer(&mut self, windows: &BTreeMap<u32, Window>) {
+        let (x, y) = confine(windows, self, self.pointer_x as i32, self.pointer_y as i32);
+        if (x, y) == (self.pointer_x, self.pointer_y) {
+            return;
+        }
+        self.pointer_x = x;
+        self.pointer_y = y;
+        self.update_pointer_window(windows);
+        let raw = RawInput::Motion {
+            x: x as i32,
+            y: y as i32,
+            relative: false,
+        };
+        let template = template(self, 0, self.state());
+        dispatch(windows, self, raw, template, None);
+    }
+}
+
+/// Queues input and processes it unless its device is frozen by a grab.
+pub fn inject(raw: RawInput) {
+    let windows = WINDOWS.lock().unwrap();
+    let mut input = INPUT.lock().unwrap();
+    input.pending.push_back(raw);
+    process_pending(&windows, &mut input);
+}
+
+/// Whether injected key presses may use `keycode`.
+pub fn is_valid_keycode(keycode: u8) -> bool {
+    (MIN_KEYCODE..=MAX_KEYCODE).contains(&keycode)
+}
+
+/// Whether injected button presses may use `button`.
+pub fn is_valid_button(button: u8) -> bool {
+    (1..=POINTER_BUTTONS).contains(&button)
+}
+
+/// Presses or releases the key with the given keycode.
+pub fn inject_key(keycode: u8, pressed: bool) {
+    inject(RawInput::Key { keycode, pressed });
+}
+
+/// Presses or releases the first key producing `keysym`. Keysyms that only appear in
+/// the shifted column are wrapped in a Shift press. Returns false if no key produces it.
+pub fn inject_keysym(keysym: u32, pressed: bool) -> bool {
+    let keymap = KEYMAP.lock().unwrap();
+    let Some((keycode, column)) = keymap.keycode_for_keysym(keysym) else {
+        return false;
+    };
+    let shift = keymap.keycode_for_keysym(SHIFT_L).map(|(keycode, _)| keycode);
+    let needs_shift = column % 2 == 1 && keymap.modifier_mask(keycode) & SHIFT_MASK == 0;
+    drop(keymap);
+    match (needs_shift, shift) {
+        (true, Some(shift)) if pressed => {
+            inject_key(shift, true);
+            inject_key(keycode, true);
+        }
+        (true, Some(shift)) => {
+            inject_key(keycode, false);
+            inject_key(shift, false);
+        }
+        _ => inject_key(keycode, pressed),
+    }
+    true
+}
+
+/// Presses or releases a pointer button.
+pub fn inject_button(button: u8, pressed: bool) {
+    inject(RawInput::Button {
+        button,
+        pressed,
+        emulated: false,
+    });
+}
+
+/// Moves the pointer to the given root coordinates, clamped to the screen.
+pub fn inject_motion(x: i32, y: i32) {
+    inject(RawInput::Motion { x, y, relative: false });
+}
+
+/// Moves the pointer by the given offset, clamped to the screen.
+pub fn inject_relative_motion(dx: i32, dy: i32) {
+    inject(RawInput::Motion {
+        x: dx,
+        y: dy,
+        relative: true,
+    });
+}
+
+/// Scrolls by the given number of steps, positive down and right.
+pub fn inject_scroll(dx: i32, dy: i32) {
+    inject(RawInput::Scroll { dx, dy });
+}
+
+/// Begins, moves or ends the touch in `slot` at the given root coordinates.
+pub fn inject_touch(slot: u32, phase: TouchPhase, x: i32, y: i32) {
+    inject(RawInput::Touch { slot, phase, x, y });
+}
@@ -0,0 +1,188 @@
+use std::sync::Mutex;
+
+use crate::{
+    error::{ErrorCode, XError},
+    xkb::XKB,
+    xkbcomp::compile_keymap,
+};
+
+pub const MIN_KEYCODE: u8 = 8;
+pub const MAX_KEYCODE: u8 = 255;
+
+pub const NO_SYMBOL: u32 = 0;
+
+pub const SHIFT_MASK: u16 = 1 << 0;
+pub const LOCK_MASK: u16 = 1 << 1;
+pub const CONTROL_MASK: u16 = 1 << 2;
+pub const MOD1_MASK: u16 = 1 << 3;
+pub const MOD2_MASK: u16 = 1 << 4;
+pub const MOD3_MASK: u16 = 1 << 5;
+pub const MOD4_MASK: u16 = 1 << 6;
+pub const MOD5_MASK: u16 = 1 << 7;
+
+pub const MAPPING_MODIFIER: u8 = 0;
+pub const MAPPING_KEYBOARD: u8 = 1;
+pub const MAPPING_POINTER: u8 = 2;
+
+pub static KEYMAP: Mutex<Keymap> = Mutex::new(Keymap {
+    keysyms_per_keycode: 0,
+    keysyms: Vec::new(),
+    keycodes_per_modifier: 0,
+    modifiers: Vec::new(),
+});
+
+/// The core keyboard mapping: keysyms for every keycode between `MIN_KEYCODE` and
+/// `MAX_KEYCODE`, and the keycodes bound to each of the eight modifiers.
+#[derive(Clone, Debug)]
+pub struct Keymap {
+    pub keysyms_per_keycode: u8,
+    /// `keysyms_per_keycode` entries for each keycode, starting at `MIN_KEYCODE`.
+    pub keysyms: Vec<u32>,
+    pub keycodes_per_modifier: u8,
+    /// `keycodes_per_modifier` entries for each of Shift, Lock, Control and Mod1-Mod5.
+    pub modifiers: Vec<u8>,
+}
+
+/// The keymap used unless another is given with `--keymap`: a US layout.
+pub static DEFAULT_KEYMAP: &str = include_str!("../keymaps/us.xkb");
+
+/// Compiles an XKB text keymap and installs it, along with the core keyboard mapping
+/// derived from it.
+pub fn init_keyboard(text: &str) -> Result<(), String> {
+    let keymap = compile_keymap(text)?;
+    *KEYMAP.lock().unwrap() = keymap.core_keymap();
+    XKB.lock().unwrap().set_keymap(keymap);
+    Ok(())
+}
+
+/// Checks that `count` keycodes starting at `first_keycode` lie within the server's range.
+pub fn check_keycode_range(first_keycode: u8, count: usize) -> Result<(), XError> {
+    if first_keycode < MIN_KEYCODE || first_keycode as usize + count > MAX_KEYCODE as usize + 1 {
+        return Err(XError::new(ErrorCode::Value, first_keycode as u32));
+    }
+    Ok(())
+}
+
+impl Keymap {
+    pub fn get_keysyms(&self, first_keycode: u8, count: u8) -> Result<Vec<u32>, XError> {
+        check_keycode_range(first_keycode, count as usize)?;
+        let per = self.keysyms_per_keycode as usize;
+        let start = (first_keycode - MIN_KEYCODE) as usize * per;
+        Ok(self.keysyms[start..start + count as usize * per].to_vec())
+    }
+
+    /// Replaces the keysyms of `keysyms.len() / keysyms_per_keycode` keycodes, widening
+    /// the table if the client supplies more keysyms per keycode than we store.
+    pub fn change_keysyms(
+        &mut self,
+        first_keycode: u8,
+        keysyms_per_keycode: u8,
+        keysyms: &[u32],
+    ) -> Result<u8, XError> {
+        if keysyms_per_keycode == 0 {
+            return Err(XError::new(ErrorCode::Value, 0));
+        }
+        let count = keysyms.len() / keysyms_per_keycode as usize;
+        check_keycode_range(first_keycode, count)?;
+        if keysyms_per_keycode > self.keysyms_per_keycode {
+            let old = self.keysyms_per_keycode as usize;
+            let new = keysyms_per_keycode as usize;
+            self.keysyms = self
+                .keysyms
+                .chunks(old)
+                .flat_map(|chunk| chunk.iter().copied().chain(std::iter::repeat_n(NO_SYMBOL, new - old)))
+                .collect();
+            self.keysyms_per_keycode = keysyms_per_keycode;
+        }
+        let per = self.keysyms_per_keycode as usize;
+        for (index, chunk) in keysyms.chunks(keysyms_per_keycode as usize).enumerate() {
+            let start = (first_keycode - MIN_KEYCODE) as usize * per + index * per;
+            let entry = &mut self.keysyms[start..start + per];
+            entry.fill(NO_SYMBOL);
+            entry[..chunk.len()].copy_from_slice(chunk);
+        }
+        Ok(count as u8)
+    }
+
+    pub fn set_modifiers(&mut self, keycodes_per_modifier: u8, keycodes: &[u8]) -> Result<(), XError> {
+        for keycode in keycodes {
+            if *keycode != 0 && *keycode < MIN_KEYCODE {
+                return Err(XError::new(ErrorCode::Value, *keycode as u32));
+            }
+        }
+        self.keycodes_per_modifier = keycodes_per_modifier;
+        self.modifiers = keycodes.to_vec();
+        Ok(())
+    }
+
+    /// Returns the modifier mask bound to `keycode`, or 0 if it is not a modifier.
+    pub fn modifier_mask(&self, keycode: u8) -> u16 {
+        let per = self.keycodes_per_modifier as usize;
+        let mut mask = 0;
+        for (index, keycodes) in self.modifiers.chunks(per.max(1)).enumerate() {
+            if keycode != 0 && keycodes.contains(&keycode) {
+                mask |= 1 << index;
+            }
+        }
+        mask
+    }
+
+    pub fn keysym(&self, keycode: u8, index: usize) -> u32 {
+        let per = self.keysyms_per_keycode as usize;
+        if keycode < MIN_KEYCODE || index >= per {
+            return NO_SYMBOL;
+        }
+        self.keysyms[(keycode - MIN_KEYCODE) as usize * per + index]
+    }
+
+    /// Finds the first keycode producing `keysym`, along with the column it appears in.
+    pub fn keycode_for_keysym(&self, keysym: u32) -> Option<(u8, usize)> {
+        let per = self.keysyms_per_keycode as usize;
+        self.keysyms
+            .iter()
+            .position(|k| *k == keysym && keysym != NO_SYMBOL)
+            .map(|index| ((index / per) as u8 + MIN_KEYCODE, index % per))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keymap() -> Keymap {
+        Keymap {
+            keysyms_per_keycode: 2,
+            keysyms: vec![0; (MAX_KEYCODE - MIN_KEYCODE + 1) as usize * 2],
+            keycodes_per_modifier: 0,
+            modifiers: vec![],
+        }
+    }
+
+    #[test]
+    fn keycode_range_must_fit_the_keymap() {
+        assert!(check_keycode_range(MIN_KEYCODE, 248).is_ok());
+        assert!(check_keycode_range(MAX_KEYCODE, 1).is_ok());
+        assert!(check_keycode_range(MIN_KEYCODE, 0).is_ok());
+        assert!(check_keycode_range(MIN_KEYCODE, 249).is_err());
+        assert!(check_keycode_range(MAX_KEYCODE, 2).is_err());
+        assert!(check_keycode_range(MIN_KEYCODE - 1, 1).is_err());
+    }
+
+    #[test]
+    fn keysyms_change_up_to_the_last_keycode() {
+        let mut keymap = keymap();
+        assert_eq!(keymap.change_keysyms(MAX_KEYCODE, 2, &[0x61, 0x41]).ok(), Some(1));
+        assert_eq!(keymap.get_keysyms(MAX_KEYCODE, 1).ok(), Some(vec![0x61, 0x41]));
+        assert!(keymap.change_keysyms(MAX_KEYCODE, 1, &[0x61, 0x62]).is_err());
+        assert!(keymap.get_keysyms(MAX_KEYCODE, 2).is_err());
+    }
+
+    #[test]
+    fn wider_keysyms_widen_every_keycode() {
+        let mut keymap = keymap();
+        keymap.change_keysyms(MIN_KEYCODE, 2, &[0x61, 0x41]).unwrap();
+        keymap.change_keysyms(MIN_KEYCODE + 1, 3, &[0x62, 0x42, 0x63]).unwrap();
+        assert_eq!(keymap.keysyms_per_keycode, 3);
+        assert_eq!(keymap.get_keysyms(MIN_KEYCODE, 2).ok(), Some(vec![0x61, 0x41, 0, 0x62, 0x42, 0x63]));
+    }
+}
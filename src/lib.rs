@@ -5,5 +5,41 @@ pub mod request;
 pub mod response;
 pub mod screen;
 pub mod atom;
+pub mod blend;
+pub mod client;
+pub mod composite;
+pub mod control;
+pub mod crossing;
+pub mod cursor;
+pub mod damage;
+pub mod error;
+pub mod extension;
+pub mod focus;
+pub mod ge;
+pub mod glyph;
+pub mod gradient;
+pub mod grab;
+pub mod image;
+pub mod input;
+pub mod keyboard;
+pub mod keysym;
+pub mod picture;
+pub mod randr;
+pub mod region;
+pub mod render;
+pub mod selection;
+pub mod shape;
+pub mod shm;
+pub mod time;
+pub mod touch;
+pub mod trapezoid;
+pub mod unix;
+pub mod window;
+pub mod xfixes;
+pub mod xinerama;
+pub mod xinput;
+pub mod xkb;
+pub mod xkbcomp;
+pub mod xtest;
 
 pub static VENDOR: &str = "Xaugh X Server";
@@ -1,28 +1,120 @@
 use std::{
+    collections::BTreeMap,
+    env, fs,
+    io::{Read, Write},
     net::{TcpListener, TcpStream},
+    os::unix::net::UnixStream,
     thread,
+    time::Duration,
 };
 
-use xaugh::connection::establish_connection;
+use xaugh::{
+    atom::init_atoms,
+    client::{close_down_client, set_credentials, wait_for_server_grab},
+    connection::{establish_connection, Connection},
+    control::{spawn_control_socket, CONTROL_SOCKET},
+    extension::init_extensions,
+    keyboard::{init_keyboard, DEFAULT_KEYMAP},
+    randr::{init_randr, parse_layout},
+    time::{init_time, use_virtual_clock},
+    unix::{bind_unix_socket, peer_credentials, UnixTransport, UNIX_SOCKET},
+    window::init_windows,
+};
 
 fn main() {
+    // +extension NAME and -extension NAME switch extensions on and off.
+    let mut extension_overrides = BTreeMap::new();
+    // --layout 1920x1080+0+0,1280x720+1920+0 enables an output for each geometry.
+    let mut layout = vec![];
+    // --keymap FILE loads an XKB keymap in place of the built-in US layout.
+    let mut keymap = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--virtual-clock" => use_virtual_clock(),
+            "+extension" | "-extension" => match args.next() {
+                Some(name) => {
+                    extension_overrides.insert(name, arg.starts_with('+'));
+                }
+                None => eprintln!("{arg} needs an extension name"),
+            },
+            "--layout" => match args.next().as_deref().and_then(parse_layout) {
+                Some(outputs) => layout = outputs,
+                None => eprintln!("--layout needs geometries like 1920x1080+0+0,1280x720+1920+0"),
+            },
+            "--keymap" => match args.next().map(fs::read_to_string) {
+                Some(Ok(text)) => keymap = Some(text),
+                Some(Err(error)) => eprintln!("can't read the keymap: {error}"),
+                None => eprintln!("--keymap needs a file"),
+            },
+            _ => eprintln!("ignoring unknown argument {arg}"),
+        }
+    }
+    init_time();
+    init_extensions(&extension_overrides);
+    init_atoms();
+    init_windows();
+    init_randr(&layout);
+    if let Err(error) = init_keyboard(keymap.as_deref().unwrap_or(DEFAULT_KEYMAP)) {
+        eprintln!("can't use the keymap, falling back to the default: {error}");
+        init_keyboard(DEFAULT_KEYMAP).unwrap();
+    }
+    if let Err(error) = spawn_control_socket(CONTROL_SOCKET) {
+        eprintln!("not listening on {CONTROL_SOCKET}: {error}");
+    }
+    match bind_unix_socket(UNIX_SOCKET) {
+        Ok(listener) => {
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let stream = stream.unwrap();
+                    thread::spawn(|| {
+                        handle_connection_unix(stream);
+                        println!("Ended.")
+                    });
+                }
+            });
+        }
+        Err(error) => eprintln!("not listening on {UNIX_SOCKET}: {error}"),
+    }
     let listener = TcpListener::bind("127.0.0.1:6001").unwrap();
 
     for stream in listener.incoming() {
         let stream = stream.unwrap();
-        thread::spawn(|| match handle_connection_tcp(stream) {
-            _ => {
-                println!("Ended.")
-            }
+        thread::spawn(|| {
+            handle_connection_tcp(stream);
+            println!("Ended.")
         });
     }
 }
 
 fn handle_connection_tcp(mut stream: TcpStream) -> Option<()> {
-    let mut connection = establish_connection(&mut stream)?;
-    loop {
-        let request = connection.read_request()?;
+    let connection = establish_connection(&mut stream)?;
+    connection
+        .stream
+        .set_read_timeout(Some(Duration::from_millis(10)))
+        .ok()?;
+    serve(connection);
+    Some(())
+}
+
+fn handle_connection_unix(stream: UnixStream) -> Option<()> {
+    let credentials = peer_credentials(&stream);
+    let mut connection = establish_connection(UnixTransport::new(stream))?;
+    connection.stream.client = connection.client;
+    set_credentials(connection.client, credentials);
+    connection
+        .stream
+        .set_read_timeout(Some(Duration::from_millis(10)))
+        .ok()?;
+    serve(connection);
+    Some(())
+}
+
+fn serve<T: Read + Write>(mut connection: Connection<T>) {
+    while let Some(request) = connection.read_request() {
+        wait_for_server_grab(connection.client);
         println!("{request:#?}");
         connection.write_response(request);
     }
+    close_down_client(connection.client);
 }
@@ -0,0 +1,564 @@
+//! RENDER pictures: the picture formats the server supports, the state of each picture,
+//! and reading and writing premultiplied colors through a picture's drawable.
+
+use std::{
+    collections::BTreeMap,
+    sync::{Mutex, MutexGuard},
+};
+
+use crate::{
+    blend::combine,
+    composite::BACKING_PIXMAPS,
+    damage::damage_drawable,
+    error::{ErrorCode, XError},
+    gradient::Fill,
+    pixmap::{bitmap_region, Pixmap, PIXMAPS},
+    region::{Rectangle, Region},
+    render::{bad_picture, bad_pict_format},
+    screen::{DEFAULT_SCREEN, DEFAULT_VISUAL, FRAMEBUFFER},
+    window::{surface_origin, Window},
+};
+
+pub const REPEAT_NONE: u8 = 0;
+pub const REPEAT_NORMAL: u8 = 1;
+pub const REPEAT_PAD: u8 = 2;
+pub const REPEAT_REFLECT: u8 = 3;
+
+pub const CLIP_BY_CHILDREN: u8 = 0;
+pub const INCLUDE_INFERIORS: u8 = 1;
+
+pub const POLY_EDGE_SHARP: u8 = 0;
+pub const POLY_EDGE_SMOOTH: u8 = 1;
+
+const CP_REPEAT: u32 = 1 << 0;
+const CP_ALPHA_MAP: u32 = 1 << 1;
+const CP_ALPHA_X_ORIGIN: u32 = 1 << 2;
+const CP_ALPHA_Y_ORIGIN: u32 = 1 << 3;
+const CP_CLIP_X_ORIGIN: u32 = 1 << 4;
+const CP_CLIP_Y_ORIGIN: u32 = 1 << 5;
+const CP_CLIP_MASK: u32 = 1 << 6;
+const CP_GRAPHICS_EXPOSURE: u32 = 1 << 7;
+const CP_SUBWINDOW_MODE: u32 = 1 << 8;
+const CP_POLY_EDGE: u32 = 1 << 9;
+const CP_POLY_MODE: u32 = 1 << 10;
+const CP_DITHER: u32 = 1 << 11;
+const CP_COMPONENT_ALPHA: u32 = 1 << 12;
+
+/// One channel of a direct picture format: `mask` applies after shifting by `shift`.
+#[derive(Clone, Copy, Debug)]
+pub struct Channel {
+    pub shift: u16,
+    pub mask: u16,
+}
+
+impl Channel {
+    const fn from_mask(mask: u32) -> Channel {
+        let shift = if mask == 0 { 0 } else { mask.trailing_zeros() };
+        Channel {
+            shift: shift as u16,
+            mask: (mask >> shift) as u16,
+        }
+    }
+
+    /// The channel's value in `pixel`, scaled to eight bits.
+    fn get(&self, pixel: u32) -> Option<u8> {
+        let mask = self.mask as u32;
+        (mask != 0).then(|| (((pixel >> self.shift & mask) * 255 + mask / 2) / mask) as u8)
+    }
+
+    fn put(&self, value: u8) -> u32 {
+        let mask = self.mask as u32;
+        ((value as u32 * mask + 127) / 255) << self.shift
+    }
+}
+
+/// A direct picture format. Every format stores one pixel per 32-bit word, like the
+/// pixmaps it describes.
+#[derive(Debug)]
+pub struct PictFormat {
+    pub id: u32,
+    pub depth: u8,
+    pub red: Channel,
+    pub green: Channel,
+    pub blue: Channel,
+    pub alpha: Channel,
+}
+
+const fn pict_format(id: u32, depth: u8, alpha: u32, red: u32, green: u32, blue: u32) -> PictFormat {
+    PictFormat {
+        id,
+        depth,
+        red: Channel::from_mask(red),
+        green: Channel::from_mask(green),
+        blue: Channel::from_mask(blue),
+        alpha: Channel::from_mask(alpha),
+    }
+}
+
+/// The format of windows with the root visual.
+pub const VISUAL_FORMAT: u32 = 0x20;
+pub const A1_FORMAT: u32 = 0x21;
+pub const A8_FORMAT: u32 = 0x22;
+pub const X8R8G8B8_FORMAT: u32 = 0x23;
+pub const A8R8G8B8_FORMAT: u32 = 0x24;
+
+pub static PICT_FORMATS: [PictFormat; 5] = [
+    pict_format(
+        VISUAL_FORMAT,
+        DEFAULT_SCREEN.root_depth,
+        0,
+        DEFAULT_VISUAL.red_mask,
+        DEFAULT_VISUAL.green_mask,
+        DEFAULT_VISUAL.blue_mask,
+    ),
+    pict_format(A1_FORMAT, 1, 0x1, 0, 0, 0),
+    pict_format(A8_FORMAT, 8, 0xff, 0, 0, 0),
+    pict_format(X8R8G8B8_FORMAT, 24, 0, 0xff0000, 0xff00, 0xff),
+    pict_format(A8R8G8B8_FORMAT, 32, 0xff000000, 0xff0000, 0xff00, 0xff),
+];
+
+pub fn find_format(id: u32) -> Result<&'static PictFormat, XError> {
+    PICT_FORMATS.iter().find(|f| f.id == id).ok_or(bad_pict_format(id))
+}
+
+impl PictFormat {
+    /// Unpacks `pixel` into `[alpha, red, green, blue]`. Formats without alpha are
+    /// opaque, and alpha-only formats have black color channels.
+    pub fn fetch(&self, pixel: u32) -> [u8; 4] {
+        [
+            self.alpha.get(pixel).unwrap_or(255),
+            self.red.get(pixel).unwrap_or(0),
+            self.green.get(pixel).unwrap_or(0),
+            self.blue.get(pixel).unwrap_or(0),
+        ]
+    }
+
+    pub fn store(&self, color: [u8; 4]) -> u32 {
+        self.alpha.put(color[0]) | self.red.put(color[1]) | self.green.put(color[2]) | self.blue.put(color[3])
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Filter {
+    Nearest,
+    Bilinear,
+}
+
+/// Filter names, and the filter each stands for. "fast", "good" and "best" are aliases.
+pub const FILTERS: [(&str, Filter); 5] = [
+    ("nearest", Filter::Nearest),
+    ("bilinear", Filter::Bilinear),
+    ("fast", Filter::Nearest),
+    ("good", Filter::Bilinear),
+    ("best", Filter::Bilinear),
+];
+
+#[derive(Clone, Debug)]
+pub struct Picture {
+    /// The picture's drawable, or 0 for solid fills and gradients.
+    pub drawable: u32,
+    pub format: &'static PictFormat,
+    pub repeat: u8,
+    /// A pixmap picture whose alpha replaces this picture's, or 0.
+    pub alpha_map: u32,
+    pub alpha_origin: (i16, i16),
+    pub clip_origin: (i16, i16),
+    /// The client clip relative to the clip origin; `None` doesn't clip.
+    pub clip: Option<Region>,
+    pub graphics_exposures: bool,
+    pub subwindow_mode: u8,
+    pub poly_edge: u8,
+    pub poly_mode: u8,
+    pub dither: u32,
+    pub component_alpha: bool,
+    /// Maps destination coordinates to picture coordinates; `None` is the identity.
+    pub transform: Option<[[f64; 3]; 3]>,
+    pub filter: Filter,
+    /// What pictures without a drawable show.
+    pub fill: Option<Fill>,
+}
+
+/// Pictures by id. Lock after `WINDOWS` and before `PIXMAPS`.
+pub static PICTURES: Mutex<BTreeMap<u32, Picture>> = Mutex::new(BTreeMap::new());
+
+pub fn get_picture(pictures: &BTreeMap<u32, Picture>, id: u32) -> Result<&Picture, XError> {
+    pictures.get(&id).ok_or(bad_picture(id))
+}
+
+/// Looks up a picture to draw to, which needs a drawable.
+pub fn get_destination(pictures: &BTreeMap<u32, Picture>, id: u32) -> Result<&Picture, XError> {
+    let picture = get_picture(pictures, id)?;
+    if picture.fill.is_some() {
+        return Err(XError::new(ErrorCode::Drawable, id));
+    }
+    Ok(picture)
+}
+
+/// Maps `coordinate` into `0..size` according to `repeat`, or `None` if it falls
+/// outside a picture that doesn't repeat.
+fn repeat_coordinate(repeat: u8, coordinate: i32, size: i32) -> Option<i32> {
+    if size <= 0 {
+        return None;
+    }
+    match repeat {
+        REPEAT_NORMAL => Some(coordinate.rem_euclid(size)),
+        REPEAT_PAD => Some(coordinate.clamp(0, size - 1)),
+        REPEAT_REFLECT => {
+            let coordinate = coordinate.rem_euclid(2 * size);
+            Some(if coordinate >= size { 2 * size - 1 - coordinate } else { coordinate })
+        }
+        _ => (0..size).contains(&coordinate).then_some(coordinate),
+    }
+}
+
+/// The windows, pixmaps, backing pixmaps and framebuffer, locked for reading and writing
+/// drawables.
+pub struct Drawables<'a> {
+    pub windows: &'a BTreeMap<u32, Window>,
+    pub pixmaps: MutexGuard<'static, BTreeMap<u32, Pixmap>>,
+    pub backing_pixmaps: MutexGuard<'static, BTreeMap<u32, Pixmap>>,
+    pub framebuffer: MutexGuard<'static, Pixmap>,
+}
+
+impl Drawables<'_> {
+    pub fn lock(windows: &BTreeMap<u32, Window>) -> Drawables<'_> {
+        Drawables {
+            windows,
+            pixmaps: PIXMAPS.lock().unwrap(),
+            backing_pixmaps: BACKING_PIXMAPS.lock().unwrap(),
+            framebuffer: FRAMEBUFFER.lock().unwrap(),
+        }
+    }
+
+    pub fn size(&self, drawable: u32) -> Option<(i32, i32)> {
+        match self.windows.get(&drawable) {
+            Some(window) => Some((window.width as i32, window.height as i32)),
+            None => self
+                .pixmaps
+                .get(&drawable)
+                .map(|pixmap| (pixmap.width as i32, pixmap.height as i32)),
+        }
+    }
+
+    /// The pixels windows in `surface` are drawn to, the framebuffer or a redirected
+    /// window's backing pixmap, and the screen position of their top-left corner.
+    pub fn surface(&mut self, surface: u32) -> Option<(&mut Pixmap, i32, i32)> {
+        if surface == 0 {
+            return Some((&mut *self.framebuffer, 0, 0));
+        }
+        let (x, y) = surface_origin(self.windows, surface);
+        self.backing_pixmaps.get_mut(&surface).map(|pixmap| (pixmap, x, y))
+    }
+
+    fn surface_ref(&self, surface: u32) -> Option<(&Pixmap, i32, i32)> {
+        if surface == 0 {
+            return Some((&*self.framebuffer, 0, 0));
+        }
+        let (x, y) = surface_origin(self.windows, surface);
+        self.backing_pixmaps.get(&surface).map(|pixmap| (pixmap, x, y))
+    }
+
+    pub fn get(&self, drawable: u32, x: i32, y: i32) -> u32 {
+        match self.windows.get(&drawable) {
+            Some(window) => self.surface_ref(window.surface).map_or(0, |(pixels, origin_x, origin_y)| {
+                pixels.get(window.screen_rectangle.x + x - origin_x, window.screen_rectangle.y + y - origin_y)
+            }),
+            None => self.pixmaps.get(&drawable).map_or(0, |pixmap| pixmap.get(x, y)),
+        }
+    }
+
+    pub fn set(&mut self, drawable: u32, x: i32, y: i32, pixel: u32) {
+        match self.windows.get(&drawable) {
+            Some(window) => {
+                let (screen_x, screen_y) = (window.screen_rectangle.x + x, window.screen_rectangle.y + y);
+                if let Some((pixels, origin_x, origin_y)) = self.surface(window.surface) {
+                    pixels.set(screen_x - origin_x, screen_y - origin_y, pixel);
+                }
+            }
+            None => {
+                if let Some(pixmap) = self.pixmaps.get_mut(&drawable) {
+                    pixmap.set(x, y, pixel);
+                }
+            }
+        }
+    }
+
+    /// The part of `drawable` that drawing may touch, in its own coordinates: the
+    /// visible part of a window, including or excluding its inferiors, or a whole pixmap.
+    pub fn drawable_clip(&self, drawable: u32, subwindow_mode: u8) -> Region {
+        let Some(window) = self.windows.get(&drawable) else {
+            return self
+                .pixmaps
+                .get(&drawable)
+                .map_or(Region::new(), |pixmap| Region::from_rectangle(pixmap.bounds()));
+        };
+        let mut clip = window.clip.clone();
+        if subwindow_mode == INCLUDE_INFERIORS {
+            let mut pending = window.children.clone();
+            while let Some(child) = pending.pop() {
+                let child = &self.windows[&child];
+                // Redirected inferiors draw into their own pixmaps.
+                if child.surface == window.surface {
+                    clip = clip.union(&child.clip);
+                    pending.extend(&child.children);
+                }
+            }
+        }
+        clip.translate(-window.screen_rectangle.x, -window.screen_rectangle.y)
+    }
+}
+
+impl Picture {
+    pub fn new(drawable: u32, format: &'static PictFormat) -> Picture {
+        Picture {
+            drawable,
+            format,
+            repeat: REPEAT_NONE,
+            alpha_map: 0,
+            alpha_origin: (0, 0),
+            clip_origin: (0, 0),
+            clip: None,
+            graphics_exposures: true,
+            subwindow_mode: CLIP_BY_CHILDREN,
+            poly_edge: POLY_EDGE_SHARP,
+            poly_mode: 0,
+            dither: 0,
+            component_alpha: false,
+            transform: None,
+            filter: Filter::Nearest,
+            fill: None,
+        }
+    }
+
+    /// A source picture showing `fill`. Its format only matters to requests that
+    /// report it.
+    pub fn with_fill(fill: Fill) -> Picture {
+        Picture {
+            fill: Some(fill),
+            ..Picture::new(0, &PICT_FORMATS[4])
+        }
+    }
+
+    /// The client clip in picture coordinates.
+    fn client_clip(&self) -> Option<Region> {
+        let (x, y) = self.clip_origin;
+        self.clip.as_ref().map(|clip| clip.translate(x as i32, y as i32))
+    }
+
+    /// Applies the CreatePicture and ChangePicture attributes in `value_mask`. Nothing
+    /// changes if any value is bad.
+    pub fn change(
+        &mut self,
+        pictures: &BTreeMap<u32, Picture>,
+        value_mask: u32,
+        values: &[u32],
+    ) -> Result<(), XError> {
+        let mut picture = self.clone();
+        let mut values = values.iter().copied();
+        for bit in (0..32).map(|bit| 1 << bit).filter(|bit| value_mask & bit != 0) {
+            let value = values.next().ok_or(XError::new(ErrorCode::Length, 0))?;
+            let boolean = || match value {
+                0 | 1 => Ok(value == 1),
+                _ => Err(XError::new(ErrorCode::Value, value)),
+            };
+            match bit {
+                CP_REPEAT if value > REPEAT_REFLECT as u32 => {
+                    return Err(XError::new(ErrorCode::Value, value));
+                }
+                CP_REPEAT => picture.repeat = value as u8,
+                CP_ALPHA_MAP => {
+                    if value != 0 {
+                        let alpha_map = get_picture(pictures, value)?;
+                        if !PIXMAPS.lock().unwrap().contains_key(&alpha_map.drawable) {
+                            return Err(XError::new(ErrorCode::Match, value));
+                        }
+                    }
+                    picture.alpha_map = value;
+                }
+                CP_ALPHA_X_ORIGIN => picture.alpha_origin.0 = value as i16,
+                CP_ALPHA_Y_ORIGIN => picture.alpha_origin.1 = value as i16,
+                CP_CLIP_X_ORIGIN => picture.clip_origin.0 = value as i16,
+                CP_CLIP_Y_ORIGIN => picture.clip_origin.1 = value as i16,
+                CP_CLIP_MASK => picture.clip = if value == 0 { None } else { Some(bitmap_region(value)?) },
+                CP_GRAPHICS_EXPOSURE => picture.graphics_exposures = boolean()?,
+                CP_SUBWINDOW_MODE => picture.subwindow_mode = boolean()? as u8,
+                CP_POLY_EDGE => picture.poly_edge = boolean()? as u8,
+                CP_POLY_MODE => picture.poly_mode = boolean()? as u8,
+                CP_DITHER => picture.dither = value,
+                CP_COMPONENT_ALPHA => picture.component_alpha = boolean()?,
+                _ => return Err(XError::new(ErrorCode::Value, value_mask)),
+            }
+        }
+        *self = picture;
+        Ok(())
+    }
+
+    /// The color of the pixel at (`x`, `y`) of the drawable, after repeat, with its
+    /// alpha taken from the alpha map if there is one.
+    fn texel(
+        &self,
+        drawables: &Drawables,
+        pictures: &BTreeMap<u32, Picture>,
+        x: i32,
+        y: i32,
+    ) -> [u8; 4] {
+        let Some((width, height)) = drawables.size(self.drawable) else {
+            return [0; 4];
+        };
+        let (Some(x), Some(y)) = (
+            repeat_coordinate(self.repeat, x, width),
+            repeat_coordinate(self.repeat, y, height),
+        ) else {
+            return [0; 4];
+        };
+        let mut color = self.format.fetch(drawables.get(self.drawable, x, y));
+        if let Some(alpha_map) = pictures.get(&self.alpha_map) {
+            let (x, y) = (x - self.alpha_origin.0 as i32, y - self.alpha_origin.1 as i32);
+            let inside = drawables
+                .size(alpha_map.drawable)
+                .is_some_and(|(width, height)| (0..width).contains(&x) && (0..height).contains(&y));
+            color[0] = if inside {
+                alpha_map.format.fetch(drawables.get(alpha_map.drawable, x, y))[0]
+            } else {
+                0
+            };
+        }
+        color
+    }
+
+    /// The color of the picture at (`x`, `y`), through its transform and filter.
+    pub fn sample(
+        &self,
+        drawables: &Drawables,
+        pictures: &BTreeMap<u32, Picture>,
+        x: i32,
+        y: i32,
+    ) -> [u8; 4] {
+        if let (None, None) = (&self.fill, &self.transform) {
+            return self.texel(drawables, pictures, x, y);
+        }
+        // Transforms and gradients apply to pixel centers.
+        let (mut tx, mut ty) = (x as f64 + 0.5, y as f64 + 0.5);
+        if let Some(t) = &self.transform {
+            let w = t[2][0] * tx + t[2][1] * ty + t[2][2];
+            if w == 0.0 {
+                return [0; 4];
+            }
+            (tx, ty) = (
+                (t[0][0] * tx + t[0][1] * ty + t[0][2]) / w,
+                (t[1][0] * tx + t[1][1] * ty + t[1][2]) / w,
+            );
+        }
+        if let Some(fill) = &self.fill {
+            return fill.color_at(tx, ty, self.repeat);
+        }
+        match self.filter {
+            Filter::Nearest => {
+                let e = 1.0 / 65536.0;
+                self.texel(drawables, pictures, (tx - e).floor() as i32, (ty - e).floor() as i32)
+            }
+            Filter::Bilinear => {
+                let (x0, y0) = ((tx - 0.5).floor(), (ty - 0.5).floor());
+                let (fx, fy) = (tx - 0.5 - x0, ty - 0.5 - y0);
+                let (x0, y0) = (x0 as i32, y0 as i32);
+                let corners = [
+                    (self.texel(drawables, pictures, x0, y0), (1.0 - fx) * (1.0 - fy)),
+                    (self.texel(drawables, pictures, x0 + 1, y0), fx * (1.0 - fy)),
+                    (self.texel(drawables, pictures, x0, y0 + 1), (1.0 - fx) * fy),
+                    (self.texel(drawables, pictures, x0 + 1, y0 + 1), fx * fy),
+                ];
+                std::array::from_fn(|c| {
+                    let value: f64 = corners.iter().map(|(color, weight)| color[c] as f64 * weight).sum();
+                    (value + 0.5).min(255.0) as u8
+                })
+            }
+        }
+    }
+
+    /// Samples the picture as a mask: per channel with component alpha, otherwise its
+    /// alpha repeated in every channel.
+    pub fn sample_mask(
+        &self,
+        drawables: &Drawables,
+        pictures: &BTreeMap<u32, Picture>,
+        x: i32,
+        y: i32,
+    ) -> [u8; 4] {
+        let color = self.sample(drawables, pictures, x, y);
+        if self.component_alpha {
+            color
+        } else {
+            [color[0]; 4]
+        }
+    }
+
+    /// The destination pixels that may take part when this picture is a source or mask
+    /// placed with its origin at (`dx`, `dy`) in the destination. Pictures that repeat,
+    /// are transformed or have no drawable cover everything, and give `None`.
+    pub fn source_clip(&self, drawables: &Drawables, dx: i32, dy: i32) -> Option<Region> {
+        if self.repeat != REPEAT_NONE || self.transform.is_some() || self.fill.is_some() {
+            return None;
+        }
+        let (width, height) = drawables.size(self.drawable).unwrap_or((0, 0));
+        let mut clip = Region::from_rectangle(Rectangle::new(0, 0, width, height));
+        if let Some(client_clip) = self.client_clip() {
+            clip = clip.intersect(&client_clip);
+        }
+        Some(clip.translate(dx, dy))
+    }
+
+    /// Stores `color` at (`x`, `y`), sending its alpha to the alpha map if there is one.
+    fn write(
+        &self,
+        drawables: &mut Drawables,
+        pictures: &BTreeMap<u32, Picture>,
+        x: i32,
+        y: i32,
+        color: [u8; 4],
+    ) {
+        drawables.set(self.drawable, x, y, self.format.store(color));
+        if let Some(alpha_map) = pictures.get(&self.alpha_map) {
+            let (x, y) = (x - self.alpha_origin.0 as i32, y - self.alpha_origin.1 as i32);
+            drawables.set(alpha_map.drawable, x, y, alpha_map.format.store([color[0], 0, 0, 0]));
+        }
+    }
+}
+
+/// Composites into `area` of `destination`, given in its coordinates: `source`
+/// returns the source color and the mask for each destination pixel. Only pixels in
+/// the drawable's visible part and the picture's clip change. Every result is computed
+/// before any is stored, so the destination may also be the source.
+pub fn composite(
+    drawables: &mut Drawables,
+    pictures: &BTreeMap<u32, Picture>,
+    op: u8,
+    destination: &Picture,
+    area: &Region,
+    source: impl Fn(&Drawables, i32, i32) -> ([u8; 4], [u8; 4]),
+) {
+    let drawable_clip = drawables.drawable_clip(destination.drawable, destination.subwindow_mode);
+    let mut region = area.intersect(&drawable_clip);
+    if let Some(client_clip) = destination.client_clip() {
+        region = region.intersect(&client_clip);
+    }
+    let mut results = vec![];
+    for r in &region.rectangles {
+        for y in r.y..r.y + r.height {
+            for x in r.x..r.x + r.width {
+                let (color, mask) = source(drawables, x, y);
+                let current = destination.texel(drawables, pictures, x, y);
+                results.push((x, y, combine(op, color, mask, current)));
+            }
+        }
+    }
+    for (x, y, color) in results {
+        destination.write(drawables, pictures, x, y, color);
+    }
+    damage_drawable(drawables, destination.drawable, &region);
+    if let Some(alpha_map) = pictures.get(&destination.alpha_map) {
+        let (x, y) = destination.alpha_origin;
+        let alpha_bounds = drawables.drawable_clip(alpha_map.drawable, CLIP_BY_CHILDREN);
+        let alpha_region = region.translate(-x as i32, -y as i32).intersect(&alpha_bounds);
+        damage_drawable(drawables, alpha_map.drawable, &alpha_region);
+    }
+}
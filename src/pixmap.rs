@@ -1,3 +1,17 @@
+use std::{
+    collections::BTreeMap,
+    fmt,
+    ops::{Deref, DerefMut},
+    slice,
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    error::{ErrorCode, XError},
+    region::{Rectangle, Region},
+    shm::Mapping,
+};
+
 #[repr(C)]
 #[derive(Clone, Copy, Default, Debug)]
 pub struct PixmapFormat {
@@ -8,10 +22,231 @@ pub struct PixmapFormat {
     pub pad1: u32,
 }
 
-pub static DEFAULT_PIXMAP_FORMATS: [PixmapFormat; 1] = [PixmapFormat {
-    depth: 1u8,
-    bpp: 32u8,
-    scanline_pad: 0u8,
-    pad0: 0,
-    pad1: 0,
-}];
+/// Pixmaps of every depth store one 32-bit pixel per `u32`. Depths 8, 24 and 32 exist
+/// for RENDER's standard picture formats.
+pub static DEFAULT_PIXMAP_FORMATS: [PixmapFormat; 4] = [
+    PixmapFormat {
+        depth: 1u8,
+        bpp: 32u8,
+        scanline_pad: 0u8,
+        pad0: 0,
+        pad1: 0,
+    },
+    PixmapFormat {
+        depth: 8u8,
+        bpp: 32u8,
+        scanline_pad: 32u8,
+        pad0: 0,
+        pad1: 0,
+    },
+    PixmapFormat {
+        depth: 24u8,
+        bpp: 32u8,
+        scanline_pad: 32u8,
+        pad0: 0,
+        pad1: 0,
+    },
+    PixmapFormat {
+        depth: 32u8,
+        bpp: 32u8,
+        scanline_pad: 32u8,
+        pad0: 0,
+        pad1: 0,
+    },
+];
+
+pub static PIXMAPS: Mutex<BTreeMap<u32, Pixmap>> = Mutex::new(BTreeMap::new());
+
+/// Pixel storage shared by pixmaps and the screen framebuffer, one `u32` per pixel.
+#[derive(Clone, Default, Debug)]
+pub struct Pixmap {
+    pub width: u16,
+    pub height: u16,
+    pub depth: u8,
+    pub data: PixelData,
+}
+
+/// The pixels of a pixmap: held by the server, or in a shared memory segment the
+/// client draws into directly (MIT-SHM pixmaps). Cloning always makes an owned copy.
+pub enum PixelData {
+    Owned(Vec<u32>),
+    Shared {
+        mapping: Arc<Mapping>,
+        /// Byte offset of the first pixel, a multiple of four.
+        offset: usize,
+        len: usize,
+    },
+}
+
+impl Deref for PixelData {
+    type Target = [u32];
+
+    fn deref(&self) -> &[u32] {
+        match self {
+            PixelData::Owned(pixels) => pixels,
+            PixelData::Shared { mapping, offset, len } => unsafe {
+                slice::from_raw_parts(mapping.address().add(*offset).cast::<u32>(), *len)
+            },
+        }
+    }
+}
+
+impl DerefMut for PixelData {
+    fn deref_mut(&mut self) -> &mut [u32] {
+        match self {
+            PixelData::Owned(pixels) => pixels,
+            PixelData::Shared { mapping, offset, len } => unsafe {
+                slice::from_raw_parts_mut(mapping.address().add(*offset).cast::<u32>(), *len)
+            },
+        }
+    }
+}
+
+impl Clone for PixelData {
+    fn clone(&self) -> PixelData {
+        PixelData::Owned(self.to_vec())
+    }
+}
+
+impl Default for PixelData {
+    fn default() -> PixelData {
+        PixelData::Owned(Vec::new())
+    }
+}
+
+impl fmt::Debug for PixelData {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PixelData::Owned(pixels) => f.debug_tuple("Owned").field(pixels).finish(),
+            PixelData::Shared { offset, len, .. } => f
+                .debug_struct("Shared")
+                .field("offset", offset)
+                .field("len", len)
+                .finish(),
+        }
+    }
+}
+
+impl Pixmap {
+    pub fn new(width: u16, height: u16, depth: u8) -> Pixmap {
+        Pixmap {
+            width,
+            height,
+            depth,
+            data: PixelData::Owned(vec![0; width as usize * height as usize]),
+        }
+    }
+
+    /// A pixmap whose pixels live in `mapping` at `offset`, which the caller has checked
+    /// is aligned and leaves room for every pixel.
+    pub fn shared(width: u16, height: u16, depth: u8, mapping: Arc<Mapping>, offset: usize) -> Pixmap {
+        Pixmap {
+            width,
+            height,
+            depth,
+            data: PixelData::Shared {
+                mapping,
+                offset,
+                len: width as usize * height as usize,
+            },
+        }
+    }
+
+    /// Another pixmap with the same pixels, if they are in shared memory, or else a copy.
+    pub fn share(&self) -> Pixmap {
+        match &self.data {
+            PixelData::Shared { mapping, offset, .. } => {
+                Pixmap::shared(self.width, self.height, self.depth, mapping.clone(), *offset)
+            }
+            PixelData::Owned(_) => self.clone(),
+        }
+    }
+
+    pub fn bounds(&self) -> Rectangle {
+        Rectangle::new(0, 0, self.width as i32, self.height as i32)
+    }
+
+    pub fn get(&self, x: i32, y: i32) -> u32 {
+        if x < 0 || y < 0 || x >= self.width as i32 || y >= self.height as i32 {
+            return 0;
+        }
+        self.data[y as usize * self.width as usize + x as usize]
+    }
+
+    pub fn set(&mut self, x: i32, y: i32, pixel: u32) {
+        if x < 0 || y < 0 || x >= self.width as i32 || y >= self.height as i32 {
+            return;
+        }
+        self.data[y as usize * self.width as usize + x as usize] = pixel;
+    }
+
+    pub fn fill_rectangle(&mut self, rectangle: &Rectangle, pixel: u32) {
+        let Some(r) = rectangle.intersect(&self.bounds()) else {
+            return;
+        };
+        for y in r.y..r.y + r.height {
+            let row = y as usize * self.width as usize;
+            self.data[row + r.x as usize..row + (r.x + r.width) as usize].fill(pixel);
+        }
+    }
+
+    /// Fills `rectangle` with copies of `tile`, whose top-left corner is aligned to the
+    /// given origin.
+    pub fn tile_rectangle(&mut self, rectangle: &Rectangle, tile: &Pixmap, origin_x: i32, origin_y: i32) {
+        if tile.width == 0 || tile.height == 0 {
+            return;
+        }
+        let Some(r) = rectangle.intersect(&self.bounds()) else {
+            return;
+        };
+        for y in r.y..r.y + r.height {
+            let tile_y = (y - origin_y).rem_euclid(tile.height as i32);
+            for x in r.x..r.x + r.width {
+                let tile_x = (x - origin_x).rem_euclid(tile.width as i32);
+                self.set(x, y, tile.get(tile_x, tile_y));
+            }
+        }
+    }
+}
+
+/// Builds a region from the set pixels of a depth-1 pixmap, merging identical runs in
+/// consecutive rows.
+pub fn bitmap_region(bitmap: u32) -> Result<Region, XError> {
+    let pixmaps = PIXMAPS.lock().unwrap();
+    let pixmap = pixmaps.get(&bitmap).ok_or(XError::new(ErrorCode::Pixmap, bitmap))?;
+    if pixmap.depth != 1 {
+        return Err(XError::new(ErrorCode::Match, bitmap));
+    }
+    let mut rectangles: Vec<Rectangle> = vec![];
+    let mut previous_row = 0..0;
+    for y in 0..pixmap.height as i32 {
+        let mut runs = vec![];
+        let mut x = 0;
+        while x < pixmap.width as i32 {
+            if pixmap.get(x, y) & 1 == 0 {
+                x += 1;
+                continue;
+            }
+            let start = x;
+            while x < pixmap.width as i32 && pixmap.get(x, y) & 1 != 0 {
+                x += 1;
+            }
+            runs.push((start, x - start));
+        }
+        let same_as_previous = y > 0
+            && previous_row.len() == runs.len()
+            && rectangles[previous_row.clone()]
+                .iter()
+                .zip(&runs)
+                .all(|(r, (x, width))| r.x == *x && r.width == *width);
+        if same_as_previous {
+            for r in &mut rectangles[previous_row.clone()] {
+                r.height += 1;
+            }
+        } else {
+            previous_row = rectangles.len()..rectangles.len() + runs.len();
+            rectangles.extend(runs.iter().map(|(x, width)| Rectangle::new(*x, y, *width, 1)));
+        }
+    }
+    Ok(Region { rectangles })
+}
@@ -0,0 +1,1652 @@
+//! The RANDR extension, version 1.5, over virtual CRTCs and outputs. Nothing is
+//! scanned out, so a CRTC only records which part of the screen a virtual monitor
+//! shows; clients can resize the screen and lay out outputs at any time.
+
+use std::{collections::BTreeMap, sync::Mutex};
+
+use crate::{
+    atom::{atom_exists, get_atom},
+    client::queue_event,
+    error::{ErrorCode, XError},
+    event::Event,
+    extension::{find_extension, ExtensionRequest},
+    screen::{ScreenSize, DEFAULT_SCREEN, SCREEN_SIZE},
+    time,
+    window::{get_window, resize_root, Window, WINDOWS},
+};
+
+pub static RANDR_NAME: &str = "RANDR";
+pub const RANDR_MAJOR_VERSION: u32 = 1;
+pub const RANDR_MINOR_VERSION: u32 = 5;
+
+/// Error codes, relative to the extension's first error.
+const BAD_OUTPUT: u8 = 0;
+const BAD_CRTC: u8 = 1;
+const BAD_MODE: u8 = 2;
+const BAD_PROVIDER: u8 = 3;
+
+pub const SCREEN_CHANGE_NOTIFY_MASK: u16 = 1 << 0;
+pub const CRTC_CHANGE_NOTIFY_MASK: u16 = 1 << 1;
+pub const OUTPUT_CHANGE_NOTIFY_MASK: u16 = 1 << 2;
+pub const OUTPUT_PROPERTY_NOTIFY_MASK: u16 = 1 << 3;
+pub const PROVIDER_CHANGE_NOTIFY_MASK: u16 = 1 << 4;
+pub const PROVIDER_PROPERTY_NOTIFY_MASK: u16 = 1 << 5;
+pub const RESOURCE_CHANGE_NOTIFY_MASK: u16 = 1 << 6;
+const ALL_NOTIFY_MASKS: u16 = (1 << 7) - 1;
+
+pub const ROTATE_0: u16 = 1 << 0;
+pub const ROTATE_90: u16 = 1 << 1;
+pub const ROTATE_180: u16 = 1 << 2;
+pub const ROTATE_270: u16 = 1 << 3;
+pub const REFLECT_X: u16 = 1 << 4;
+pub const REFLECT_Y: u16 = 1 << 5;
+/// Every CRTC supports every rotation and reflection.
+const ALL_ROTATIONS: u16 = ROTATE_0 | ROTATE_90 | ROTATE_180 | ROTATE_270 | REFLECT_X | REFLECT_Y;
+
+pub const SET_CONFIG_SUCCESS: u8 = 0;
+pub const SET_CONFIG_INVALID_CONFIG_TIME: u8 = 1;
+pub const SET_CONFIG_INVALID_TIME: u8 = 2;
+pub const SET_CONFIG_FAILED: u8 = 3;
+
+pub const CONNECTED: u8 = 0;
+
+const PROPERTY_REPLACE: u8 = 0;
+const PROPERTY_PREPEND: u8 = 1;
+const PROPERTY_APPEND: u8 = 2;
+
+/// The screen sizes SetScreenSize accepts.
+pub const MIN_SCREEN_SIZE: u16 = 8;
+pub const MAX_SCREEN_SIZE: u16 = 8192;
+
+pub const VIRTUAL_OUTPUTS: u32 = 4;
+const FIRST_CRTC: u32 = 0x200;
+const FIRST_OUTPUT: u32 = 0x300;
+const FIRST_MODE: u32 = 0x400;
+const GAMMA_SIZE: usize = 256;
+
+/// The modes every virtual output offers, the first being preferred.
+const VIRTUAL_MODES: [(u16, u16); 11] = [
+    (1920, 1080),
+    (1680, 1050),
+    (1600, 900),
+    (1440, 900),
+    (1366, 768),
+    (1280, 1024),
+    (1280, 800),
+    (1280, 720),
+    (1024, 768),
+    (800, 600),
+    (640, 480),
+];
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ModeInfo {
+    pub width: u16,
+    pub height: u16,
+    pub dot_clock: u32,
+    pub hsync_start: u16,
+    pub hsync_end: u16,
+    pub htotal: u16,
+    pub hskew: u16,
+    pub vsync_start: u16,
+    pub vsync_end: u16,
+    pub vtotal: u16,
+    pub flags: u32,
+}
+
+impl ModeInfo {
+    /// Timings for a 60Hz mode of the given size with reduced blanking.
+    fn virtual_mode(width: u16, height: u16) -> ModeInfo {
+        let (htotal, vtotal) = (width + 160, height + 30);
+        ModeInfo {
+            width,
+            height,
+            dot_clock: htotal as u32 * vtotal as u32 * 60,
+            hsync_start: width + 48,
+            hsync_end: width + 80,
+            htotal,
+            hskew: 0,
+            vsync_start: height + 3,
+            vsync_end: height + 9,
+            vtotal,
+            flags: 0,
+        }
+    }
+
+    /// The refresh rate in Hz, rounded.
+    fn refresh(&self) -> u16 {
+        let pixels = self.htotal as u64 * self.vtotal as u64;
+        if pixels == 0 {
+            return 0;
+        }
+        ((self.dot_clock as u64 + pixels / 2) / pixels) as u16
+    }
+
+    /// The size of the screen area shown with `rotation`.
+    fn rotated_size(&self, rotation: u16) -> (u16, u16) {
+        if rotation & (ROTATE_90 | ROTATE_270) != 0 {
+            (self.height, self.width)
+        } else {
+            (self.width, self.height)
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Mode {
+    pub name: String,
+    pub info: ModeInfo,
+    /// Created with CreateMode rather than offered by an output.
+    pub user: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct Crtc {
+    pub x: i16,
+    pub y: i16,
+    /// 0 when the CRTC is disabled.
+    pub mode: u32,
+    pub rotation: u16,
+    pub outputs: Vec<u32>,
+    /// The red, green and blue ramps.
+    pub gamma: [Vec<u16>; 3],
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PropertyValue {
+    pub kind: u32,
+    pub format: u8,
+    pub items: Vec<u32>,
+}
+
+impl PropertyValue {
+    fn bytes(&self, request: &ExtensionRequest) -> Vec<u8> {
+        self.items
+            .iter()
+            .flat_map(|item| match self.format {
+                8 => vec![*item as u8],
+                16 => request.to_bytes_16(*item as u16).to_vec(),
+                _ => request.to_bytes_32(*item).to_vec(),
+            })
+            .collect()
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct OutputProperty {
+    pub value: PropertyValue,
+    /// A value waiting for the output's next SetCrtcConfig.
+    pub pending_value: Option<PropertyValue>,
+    pub pending: bool,
+    pub range: bool,
+    pub immutable: bool,
+    /// The values allowed, as pairs of bounds if `range` is set.
+    pub valid_values: Vec<i32>,
+}
+
+impl OutputProperty {
+    fn accepts(&self, value: &PropertyValue) -> bool {
+        if self.valid_values.is_empty() {
+            return true;
+        }
+        value.format == 32
+            && value.items.iter().all(|item| {
+                let item = *item as i32;
+                if self.range {
+                    self.valid_values.chunks_exact(2).any(|r| (r[0]..=r[1]).contains(&item))
+                } else {
+                    self.valid_values.contains(&item)
+                }
+            })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Output {
+    pub name: String,
+    /// 0 when the output is disabled.
+    pub crtc: u32,
+    /// The modes the output offers, the first being preferred.
+    pub modes: Vec<u32>,
+    /// Modes added with AddOutputMode.
+    pub user_modes: Vec<u32>,
+    pub properties: BTreeMap<u32, OutputProperty>,
+}
+
+impl Output {
+    fn has_mode(&self, mode: u32) -> bool {
+        self.modes.contains(&mode) || self.user_modes.contains(&mode)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Monitor {
+    pub name: u32,
+    pub primary: bool,
+    pub automatic: bool,
+    pub x: i16,
+    pub y: i16,
+    pub width: u16,
+    pub height: u16,
+    pub mm_width: u32,
+    pub mm_height: u32,
+    pub outputs: Vec<u32>,
+}
+
+#[derive(Debug)]
+pub struct RandrState {
+    /// When a CRTC was last configured.
+    pub timestamp: u32,
+    /// When the set of outputs and their modes last changed.
+    pub config_timestamp: u32,
+    pub modes: BTreeMap<u32, Mode>,
+    next_mode: u32,
+    pub crtcs: BTreeMap<u32, Crtc>,
+    pub outputs: BTreeMap<u32, Output>,
+    pub primary: u32,
+    /// Monitors defined with SetMonitor.
+    pub monitors: Vec<Monitor>,
+}
+
+pub static RANDR: Mutex<RandrState> = Mutex::new(RandrState {
+    timestamp: 0,
+    config_timestamp: 0,
+    modes: BTreeMap::new(),
+    next_mode: FIRST_MODE,
+    crtcs: BTreeMap::new(),
+    outputs: BTreeMap::new(),
+    primary: 0,
+    monitors: Vec::new(),
+});
+
+/// Where an output shows the screen when the server starts.
+#[derive(Clone, Copy, Debug)]
+pub struct OutputLayout {
+    pub width: u16,
+    pub height: u16,
+    pub x: i16,
+    pub y: i16,
+}
+
+/// Parses a layout given on the command line, one `WIDTHxHEIGHT+X+Y` geometry for each
+/// output separated by commas.
+pub fn parse_layout(spec: &str) -> Option<Vec<OutputLayout>> {
+    let layout = spec
+        .split(',')
+        .map(|geometry| {
+            let (size, position) = geometry.split_once('+')?;
+            let (width, height) = size.split_once('x')?;
+            let (x, y) = position.split_once('+')?;
+            Some(OutputLayout {
+                width: width.parse().ok()?,
+                height: height.parse().ok()?,
+                x: x.parse().ok()?,
+                y: y.parse().ok()?,
+            })
+        })
+        .collect::<Option<Vec<OutputLayout>>>()?;
+    let valid = layout.len() <= VIRTUAL_OUTPUTS as usize
+        && layout.iter().all(|output| {
+            let right = output.x as i32 + output.width as i32;
+            let bottom = output.y as i32 + output.height as i32;
+            output.width >= MIN_SCREEN_SIZE
+                && output.height >= MIN_SCREEN_SIZE
+                && right <= MAX_SCREEN_SIZE as i32
+                && bottom <= MAX_SCREEN_SIZE as i32
+        });
+    valid.then_some(layout)
+}
+
+/// Sets up the virtual outputs, each with a CRTC of its own, and enables outputs as
+/// `layout` says, resizing the screen to fit them. Without a layout the first output
+/// shows the whole screen.
+pub fn init_randr(layout: &[OutputLayout]) {
+    let mut windows = WINDOWS.lock().unwrap();
+    let mut randr = RANDR.lock().unwrap();
+    let modes: Vec<u32> = VIRTUAL_MODES
+        .iter()
+        .map(|(width, height)| {
+            let info = ModeInfo::virtual_mode(*width, *height);
+            randr.add_mode(format!("{width}x{height}"), info, false)
+        })
+        .collect();
+    let linear: Vec<u16> = (0..GAMMA_SIZE as u16).map(|i| i << 8 | i).collect();
+    for i in 0..VIRTUAL_OUTPUTS {
+        let crtc = Crtc {
+            x: 0,
+            y: 0,
+            mode: 0,
+            rotation: ROTATE_0,
+            outputs: vec![],
+            gamma: [linear.clone(), linear.clone(), linear.clone()],
+        };
+        randr.crtcs.insert(FIRST_CRTC + i, crtc);
+        let output = Output {
+            name: format!("VIRTUAL-{}", i + 1),
+            crtc: 0,
+            modes: modes.clone(),
+            user_modes: vec![],
+            properties: BTreeMap::new(),
+        };
+        randr.outputs.insert(FIRST_OUTPUT + i, output);
+    }
+    let default_layout = [OutputLayout {
+        width: DEFAULT_SCREEN.width_px,
+        height: DEFAULT_SCREEN.height_px,
+        x: 0,
+        y: 0,
+    }];
+    let layout = if layout.is_empty() { &default_layout[..] } else { layout };
+    for (i, geometry) in (0..).zip(layout) {
+        let (crtc, output) = (FIRST_CRTC + i, FIRST_OUTPUT + i);
+        let mode = match randr.find_mode(output, geometry.width, geometry.height, 0) {
+            Some(mode) => mode,
+            None => {
+                let info = ModeInfo::virtual_mode(geometry.width, geometry.height);
+                let mode = randr.add_mode(format!("{}x{}", geometry.width, geometry.height), info, false);
+                randr.outputs.get_mut(&output).unwrap().modes.push(mode);
+                mode
+            }
+        };
+        randr.set_crtc(crtc, geometry.x, geometry.y, mode, ROTATE_0, &[output]);
+    }
+    let width = layout.iter().map(|output| output.x as u16 + output.width).max().unwrap();
+    let height = layout.iter().map(|output| output.y as u16 + output.height).max().unwrap();
+    if (width, height) != (DEFAULT_SCREEN.width_px, DEFAULT_SCREEN.height_px) {
+        // Keep the default resolution.
+        let mm = |px: u16, default_mm: u16, default_px: u16| {
+            (px as u32 * default_mm as u32 / default_px as u32) as u16
+        };
+        let mm_width = mm(width, DEFAULT_SCREEN.width_mm, DEFAULT_SCREEN.width_px);
+        let mm_height = mm(height, DEFAULT_SCREEN.height_mm, DEFAULT_SCREEN.height_px);
+        set_screen_size(&mut windows, &mut randr, (width, height), (mm_width, mm_height));
+    }
+    randr.timestamp = time::now();
+    randr.config_timestamp = randr.timestamp;
+}
+
+fn randr_error(offset: u8, bad_value: u32) -> XError {
+    let first_error = find_extension(RANDR_NAME).map_or(0, |e| e.first_error);
+    XError::extension(first_error + offset, bad_value)
+}
+
+fn check_atom(atom: u32) -> Result<(), XError> {
+    if !atom_exists(atom) {
+        return Err(XError::new(ErrorCode::Atom, atom));
+    }
+    Ok(())
+}
+
+fn check_rotation(rotation: u16) -> Result<(), XError> {
+    if rotation & !ALL_ROTATIONS != 0 || (rotation & 0xf).count_ones() != 1 {
+        return Err(XError::new(ErrorCode::Value, rotation as u32));
+    }
+    Ok(())
+}
+
+impl RandrState {
+    fn add_mode(&mut self, name: String, info: ModeInfo, user: bool) -> u32 {
+        let id = self.next_mode;
+        self.next_mode += 1;
+        self.modes.insert(id, Mode { name, info, user });
+        id
+    }
+
+    fn crtc(&self, id: u32) -> Result<&Crtc, XError> {
+        self.crtcs.get(&id).ok_or(randr_error(BAD_CRTC, id))
+    }
+
+    fn output(&self, id: u32) -> Result<&Output, XError> {
+        self.outputs.get(&id).ok_or(randr_error(BAD_OUTPUT, id))
+    }
+
+    fn output_mut(&mut self, id: u32) -> Result<&mut Output, XError> {
+        self.outputs.get_mut(&id).ok_or(randr_error(BAD_OUTPUT, id))
+    }
+
+    fn mode(&self, id: u32) -> Result<&Mode, XError> {
+        self.modes.get(&id).ok_or(randr_error(BAD_MODE, id))
+    }
+
+    /// The part of the screen a CRTC shows, if it is enabled.
+    fn crtc_area(&self, id: u32) -> Option<(i16, i16, u16, u16)> {
+        let crtc = &self.crtcs[&id];
+        let (width, height) = self.modes.get(&crtc.mode)?.info.rotated_size(crtc.rotation);
+        Some((crtc.x, crtc.y, width, height))
+    }
+
+    /// A mode of `output` with the given size and refresh rate, 0 meaning any rate.
+    fn find_mode(&self, output: u32, width: u16, height: u16, rate: u16) -> Option<u32> {
+        let output = &self.outputs[&output];
+        output.modes.iter().chain(&output.user_modes).copied().find(|id| {
+            let info = &self.modes[id].info;
+            info.width == width && info.height == height && (rate == 0 || info.refresh() == rate)
+        })
+    }
+
+    /// Points `crtc` at `mode` showing `outputs`, taking the outputs from any other
+    /// CRTC and disabling CRTCs left without outputs. Returns the CRTCs and outputs
+    /// that changed.
+    fn set_crtc(
+        &mut self,
+        id: u32,
+        x: i16,
+        y: i16,
+        mode: u32,
+        rotation: u16,
+        outputs: &[u32],
+    ) -> (Vec<u32>, Vec<u32>) {
+        let mut changed_crtcs = vec![id];
+        let mut changed_outputs: Vec<u32> = self.crtcs[&id].outputs.clone();
+        for output in outputs {
+            let previous = self.outputs[output].crtc;
+            if previous != 0 && previous != id {
+                let crtc = self.crtcs.get_mut(&previous).unwrap();
+                crtc.outputs.retain(|o| o != output);
+                if crtc.outputs.is_empty() {
+                    crtc.mode = 0;
+                }
+                changed_crtcs.push(previous);
+            }
+            if !changed_outputs.contains(output) {
+                changed_outputs.push(*output);
+            }
+        }
+        for output in &changed_outputs {
+            self.outputs.get_mut(output).unwrap().crtc = 0;
+        }
+        for output in outputs {
+            let output = self.outputs.get_mut(output).unwrap();
+            output.crtc = id;
+            // Pending property values take effect with the new configuration.
+            for property in output.properties.values_mut() {
+                if let Some(value) = property.pending_value.take() {
+                    property.value = value;
+                }
+            }
+        }
+        let crtc = self.crtcs.get_mut(&id).unwrap();
+        crtc.x = x;
+        crtc.y = y;
+        crtc.mode = mode;
+        crtc.rotation = rotation;
+        crtc.outputs = outputs.to_vec();
+        (changed_crtcs, changed_outputs)
+    }
+
+    /// The output RANDR 1.0 requests work with: the primary output, or else the first
+    /// enabled one.
+    fn legacy_output(&self) -> u32 {
+        if self.primary != 0 {
+            return self.primary;
+        }
+        let mut outputs = self.outputs.iter();
+        outputs
+            .clone()
+            .find(|(_, output)| output.crtc != 0)
+            .or(outputs.next())
+            .map_or(0, |(id, _)| *id)
+    }
+
+    /// The sizes of the legacy output's modes in the order it offers them, each with its
+    /// refresh rates.
+    fn legacy_sizes(&self) -> Vec<((u16, u16), Vec<u16>)> {
+        let output = &self.outputs[&self.legacy_output()];
+        let mut sizes: Vec<((u16, u16), Vec<u16>)> = vec![];
+        for id in output.modes.iter().chain(&output.user_modes) {
+            let info = &self.modes[id].info;
+            let rate = info.refresh();
+            match sizes.iter_mut().find(|(size, _)| *size == (info.width, info.height)) {
+                Some((_, rates)) if !rates.contains(&rate) => rates.push(rate),
+                Some(_) => {}
+                None => sizes.push(((info.width, info.height), vec![rate])),
+            }
+        }
+        sizes
+    }
+
+    /// The legacy output's size index, rotation and refresh rate.
+    fn legacy_config(&self) -> (u16, u16, u16) {
+        let output = &self.outputs[&self.legacy_output()];
+        let Some(crtc) = self.crtcs.get(&output.crtc) else {
+            return (0, ROTATE_0, 0);
+        };
+        let info = &self.modes[&crtc.mode].info;
+        let size_id = self
+            .legacy_sizes()
+            .iter()
+            .position(|(size, _)| *size == (info.width, info.height))
+            .unwrap_or(0);
+        (size_id as u16, crtc.rotation, info.refresh())
+    }
+
+    /// The user-defined monitors and one monitor for each enabled output that no
+    /// user-defined monitor includes, the primary monitor first. With `active_only`,
+    /// user-defined monitors whose outputs are all disabled are left out.
+    pub fn list_monitors(&self, active_only: bool) -> Vec<Monitor> {
+        let enabled = |output: &u32| self.outputs.get(output).is_some_and(|output| output.crtc != 0);
+        let mut monitors = vec![];
+        for monitor in &self.monitors {
+            if active_only && !monitor.outputs.is_empty() && !monitor.outputs.iter().any(enabled) {
+                continue;
+            }
+            let mut monitor = monitor.clone();
+            if monitor.width == 0 && monitor.height == 0 {
+                // Monitors without a size cover their outputs.
+                let areas: Vec<_> = monitor
+                    .outputs
+                    .iter()
+                    .filter(|output| enabled(output))
+                    .filter_map(|output| self.crtc_area(self.outputs[output].crtc))
+                    .collect();
+                if let Some(x1) = areas.iter().map(|a| a.0 as i32).min() {
+                    let y1 = areas.iter().map(|a| a.1 as i32).min().unwrap();
+                    let x2 = areas.iter().map(|a| a.0 as i32 + a.2 as i32).max().unwrap();
+                    let y2 = areas.iter().map(|a| a.1 as i32 + a.3 as i32).max().unwrap();
+                    (monitor.x, monitor.y) = (x1 as i16, y1 as i16);
+                    (monitor.width, monitor.height) = ((x2 - x1) as u16, (y2 - y1) as u16);
+                }
+            }
+            monitors.push(monitor);
+        }
+        let user_primary = self.monitors.iter().any(|m| m.primary);
+        for (id, output) in &self.outputs {
+            if self.monitors.iter().any(|m| m.outputs.contains(id)) {
+                continue;
+            }
+            if output.crtc == 0 {
+                continue;
+            }
+            let Some((x, y, width, height)) = self.crtc_area(output.crtc) else {
+                continue;
+            };
+            monitors.push(Monitor {
+                name: get_atom(false, output.name.clone()),
+                primary: !user_primary && *id == self.primary,
+                automatic: true,
+                x,
+                y,
+                width,
+                height,
+                mm_width: 0,
+                mm_height: 0,
+                outputs: vec![*id],
+            });
+        }
+        monitors.sort_by_key(|monitor| !monitor.primary);
+        monitors
+    }
+}
+
+/// Queues an event for every client that selected `mask` on a window, `make_event`
+/// filling in the window.
+fn deliver_randr_event(windows: &BTreeMap<u32, Window>, mask: u16, make_event: impl Fn(u32) -> Event) {
+    for window in windows.values() {
+        for (client, selected) in &window.randr_masks {
+            if selected & mask != 0 {
+                queue_event(*client, make_event(window.id));
+            }
+        }
+    }
+}
+
+/// Sends ScreenChangeNotify, then CrtcChangeNotify and OutputChangeNotify for the
+/// CRTCs and outputs that changed.
+fn tell_changed(windows: &BTreeMap<u32, Window>, randr: &RandrState, crtcs: &[u32], outputs: &[u32]) {
+    let size = *SCREEN_SIZE.lock().unwrap();
+    let (size_id, rotation, _) = randr.legacy_config();
+    deliver_randr_event(windows, SCREEN_CHANGE_NOTIFY_MASK, |window| Event::ScreenChangeNotify {
+        rotation: rotation as u8,
+        timestamp: randr.timestamp,
+        config_timestamp: randr.config_timestamp,
+        root: DEFAULT_SCREEN.root_window,
+        window,
+        size_id,
+        width: size.width,
+        height: size.height,
+        mm_width: size.mm_width,
+        mm_height: size.mm_height,
+    });
+    for id in crtcs {
+        let crtc = &randr.crtcs[id];
+        let (x, y, width, height) = randr.crtc_area(*id).unwrap_or_default();
+        deliver_randr_event(windows, CRTC_CHANGE_NOTIFY_MASK, |window| Event::CrtcChangeNotify {
+            timestamp: randr.timestamp,
+            window,
+            crtc: *id,
+            mode: crtc.mode,
+            rotation: crtc.rotation,
+            x,
+            y,
+            width,
+            height,
+        });
+    }
+    for id in outputs {
+        let output = &randr.outputs[id];
+        let crtc = randr.crtcs.get(&output.crtc);
+        deliver_randr_event(windows, OUTPUT_CHANGE_NOTIFY_MASK, |window| Event::OutputChangeNotify {
+            timestamp: randr.timestamp,
+            config_timestamp: randr.config_timestamp,
+            window,
+            output: *id,
+            crtc: output.crtc,
+            mode: crtc.map_or(0, |crtc| crtc.mode),
+            rotation: crtc.map_or(ROTATE_0, |crtc| crtc.rotation),
+            connection: CONNECTED,
+        });
+    }
+}
+
+fn tell_property_changed(windows: &BTreeMap<u32, Window>, output: u32, atom: u32, deleted: bool) {
+    let timestamp = time::now();
+    deliver_randr_event(windows, OUTPUT_PROPERTY_NOTIFY_MASK, |window| Event::OutputPropertyNotify {
+        window,
+        output,
+        atom,
+        timestamp,
+        deleted,
+    });
+}
+
+fn tell_resources_changed(windows: &BTreeMap<u32, Window>) {
+    let timestamp = time::now();
+    deliver_randr_event(windows, RESOURCE_CHANGE_NOTIFY_MASK, |window| Event::ResourceChangeNotify {
+        timestamp,
+        window,
+    });
+}
+
+/// Resizes the screen, disabling CRTCs that no longer fit when `disable_outside` is
+/// set. Returns the CRTCs and outputs that were disabled.
+fn set_screen_size(
+    windows: &mut BTreeMap<u32, Window>,
+    randr: &mut RandrState,
+    (width, height): (u16, u16),
+    (mm_width, mm_height): (u16, u16),
+) -> (Vec<u32>, Vec<u32>) {
+    let (mut crtcs, mut outputs) = (vec![], vec![]);
+    let ids: Vec<u32> = randr.crtcs.keys().copied().collect();
+    for id in ids {
+        let Some((x, y, crtc_width, crtc_height)) = randr.crtc_area(id) else {
+            continue;
+        };
+        if x as i32 + crtc_width as i32 > width as i32 || y as i32 + crtc_height as i32 > height as i32 {
+            let (changed_crtcs, changed_outputs) = randr.set_crtc(id, 0, 0, 0, ROTATE_0, &[]);
+            crtcs.extend(changed_crtcs);
+            outputs.extend(changed_outputs);
+        }
+    }
+    *SCREEN_SIZE.lock().unwrap() = ScreenSize {
+        width,
+        height,
+        mm_width,
+        mm_height,
+    };
+    resize_root(windows, width, height);
+    (crtcs, outputs)
+}
+
+fn mode_info_bytes(request: &ExtensionRequest, id: u32, mode: &Mode) -> Vec<u8> {
+    let info = &mode.info;
+    let mut bytes = request.to_bytes_32(id).to_vec();
+    bytes.extend(request.to_bytes_16(info.width));
+    bytes.extend(request.to_bytes_16(info.height));
+    bytes.extend(request.to_bytes_32(info.dot_clock));
+    for value in [
+        info.hsync_start,
+        info.hsync_end,
+        info.htotal,
+        info.hskew,
+        info.vsync_start,
+        info.vsync_end,
+        info.vtotal,
+        mode.name.len() as u16,
+    ] {
+        bytes.extend(request.to_bytes_16(value));
+    }
+    bytes.extend(request.to_bytes_32(info.flags));
+    bytes
+}
+
+fn read_mode_info(request: &ExtensionRequest, data: &[u8]) -> (ModeInfo, u16) {
+    let card16 = |offset: usize| request.card16(&data[offset..]);
+    let info = ModeInfo {
+        width: card16(4),
+        height: card16(6),
+        dot_clock: request.card32(&data[8..]),
+        hsync_start: card16(12),
+        hsync_end: card16(14),
+        htotal: card16(16),
+        hskew: card16(18),
+        vsync_start: card16(20),
+        vsync_end: card16(22),
+        vtotal: card16(24),
+        flags: request.card32(&data[28..]),
+    };
+    (info, card16(26))
+}
+
+#[derive(Debug)]
+pub enum RandrRequest {
+    QueryVersion,
+    SetScreenConfig {
+        window: u32,
+        timestamp: u32,
+        config_timestamp: u32,
+        size_id: u16,
+        rotation: u16,
+        rate: u16,
+    },
+    SelectInput {
+        window: u32,
+        enable: u16,
+    },
+    GetScreenInfo {
+        window: u32,
+    },
+    GetScreenSizeRange {
+        window: u32,
+    },
+    SetScreenSize {
+        window: u32,
+        width: u16,
+        height: u16,
+        mm_width: u32,
+        mm_height: u32,
+    },
+    /// GetScreenResources and GetScreenResourcesCurrent, which are the same here.
+    GetScreenResources {
+        window: u32,
+    },
+    GetOutputInfo {
+        output: u32,
+    },
+    ListOutputProperties {
+        output: u32,
+    },
+    QueryOutputProperty {
+        output: u32,
+        property: u32,
+    },
+    ConfigureOutputProperty {
+        output: u32,
+        property: u32,
+        pending: bool,
+        range: bool,
+        values: Vec<i32>,
+    },
+    ChangeOutputProperty {
+        output: u32,
+        property: u32,
+        kind: u32,
+        format: u8,
+        mode: u8,
+        data: Vec<u8>,
+    },
+    DeleteOutputProperty {
+        output: u32,
+        property: u32,
+    },
+    GetOutputProperty {
+        output: u32,
+        property: u32,
+        kind: u32,
+        long_offset: u32,
+        long_length: u32,
+        delete: u8,
+        pending: bool,
+    },
+    CreateMode {
+        window: u32,
+        info: ModeInfo,
+        name: Vec<u8>,
+    },
+    DestroyMode {
+        mode: u32,
+    },
+    AddOutputMode {
+        output: u32,
+        mode: u32,
+    },
+    DeleteOutputMode {
+        output: u32,
+        mode: u32,
+    },
+    GetCrtcInfo {
+        crtc: u32,
+    },
+    SetCrtcConfig {
+        crtc: u32,
+        timestamp: u32,
+        x: i16,
+        y: i16,
+        mode: u32,
+        rotation: u16,
+        outputs: Vec<u32>,
+    },
+    GetCrtcGammaSize {
+        crtc: u32,
+    },
+    GetCrtcGamma {
+        crtc: u32,
+    },
+    SetCrtcGamma {
+        crtc: u32,
+        size: u16,
+        data: Vec<u16>,
+    },
+    GetCrtcTransform {
+        crtc: u32,
+    },
+    GetPanning {
+        crtc: u32,
+    },
+    SetPanning {
+        crtc: u32,
+    },
+    SetOutputPrimary {
+        window: u32,
+        output: u32,
+    },
+    GetOutputPrimary {
+        window: u32,
+    },
+    GetProviders {
+        window: u32,
+    },
+    /// The provider requests, which all fail as there are no providers.
+    ProviderRequest {
+        provider: u32,
+    },
+    GetMonitors {
+        window: u32,
+        active_only: bool,
+    },
+    SetMonitor {
+        window: u32,
+        monitor: Monitor,
+    },
+    DeleteMonitor {
+        window: u32,
+        name: u32,
+    },
+    /// SetCrtcTransform, which only the identity transform would make sense for.
+    Unsupported,
+}
+
+fn read_randr_request(request: &ExtensionRequest) -> Result<RandrRequest, XError> {
+    let data = request.data;
+    let minimum_length = match request.minor_opcode {
+        0 | 4 | 9 | 11 | 14 | 18..=20 | 30 | 42 | 44 => 8,
+        5 | 6 | 8 | 10 | 17 | 22 | 23 | 25 | 27..=29 | 31..=41 => 4,
+        2 => 20,
+        7 => 16,
+        16 => 36,
+        12 => 12,
+        13 => 20,
+        15 | 21 => 24,
+        24 => 8,
+        26 => 0,
+        43 => 28,
+        minor_opcode => return Err(XError::new(ErrorCode::Request, minor_opcode as u32)),
+    };
+    if data.len() < minimum_length {
+        return Err(XError::new(ErrorCode::Length, 0));
+    }
+    let card32s = |bytes: &[u8]| -> Vec<u32> { bytes.chunks_exact(4).map(|b| request.card32(b)).collect() };
+    Ok(match request.minor_opcode {
+        0 => RandrRequest::QueryVersion,
+        2 => RandrRequest::SetScreenConfig {
+            window: request.card32(data),
+            timestamp: request.card32(&data[4..]),
+            config_timestamp: request.card32(&data[8..]),
+            size_id: request.card16(&data[12..]),
+            rotation: request.card16(&data[14..]),
+            rate: request.card16(&data[16..]),
+        },
+        4 => RandrRequest::SelectInput {
+            window: request.card32(data),
+            enable: request.card16(&data[4..]),
+        },
+        5 => RandrRequest::GetScreenInfo {
+            window: request.card32(data),
+        },
+        6 => RandrRequest::GetScreenSizeRange {
+            window: request.card32(data),
+        },
+        7 => RandrRequest::SetScreenSize {
+            window: request.card32(data),
+            width: request.card16(&data[4..]),
+            height: request.card16(&data[6..]),
+            mm_width: request.card32(&data[8..]),
+            mm_height: request.card32(&data[12..]),
+        },
+        8 | 25 => RandrRequest::GetScreenResources {
+            window: request.card32(data),
+        },
+        9 => RandrRequest::GetOutputInfo {
+            output: request.card32(data),
+        },
+        10 => RandrRequest::ListOutputProperties {
+            output: request.card32(data),
+        },
+        11 => RandrRequest::QueryOutputProperty {
+            output: request.card32(data),
+            property: request.card32(&data[4..]),
+        },
+        12 => RandrRequest::ConfigureOutputProperty {
+            output: request.card32(data),
+            property: request.card32(&data[4..]),
+            pending: data[8] != 0,
+            range: data[9] != 0,
+            values: card32s(&data[12..]).into_iter().map(|value| value as i32).collect(),
+        },
+        13 => {
+            let (format, units) = (data[12], request.card32(&data[16..]));
+            let length = units as usize * format as usize / 8;
+            if data.len() < 20 + length {
+                return Err(XError::new(ErrorCode::Length, 0));
+            }
+            RandrRequest::ChangeOutputProperty {
+                output: request.card32(data),
+                property: request.card32(&data[4..]),
+                kind: request.card32(&data[8..]),
+                format,
+                mode: data[13],
+                data: data[20..20 + length].to_vec(),
+            }
+        }
+        14 => RandrRequest::DeleteOutputProperty {
+            output: request.card32(data),
+            property: request.card32(&data[4..]),
+        },
+        15 => RandrRequest::GetOutputProperty {
+            output: request.card32(data),
+            property: request.card32(&data[4..]),
+            kind: request.card32(&data[8..]),
+            long_offset: request.card32(&data[12..]),
+            long_length: request.card32(&data[16..]),
+            delete: data[20],
+            pending: data[21] != 0,
+        },
+        16 => {
+            let (info, name_length) = read_mode_info(request, &data[4..36]);
+            let name = data
+                .get(36..36 + name_length as usize)
+                .ok_or(XError::new(ErrorCode::Length, 0))?;
+            RandrRequest::CreateMode {
+                window: request.card32(data),
+                info,
+                name: name.to_vec(),
+            }
+        }
+        17 => RandrRequest::DestroyMode {
+            mode: request.card32(data),
+        },
+        18 => RandrRequest::AddOutputMode {
+            output: request.card32(data),
+            mode: request.card32(&data[4..]),
+        },
+        19 => RandrRequest::DeleteOutputMode {
+            output: request.card32(data),
+            mode: request.card32(&data[4..]),
+        },
+        20 => RandrRequest::GetCrtcInfo {
+            crtc: request.card32(data),
+        },
+        21 => RandrRequest::SetCrtcConfig {
+            crtc: request.card32(data),
+            timestamp: request.card32(&data[4..]),
+            x: request.int16(&data[12..]),
+            y: request.int16(&data[14..]),
+            mode: request.card32(&data[16..]),
+            rotation: request.card16(&data[20..]),
+            outputs: card32s(&data[24..]),
+        },
+        22 => RandrRequest::GetCrtcGammaSize {
+            crtc: request.card32(data),
+        },
+        23 => RandrRequest::GetCrtcGamma {
+            crtc: request.card32(data),
+        },
+        24 => {
+            let size = request.card16(&data[4..]);
+            let ramps = data
+                .get(8..8 + 6 * size as usize)
+                .ok_or(XError::new(ErrorCode::Length, 0))?;
+            RandrRequest::SetCrtcGamma {
+                crtc: request.card32(data),
+                size,
+                data: ramps.chunks_exact(2).map(|b| request.card16(b)).collect(),
+            }
+        }
+        27 => RandrRequest::GetCrtcTransform {
+            crtc: request.card32(data),
+        },
+        28 => RandrRequest::GetPanning {
+            crtc: request.card32(data),
+        },
+        29 => RandrRequest::SetPanning {
+            crtc: request.card32(data),
+        },
+        30 => RandrRequest::SetOutputPrimary {
+            window: request.card32(data),
+            output: request.card32(&data[4..]),
+        },
+        31 => RandrRequest::GetOutputPrimary {
+            window: request.card32(data),
+        },
+        32 => RandrRequest::GetProviders {
+            window: request.card32(data),
+        },
+        33..=41 => RandrRequest::ProviderRequest {
+            provider: request.card32(data),
+        },
+        42 => RandrRequest::GetMonitors {
+            window: request.card32(data),
+            active_only: data[4] != 0,
+        },
+        43 => {
+            let count = request.card16(&data[10..]) as usize;
+            let outputs = data
+                .get(28..28 + 4 * count)
+                .ok_or(XError::new(ErrorCode::Length, 0))?;
+            RandrRequest::SetMonitor {
+                window: request.card32(data),
+                monitor: Monitor {
+                    name: request.card32(&data[4..]),
+                    primary: data[8] != 0,
+                    automatic: false,
+                    x: request.int16(&data[12..]),
+                    y: request.int16(&data[14..]),
+                    width: request.card16(&data[16..]),
+                    height: request.card16(&data[18..]),
+                    mm_width: request.card32(&data[20..]),
+                    mm_height: request.card32(&data[24..]),
+                    outputs: card32s(outputs),
+                },
+            }
+        }
+        44 => RandrRequest::DeleteMonitor {
+            window: request.card32(data),
+            name: request.card32(&data[4..]),
+        },
+        _ => RandrRequest::Unsupported,
+    })
+}
+
+pub fn handle_randr_request(request: &ExtensionRequest) -> Result<Option<Vec<u8>>, XError> {
+    let randr_request = read_randr_request(request)?;
+    let mut windows = WINDOWS.lock().unwrap();
+    let mut randr = RANDR.lock().unwrap();
+    match randr_request {
+        RandrRequest::QueryVersion => {
+            let mut body = request.to_bytes_32(RANDR_MAJOR_VERSION).to_vec();
+            body.extend(request.to_bytes_32(RANDR_MINOR_VERSION));
+            Ok(Some(request.reply(0, body)))
+        }
+        RandrRequest::SetScreenConfig {
+            window,
+            timestamp,
+            config_timestamp,
+            size_id,
+            rotation,
+            rate,
+        } => {
+            get_window(&windows, window)?;
+            let status = if config_timestamp != randr.config_timestamp {
+                SET_CONFIG_INVALID_CONFIG_TIME
+            } else if let Some(timestamp) = time::check(timestamp, randr.timestamp) {
+                let (width, height) = randr
+                    .legacy_sizes()
+                    .get(size_id as usize)
+                    .ok_or(XError::new(ErrorCode::Value, size_id as u32))?
+                    .0;
+                check_rotation(rotation)?;
+                let output = randr.legacy_output();
+                let mode = randr
+                    .find_mode(output, width, height, rate)
+                    .ok_or(XError::new(ErrorCode::Value, rate as u32))?;
+                let crtc = match randr.outputs[&output].crtc {
+                    0 => randr.crtcs.iter().find(|(_, crtc)| crtc.mode == 0).map(|(id, _)| *id),
+                    crtc => Some(crtc),
+                };
+                match crtc {
+                    Some(crtc) => {
+                        // The screen takes the size of the mode, keeping its resolution.
+                        let size = *SCREEN_SIZE.lock().unwrap();
+                        let (width, height) = randr.modes[&mode].info.rotated_size(rotation);
+                        let mm_width = (width as u32 * size.mm_width as u32 / size.width as u32) as u16;
+                        let mm_height = (height as u32 * size.mm_height as u32 / size.height as u32) as u16;
+                        let (mut crtcs, mut outputs) =
+                            set_screen_size(&mut windows, &mut randr, (width, height), (mm_width, mm_height));
+                        let (changed_crtcs, changed_outputs) =
+                            randr.set_crtc(crtc, 0, 0, mode, rotation, &[output]);
+                        crtcs.extend(changed_crtcs);
+                        outputs.extend(changed_outputs);
+                        randr.timestamp = timestamp;
+                        tell_changed(&windows, &randr, &crtcs, &outputs);
+                        SET_CONFIG_SUCCESS
+                    }
+                    None => SET_CONFIG_FAILED,
+                }
+            } else {
+                SET_CONFIG_INVALID_TIME
+            };
+            let mut body = request.to_bytes_32(randr.timestamp).to_vec();
+            body.extend(request.to_bytes_32(randr.config_timestamp));
+            body.extend(request.to_bytes_32(DEFAULT_SCREEN.root_window));
+            Ok(Some(request.reply(status, body)))
+        }
+        RandrRequest::SelectInput { window, enable } => {
+            if enable & !ALL_NOTIFY_MASKS != 0 {
+                return Err(XError::new(ErrorCode::Value, enable as u32));
+            }
+            let masks = &mut windows
+                .get_mut(&window)
+                .ok_or(XError::new(ErrorCode::Window, window))?
+                .randr_masks;
+            if enable == 0 {
+                masks.remove(&request.client);
+            } else {
+                masks.insert(request.client, enable);
+            }
+            Ok(None)
+        }
+        RandrRequest::GetScreenInfo { window } => {
+            get_window(&windows, window)?;
+            let screen = *SCREEN_SIZE.lock().unwrap();
+            let sizes = randr.legacy_sizes();
+            let (size_id, rotation, rate) = randr.legacy_config();
+            let info_count = sizes.len() + sizes.iter().map(|(_, rates)| 1 + rates.len()).sum::<usize>();
+            let mut body = request.to_bytes_32(DEFAULT_SCREEN.root_window).to_vec();
+            body.extend(request.to_bytes_32(randr.timestamp));
+            body.extend(request.to_bytes_32(randr.config_timestamp));
+            for value in [sizes.len() as u16, size_id, rotation, rate, info_count as u16, 0] {
+                body.extend(request.to_bytes_16(value));
+            }
+            for ((width, height), _) in &sizes {
+                let mm_width = (*width as u32 * screen.mm_width as u32 / screen.width as u32) as u16;
+                let mm_height = (*height as u32 * screen.mm_height as u32 / screen.height as u32) as u16;
+                for value in [*width, *height, mm_width, mm_height] {
+                    body.extend(request.to_bytes_16(value));
+                }
+            }
+            for (_, rates) in &sizes {
+                body.extend(request.to_bytes_16(rates.len() as u16));
+                for rate in rates {
+                    body.extend(request.to_bytes_16(*rate));
+                }
+            }
+            Ok(Some(request.reply(ALL_ROTATIONS as u8, body)))
+        }
+        RandrRequest::GetScreenSizeRange { window } => {
+            get_window(&windows, window)?;
+            let mut body = vec![];
+            for value in [MIN_SCREEN_SIZE, MIN_SCREEN_SIZE, MAX_SCREEN_SIZE, MAX_SCREEN_SIZE] {
+                body.extend(request.to_bytes_16(value));
+            }
+            Ok(Some(request.reply(0, body)))
+        }
+        RandrRequest::SetScreenSize {
+            window,
+            width,
+            height,
+            mm_width,
+            mm_height,
+        } => {
+            get_window(&windows, window)?;
+            for value in [width, height] {
+                if !(MIN_SCREEN_SIZE..=MAX_SCREEN_SIZE).contains(&value) {
+                    return Err(XError::new(ErrorCode::Value, value as u32));
+                }
+            }
+            for value in [mm_width, mm_height] {
+                if value == 0 || value > u16::MAX as u32 {
+                    return Err(XError::new(ErrorCode::Value, value));
+                }
+            }
+            // Enabled CRTCs must stay within the screen.
+            for id in randr.crtcs.keys() {
+                if let Some((x, y, crtc_width, crtc_height)) = randr.crtc_area(*id) {
+                    let (right, bottom) = (x as i32 + crtc_width as i32, y as i32 + crtc_height as i32);
+                    if right > width as i32 || bottom > height as i32 {
+                        return Err(XError::new(ErrorCode::Match, *id));
+                    }
+                }
+            }
+            set_screen_size(&mut windows, &mut randr, (width, height), (mm_width as u16, mm_height as u16));
+            tell_changed(&windows, &randr, &[], &[]);
+            Ok(None)
+        }
+        RandrRequest::GetScreenResources { window } => {
+            get_window(&windows, window)?;
+            let names: Vec<u8> = randr.modes.values().flat_map(|mode| mode.name.bytes()).collect();
+            let mut body = request.to_bytes_32(randr.timestamp).to_vec();
+            body.extend(request.to_bytes_32(randr.config_timestamp));
+            for count in [randr.crtcs.len(), randr.outputs.len(), randr.modes.len(), names.len()] {
+                body.extend(request.to_bytes_16(count as u16));
+            }
+            body.resize(24, 0);
+            for id in randr.crtcs.keys().chain(randr.outputs.keys()) {
+                body.extend(request.to_bytes_32(*id));
+            }
+            for (id, mode) in &randr.modes {
+                body.extend(mode_info_bytes(request, *id, mode));
+            }
+            body.extend(names);
+            Ok(Some(request.reply(0, body)))
+        }
+        RandrRequest::GetOutputInfo { output } => {
+            let output = randr.output(output)?;
+            let mut body = request.to_bytes_32(randr.timestamp).to_vec();
+            body.extend(request.to_bytes_32(output.crtc));
+            // Virtual outputs have no physical size.
+            body.extend(request.to_bytes_32(0));
+            body.extend(request.to_bytes_32(0));
+            body.extend([CONNECTED, 0]);
+            let modes = output.modes.len() + output.user_modes.len();
+            for count in [randr.crtcs.len(), modes, 1, 0, output.name.len()] {
+                body.extend(request.to_bytes_16(count as u16));
+            }
+            for id in randr.crtcs.keys().chain(&output.modes).chain(&output.user_modes) {
+                body.extend(request.to_bytes_32(*id));
+            }
+            body.extend(output.name.bytes());
+            Ok(Some(request.reply(SET_CONFIG_SUCCESS, body)))
+        }
+        RandrRequest::ListOutputProperties { output } => {
+            let properties = &randr.output(output)?.properties;
+            let mut body = request.to_bytes_16(properties.len() as u16).to_vec();
+            body.resize(24, 0);
+            for atom in properties.keys() {
+                body.extend(request.to_bytes_32(*atom));
+            }
+            Ok(Some(request.reply(0, body)))
+        }
+        RandrRequest::QueryOutputProperty { output, property } => {
+            let property = randr
+                .output(output)?
+                .properties
+                .get(&property)
+                .ok_or(XError::new(ErrorCode::Name, property))?;
+            let mut body = vec![property.pending as u8, property.range as u8, property.immutable as u8];
+            body.resize(24, 0);
+            for value in &property.valid_values {
+                body.extend(request.to_bytes_32(*value as u32));
+            }
+            Ok(Some(request.reply(0, body)))
+        }
+        RandrRequest::ConfigureOutputProperty {
+            output,
+            property,
+            pending,
+            range,
+            values,
+        } => {
+            let output = randr.output_mut(output)?;
+            check_atom(property)?;
+            if range && values.len() % 2 != 0 {
+                return Err(XError::new(ErrorCode::Length, 0));
+            }
+            let configured = output.properties.entry(property).or_default();
+            if configured.immutable {
+                return Err(XError::new(ErrorCode::Access, property));
+            }
+            configured.pending = pending;
+            configured.range = range;
+            configured.valid_values = values;
+            Ok(None)
+        }
+        RandrRequest::ChangeOutputProperty {
+            output: id,
+            property,
+            kind,
+            format,
+            mode,
+            data,
+        } => {
+            let output = randr.output_mut(id)?;
+            check_atom(property)?;
+            check_atom(kind)?;
+            if ![8, 16, 32].contains(&format) {
+                return Err(XError::new(ErrorCode::Value, format as u32));
+            }
+            if mode > PROPERTY_APPEND {
+                return Err(XError::new(ErrorCode::Value, mode as u32));
+            }
+            let items: Vec<u32> = match format {
+                8 => data.iter().map(|byte| *byte as u32).collect(),
+                16 => data.chunks_exact(2).map(|b| request.card16(b) as u32).collect(),
+                _ => data.chunks_exact(4).map(|b| request.card32(b)).collect(),
+            };
+            let existing = output.properties.entry(property).or_default();
+            if existing.immutable {
+                return Err(XError::new(ErrorCode::Access, property));
+            }
+            let current = match &existing.pending_value {
+                Some(value) if existing.pending => value.clone(),
+                _ => existing.value.clone(),
+            };
+            let compatible = current.items.is_empty() || (current.kind == kind && current.format == format);
+            let value = match mode {
+                PROPERTY_REPLACE => PropertyValue { kind, format, items },
+                _ if !compatible => return Err(XError::new(ErrorCode::Match, property)),
+                PROPERTY_PREPEND => PropertyValue {
+                    kind,
+                    format,
+                    items: items.into_iter().chain(current.items).collect(),
+                },
+                _ => PropertyValue {
+                    kind,
+                    format,
+                    items: current.items.into_iter().chain(items).collect(),
+                },
+            };
+            if !existing.accepts(&value) {
+                return Err(XError::new(ErrorCode::Value, property));
+            }
+            if existing.pending {
+                existing.pending_value = Some(value);
+            } else {
+                existing.value = value;
+                tell_property_changed(&windows, id, property, false);
+            }
+            Ok(None)
+        }
+        RandrRequest::DeleteOutputProperty { output: id, property } => {
+            let output = randr.output_mut(id)?;
+            check_atom(property)?;
+            match output.properties.get(&property) {
+                Some(existing) if existing.immutable => return Err(XError::new(ErrorCode::Access, property)),
+                Some(_) => {
+                    output.properties.remove(&property);
+                    tell_property_changed(&windows, id, property, true);
+                }
+                None => {}
+            }
+            Ok(None)
+        }
+        RandrRequest::GetOutputProperty {
+            output: id,
+            property,
+            kind,
+            long_offset,
+            long_length,
+            delete,
+            pending,
+        } => {
+            let output = randr.output_mut(id)?;
+            check_atom(property)?;
+            if kind != 0 {
+                check_atom(kind)?;
+            }
+            if delete > 1 {
+                return Err(XError::new(ErrorCode::Value, delete as u32));
+            }
+            let Some(existing) = output.properties.get(&property) else {
+                return Ok(Some(request.reply(0, vec![0; 12])));
+            };
+            let value = match &existing.pending_value {
+                Some(value) if pending => value,
+                _ => &existing.value,
+            };
+            let bytes = value.bytes(request);
+            if kind != 0 && kind != value.kind {
+                let mut body = request.to_bytes_32(value.kind).to_vec();
+                body.extend(request.to_bytes_32(bytes.len() as u32));
+                return Ok(Some(request.reply(value.format, body)));
+            }
+            let start = 4 * long_offset as usize;
+            if start > bytes.len() {
+                return Err(XError::new(ErrorCode::Value, long_offset));
+            }
+            let end = bytes.len().min(start.saturating_add(4 * long_length as usize));
+            let bytes_after = bytes.len() - end;
+            let unit = (value.format as usize / 8).max(1);
+            let mut body = request.to_bytes_32(value.kind).to_vec();
+            body.extend(request.to_bytes_32(bytes_after as u32));
+            body.extend(request.to_bytes_32(((end - start) / unit) as u32));
+            body.resize(24, 0);
+            body.extend(&bytes[start..end]);
+            let format = value.format;
+            if delete == 1 && bytes_after == 0 {
+                if existing.immutable {
+                    return Err(XError::new(ErrorCode::Access, property));
+                }
+                output.properties.remove(&property);
+                tell_property_changed(&windows, id, property, true);
+            }
+            Ok(Some(request.reply(format, body)))
+        }
+        RandrRequest::CreateMode { window, info, name } => {
+            get_window(&windows, window)?;
+            let name = String::from_utf8_lossy(&name).to_string();
+            if randr.modes.values().any(|mode| mode.name == name) {
+                return Err(XError::new(ErrorCode::Name, 0));
+            }
+            let id = randr.add_mode(name, info, true);
+            tell_resources_changed(&windows);
+            Ok(Some(request.reply(0, request.to_bytes_32(id).to_vec())))
+        }
+        RandrRequest::DestroyMode { mode } => {
+            if !randr.mode(mode)?.user
+                || randr.outputs.values().any(|output| output.user_modes.contains(&mode))
+                || randr.crtcs.values().any(|crtc| crtc.mode == mode)
+            {
+                return Err(XError::new(ErrorCode::Access, mode));
+            }
+            randr.modes.remove(&mode);
+            tell_resources_changed(&windows);
+            Ok(None)
+        }
+        RandrRequest::AddOutputMode { output, mode } => {
+            randr.mode(mode)?;
+            let output_state = randr.output_mut(output)?;
+            if !output_state.has_mode(mode) {
+                output_state.user_modes.push(mode);
+                randr.config_timestamp = time::now();
+                tell_changed(&windows, &randr, &[], &[output]);
+            }
+            Ok(None)
+        }
+        RandrRequest::DeleteOutputMode { output, mode } => {
+            randr.mode(mode)?;
+            let crtc = randr.output(output)?.crtc;
+            let in_use = randr.crtcs.get(&crtc).is_some_and(|crtc| crtc.mode == mode);
+            let output_state = randr.output_mut(output)?;
+            if !output_state.user_modes.contains(&mode) || in_use {
+                return Err(XError::new(ErrorCode::Match, mode));
+            }
+            output_state.user_modes.retain(|m| *m != mode);
+            randr.config_timestamp = time::now();
+            tell_changed(&windows, &randr, &[], &[output]);
+            Ok(None)
+        }
+        RandrRequest::GetCrtcInfo { crtc: id } => {
+            let crtc = randr.crtc(id)?;
+            let (x, y, width, height) = randr.crtc_area(id).unwrap_or_default();
+            let mut body = request.to_bytes_32(randr.timestamp).to_vec();
+            for value in [x as u16, y as u16, width, height] {
+                body.extend(request.to_bytes_16(value));
+            }
+            body.extend(request.to_bytes_32(crtc.mode));
+            let (outputs, possible) = (crtc.outputs.len() as u16, randr.outputs.len() as u16);
+            for value in [crtc.rotation, ALL_ROTATIONS, outputs, possible] {
+                body.extend(request.to_bytes_16(value));
+            }
+            for output in crtc.outputs.iter().chain(randr.outputs.keys()) {
+                body.extend(request.to_bytes_32(*output));
+            }
+            Ok(Some(request.reply(SET_CONFIG_SUCCESS, body)))
+        }
+        RandrRequest::SetCrtcConfig {
+            crtc,
+            timestamp,
+            x,
+            y,
+            mode,
+            rotation,
+            outputs,
+        } => {
+            randr.crtc(crtc)?;
+            check_rotation(rotation)?;
+            for output in &outputs {
+                randr.output(*output)?;
+            }
+            if mode == 0 {
+                if !outputs.is_empty() {
+                    return Err(XError::new(ErrorCode::Match, 0));
+                }
+            } else {
+                let (width, height) = randr.mode(mode)?.info.rotated_size(rotation);
+                if outputs.is_empty() {
+                    return Err(XError::new(ErrorCode::Match, 0));
+                }
+                if let Some(output) = outputs.iter().find(|output| !randr.outputs[output].has_mode(mode)) {
+                    return Err(XError::new(ErrorCode::Match, *output));
+                }
+                let screen = *SCREEN_SIZE.lock().unwrap();
+                if x < 0 || x as i32 + width as i32 > screen.width as i32 {
+                    return Err(XError::new(ErrorCode::Value, x as u32));
+                }
+                if y < 0 || y as i32 + height as i32 > screen.height as i32 {
+                    return Err(XError::new(ErrorCode::Value, y as u32));
+                }
+            }
+            let status = match time::check(timestamp, randr.timestamp) {
+                Some(timestamp) => {
+                    let (crtcs, outputs) = randr.set_crtc(crtc, x, y, mode, rotation, &outputs);
+                    randr.timestamp = timestamp;
+                    tell_changed(&windows, &randr, &crtcs, &outputs);
+                    SET_CONFIG_SUCCESS
+                }
+                None => SET_CONFIG_INVALID_TIME,
+            };
+            Ok(Some(request.reply(status, request.to_bytes_32(randr.timestamp).to_vec())))
+        }
+        RandrRequest::GetCrtcGammaSize { crtc } => {
+            let size = randr.crtc(crtc)?.gamma[0].len() as u16;
+            Ok(Some(request.reply(0, request.to_bytes_16(size).to_vec())))
+        }
+        RandrRequest::GetCrtcGamma { crtc } => {
+            let gamma = &randr.crtc(crtc)?.gamma;
+            let mut body = request.to_bytes_16(gamma[0].len() as u16).to_vec();
+            body.resize(24, 0);
+            for value in gamma.iter().flatten() {
+                body.extend(request.to_bytes_16(*value));
+            }
+            Ok(Some(request.reply(0, body)))
+        }
+        RandrRequest::SetCrtcGamma { crtc, size, data } => {
+            randr.crtc(crtc)?;
+            if size as usize != GAMMA_SIZE {
+                return Err(XError::new(ErrorCode::Value, size as u32));
+            }
+            let mut ramps = data.chunks_exact(GAMMA_SIZE).map(|ramp| ramp.to_vec());
+            let gamma = [(); 3].map(|_| ramps.next().unwrap());
+            randr.crtcs.get_mut(&crtc).unwrap().gamma = gamma;
+            Ok(None)
+        }
+        RandrRequest::GetCrtcTransform { crtc } => {
+            randr.crtc(crtc)?;
+            // CRTCs only have the identity transform, pending and current, and no filter.
+            let identity: Vec<u8> = [1, 0, 0, 0, 1, 0, 0, 0, 1]
+                .iter()
+                .flat_map(|value: &u32| request.to_bytes_32(value << 16))
+                .collect();
+            let mut body = identity.clone();
+            body.extend([0; 4]);
+            body.extend(identity);
+            body.extend([0; 12]);
+            Ok(Some(request.reply(0, body)))
+        }
+        RandrRequest::GetPanning { crtc } => {
+            randr.crtc(crtc)?;
+            let mut body = request.to_bytes_32(randr.timestamp).to_vec();
+            body.resize(28, 0);
+            Ok(Some(request.reply(SET_CONFIG_SUCCESS, body)))
+        }
+        RandrRequest::SetPanning { crtc } => {
+            // Virtual CRTCs show a fixed area, so they can't pan.
+            randr.crtc(crtc)?;
+            Ok(Some(request.reply(SET_CONFIG_FAILED, request.to_bytes_32(randr.timestamp).to_vec())))
+        }
+        RandrRequest::SetOutputPrimary { window, output } => {
+            get_window(&windows, window)?;
+            if output != 0 {
+                randr.output(output)?;
+            }
+            let previous = randr.primary;
+            if previous != output {
+                randr.primary = output;
+                let changed: Vec<u32> = [previous, output].into_iter().filter(|id| *id != 0).collect();
+                tell_changed(&windows, &randr, &[], &changed);
+            }
+            Ok(None)
+        }
+        RandrRequest::GetOutputPrimary { window } => {
+            get_window(&windows, window)?;
+            Ok(Some(request.reply(0, request.to_bytes_32(randr.primary).to_vec())))
+        }
+        RandrRequest::GetProviders { window } => {
+            get_window(&windows, window)?;
+            Ok(Some(request.reply(0, request.to_bytes_32(randr.timestamp).to_vec())))
+        }
+        RandrRequest::ProviderRequest { provider } => Err(randr_error(BAD_PROVIDER, provider)),
+        RandrRequest::GetMonitors { window, active_only } => {
+            get_window(&windows, window)?;
+            let monitors = randr.list_monitors(active_only);
+            let outputs: usize = monitors.iter().map(|monitor| monitor.outputs.len()).sum();
+            let mut body = request.to_bytes_32(randr.timestamp).to_vec();
+            body.extend(request.to_bytes_32(monitors.len() as u32));
+            body.extend(request.to_bytes_32(outputs as u32));
+            body.resize(24, 0);
+            for monitor in &monitors {
+                body.extend(request.to_bytes_32(monitor.name));
+                body.extend([monitor.primary as u8, monitor.automatic as u8]);
+                let (x, y) = (monitor.x as u16, monitor.y as u16);
+                for value in [monitor.outputs.len() as u16, x, y, monitor.width, monitor.height] {
+                    body.extend(request.to_bytes_16(value));
+                }
+                body.extend(request.to_bytes_32(monitor.mm_width));
+                body.extend(request.to_bytes_32(monitor.mm_height));
+                for output in &monitor.outputs {
+                    body.extend(request.to_bytes_32(*output));
+                }
+            }
+            Ok(Some(request.reply(0, body)))
+        }
+        RandrRequest::SetMonitor { window, monitor } => {
+            get_window(&windows, window)?;
+            check_atom(monitor.name)?;
+            for output in &monitor.outputs {
+                randr.output(*output)?;
+            }
+            // A monitor replaces the one with the same name and any sharing its outputs.
+            randr.monitors.retain(|existing| {
+                existing.name != monitor.name && !existing.outputs.iter().any(|o| monitor.outputs.contains(o))
+            });
+            if monitor.primary {
+                for existing in &mut randr.monitors {
+                    existing.primary = false;
+                }
+            }
+            randr.monitors.push(monitor);
+            tell_changed(&windows, &randr, &[], &[]);
+            Ok(None)
+        }
+        RandrRequest::DeleteMonitor { window, name } => {
+            get_window(&windows, window)?;
+            let count = randr.monitors.len();
+            randr.monitors.retain(|monitor| monitor.name != name);
+            if randr.monitors.len() == count {
+                return Err(XError::new(ErrorCode::Value, name));
+            }
+            tell_changed(&windows, &randr, &[], &[]);
+            Ok(None)
+        }
+        RandrRequest::Unsupported => Err(XError::new(ErrorCode::Implementation, 0)),
+    }
+}
@@ -0,0 +1,205 @@
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub struct Rectangle {
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+}
+
+impl Rectangle {
+    pub fn new(x: i32, y: i32, width: i32, height: i32) -> Rectangle {
+        Rectangle {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.width <= 0 || self.height <= 0
+    }
+
+    pub fn contains(&self, x: i32, y: i32) -> bool {
+        x >= self.x && y >= self.y && x < self.x + self.width && y < self.y + self.height
+    }
+
+    pub fn translate(&self, dx: i32, dy: i32) -> Rectangle {
+        Rectangle::new(self.x + dx, self.y + dy, self.width, self.height)
+    }
+
+    pub fn intersect(&self, other: &Rectangle) -> Option<Rectangle> {
+        let x1 = self.x.max(other.x);
+        let y1 = self.y.max(other.y);
+        let x2 = (self.x + self.width).min(other.x + other.width);
+        let y2 = (self.y + self.height).min(other.y + other.height);
+        let rectangle = Rectangle::new(x1, y1, x2 - x1, y2 - y1);
+        (!rectangle.is_empty()).then_some(rectangle)
+    }
+
+    /// Splits `self` into at most four bands that do not overlap `other`.
+    pub fn subtract(&self, other: &Rectangle) -> Vec<Rectangle> {
+        let Some(overlap) = self.intersect(other) else {
+            return vec![*self];
+        };
+        let mut pieces = vec![
+            Rectangle::new(self.x, self.y, self.width, overlap.y - self.y),
+            Rectangle::new(self.x, overlap.y, overlap.x - self.x, overlap.height),
+            Rectangle::new(
+                overlap.x + overlap.width,
+                overlap.y,
+                self.x + self.width - overlap.x - overlap.width,
+                overlap.height,
+            ),
+            Rectangle::new(
+                self.x,
+                overlap.y + overlap.height,
+                self.width,
+                self.y + self.height - overlap.y - overlap.height,
+            ),
+        ];
+        pieces.retain(|piece| !piece.is_empty());
+        pieces
+    }
+}
+
+/// A set of non-overlapping rectangles.
+#[derive(Clone, Default, Debug, PartialEq, Eq)]
+pub struct Region {
+    pub rectangles: Vec<Rectangle>,
+}
+
+impl Region {
+    pub fn new() -> Region {
+        Region::default()
+    }
+
+    pub fn from_rectangle(rectangle: Rectangle) -> Region {
+        if rectangle.is_empty() {
+            Region::new()
+        } else {
+            Region {
+                rectangles: vec![rectangle],
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rectangles.is_empty()
+    }
+
+    pub fn contains(&self, x: i32, y: i32) -> bool {
+        self.rectangles.iter().any(|r| r.contains(x, y))
+    }
+
+    pub fn translate(&self, dx: i32, dy: i32) -> Region {
+        Region {
+            rectangles: self.rectangles.iter().map(|r| r.translate(dx, dy)).collect(),
+        }
+    }
+
+    pub fn extents(&self) -> Rectangle {
+        let Some(first) = self.rectangles.first() else {
+            return Rectangle::default();
+        };
+        let (mut x1, mut y1) = (first.x, first.y);
+        let (mut x2, mut y2) = (first.x + first.width, first.y + first.height);
+        for r in &self.rectangles[1..] {
+            x1 = x1.min(r.x);
+            y1 = y1.min(r.y);
+            x2 = x2.max(r.x + r.width);
+            y2 = y2.max(r.y + r.height);
+        }
+        Rectangle::new(x1, y1, x2 - x1, y2 - y1)
+    }
+
+    pub fn intersect(&self, other: &Region) -> Region {
+        let mut rectangles = vec![];
+        for a in &self.rectangles {
+            for b in &other.rectangles {
+                if let Some(r) = a.intersect(b) {
+                    rectangles.push(r);
+                }
+            }
+        }
+        Region { rectangles }
+    }
+
+    pub fn intersect_rectangle(&self, rectangle: &Rectangle) -> Region {
+        self.intersect(&Region::from_rectangle(*rectangle))
+    }
+
+    pub fn subtract(&self, other: &Region) -> Region {
+        let mut rectangles = self.rectangles.clone();
+        for b in &other.rectangles {
+            rectangles = rectangles.iter().flat_map(|a| a.subtract(b)).collect();
+        }
+        Region { rectangles }
+    }
+
+    pub fn subtract_rectangle(&self, rectangle: &Rectangle) -> Region {
+        self.subtract(&Region::from_rectangle(*rectangle))
+    }
+
+    pub fn union(&self, other: &Region) -> Region {
+        let mut rectangles = self.rectangles.clone();
+        rectangles.append(&mut other.subtract(self).rectangles);
+        Region { rectangles }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn area(region: &Region) -> i32 {
+        region.rectangles.iter().map(|r| r.width * r.height).sum()
+    }
+
+    fn assert_disjoint(region: &Region) {
+        for (i, a) in region.rectangles.iter().enumerate() {
+            for b in &region.rectangles[i + 1..] {
+                assert_eq!(a.intersect(b), None, "{a:?} overlaps {b:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn touching_rectangles_do_not_intersect() {
+        let a = Rectangle::new(0, 0, 10, 10);
+        assert_eq!(a.intersect(&Rectangle::new(10, 0, 10, 10)), None);
+        assert_eq!(a.intersect(&Rectangle::new(5, 5, 10, 10)), Some(Rectangle::new(5, 5, 5, 5)));
+    }
+
+    #[test]
+    fn subtract_leaves_a_hole() {
+        let region = Region::from_rectangle(Rectangle::new(0, 0, 10, 10));
+        let holed = region.subtract_rectangle(&Rectangle::new(4, 4, 2, 2));
+        assert_disjoint(&holed);
+        assert_eq!(area(&holed), 96);
+        assert!(!holed.contains(4, 4) && !holed.contains(5, 5));
+        assert!(holed.contains(3, 4) && holed.contains(6, 5));
+        assert_eq!(holed.extents(), Rectangle::new(0, 0, 10, 10));
+    }
+
+    #[test]
+    fn union_covers_overlaps_once() {
+        let a = Region::from_rectangle(Rectangle::new(0, 0, 10, 10));
+        let b = Region::from_rectangle(Rectangle::new(5, 5, 10, 10));
+        let union = a.union(&b);
+        assert_disjoint(&union);
+        assert_eq!(area(&union), 175);
+        assert_eq!(union.extents(), Rectangle::new(0, 0, 15, 15));
+        assert_eq!(area(&union.intersect(&a)), 100);
+        assert!(union.subtract(&a).subtract(&b).is_empty());
+    }
+
+    #[test]
+    fn empty_regions() {
+        assert!(Region::from_rectangle(Rectangle::new(3, 3, 0, 5)).is_empty());
+        assert_eq!(Region::new().extents(), Rectangle::default());
+        let a = Region::from_rectangle(Rectangle::new(0, 0, 4, 4));
+        assert!(a.intersect(&a.translate(4, 0)).is_empty());
+        assert!(a.subtract(&a).is_empty());
+    }
+}
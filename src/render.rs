@@ -0,0 +1,1031 @@
+//! The RENDER extension, version 0.11: pictures in the standard direct formats, and
+//! compositing them with the Porter-Duff and PDF blend operators, including through
+//! glyphs uploaded by the client.
+
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    blend::{is_valid_op, PICT_OP_ADD},
+    cursor::{add_cursor, Cursor},
+    error::{ErrorCode, XError},
+    extension::{find_extension, ExtensionRequest},
+    gradient::{make_stops, Fill, Stop},
+    glyph::{add_glyphs, glyph_mask, layout_glyphs, GlyphInfo, GlyphItem, GlyphSet, GLYPH_FORMATS, GLYPH_SETS},
+    picture::{
+        composite, find_format, get_destination, get_picture, Drawables, Filter, Picture, FILTERS, PICTURES,
+        PICT_FORMATS, POLY_EDGE_SHARP, VISUAL_FORMAT,
+    },
+    pixmap::PIXMAPS,
+    region::{Rectangle, Region},
+    screen::{DEFAULT_SCREEN, DEFAULT_VISUAL},
+    trapezoid::{rasterize, triangle_trapezoids, Line, Trapezoid},
+    window::{INPUT_ONLY, WINDOWS},
+};
+
+pub static RENDER_NAME: &str = "RENDER";
+pub const RENDER_MAJOR_VERSION: u32 = 0;
+pub const RENDER_MINOR_VERSION: u32 = 11;
+
+/// Errors relative to the extension's first error.
+const BAD_PICT_FORMAT: u8 = 0;
+const BAD_PICTURE: u8 = 1;
+const BAD_PICT_OP: u8 = 2;
+const BAD_GLYPH_SET: u8 = 3;
+const BAD_GLYPH: u8 = 4;
+
+const PICT_TYPE_DIRECT: u8 = 1;
+const SUB_PIXEL_UNKNOWN: u32 = 0;
+
+/// The filter aliases reported by QueryFilters: the index of the filter each name
+/// stands for, or 0xffff for filters that are not aliases.
+const FILTER_ALIASES: [u16; 5] = [0xffff, 0xffff, 0, 1, 1];
+
+fn render_error(offset: u8, bad_value: u32) -> XError {
+    let first_error = find_extension(RENDER_NAME).map_or(0, |e| e.first_error);
+    XError::extension(first_error + offset, bad_value)
+}
+
+pub fn bad_pict_format(format: u32) -> XError {
+    render_error(BAD_PICT_FORMAT, format)
+}
+
+pub fn bad_picture(picture: u32) -> XError {
+    render_error(BAD_PICTURE, picture)
+}
+
+pub fn bad_glyph_set(glyphset: u32) -> XError {
+    render_error(BAD_GLYPH_SET, glyphset)
+}
+
+pub fn bad_glyph(glyph: u32) -> XError {
+    render_error(BAD_GLYPH, glyph)
+}
+
+pub fn check_op(op: u8) -> Result<(), XError> {
+    if !is_valid_op(op) {
+        return Err(render_error(BAD_PICT_OP, op as u32));
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+pub enum RenderRequest {
+    QueryVersion {
+        major_version: u32,
+        minor_version: u32,
+    },
+    QueryPictFormats,
+    QueryPictIndexValues {
+        format: u32,
+    },
+    CreatePicture {
+        pid: u32,
+        drawable: u32,
+        format: u32,
+        value_mask: u32,
+        values: Vec<u32>,
+    },
+    ChangePicture {
+        picture: u32,
+        value_mask: u32,
+        values: Vec<u32>,
+    },
+    SetPictureClipRectangles {
+        picture: u32,
+        x_origin: i16,
+        y_origin: i16,
+        rectangles: Vec<Rectangle>,
+    },
+    FreePicture {
+        picture: u32,
+    },
+    Composite {
+        op: u8,
+        src: u32,
+        mask: u32,
+        dst: u32,
+        src_x: i16,
+        src_y: i16,
+        mask_x: i16,
+        mask_y: i16,
+        dst_x: i16,
+        dst_y: i16,
+        width: u16,
+        height: u16,
+    },
+    Trapezoids {
+        op: u8,
+        src: u32,
+        dst: u32,
+        mask_format: u32,
+        src_x: i16,
+        src_y: i16,
+        trapezoids: Vec<Trapezoid>,
+    },
+    /// Triangles, TriStrip and TriFan, with strips and fans already split into
+    /// triangles.
+    Triangles {
+        op: u8,
+        src: u32,
+        dst: u32,
+        mask_format: u32,
+        src_x: i16,
+        src_y: i16,
+        triangles: Vec<[(f64, f64); 3]>,
+    },
+    CreateGlyphSet {
+        gsid: u32,
+        format: u32,
+    },
+    ReferenceGlyphSet {
+        gsid: u32,
+        existing: u32,
+    },
+    FreeGlyphSet {
+        glyphset: u32,
+    },
+    AddGlyphs {
+        glyphset: u32,
+        glyph_ids: Vec<u32>,
+        infos: Vec<GlyphInfo>,
+        data: Vec<u8>,
+    },
+    FreeGlyphs {
+        glyphset: u32,
+        glyphs: Vec<u32>,
+    },
+    CompositeGlyphs {
+        op: u8,
+        src: u32,
+        dst: u32,
+        mask_format: u32,
+        glyphset: u32,
+        src_x: i16,
+        src_y: i16,
+        items: Vec<GlyphItem>,
+    },
+    FillRectangles {
+        op: u8,
+        dst: u32,
+        /// Premultiplied 16-bit red, green, blue and alpha.
+        color: [u16; 4],
+        rectangles: Vec<Rectangle>,
+    },
+    CreateCursor {
+        cid: u32,
+        src: u32,
+        x: u16,
+        y: u16,
+    },
+    SetPictureTransform {
+        picture: u32,
+        transform: [[i32; 3]; 3],
+    },
+    QueryFilters {
+        drawable: u32,
+    },
+    SetPictureFilter {
+        picture: u32,
+        filter: String,
+        values: Vec<i32>,
+    },
+    AddTraps {
+        picture: u32,
+        x_offset: i16,
+        y_offset: i16,
+        trapezoids: Vec<Trapezoid>,
+    },
+    CreateSolidFill {
+        pid: u32,
+        /// Premultiplied 16-bit red, green, blue and alpha.
+        color: [u16; 4],
+    },
+    /// CreateLinearGradient, CreateRadialGradient and CreateConicalGradient.
+    CreateGradient {
+        pid: u32,
+        fill: Fill,
+    },
+    /// Requests the server doesn't implement, including the obsolete ones.
+    Unsupported,
+}
+
+pub fn read_rectangles(request: &ExtensionRequest, data: &[u8]) -> Vec<Rectangle> {
+    data.chunks_exact(8)
+        .map(|r| {
+            Rectangle::new(
+                request.int16(r) as i32,
+                request.int16(&r[2..]) as i32,
+                request.card16(&r[4..]) as i32,
+                request.card16(&r[6..]) as i32,
+            )
+        })
+        .collect()
+}
+
+fn read_values(request: &ExtensionRequest, data: &[u8]) -> Vec<u32> {
+    data.chunks_exact(4).map(|value| request.card32(value)).collect()
+}
+
+/// Reads the glyph items of CompositeGlyphs8, 16 or 32, whose glyph ids are `size`
+/// bytes each. A length of 255 marks an item that switches glyph set.
+fn read_glyph_items(request: &ExtensionRequest, mut data: &[u8], size: usize) -> Vec<GlyphItem> {
+    let mut items = vec![];
+    while data.len() >= 8 {
+        let len = data[0] as usize;
+        let (dx, dy) = (request.int16(&data[4..]), request.int16(&data[6..]));
+        data = &data[8..];
+        if len == 0xff {
+            if data.len() < 4 {
+                break;
+            }
+            items.push(GlyphItem::GlyphSet(request.card32(data)));
+            data = &data[4..];
+            continue;
+        }
+        let Some(ids) = data.get(..len * size) else {
+            break;
+        };
+        let glyphs = ids
+            .chunks_exact(size)
+            .map(|id| match size {
+                1 => id[0] as u32,
+                2 => request.card16(id) as u32,
+                _ => request.card32(id),
+            })
+            .collect();
+        items.push(GlyphItem::Glyphs { dx, dy, glyphs });
+        data = &data[(len * size).next_multiple_of(4).min(data.len())..];
+    }
+    items
+}
+
+fn fixed(request: &ExtensionRequest, bytes: &[u8]) -> f64 {
+    request.card32(bytes) as i32 as f64 / 65536.0
+}
+
+fn read_point(request: &ExtensionRequest, bytes: &[u8]) -> (f64, f64) {
+    (fixed(request, bytes), fixed(request, &bytes[4..]))
+}
+
+fn read_line(request: &ExtensionRequest, bytes: &[u8]) -> Line {
+    Line {
+        p1: read_point(request, bytes),
+        p2: read_point(request, &bytes[8..]),
+    }
+}
+
+/// Reads the stop count, offsets and colors that end each gradient request.
+fn read_stops(request: &ExtensionRequest, data: &[u8]) -> Result<Vec<Stop>, XError> {
+    let count = request.card32(data) as usize;
+    let colors_start = 4 + 4 * count;
+    if data.len() < colors_start + 8 * count {
+        return Err(XError::new(ErrorCode::Length, 0));
+    }
+    let offsets: Vec<i32> = read_values(request, &data[4..colors_start])
+        .into_iter()
+        .map(|offset| offset as i32)
+        .collect();
+    let colors: Vec<[u16; 4]> = data[colors_start..colors_start + 8 * count]
+        .chunks_exact(8)
+        .map(|color| std::array::from_fn(|i| request.card16(&color[2 * i..])))
+        .collect();
+    make_stops(&offsets, &colors)
+}
+
+fn read_render_request(request: &ExtensionRequest) -> Result<RenderRequest, XError> {
+    let data = request.data;
+    let minimum_length = match request.minor_opcode {
+        0 => 8,
+        1 => 0,
+        2 | 7 | 29 => 4,
+        4 => 16,
+        5 | 6 | 30 => 8,
+        8 => 32,
+        26 | 27 => 12,
+        28 => 40,
+        17 | 18 | 20 => 8,
+        19 | 22 => 4,
+        23..=25 => 24,
+        10..=13 | 36 => 20,
+        32 => 8,
+        33 => 12,
+        34 => 24,
+        35 => 32,
+        3 | 9 | 14..=16 | 21 | 31 => 0,
+        minor_opcode => return Err(XError::new(ErrorCode::Request, minor_opcode as u32)),
+    };
+    if data.len() < minimum_length {
+        return Err(XError::new(ErrorCode::Length, 0));
+    }
+    Ok(match request.minor_opcode {
+        0 => RenderRequest::QueryVersion {
+            major_version: request.card32(data),
+            minor_version: request.card32(&data[4..]),
+        },
+        1 => RenderRequest::QueryPictFormats,
+        2 => RenderRequest::QueryPictIndexValues {
+            format: request.card32(data),
+        },
+        4 => RenderRequest::CreatePicture {
+            pid: request.card32(data),
+            drawable: request.card32(&data[4..]),
+            format: request.card32(&data[8..]),
+            value_mask: request.card32(&data[12..]),
+            values: read_values(request, &data[16..]),
+        },
+        5 => RenderRequest::ChangePicture {
+            picture: request.card32(data),
+            value_mask: request.card32(&data[4..]),
+            values: read_values(request, &data[8..]),
+        },
+        6 => RenderRequest::SetPictureClipRectangles {
+            picture: request.card32(data),
+            x_origin: request.int16(&data[4..]),
+            y_origin: request.int16(&data[6..]),
+            rectangles: read_rectangles(request, &data[8..]),
+        },
+        7 => RenderRequest::FreePicture {
+            picture: request.card32(data),
+        },
+        8 => RenderRequest::Composite {
+            op: data[0],
+            src: request.card32(&data[4..]),
+            mask: request.card32(&data[8..]),
+            dst: request.card32(&data[12..]),
+            src_x: request.int16(&data[16..]),
+            src_y: request.int16(&data[18..]),
+            mask_x: request.int16(&data[20..]),
+            mask_y: request.int16(&data[22..]),
+            dst_x: request.int16(&data[24..]),
+            dst_y: request.int16(&data[26..]),
+            width: request.card16(&data[28..]),
+            height: request.card16(&data[30..]),
+        },
+        10 => RenderRequest::Trapezoids {
+            op: data[0],
+            src: request.card32(&data[4..]),
+            dst: request.card32(&data[8..]),
+            mask_format: request.card32(&data[12..]),
+            src_x: request.int16(&data[16..]),
+            src_y: request.int16(&data[18..]),
+            trapezoids: data[20..]
+                .chunks_exact(40)
+                .map(|t| Trapezoid {
+                    top: fixed(request, t),
+                    bottom: fixed(request, &t[4..]),
+                    left: read_line(request, &t[8..]),
+                    right: read_line(request, &t[24..]),
+                })
+                .collect(),
+        },
+        11..=13 => {
+            let triangles = match request.minor_opcode {
+                11 => data[20..]
+                    .chunks_exact(24)
+                    .map(|t| std::array::from_fn(|i| read_point(request, &t[8 * i..])))
+                    .collect(),
+                minor_opcode => {
+                    let points: Vec<(f64, f64)> =
+                        data[20..].chunks_exact(8).map(|p| read_point(request, p)).collect();
+                    let count = points.len().saturating_sub(2);
+                    if minor_opcode == 12 {
+                        (0..count).map(|i| [points[i], points[i + 1], points[i + 2]]).collect()
+                    } else {
+                        (0..count).map(|i| [points[0], points[i + 1], points[i + 2]]).collect()
+                    }
+                }
+            };
+            RenderRequest::Triangles {
+                op: data[0],
+                src: request.card32(&data[4..]),
+                dst: request.card32(&data[8..]),
+                mask_format: request.card32(&data[12..]),
+                src_x: request.int16(&data[16..]),
+                src_y: request.int16(&data[18..]),
+                triangles,
+            }
+        }
+        17 => RenderRequest::CreateGlyphSet {
+            gsid: request.card32(data),
+            format: request.card32(&data[4..]),
+        },
+        18 => RenderRequest::ReferenceGlyphSet {
+            gsid: request.card32(data),
+            existing: request.card32(&data[4..]),
+        },
+        19 => RenderRequest::FreeGlyphSet {
+            glyphset: request.card32(data),
+        },
+        20 => {
+            let count = request.card32(&data[4..]) as usize;
+            let infos_start = 8 + 4 * count;
+            let data_start = infos_start + 12 * count;
+            if data.len() < data_start {
+                return Err(XError::new(ErrorCode::Length, 0));
+            }
+            RenderRequest::AddGlyphs {
+                glyphset: request.card32(data),
+                glyph_ids: read_values(request, &data[8..infos_start]),
+                infos: data[infos_start..data_start]
+                    .chunks_exact(12)
+                    .map(|info| GlyphInfo {
+                        width: request.card16(info),
+                        height: request.card16(&info[2..]),
+                        x: request.int16(&info[4..]),
+                        y: request.int16(&info[6..]),
+                        x_off: request.int16(&info[8..]),
+                        y_off: request.int16(&info[10..]),
+                    })
+                    .collect(),
+                data: data[data_start..].to_vec(),
+            }
+        }
+        22 => RenderRequest::FreeGlyphs {
+            glyphset: request.card32(data),
+            glyphs: read_values(request, &data[4..]),
+        },
+        23..=25 => RenderRequest::CompositeGlyphs {
+            op: data[0],
+            src: request.card32(&data[4..]),
+            dst: request.card32(&data[8..]),
+            mask_format: request.card32(&data[12..]),
+            glyphset: request.card32(&data[16..]),
+            src_x: request.int16(&data[20..]),
+            src_y: request.int16(&data[22..]),
+            items: read_glyph_items(request, &data[24..], 1 << (request.minor_opcode - 23)),
+        },
+        26 => RenderRequest::FillRectangles {
+            op: data[0],
+            dst: request.card32(&data[4..]),
+            color: std::array::from_fn(|i| request.card16(&data[8 + 2 * i..])),
+            rectangles: read_rectangles(request, &data[16..]),
+        },
+        27 => RenderRequest::CreateCursor {
+            cid: request.card32(data),
+            src: request.card32(&data[4..]),
+            x: request.card16(&data[8..]),
+            y: request.card16(&data[10..]),
+        },
+        28 => RenderRequest::SetPictureTransform {
+            picture: request.card32(data),
+            transform: std::array::from_fn(|row| {
+                std::array::from_fn(|column| request.card32(&data[4 + 12 * row + 4 * column..]) as i32)
+            }),
+        },
+        29 => RenderRequest::QueryFilters {
+            drawable: request.card32(data),
+        },
+        30 => {
+            let len = request.card16(&data[4..]) as usize;
+            let name = data.get(8..8 + len).ok_or(XError::new(ErrorCode::Length, 0))?;
+            let values_start = (8 + len).next_multiple_of(4).min(data.len());
+            RenderRequest::SetPictureFilter {
+                picture: request.card32(data),
+                filter: String::from_utf8_lossy(name).into_owned(),
+                values: read_values(request, &data[values_start..]).into_iter().map(|v| v as i32).collect(),
+            }
+        }
+        32 => RenderRequest::AddTraps {
+            picture: request.card32(data),
+            x_offset: request.int16(&data[4..]),
+            y_offset: request.int16(&data[6..]),
+            trapezoids: data[8..]
+                .chunks_exact(24)
+                .map(|t| {
+                    // Top left, right and y, then bottom left, right and y.
+                    let [top_left, top_right, top, bottom_left, bottom_right, bottom] =
+                        std::array::from_fn(|i| fixed(request, &t[4 * i..]));
+                    Trapezoid {
+                        top,
+                        bottom,
+                        left: Line {
+                            p1: (top_left, top),
+                            p2: (bottom_left, bottom),
+                        },
+                        right: Line {
+                            p1: (top_right, top),
+                            p2: (bottom_right, bottom),
+                        },
+                    }
+                })
+                .collect(),
+        },
+        33 => RenderRequest::CreateSolidFill {
+            pid: request.card32(data),
+            color: std::array::from_fn(|i| request.card16(&data[4 + 2 * i..])),
+        },
+        34 => RenderRequest::CreateGradient {
+            pid: request.card32(data),
+            fill: Fill::Linear {
+                p1: read_point(request, &data[4..]),
+                p2: read_point(request, &data[12..]),
+                stops: read_stops(request, &data[20..])?,
+            },
+        },
+        35 => RenderRequest::CreateGradient {
+            pid: request.card32(data),
+            fill: Fill::Radial {
+                inner: (
+                    fixed(request, &data[4..]),
+                    fixed(request, &data[8..]),
+                    fixed(request, &data[20..]),
+                ),
+                outer: (
+                    fixed(request, &data[12..]),
+                    fixed(request, &data[16..]),
+                    fixed(request, &data[24..]),
+                ),
+                stops: read_stops(request, &data[28..])?,
+            },
+        },
+        36 => RenderRequest::CreateGradient {
+            pid: request.card32(data),
+            fill: Fill::Conical {
+                center: read_point(request, &data[4..]),
+                angle: fixed(request, &data[12..]),
+                stops: read_stops(request, &data[16..])?,
+            },
+        },
+        _ => RenderRequest::Unsupported,
+    })
+}
+
+fn query_pict_formats(request: &ExtensionRequest) -> Vec<u8> {
+    let mut body = vec![];
+    body.extend(request.to_bytes_32(PICT_FORMATS.len() as u32));
+    // One screen with one depth holding the one visual.
+    body.extend(request.to_bytes_32(1));
+    body.extend(request.to_bytes_32(1));
+    body.extend(request.to_bytes_32(1));
+    body.extend(request.to_bytes_32(1));
+    body.extend([0; 4]);
+    for format in &PICT_FORMATS {
+        body.extend(request.to_bytes_32(format.id));
+        body.extend([PICT_TYPE_DIRECT, format.depth, 0, 0]);
+        for channel in [format.red, format.green, format.blue, format.alpha] {
+            body.extend(request.to_bytes_16(channel.shift));
+            body.extend(request.to_bytes_16(channel.mask));
+        }
+        // No colormap.
+        body.extend(request.to_bytes_32(0));
+    }
+    body.extend(request.to_bytes_32(1));
+    body.extend(request.to_bytes_32(VISUAL_FORMAT));
+    body.extend([DEFAULT_SCREEN.root_depth, 0]);
+    body.extend(request.to_bytes_16(1));
+    body.extend([0; 4]);
+    body.extend(request.to_bytes_32(DEFAULT_VISUAL.visual_id));
+    body.extend(request.to_bytes_32(VISUAL_FORMAT));
+    body.extend(request.to_bytes_32(SUB_PIXEL_UNKNOWN));
+    request.reply(0, body)
+}
+
+fn query_filters(request: &ExtensionRequest) -> Vec<u8> {
+    let mut body = request.to_bytes_32(FILTER_ALIASES.len() as u32).to_vec();
+    body.extend(request.to_bytes_32(FILTERS.len() as u32));
+    body.extend([0; 16]);
+    for alias in FILTER_ALIASES {
+        body.extend(request.to_bytes_16(alias));
+    }
+    body.resize(body.len().next_multiple_of(4), 0);
+    for (name, _) in FILTERS {
+        body.push(name.len() as u8);
+        body.extend(name.as_bytes());
+    }
+    request.reply(0, body)
+}
+
+fn get_glyph_set(glyphset: u32) -> Result<Arc<Mutex<GlyphSet>>, XError> {
+    GLYPH_SETS
+        .lock()
+        .unwrap()
+        .get(&glyphset)
+        .cloned()
+        .ok_or(bad_glyph_set(glyphset))
+}
+
+/// Draws shapes for Trapezoids and Triangles, each shape being the trapezoids of a
+/// trapezoid or triangle. The source lines up with the first point of the first shape,
+/// and without a mask format every shape is drawn on its own, lining up with its own
+/// first point.
+fn composite_shapes(
+    op: u8,
+    src: u32,
+    dst: u32,
+    mask_format: u32,
+    src_x: i16,
+    src_y: i16,
+    shapes: Vec<((f64, f64), Vec<Trapezoid>)>,
+) -> Result<(), XError> {
+    check_op(op)?;
+    let windows = WINDOWS.lock().unwrap();
+    let pictures = PICTURES.lock().unwrap();
+    let source = get_picture(&pictures, src)?;
+    let destination = get_destination(&pictures, dst)?;
+    let mask_format = match mask_format {
+        0 => None,
+        format => Some(find_format(format)?),
+    };
+    let mut drawables = Drawables::lock(&windows);
+    let (width, height) = drawables.size(destination.drawable).unwrap_or_default();
+    let bounds = Rectangle::new(0, 0, width, height);
+    let groups = match mask_format {
+        Some(_) => match shapes.first() {
+            Some((anchor, _)) => vec![(*anchor, shapes.into_iter().flat_map(|(_, shape)| shape).collect())],
+            None => vec![],
+        },
+        None => shapes,
+    };
+    for ((anchor_x, anchor_y), trapezoids) in groups {
+        let sharp = mask_format.map_or(destination.poly_edge == POLY_EDGE_SHARP, |format| format.depth == 1);
+        let coverage = rasterize(&trapezoids, &bounds, sharp);
+        let src_dx = anchor_x.floor() as i32 - src_x as i32;
+        let src_dy = anchor_y.floor() as i32 - src_y as i32;
+        let mut area = Region::from_rectangle(coverage.extents);
+        if let Some(clip) = source.source_clip(&drawables, src_dx, src_dy) {
+            area = area.intersect(&clip);
+        }
+        composite(&mut drawables, &pictures, op, destination, &area, |drawables, x, y| {
+            let color = source.sample(drawables, &pictures, x - src_dx, y - src_dy);
+            let alpha = coverage.get(x, y);
+            let alpha = mask_format.map_or(alpha, |format| format.fetch(format.store([alpha, 0, 0, 0]))[0]);
+            (color, [alpha; 4])
+        });
+    }
+    Ok(())
+}
+
+pub fn handle_render_request(request: &ExtensionRequest) -> Result<Option<Vec<u8>>, XError> {
+    let render_request = read_render_request(request)?;
+    match render_request {
+        RenderRequest::QueryVersion { .. } => {
+            let mut body = request.to_bytes_32(RENDER_MAJOR_VERSION).to_vec();
+            body.extend(request.to_bytes_32(RENDER_MINOR_VERSION));
+            Ok(Some(request.reply(0, body)))
+        }
+        RenderRequest::QueryPictFormats => Ok(Some(query_pict_formats(request))),
+        RenderRequest::QueryPictIndexValues { format } => {
+            // Every format is direct, so none has index values.
+            find_format(format)?;
+            Err(XError::new(ErrorCode::Match, format))
+        }
+        RenderRequest::CreatePicture {
+            pid,
+            drawable,
+            format,
+            value_mask,
+            values,
+        } => {
+            let windows = WINDOWS.lock().unwrap();
+            let mut pictures = PICTURES.lock().unwrap();
+            if pictures.contains_key(&pid) {
+                return Err(XError::new(ErrorCode::IDChoice, pid));
+            }
+            let format = find_format(format)?;
+            match windows.get(&drawable) {
+                Some(window) if window.class == INPUT_ONLY || format.id != VISUAL_FORMAT => {
+                    return Err(XError::new(ErrorCode::Match, drawable));
+                }
+                Some(_) => {}
+                None => match PIXMAPS.lock().unwrap().get(&drawable) {
+                    Some(pixmap) if pixmap.depth != format.depth => {
+                        return Err(XError::new(ErrorCode::Match, drawable));
+                    }
+                    Some(_) => {}
+                    None => return Err(XError::new(ErrorCode::Drawable, drawable)),
+                },
+            }
+            let mut picture = Picture::new(drawable, format);
+            picture.change(&pictures, value_mask, &values)?;
+            pictures.insert(pid, picture);
+            Ok(None)
+        }
+        RenderRequest::ChangePicture {
+            picture,
+            value_mask,
+            values,
+        } => {
+            let mut pictures = PICTURES.lock().unwrap();
+            let mut changed = get_picture(&pictures, picture)?.clone();
+            changed.change(&pictures, value_mask, &values)?;
+            pictures.insert(picture, changed);
+            Ok(None)
+        }
+        RenderRequest::SetPictureClipRectangles {
+            picture,
+            x_origin,
+            y_origin,
+            rectangles,
+        } => {
+            let mut pictures = PICTURES.lock().unwrap();
+            let picture = pictures.get_mut(&picture).ok_or(bad_picture(picture))?;
+            let clip = rectangles
+                .iter()
+                .fold(Region::new(), |clip, r| clip.union(&Region::from_rectangle(*r)));
+            picture.clip = Some(clip);
+            picture.clip_origin = (x_origin, y_origin);
+            Ok(None)
+        }
+        RenderRequest::FreePicture { picture } => {
+            PICTURES.lock().unwrap().remove(&picture).ok_or(bad_picture(picture))?;
+            Ok(None)
+        }
+        RenderRequest::Composite {
+            op,
+            src,
+            mask,
+            dst,
+            src_x,
+            src_y,
+            mask_x,
+            mask_y,
+            dst_x,
+            dst_y,
+            width,
+            height,
+        } => {
+            check_op(op)?;
+            let windows = WINDOWS.lock().unwrap();
+            let pictures = PICTURES.lock().unwrap();
+            let source = get_picture(&pictures, src)?;
+            let mask = match mask {
+                0 => None,
+                mask => Some(get_picture(&pictures, mask)?),
+            };
+            let destination = get_destination(&pictures, dst)?;
+            let mut drawables = Drawables::lock(&windows);
+            let (dst_x, dst_y) = (dst_x as i32, dst_y as i32);
+            let (src_dx, src_dy) = (dst_x - src_x as i32, dst_y - src_y as i32);
+            let (mask_dx, mask_dy) = (dst_x - mask_x as i32, dst_y - mask_y as i32);
+            let mut area = Region::from_rectangle(Rectangle::new(dst_x, dst_y, width as i32, height as i32));
+            if let Some(clip) = source.source_clip(&drawables, src_dx, src_dy) {
+                area = area.intersect(&clip);
+            }
+            if let Some(clip) = mask.and_then(|mask| mask.source_clip(&drawables, mask_dx, mask_dy)) {
+                area = area.intersect(&clip);
+            }
+            composite(&mut drawables, &pictures, op, destination, &area, |drawables, x, y| {
+                let color = source.sample(drawables, &pictures, x - src_dx, y - src_dy);
+                let mask = mask.map_or([255; 4], |mask| {
+                    mask.sample_mask(drawables, &pictures, x - mask_dx, y - mask_dy)
+                });
+                (color, mask)
+            });
+            Ok(None)
+        }
+        RenderRequest::Trapezoids {
+            op,
+            src,
+            dst,
+            mask_format,
+            src_x,
+            src_y,
+            trapezoids,
+        } => {
+            let shapes = trapezoids.into_iter().map(|t| (t.left.p1, vec![t])).collect();
+            composite_shapes(op, src, dst, mask_format, src_x, src_y, shapes)?;
+            Ok(None)
+        }
+        RenderRequest::Triangles {
+            op,
+            src,
+            dst,
+            mask_format,
+            src_x,
+            src_y,
+            triangles,
+        } => {
+            let shapes = triangles.into_iter().map(|t| (t[0], triangle_trapezoids(t))).collect();
+            composite_shapes(op, src, dst, mask_format, src_x, src_y, shapes)?;
+            Ok(None)
+        }
+        RenderRequest::CreateGlyphSet { gsid, format } => {
+            let mut glyph_sets = GLYPH_SETS.lock().unwrap();
+            if glyph_sets.contains_key(&gsid) {
+                return Err(XError::new(ErrorCode::IDChoice, gsid));
+            }
+            let format = find_format(format)?;
+            if !GLYPH_FORMATS.contains(&format.id) {
+                return Err(XError::new(ErrorCode::Match, format.id));
+            }
+            let glyph_set = GlyphSet {
+                format,
+                glyphs: BTreeMap::new(),
+            };
+            glyph_sets.insert(gsid, Arc::new(Mutex::new(glyph_set)));
+            Ok(None)
+        }
+        RenderRequest::ReferenceGlyphSet { gsid, existing } => {
+            let mut glyph_sets = GLYPH_SETS.lock().unwrap();
+            if glyph_sets.contains_key(&gsid) {
+                return Err(XError::new(ErrorCode::IDChoice, gsid));
+            }
+            let glyph_set = glyph_sets.get(&existing).ok_or(bad_glyph_set(existing))?.clone();
+            glyph_sets.insert(gsid, glyph_set);
+            Ok(None)
+        }
+        RenderRequest::FreeGlyphSet { glyphset } => {
+            GLYPH_SETS.lock().unwrap().remove(&glyphset).ok_or(bad_glyph_set(glyphset))?;
+            Ok(None)
+        }
+        RenderRequest::AddGlyphs {
+            glyphset,
+            glyph_ids,
+            infos,
+            data,
+        } => {
+            let glyph_set = get_glyph_set(glyphset)?;
+            add_glyphs(&mut glyph_set.lock().unwrap(), &glyph_ids, &infos, &data)?;
+            Ok(None)
+        }
+        RenderRequest::FreeGlyphs { glyphset, glyphs } => {
+            let glyph_set = get_glyph_set(glyphset)?;
+            let mut glyph_set = glyph_set.lock().unwrap();
+            // Every glyph must exist before any is freed.
+            if let Some(missing) = glyphs.iter().find(|id| !glyph_set.glyphs.contains_key(id)) {
+                return Err(bad_glyph(*missing));
+            }
+            for id in glyphs {
+                glyph_set.glyphs.remove(&id);
+            }
+            Ok(None)
+        }
+        RenderRequest::CompositeGlyphs {
+            op,
+            src,
+            dst,
+            mask_format,
+            glyphset,
+            src_x,
+            src_y,
+            items,
+        } => {
+            check_op(op)?;
+            let windows = WINDOWS.lock().unwrap();
+            let pictures = PICTURES.lock().unwrap();
+            let source = get_picture(&pictures, src)?;
+            let destination = get_destination(&pictures, dst)?;
+            let mask_format = match mask_format {
+                0 => None,
+                format => Some(find_format(format)?),
+            };
+            let glyphs = layout_glyphs(get_glyph_set(glyphset)?, &items, get_glyph_set)?;
+            // The source lines up with the pen position of the first glyph item.
+            let (first_x, first_y) = items
+                .iter()
+                .find_map(|item| match item {
+                    GlyphItem::Glyphs { dx, dy, .. } => Some((*dx as i32, *dy as i32)),
+                    GlyphItem::GlyphSet(_) => None,
+                })
+                .unwrap_or_default();
+            let (src_dx, src_dy) = (first_x - src_x as i32, first_y - src_y as i32);
+            let mut drawables = Drawables::lock(&windows);
+            let source_clip = source.source_clip(&drawables, src_dx, src_dy);
+            let clip = |area: Region| match &source_clip {
+                Some(clip) => area.intersect(clip),
+                None => area,
+            };
+            if let Some(mask_format) = mask_format {
+                let (extents, mask) = glyph_mask(&glyphs, mask_format);
+                let component_alpha = mask_format.red.mask != 0;
+                let area = clip(Region::from_rectangle(extents));
+                composite(&mut drawables, &pictures, op, destination, &area, |drawables, x, y| {
+                    let color = source.sample(drawables, &pictures, x - src_dx, y - src_dy);
+                    let index = (y - extents.y) as usize * extents.width as usize + (x - extents.x) as usize;
+                    (color, if component_alpha { mask[index] } else { [mask[index][0]; 4] })
+                });
+            } else {
+                for (glyph, x, y) in &glyphs {
+                    let r = glyph.rectangle(*x, *y);
+                    let area = clip(Region::from_rectangle(r));
+                    composite(&mut drawables, &pictures, op, destination, &area, |drawables, x, y| {
+                        let color = source.sample(drawables, &pictures, x - src_dx, y - src_dy);
+                        (color, glyph.mask(x - r.x, y - r.y))
+                    });
+                }
+            }
+            Ok(None)
+        }
+        RenderRequest::FillRectangles {
+            op,
+            dst,
+            color,
+            rectangles,
+        } => {
+            check_op(op)?;
+            let windows = WINDOWS.lock().unwrap();
+            let pictures = PICTURES.lock().unwrap();
+            let destination = get_destination(&pictures, dst)?;
+            let mut drawables = Drawables::lock(&windows);
+            let [red, green, blue, alpha] = color.map(|channel| (channel >> 8) as u8);
+            let area = rectangles
+                .iter()
+                .fold(Region::new(), |area, r| area.union(&Region::from_rectangle(*r)));
+            composite(&mut drawables, &pictures, op, destination, &area, |_, _, _| {
+                ([alpha, red, green, blue], [255; 4])
+            });
+            Ok(None)
+        }
+        RenderRequest::CreateCursor { cid, src, x, y } => {
+            let windows = WINDOWS.lock().unwrap();
+            let pictures = PICTURES.lock().unwrap();
+            let source = get_destination(&pictures, src)?;
+            let drawables = Drawables::lock(&windows);
+            let (width, height) = drawables.size(source.drawable).unwrap_or_default();
+            if x as i32 >= width || y as i32 >= height {
+                return Err(XError::new(ErrorCode::Match, src));
+            }
+            let mut pixels = vec![];
+            for py in 0..height {
+                for px in 0..width {
+                    let [alpha, red, green, blue] = source.sample(&drawables, &pictures, px, py);
+                    pixels.push(u32::from_be_bytes([alpha, red, green, blue]));
+                }
+            }
+            drop(drawables);
+            add_cursor(cid, Cursor::new(width as u16, height as u16, x, y, pixels))?;
+            Ok(None)
+        }
+        RenderRequest::SetPictureTransform { picture, transform } => {
+            let mut pictures = PICTURES.lock().unwrap();
+            let picture = pictures.get_mut(&picture).ok_or(bad_picture(picture))?;
+            let transform = transform.map(|row| row.map(|value| value as f64 / 65536.0));
+            let identity = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+            picture.transform = (transform != identity).then_some(transform);
+            Ok(None)
+        }
+        RenderRequest::QueryFilters { drawable } => {
+            let windows = WINDOWS.lock().unwrap();
+            if !windows.contains_key(&drawable) && !PIXMAPS.lock().unwrap().contains_key(&drawable) {
+                return Err(XError::new(ErrorCode::Drawable, drawable));
+            }
+            Ok(Some(query_filters(request)))
+        }
+        RenderRequest::SetPictureFilter {
+            picture,
+            filter,
+            values,
+        } => {
+            let mut pictures = PICTURES.lock().unwrap();
+            let picture = pictures.get_mut(&picture).ok_or(bad_picture(picture))?;
+            let filter: Filter = FILTERS
+                .iter()
+                .find(|(name, _)| *name == filter)
+                .map(|(_, filter)| *filter)
+                .ok_or(XError::new(ErrorCode::Name, 0))?;
+            // None of the supported filters take parameters.
+            if !values.is_empty() {
+                return Err(XError::new(ErrorCode::Match, 0));
+            }
+            picture.filter = filter;
+            Ok(None)
+        }
+        RenderRequest::AddTraps {
+            picture,
+            x_offset,
+            y_offset,
+            trapezoids,
+        } => {
+            let windows = WINDOWS.lock().unwrap();
+            let pictures = PICTURES.lock().unwrap();
+            let destination = get_destination(&pictures, picture)?;
+            let format = destination.format;
+            if format.red.mask != 0 || format.green.mask != 0 || format.blue.mask != 0 {
+                return Err(XError::new(ErrorCode::Match, picture));
+            }
+            let mut drawables = Drawables::lock(&windows);
+            let (width, height) = drawables.size(destination.drawable).unwrap_or_default();
+            let trapezoids: Vec<Trapezoid> = trapezoids
+                .iter()
+                .map(|t| t.translate(x_offset as f64, y_offset as f64))
+                .collect();
+            let coverage = rasterize(&trapezoids, &Rectangle::new(0, 0, width, height), format.depth == 1);
+            let area = Region::from_rectangle(coverage.extents);
+            composite(&mut drawables, &pictures, PICT_OP_ADD, destination, &area, |_, x, y| {
+                ([255; 4], [coverage.get(x, y); 4])
+            });
+            Ok(None)
+        }
+        RenderRequest::CreateSolidFill { pid, color } => {
+            let mut pictures = PICTURES.lock().unwrap();
+            if pictures.contains_key(&pid) {
+                return Err(XError::new(ErrorCode::IDChoice, pid));
+            }
+            let [red, green, blue, alpha] = color.map(|channel| (channel >> 8) as u8);
+            pictures.insert(pid, Picture::with_fill(Fill::Solid([alpha, red, green, blue])));
+            Ok(None)
+        }
+        RenderRequest::CreateGradient { pid, fill } => {
+            let mut pictures = PICTURES.lock().unwrap();
+            if pictures.contains_key(&pid) {
+                return Err(XError::new(ErrorCode::IDChoice, pid));
+            }
+            pictures.insert(pid, Picture::with_fill(fill));
+            Ok(None)
+        }
+        RenderRequest::Unsupported => Err(XError::new(ErrorCode::Implementation, 0)),
+    }
+}
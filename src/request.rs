@@ -1,11 +1,12 @@
 use std::io::{Read, Write};
 
-use crate::{connection::Connection, event::Event};
+use crate::{connection::Connection, event::Event, extension::FIRST_EXTENSION_OPCODE};
 
 #[repr(C)]
 #[derive(Clone, Debug)]
 pub enum Request {
     CreateWindow {
+        depth: u8,
         window: u32,
         parent: u32,
         x: u16,
@@ -15,10 +16,12 @@ pub enum Request {
         border_width: u16,
         class: u16,
         visual: u32,
+        value_mask: u32,
         values: WindowAttributes,
     },
     ChangeWindowAttributes {
         window: u32,
+        value_mask: u32,
         values: WindowAttributes,
     },
     GetWindowAttributes {
@@ -37,8 +40,8 @@ pub enum Request {
     ReparentWindow {
         window: u32,
         parent: u32,
-        x: u16,
-        y: u16,
+        x: i16,
+        y: i16,
     },
     MapWindow {
         window: u32,
@@ -54,6 +57,7 @@ pub enum Request {
     },
     ConfigureWindow {
         window: u32,
+        value_mask: u16,
         values: ConfigureValues,
     },
     CirculateWindow {
@@ -257,8 +261,25 @@ pub enum Request {
     FreeGC {
         gc: u32,
     },
-    ClearArea,
-    CopyArea,
+    ClearArea {
+        exposures: bool,
+        window: u32,
+        x: i16,
+        y: i16,
+        width: u16,
+        height: u16,
+    },
+    CopyArea {
+        src_drawable: u32,
+        dst_drawable: u32,
+        gc: u32,
+        src_x: i16,
+        src_y: i16,
+        dst_x: i16,
+        dst_y: i16,
+        width: u16,
+        height: u16,
+    },
     CopyPlane,
     PolyPoint,
     PolyLine,
@@ -300,19 +321,43 @@ pub enum Request {
     StoreNamedColor,
     QueryColors,
     LookupColor,
-    CreateCursor,
+    CreateCursor {
+        cid: u32,
+        source: u32,
+        mask: u32,
+        foreground: [u16; 3],
+        background: [u16; 3],
+        x: u16,
+        y: u16,
+    },
     CreateGlyphCursor,
-    FreeCursor,
-    RecolorCursor,
+    FreeCursor {
+        cursor: u32,
+    },
+    RecolorCursor {
+        cursor: u32,
+        foreground: [u16; 3],
+        background: [u16; 3],
+    },
     QueryBestSize,
     QueryExtension {
         name: String,
     },
     ListExtensions,
-    SetModifierMapping,
+    SetModifierMapping {
+        keycodes_per_modifier: u8,
+        keycodes: Vec<u8>,
+    },
     GetModifierMapping,
-    ChangeKeyboardMapping,
-    GetKeyboardMapping,
+    ChangeKeyboardMapping {
+        first_keycode: u8,
+        keysyms_per_keycode: u8,
+        keysyms: Vec<u32>,
+    },
+    GetKeyboardMapping {
+        first_keycode: u8,
+        count: u8,
+    },
     ChangeKeyboardControl,
     GetKeyboardControl,
     Bell,
@@ -326,41 +371,79 @@ pub enum Request {
     ChangeHosts,
     ListHosts,
     SetAccessControl,
-    SetCloseDownMode,
-    KillClient,
+    SetCloseDownMode {
+        mode: u8,
+    },
+    KillClient {
+        resource: u32,
+    },
     NoOperation,
+    /// A request to an extension, handled through the extension registry.
+    Extension {
+        major_opcode: u8,
+        minor_opcode: u8,
+        data: Vec<u8>,
+    },
 }
 
 #[repr(C)]
 #[derive(Clone, Copy, Debug, Default)]
 pub struct ConfigureValues {
-    x: u32,
-    y: u32,
-    width: u32,
-    height: u32,
-    border_width: u32,
-    sibling: u32,
-    stack_mode: u32,
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    pub border_width: u32,
+    pub sibling: u32,
+    pub stack_mode: u32,
 }
 
 #[repr(C)]
 #[derive(Clone, Copy, Debug, Default)]
 pub struct WindowAttributes {
-    background_pixmap: u32,
-    background_pixel: u32,
-    border_pixmap: u32,
-    border_pixel: u32,
-    border_gravity: u32,
-    win_gravity: u32,
-    backing_store: u32,
-    backing_planes: u32,
-    backing_pixel: u32,
-    override_redirect: u32,
-    save_under: u32,
-    event_mask: u32,
-    do_not_propogate_mask: u32,
-    colormap: u32,
-    cursor: u32,
+    pub background_pixmap: u32,
+    pub background_pixel: u32,
+    pub border_pixmap: u32,
+    pub border_pixel: u32,
+    pub border_gravity: u32,
+    pub win_gravity: u32,
+    pub backing_store: u32,
+    pub backing_planes: u32,
+    pub backing_pixel: u32,
+    pub override_redirect: u32,
+    pub save_under: u32,
+    pub event_mask: u32,
+    pub do_not_propogate_mask: u32,
+    pub colormap: u32,
+    pub cursor: u32,
+}
+
+impl WindowAttributes {
+    /// Copies the attributes selected by `value_mask` from `values`.
+    pub fn merge(&mut self, value_mask: u32, values: &WindowAttributes) {
+        let fields = [
+            (&mut self.background_pixmap, values.background_pixmap),
+            (&mut self.background_pixel, values.background_pixel),
+            (&mut self.border_pixmap, values.border_pixmap),
+            (&mut self.border_pixel, values.border_pixel),
+            (&mut self.border_gravity, values.border_gravity),
+            (&mut self.win_gravity, values.win_gravity),
+            (&mut self.backing_store, values.backing_store),
+            (&mut self.backing_planes, values.backing_planes),
+            (&mut self.backing_pixel, values.backing_pixel),
+            (&mut self.override_redirect, values.override_redirect),
+            (&mut self.save_under, values.save_under),
+            (&mut self.event_mask, values.event_mask),
+            (&mut self.do_not_propogate_mask, values.do_not_propogate_mask),
+            (&mut self.colormap, values.colormap),
+            (&mut self.cursor, values.cursor),
+        ];
+        for (bit, (field, value)) in fields.into_iter().enumerate() {
+            if value_mask & (1 << bit) != 0 {
+                *field = value;
+            }
+        }
+    }
 }
 
 #[repr(C)]
@@ -375,7 +458,7 @@ impl<T: Read + Write> Connection<T> {
     pub fn read_request(&mut self) -> Option<Request> {
         let request_prefix = {
             let mut request_prefix_bytes = [0u8; 4];
-            self.stream.read(&mut request_prefix_bytes).unwrap();
+            self.read_bytes(&mut request_prefix_bytes)?;
             RequestPrefix {
                 opcode: request_prefix_bytes[0],
                 extra: request_prefix_bytes[1],
@@ -384,7 +467,7 @@ impl<T: Read + Write> Connection<T> {
         };
         let mut request_bytes =
             vec![0; (request_prefix.request_length as usize).saturating_sub(1) * 4];
-        self.stream.read(&mut request_bytes).unwrap();
+        self.read_bytes(&mut request_bytes)?;
         Some(match request_prefix.opcode {
             0 => {
                 return None;
@@ -402,7 +485,7 @@ impl<T: Read + Write> Connection<T> {
                 let value_mask = self.card32(&request_bytes[24..]);
                 let value_list = &self.copy8to32(&request_bytes[28..]);
                 let mut values = WindowAttributes::default();
-                for (index, value) in value_list.into_iter().enumerate() {
+                for (index, value) in value_list.iter().enumerate() {
                     let mut times = 0;
                     let mut w = 0;
                     let which = loop {
@@ -438,6 +521,7 @@ impl<T: Read + Write> Connection<T> {
                     }
                 }
                 Request::CreateWindow {
+                    depth: request_prefix.extra,
                     window,
                     parent,
                     x,
@@ -447,6 +531,7 @@ impl<T: Read + Write> Connection<T> {
                     border_width,
                     class,
                     visual,
+                    value_mask,
                     values,
                 }
             }
@@ -455,7 +540,7 @@ impl<T: Read + Write> Connection<T> {
                 let value_mask = self.card32(&request_bytes[4..]);
                 let value_list = &self.copy8to32(&request_bytes[8..]);
                 let mut values = WindowAttributes::default();
-                for (index, value) in value_list.into_iter().enumerate() {
+                for (index, value) in value_list.iter().enumerate() {
                     let mut times = 0;
                     let mut w = 0;
                     let which = loop {
@@ -490,7 +575,11 @@ impl<T: Read + Write> Connection<T> {
                         _ => panic!("invalid bit in window attribute mask"),
                     }
                 }
-                Request::ChangeWindowAttributes { window, values }
+                Request::ChangeWindowAttributes {
+                    window,
+                    value_mask,
+                    values,
+                }
             }
             3 => {
                 let window = self.card32(&request_bytes);
@@ -510,8 +599,8 @@ impl<T: Read + Write> Connection<T> {
             7 => Request::ReparentWindow {
                 window: self.card32(&request_bytes),
                 parent: self.card32(&request_bytes[4..]),
-                x: self.card16(&request_bytes[6..]),
-                y: self.card16(&request_bytes[8..]),
+                x: self.int16(&request_bytes[8..]),
+                y: self.int16(&request_bytes[10..]),
             },
             8 => Request::MapWindow {
                 window: self.card32(&request_bytes),
@@ -530,7 +619,7 @@ impl<T: Read + Write> Connection<T> {
                 let value_mask = self.card16(&request_bytes[4..]);
                 let value_list = &self.copy8to32(&request_bytes[8..]);
                 let mut values = ConfigureValues::default();
-                for (index, value) in value_list.into_iter().enumerate() {
+                for (index, value) in value_list.iter().enumerate() {
                     let mut times = 0;
                     let mut w = 0;
                     let which = loop {
@@ -557,7 +646,11 @@ impl<T: Read + Write> Connection<T> {
                         _ => panic!("invalid bit in window attribute mask"),
                     }
                 }
-                Request::ConfigureWindow { window, values }
+                Request::ConfigureWindow {
+                    window,
+                    value_mask,
+                    values,
+                }
             }
             13 => Request::CirculateWindow {
                 direction: request_prefix.extra,
@@ -650,8 +743,8 @@ impl<T: Read + Write> Connection<T> {
                 keyboard_mode: request_bytes[7],
                 confine_to: self.card32(&request_bytes[8..]),
                 cursor: self.card32(&request_bytes[12..]),
-                button: request_bytes[14],
-                modifiers: self.card16(&request_bytes[16..]),
+                button: request_bytes[16],
+                modifiers: self.card16(&request_bytes[18..]),
             },
             29 => Request::UngrabButton {
                 button: request_prefix.extra,
@@ -759,7 +852,7 @@ impl<T: Read + Write> Connection<T> {
                 let value_mask = self.card32(&request_bytes[8..]);
                 let mut value_list = [0u32; 23];
                 let values = &self.copy8to32(&request_bytes[12..]);
-                for (index, value) in values.into_iter().enumerate() {
+                for (index, value) in values.iter().enumerate() {
                     let mut times = 0;
                     let mut w = 0;
                     let which = loop {
@@ -784,6 +877,25 @@ impl<T: Read + Write> Connection<T> {
             60 => Request::FreeGC {
                 gc: self.card32(&request_bytes),
             },
+            61 => Request::ClearArea {
+                exposures: request_prefix.extra != 0,
+                window: self.card32(&request_bytes),
+                x: self.int16(&request_bytes[4..]),
+                y: self.int16(&request_bytes[6..]),
+                width: self.card16(&request_bytes[8..]),
+                height: self.card16(&request_bytes[10..]),
+            },
+            62 => Request::CopyArea {
+                src_drawable: self.card32(&request_bytes),
+                dst_drawable: self.card32(&request_bytes[4..]),
+                gc: self.card32(&request_bytes[8..]),
+                src_x: self.int16(&request_bytes[12..]),
+                src_y: self.int16(&request_bytes[14..]),
+                dst_x: self.int16(&request_bytes[16..]),
+                dst_y: self.int16(&request_bytes[18..]),
+                width: self.card16(&request_bytes[20..]),
+                height: self.card16(&request_bytes[22..]),
+            },
             72 => Request::PutImage {
                 format: request_prefix.extra,
                 drawable: self.card32(&request_bytes),
@@ -802,13 +914,64 @@ impl<T: Read + Write> Connection<T> {
                 )
                 .to_string(),
             },
+            93 => Request::CreateCursor {
+                cid: self.card32(&request_bytes),
+                source: self.card32(&request_bytes[4..]),
+                mask: self.card32(&request_bytes[8..]),
+                foreground: std::array::from_fn(|i| self.card16(&request_bytes[12 + 2 * i..])),
+                background: std::array::from_fn(|i| self.card16(&request_bytes[18 + 2 * i..])),
+                x: self.card16(&request_bytes[24..]),
+                y: self.card16(&request_bytes[26..]),
+            },
+            95 => Request::FreeCursor {
+                cursor: self.card32(&request_bytes),
+            },
+            96 => Request::RecolorCursor {
+                cursor: self.card32(&request_bytes),
+                foreground: std::array::from_fn(|i| self.card16(&request_bytes[4 + 2 * i..])),
+                background: std::array::from_fn(|i| self.card16(&request_bytes[10 + 2 * i..])),
+            },
+            99 => Request::ListExtensions,
+            100 => {
+                let mut keysyms = self.copy8to32(&request_bytes[4..]);
+                keysyms.truncate(request_prefix.extra as usize * request_bytes[1] as usize);
+                Request::ChangeKeyboardMapping {
+                    first_keycode: request_bytes[0],
+                    keysyms_per_keycode: request_bytes[1],
+                    keysyms,
+                }
+            }
+            101 => Request::GetKeyboardMapping {
+                first_keycode: request_bytes[0],
+                count: request_bytes[1],
+            },
             103 => Request::GetKeyboardControl,
             114 => Request::RotateProperties {
                 window: self.card32(&request_bytes),
                 delta: self.int16(&request_bytes[6..]),
                 properties: self.copy8to32(&request_bytes[8..]),
             },
+            118 => {
+                let mut keycodes = request_bytes.clone();
+                keycodes.truncate(request_prefix.extra as usize * 8);
+                Request::SetModifierMapping {
+                    keycodes_per_modifier: request_prefix.extra,
+                    keycodes,
+                }
+            }
+            119 => Request::GetModifierMapping,
+            112 => Request::SetCloseDownMode {
+                mode: request_prefix.extra,
+            },
+            113 => Request::KillClient {
+                resource: self.card32(&request_bytes),
+            },
             127 => Request::NoOperation,
+            major_opcode if major_opcode >= FIRST_EXTENSION_OPCODE => Request::Extension {
+                major_opcode,
+                minor_opcode: request_prefix.extra,
+                data: request_bytes,
+            },
             _ => todo!("{}", request_prefix.opcode),
         })
     }
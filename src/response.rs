@@ -1,13 +1,38 @@
 use std::io::{Read, Write};
 
-use crate::{connection::Connection, request::Request};
+use crate::{
+    connection::Connection,
+    cursor::{self, check_cursor},
+    client::{
+        broadcast_event, change_save_set, grab_server, kill_client, queue_event, set_close_down_mode,
+        ungrab_server, SaveSetMode,
+    },
+    damage::free_drawable_damages,
+    error::{ErrorCode, XError},
+    event::{Event, KEY_PRESS_MASK, KEY_RELEASE_MASK},
+    extension::{extension_by_opcode, extension_names, find_extension, ExtensionRequest},
+    grab::{
+        check_grab_arguments, check_grab_key, check_modifiers, check_pointer_event_mask, Device,
+        Grab, GrabKind, PassiveGrab, GRAB_SUCCESS,
+    },
+    image::{copy_area, put_image, Image},
+    input::{dispatch, process_pending, INPUT},
+    keyboard::{KEYMAP, MAPPING_KEYBOARD, MAPPING_MODIFIER},
+    pixmap::{Pixmap, DEFAULT_PIXMAP_FORMATS, PIXMAPS},
+    region::Rectangle,
+    request::Request,
+    screen::DEFAULT_SCREEN,
+    selection,
+    window::{self, child_towards, get_window, Window, WINDOWS},
+    xkb::{core_keysyms_changed, core_modifiers_changed, CONTROL_REPEAT_KEYS, XKB},
+};
 
 impl<T: Read + Write> Connection<T> {
     pub fn stub_response(&mut self, extra_length: u32) {
         println!("(stubbed)");
         let mut bytes_to_write = self.empty_response(extra_length,0);
         bytes_to_write.append(&mut vec![0;24 + extra_length as usize * 4]);
-        self.stream.write(&bytes_to_write).unwrap();
+        self.stream.write_all(&bytes_to_write).unwrap();
     }
 
     pub fn empty_response(&mut self, extra_length: u32, extra: u8) -> Vec<u8> {
@@ -26,20 +51,111 @@ impl<T: Read + Write> Connection<T> {
     pub fn write_response(&mut self, request: Request) {
         self.sequence_number += 1;
         match request {
-            Request::CreateWindow { .. } => {}
-            Request::ChangeWindowAttributes { .. } => {}
+            Request::CreateWindow {
+                depth,
+                window,
+                parent,
+                x,
+                y,
+                width,
+                height,
+                border_width,
+                class,
+                visual,
+                value_mask,
+                values,
+            } => {
+                let mut new_window = Window::new(window, parent);
+                new_window.x = x as i16;
+                new_window.y = y as i16;
+                new_window.width = width;
+                new_window.height = height;
+                new_window.border_width = border_width;
+                new_window.class = class;
+                new_window.depth = depth;
+                new_window.visual = visual;
+                let mut windows = WINDOWS.lock().unwrap();
+                if let Err(error) =
+                    window::create_window(&mut windows, self.client, new_window, value_mask, &values)
+                {
+                    self.write_error(error, 1);
+                }
+            }
+            Request::ChangeWindowAttributes {
+                window,
+                value_mask,
+                values,
+            } => {
+                let mut windows = WINDOWS.lock().unwrap();
+                if let Err(error) = window::change_window_attributes(
+                    &mut windows,
+                    self.client,
+                    window,
+                    value_mask,
+                    &values,
+                ) {
+                    self.write_error(error, 2);
+                }
+            }
             Request::GetWindowAttributes { .. } => {
                 self.stub_response(0);
             }
-            Request::DestroyWindow { .. } => {}
-            Request::DestroySubwindows { .. } => {}
-            Request::ChangeSaveSet { .. } => {}
-            Request::ReparentWindow { .. } => {}
-            Request::MapWindow { .. } => {}
-            Request::MapSubwindows { .. } => {}
-            Request::UnmapWindow { .. } => {}
-            Request::UnmapSubwindows { .. } => {}
-            Request::ConfigureWindow { .. } => {}
+            Request::DestroyWindow { window } => {
+                if let Err(error) = window::destroy_window(&mut WINDOWS.lock().unwrap(), window) {
+                    self.write_error(error, 4);
+                }
+            }
+            Request::DestroySubwindows { window } => {
+                if let Err(error) = window::destroy_subwindows(&mut WINDOWS.lock().unwrap(), window)
+                {
+                    self.write_error(error, 5);
+                }
+            }
+            Request::ChangeSaveSet { mode, window } => {
+                let windows = WINDOWS.lock().unwrap();
+                let result = change_save_set(&windows, self.client, mode, window, SaveSetMode::default());
+                if let Err(error) = result {
+                    self.write_error(error, 6);
+                }
+            }
+            Request::ReparentWindow { window, parent, x, y } => {
+                let mut windows = WINDOWS.lock().unwrap();
+                if let Err(error) = window::reparent_window(&mut windows, window, parent, x, y) {
+                    self.write_error(error, 7);
+                }
+            }
+            Request::MapWindow { window } => {
+                if let Err(error) = window::map_window(&mut WINDOWS.lock().unwrap(), window) {
+                    self.write_error(error, 8);
+                }
+            }
+            Request::MapSubwindows { window } => {
+                if let Err(error) = window::map_subwindows(&mut WINDOWS.lock().unwrap(), window) {
+                    self.write_error(error, 9);
+                }
+            }
+            Request::UnmapWindow { window } => {
+                if let Err(error) = window::unmap_window(&mut WINDOWS.lock().unwrap(), window) {
+                    self.write_error(error, 10);
+                }
+            }
+            Request::UnmapSubwindows { window } => {
+                if let Err(error) = window::unmap_subwindows(&mut WINDOWS.lock().unwrap(), window) {
+                    self.write_error(error, 11);
+                }
+            }
+            Request::ConfigureWindow {
+                window,
+                value_mask,
+                values,
+            } => {
+                let mut windows = WINDOWS.lock().unwrap();
+                if let Err(error) =
+                    window::configure_window(&mut windows, window, value_mask, &values)
+                {
+                    self.write_error(error, 12);
+                }
+            }
             Request::CirculateWindow { .. } => {}
             Request::GetGeometry { .. } => {
                 self.stub_response(0);
@@ -52,7 +168,7 @@ impl<T: Read + Write> Connection<T> {
                 let mut bytes_to_write = self.empty_response(0, 0);
                 bytes_to_write.append(&mut self.to_bytes_32(atom).to_vec());
                 bytes_to_write.append(&mut vec![0;20]);
-                self.stream.write(&bytes_to_write).unwrap();
+                self.stream.write_all(&bytes_to_write).unwrap();
             }
             Request::GetAtomName { .. } => {
                 self.stub_response(0);
@@ -62,12 +178,400 @@ impl<T: Read + Write> Connection<T> {
             Request::GetProperty { .. } => {
                 self.stub_response(0);
             }
-            Request::GetSelectionOwner { .. } => {
-                self.stub_response(0);
+            Request::SetSelectionOwner { owner, selection, time } => {
+                let windows = WINDOWS.lock().unwrap();
+                if let Err(error) = selection::set_selection_owner(&windows, self.client, owner, selection, time) {
+                    self.write_error(error, 22);
+                }
+            }
+            Request::GetSelectionOwner { selection } => match selection::get_selection_owner(selection) {
+                Ok(owner) => {
+                    let mut bytes_to_write = self.empty_response(0, 0);
+                    bytes_to_write.append(&mut self.to_bytes_32(owner).to_vec());
+                    bytes_to_write.append(&mut vec![0; 20]);
+                    self.stream.write_all(&bytes_to_write).unwrap();
+                }
+                Err(error) => self.write_error(error, 23),
+            },
+            Request::ConvertSelection {
+                requestor,
+                selection,
+                target,
+                property,
+                time,
+            } => {
+                let windows = WINDOWS.lock().unwrap();
+                let result =
+                    selection::convert_selection(&windows, self.client, requestor, selection, target, property, time);
+                if let Err(error) = result {
+                    self.write_error(error, 24);
+                }
+            }
+            Request::GrabPointer {
+                owner_events,
+                grab_window,
+                event_mask,
+                pointer_mode,
+                keyboard_mode,
+                confine_to,
+                cursor,
+                time,
+            } => {
+                let windows = WINDOWS.lock().unwrap();
+                let mut input = INPUT.lock().unwrap();
+                let result = check_grab_arguments(&windows, grab_window, confine_to, pointer_mode, keyboard_mode)
+                    .and_then(|()| check_pointer_event_mask(event_mask as u32))
+                    .and_then(|()| check_cursor(cursor))
+                    .map(|()| {
+                        let grab = Grab {
+                            client: self.client,
+                            window: grab_window,
+                            owner_events,
+                            event_mask: event_mask as u32,
+                            pointer_mode,
+                            keyboard_mode,
+                            confine_to,
+                            cursor,
+                            time,
+                            kind: GrabKind::Active,
+                            xi2_mask: None,
+                        };
+                        let status = input.grab_device(&windows, Device::Pointer, grab);
+                        if status == GRAB_SUCCESS && confine_to != 0 {
+                            input.confine_pointer(&windows);
+                        }
+                        process_pending(&windows, &mut input);
+                        status
+                    });
+                drop(input);
+                drop(windows);
+                match result {
+                    Ok(status) => {
+                        let mut bytes_to_write = self.empty_response(0, status);
+                        bytes_to_write.append(&mut vec![0; 24]);
+                        self.stream.write_all(&bytes_to_write).unwrap();
+                    }
+                    Err(error) => self.write_error(error, 26),
+                }
+            }
+            Request::UngrabPointer { time } => {
+                let windows = WINDOWS.lock().unwrap();
+                let mut input = INPUT.lock().unwrap();
+                input.ungrab_device(&windows, Device::Pointer, self.client, time);
+                process_pending(&windows, &mut input);
+            }
+            Request::GrabButton {
+                owner_events,
+                grab_window,
+                event_mask,
+                pointer_mode,
+                keyboard_mode,
+                confine_to,
+                cursor,
+                button,
+                modifiers,
+            } => {
+                let windows = WINDOWS.lock().unwrap();
+                let result = check_grab_arguments(&windows, grab_window, confine_to, pointer_mode, keyboard_mode)
+                    .and_then(|()| check_pointer_event_mask(event_mask as u32))
+                    .and_then(|()| check_cursor(cursor))
+                    .and_then(|()| check_modifiers(modifiers))
+                    .and_then(|()| {
+                        INPUT.lock().unwrap().grab_passive(PassiveGrab {
+                            device: Device::Pointer,
+                            client: self.client,
+                            window: grab_window,
+                            detail: button,
+                            modifiers,
+                            owner_events,
+                            event_mask: event_mask as u32,
+                            pointer_mode,
+                            keyboard_mode,
+                            confine_to,
+                            cursor,
+                            xi2_mask: None,
+                        })
+                    });
+                drop(windows);
+                if let Err(error) = result {
+                    self.write_error(error, 28);
+                }
+            }
+            Request::UngrabButton {
+                button,
+                grab_window,
+                modifiers,
+            } => {
+                let windows = WINDOWS.lock().unwrap();
+                let result = get_window(&windows, grab_window)
+                    .and_then(|_| check_modifiers(modifiers))
+                    .map(|()| {
+                        INPUT.lock().unwrap().ungrab_passive(
+                            Device::Pointer,
+                            self.client,
+                            grab_window,
+                            button,
+                            modifiers,
+                            false,
+                        )
+                    });
+                drop(windows);
+                if let Err(error) = result {
+                    self.write_error(error, 29);
+                }
+            }
+            Request::ChangeActivePointerGrab {
+                cursor,
+                time,
+                event_mask,
+            } => {
+                let windows = WINDOWS.lock().unwrap();
+                let result = check_pointer_event_mask(event_mask as u32).and_then(|()| check_cursor(cursor));
+                match result {
+                    Ok(()) => INPUT.lock().unwrap().change_active_pointer_grab(
+                        &windows,
+                        self.client,
+                        cursor,
+                        time,
+                        event_mask as u32,
+                    ),
+                    Err(error) => self.write_error(error, 30),
+                }
+            }
+            Request::GrabKeyboard {
+                owner_events,
+                grab_window,
+                time,
+                pointer_mode,
+                keyboard_mode,
+            } => {
+                let windows = WINDOWS.lock().unwrap();
+                let mut input = INPUT.lock().unwrap();
+                let result = check_grab_arguments(&windows, grab_window, 0, pointer_mode, keyboard_mode)
+                    .map(|()| {
+                        let grab = Grab {
+                            client: self.client,
+                            window: grab_window,
+                            owner_events,
+                            event_mask: KEY_PRESS_MASK | KEY_RELEASE_MASK,
+                            pointer_mode,
+                            keyboard_mode,
+                            confine_to: 0,
+                            cursor: 0,
+                            time,
+                            kind: GrabKind::Active,
+                            xi2_mask: None,
+                        };
+                        let status = input.grab_device(&windows, Device::Keyboard, grab);
+                        process_pending(&windows, &mut input);
+                        status
+                    });
+                drop(input);
+                drop(windows);
+                match result {
+                    Ok(status) => {
+                        let mut bytes_to_write = self.empty_response(0, status);
+                        bytes_to_write.append(&mut vec![0; 24]);
+                        self.stream.write_all(&bytes_to_write).unwrap();
+                    }
+                    Err(error) => self.write_error(error, 31),
+                }
+            }
+            Request::UngrabKeyboard { time } => {
+                let windows = WINDOWS.lock().unwrap();
+                let mut input = INPUT.lock().unwrap();
+                input.ungrab_device(&windows, Device::Keyboard, self.client, time);
+                process_pending(&windows, &mut input);
+            }
+            Request::GrabKey {
+                owner_events,
+                grab_window,
+                modifiers,
+                key,
+                pointer_mode,
+                keyboard_mode,
+            } => {
+                let windows = WINDOWS.lock().unwrap();
+                let result = check_grab_arguments(&windows, grab_window, 0, pointer_mode, keyboard_mode)
+                    .and_then(|()| check_grab_key(key))
+                    .and_then(|()| check_modifiers(modifiers))
+                    .and_then(|()| {
+                        INPUT.lock().unwrap().grab_passive(PassiveGrab {
+                            device: Device::Keyboard,
+                            client: self.client,
+                            window: grab_window,
+                            detail: key,
+                            modifiers,
+                            owner_events,
+                            event_mask: KEY_PRESS_MASK | KEY_RELEASE_MASK,
+                            pointer_mode,
+                            keyboard_mode,
+                            confine_to: 0,
+                            cursor: 0,
+                            xi2_mask: None,
+                        })
+                    });
+                drop(windows);
+                if let Err(error) = result {
+                    self.write_error(error, 33);
+                }
+            }
+            Request::UngrabKey {
+                key,
+                grab_window,
+                modifiers,
+            } => {
+                let windows = WINDOWS.lock().unwrap();
+                let result = get_window(&windows, grab_window)
+                    .and_then(|_| check_grab_key(key))
+                    .and_then(|()| check_modifiers(modifiers))
+                    .map(|()| {
+                        INPUT.lock().unwrap().ungrab_passive(
+                            Device::Keyboard,
+                            self.client,
+                            grab_window,
+                            key,
+                            modifiers,
+                            false,
+                        )
+                    });
+                drop(windows);
+                if let Err(error) = result {
+                    self.write_error(error, 34);
+                }
+            }
+            Request::AllowEvents { mode, time } => {
+                let windows = WINDOWS.lock().unwrap();
+                let mut input = INPUT.lock().unwrap();
+                let result = input.allow_events(&windows, self.client, mode, time);
+                if let Ok(Some((raw, event, grab_window))) = result {
+                    dispatch(&windows, &mut input, raw, event, Some(grab_window));
+                }
+                process_pending(&windows, &mut input);
+                drop(input);
+                drop(windows);
+                if let Err(error) = result {
+                    self.write_error(error, 35);
+                }
+            }
+            Request::GrabServer => grab_server(self.client),
+            Request::UngrabServer => ungrab_server(self.client),
+            Request::QueryPointer { window } => {
+                let windows = WINDOWS.lock().unwrap();
+                let mut input = INPUT.lock().unwrap();
+                input.stop_motion_hint(&windows, self.client);
+                let result = get_window(&windows, window).map(|w| {
+                    let state = input.state();
+                    let child = child_towards(&windows, window, input.pointer_window);
+                    let win_x = input.pointer_x - w.screen_rectangle.x as i16;
+                    let win_y = input.pointer_y - w.screen_rectangle.y as i16;
+                    (child, input.pointer_x, input.pointer_y, win_x, win_y, state)
+                });
+                drop(input);
+                drop(windows);
+                match result {
+                    Ok((child, root_x, root_y, win_x, win_y, state)) => {
+                        let mut bytes_to_write = self.empty_response(0, 1);
+                        bytes_to_write.append(&mut self.to_bytes_32(DEFAULT_SCREEN.root_window).to_vec());
+                        bytes_to_write.append(&mut self.to_bytes_32(child).to_vec());
+                        for value in [root_x as u16, root_y as u16, win_x as u16, win_y as u16, state] {
+                            bytes_to_write.append(&mut self.to_bytes_16(value).to_vec());
+                        }
+                        bytes_to_write.append(&mut vec![0; 6]);
+                        self.stream.write_all(&bytes_to_write).unwrap();
+                    }
+                    Err(error) => self.write_error(error, 38),
+                }
+            }
+            Request::GetMotionEvents { window, start, stop } => {
+                let windows = WINDOWS.lock().unwrap();
+                let mut input = INPUT.lock().unwrap();
+                let result = input.motion_events(&windows, window, start, stop);
+                if result.is_ok() {
+                    input.stop_motion_hint(&windows, self.client);
+                }
+                drop(input);
+                drop(windows);
+                match result {
+                    Ok(events) => {
+                        let mut bytes_to_write = self.empty_response(2 * events.len() as u32, 0);
+                        bytes_to_write.append(&mut self.to_bytes_32(events.len() as u32).to_vec());
+                        bytes_to_write.append(&mut vec![0; 20]);
+                        for (time, x, y) in events {
+                            bytes_to_write.append(&mut self.to_bytes_32(time).to_vec());
+                            bytes_to_write.append(&mut self.to_bytes_16(x as u16).to_vec());
+                            bytes_to_write.append(&mut self.to_bytes_16(y as u16).to_vec());
+                        }
+                        self.stream.write_all(&bytes_to_write).unwrap();
+                    }
+                    Err(error) => self.write_error(error, 39),
+                }
+            }
+            Request::TranslateCoordinates {
+                src_window,
+                dst_window,
+                src_x,
+                src_y,
+            } => {
+                let result =
+                    window::translate_coordinates(&WINDOWS.lock().unwrap(), src_window, dst_window, src_x, src_y);
+                match result {
+                    Ok((child, dst_x, dst_y)) => {
+                        let mut bytes_to_write = self.empty_response(0, 1);
+                        bytes_to_write.append(&mut self.to_bytes_32(child).to_vec());
+                        bytes_to_write.append(&mut self.to_bytes_16(dst_x as u16).to_vec());
+                        bytes_to_write.append(&mut self.to_bytes_16(dst_y as u16).to_vec());
+                        bytes_to_write.append(&mut vec![0; 16]);
+                        self.stream.write_all(&bytes_to_write).unwrap();
+                    }
+                    Err(error) => self.write_error(error, 40),
+                }
+            }
+            Request::WarpPointer {
+                src_window,
+                dst_window,
+                src_x,
+                src_y,
+                src_width,
+                src_height,
+                dst_x,
+                dst_y,
+            } => {
+                let windows = WINDOWS.lock().unwrap();
+                let src = Rectangle::new(src_x as i32, src_y as i32, src_width as i32, src_height as i32);
+                let result =
+                    INPUT.lock().unwrap().warp_pointer(&windows, src_window, src, dst_window, dst_x, dst_y);
+                drop(windows);
+                if let Err(error) = result {
+                    self.write_error(error, 41);
+                }
+            }
+            Request::SetInputFocus {
+                revert_to,
+                focus,
+                time,
+            } => {
+                let windows = WINDOWS.lock().unwrap();
+                let result = INPUT.lock().unwrap().set_focus(&windows, focus, revert_to, time);
+                drop(windows);
+                if let Err(error) = result {
+                    self.write_error(error, 42);
+                }
             }
-            Request::GrabServer => {}
             Request::GetInputFocus => {
-                self.stub_response(0);
+                let input = INPUT.lock().unwrap();
+                let (focus, revert_to) = (input.focus, input.revert_to);
+                drop(input);
+                let mut bytes_to_write = self.empty_response(0, revert_to);
+                bytes_to_write.append(&mut self.to_bytes_32(focus.id()).to_vec());
+                bytes_to_write.append(&mut vec![0; 20]);
+                self.stream.write_all(&bytes_to_write).unwrap();
+            }
+            Request::QueryKeymap => {
+                let keys = INPUT.lock().unwrap().keys;
+                let mut bytes_to_write = self.empty_response(2, 0);
+                bytes_to_write.append(&mut keys.to_vec());
+                self.stream.write_all(&bytes_to_write).unwrap();
             }
             Request::OpenFont { .. } => {}
             Request::QueryFont { .. } => {
@@ -76,19 +580,317 @@ impl<T: Read + Write> Connection<T> {
             Request::ListFonts { .. } => {
                 self.stub_response(0);
             }
-            Request::CreatePixmap { .. } => {}
-            Request::FreePixmap { .. } => {}
+            Request::CreatePixmap {
+                depth,
+                pid,
+                drawable,
+                width,
+                height,
+            } => {
+                let windows = WINDOWS.lock().unwrap();
+                let mut pixmaps = PIXMAPS.lock().unwrap();
+                let error = if windows.contains_key(&pid) || pixmaps.contains_key(&pid) {
+                    Some(XError::new(ErrorCode::IDChoice, pid))
+                } else if !windows.contains_key(&drawable) && !pixmaps.contains_key(&drawable) {
+                    Some(XError::new(ErrorCode::Drawable, drawable))
+                } else if width == 0 || height == 0 {
+                    Some(XError::new(ErrorCode::Value, 0))
+                } else if !DEFAULT_PIXMAP_FORMATS.iter().any(|f| f.depth == depth) {
+                    Some(XError::new(ErrorCode::Value, depth as u32))
+                } else {
+                    pixmaps.insert(pid, Pixmap::new(width, height, depth));
+                    None
+                };
+                drop(pixmaps);
+                drop(windows);
+                if let Some(error) = error {
+                    self.write_error(error, 53);
+                }
+            }
+            Request::FreePixmap { pixmap } => {
+                if PIXMAPS.lock().unwrap().remove(&pixmap).is_none() {
+                    self.write_error(XError::new(ErrorCode::Pixmap, pixmap), 54);
+                } else {
+                    free_drawable_damages(pixmap);
+                }
+            }
             Request::CreateGC { .. } => {}
             Request::FreeGC { .. } => {}
-            Request::PutImage { .. } => {}
-            Request::QueryExtension { .. } => {
-                self.stub_response(0);
+            Request::ClearArea {
+                exposures,
+                window,
+                x,
+                y,
+                width,
+                height,
+            } => {
+                let rectangle = Rectangle::new(x as i32, y as i32, width as i32, height as i32);
+                if let Err(error) =
+                    window::clear_area(&WINDOWS.lock().unwrap(), window, rectangle, exposures)
+                {
+                    self.write_error(error, 61);
+                }
+            }
+            Request::CopyArea {
+                src_drawable,
+                dst_drawable,
+                src_x,
+                src_y,
+                dst_x,
+                dst_y,
+                width,
+                height,
+                ..
+            } => {
+                let source = Rectangle::new(src_x as i32, src_y as i32, width as i32, height as i32);
+                let windows = WINDOWS.lock().unwrap();
+                match copy_area(&windows, src_drawable, dst_drawable, source, dst_x, dst_y) {
+                    Ok(exposed) if exposed.is_empty() => {
+                        let event = Event::NoExposure {
+                            drawable: dst_drawable,
+                            minor_opcode: 0,
+                            major_opcode: 62,
+                        };
+                        queue_event(self.client, event);
+                    }
+                    Ok(exposed) => {
+                        let count = exposed.rectangles.len();
+                        for (index, rectangle) in exposed.rectangles.iter().enumerate() {
+                            let event = Event::GraphicsExposure {
+                                drawable: dst_drawable,
+                                x: rectangle.x as u16,
+                                y: rectangle.y as u16,
+                                width: rectangle.width as u16,
+                                height: rectangle.height as u16,
+                                minor_opcode: 0,
+                                count: (count - index - 1) as u16,
+                                major_opcode: 62,
+                            };
+                            queue_event(self.client, event);
+                        }
+                    }
+                    Err(error) => self.write_error(error, 62),
+                }
+            }
+            Request::PutImage {
+                format,
+                drawable,
+                width,
+                height,
+                dstx,
+                dsty,
+                leftpad,
+                depth,
+                data,
+                ..
+            } => {
+                let image = Image {
+                    format,
+                    depth,
+                    width,
+                    height,
+                    left_pad: leftpad,
+                    data: &data,
+                };
+                let source = Rectangle::new(0, 0, width as i32, height as i32);
+                if let Err(error) = put_image(&WINDOWS.lock().unwrap(), drawable, &image, source, dstx, dsty) {
+                    self.write_error(error, 72);
+                }
+            }
+            Request::CreateCursor {
+                cid,
+                source,
+                mask,
+                foreground,
+                background,
+                x,
+                y,
+            } => {
+                if let Err(error) = cursor::create_cursor(cid, source, mask, foreground, background, x, y) {
+                    self.write_error(error, 93);
+                }
+            }
+            Request::FreeCursor { cursor } => {
+                let windows = WINDOWS.lock().unwrap();
+                let input = INPUT.lock().unwrap();
+                if let Err(error) = cursor::free_cursor(&windows, &input, cursor) {
+                    self.write_error(error, 95);
+                }
+            }
+            Request::RecolorCursor {
+                cursor,
+                foreground,
+                background,
+            } => {
+                if let Err(error) = cursor::recolor_cursor(cursor, foreground, background) {
+                    self.write_error(error, 96);
+                }
+            }
+            Request::QueryExtension { name } => {
+                let mut bytes_to_write = self.empty_response(0, 0);
+                match find_extension(&name) {
+                    Some(extension) => bytes_to_write.append(&mut vec![
+                        1,
+                        extension.major_opcode,
+                        extension.first_event,
+                        extension.first_error,
+                    ]),
+                    None => bytes_to_write.append(&mut vec![0; 4]),
+                }
+                bytes_to_write.append(&mut vec![0; 20]);
+                self.stream.write_all(&bytes_to_write).unwrap();
+            }
+            Request::ListExtensions => {
+                let names = extension_names();
+                let mut list = vec![];
+                for name in &names {
+                    list.push(name.len() as u8);
+                    list.extend(name.bytes());
+                }
+                list.resize(list.len().next_multiple_of(4), 0);
+                let mut bytes_to_write = self.empty_response(list.len() as u32 / 4, names.len() as u8);
+                bytes_to_write.append(&mut vec![0; 24]);
+                bytes_to_write.append(&mut list);
+                self.stream.write_all(&bytes_to_write).unwrap();
+            }
+            Request::SetModifierMapping {
+                keycodes_per_modifier,
+                keycodes,
+            } => {
+                let input = INPUT.lock().unwrap();
+                let mut keymap = KEYMAP.lock().unwrap();
+                let busy = keymap
+                    .modifiers
+                    .iter()
+                    .chain(keycodes.iter())
+                    .any(|keycode| *keycode != 0 && input.is_key_down(*keycode));
+                let result = if busy {
+                    Ok(false)
+                } else {
+                    keymap.set_modifiers(keycodes_per_modifier, &keycodes).map(|()| {
+                        core_modifiers_changed(&keymap);
+                        true
+                    })
+                };
+                drop(keymap);
+                drop(input);
+                match result {
+                    Ok(false) => {
+                        let mut bytes_to_write = self.empty_response(0, 1);
+                        bytes_to_write.append(&mut vec![0; 24]);
+                        self.stream.write_all(&bytes_to_write).unwrap();
+                    }
+                    Ok(true) => {
+                        let mut bytes_to_write = self.empty_response(0, 0);
+                        bytes_to_write.append(&mut vec![0; 24]);
+                        self.stream.write_all(&bytes_to_write).unwrap();
+                        broadcast_event(Event::MappingNotify {
+                            request: MAPPING_MODIFIER,
+                            first_keycode: 0,
+                            count: 0,
+                        });
+                    }
+                    Err(error) => self.write_error(error, 118),
+                }
+            }
+            Request::GetModifierMapping => {
+                let keymap = KEYMAP.lock().unwrap().clone();
+                let mut bytes_to_write =
+                    self.empty_response(keymap.modifiers.len() as u32 / 4, keymap.keycodes_per_modifier);
+                bytes_to_write.append(&mut vec![0; 24]);
+                bytes_to_write.append(&mut keymap.modifiers.clone());
+                self.stream.write_all(&bytes_to_write).unwrap();
+            }
+            Request::ChangeKeyboardMapping {
+                first_keycode,
+                keysyms_per_keycode,
+                keysyms,
+            } => {
+                let mut keymap = KEYMAP.lock().unwrap();
+                let result = keymap.change_keysyms(first_keycode, keysyms_per_keycode, &keysyms);
+                if let Ok(count) = result {
+                    core_keysyms_changed(&keymap, first_keycode, count);
+                }
+                drop(keymap);
+                match result {
+                    Ok(count) => broadcast_event(Event::MappingNotify {
+                        request: MAPPING_KEYBOARD,
+                        first_keycode,
+                        count,
+                    }),
+                    Err(error) => self.write_error(error, 100),
+                }
+            }
+            Request::GetKeyboardMapping {
+                first_keycode,
+                count,
+            } => {
+                let keymap = KEYMAP.lock().unwrap();
+                let keysyms_per_keycode = keymap.keysyms_per_keycode;
+                let result = keymap.get_keysyms(first_keycode, count);
+                drop(keymap);
+                match result {
+                    Ok(keysyms) => {
+                        let mut bytes_to_write =
+                            self.empty_response(keysyms.len() as u32, keysyms_per_keycode);
+                        bytes_to_write.append(&mut vec![0; 24]);
+                        for keysym in keysyms {
+                            bytes_to_write.append(&mut self.to_bytes_32(keysym).to_vec());
+                        }
+                        self.stream.write_all(&bytes_to_write).unwrap();
+                    }
+                    Err(error) => self.write_error(error, 101),
+                }
             }
             Request::GetKeyboardControl => {
-                self.stub_response(5);
+                let xkb = XKB.lock().unwrap();
+                let global_auto_repeat = (xkb.enabled_controls & CONTROL_REPEAT_KEYS != 0) as u8;
+                let mut bytes_to_write = self.empty_response(5, global_auto_repeat);
+                bytes_to_write.append(&mut self.to_bytes_32(xkb.indicator_state()).to_vec());
+                // Key click percent and bell percent.
+                bytes_to_write.extend([0, 50]);
+                bytes_to_write.append(&mut self.to_bytes_16(400).to_vec());
+                bytes_to_write.append(&mut self.to_bytes_16(100).to_vec());
+                bytes_to_write.extend([0; 2]);
+                bytes_to_write.extend(xkb.per_key_repeat());
+                drop(xkb);
+                self.stream.write_all(&bytes_to_write).unwrap();
+            }
+            Request::SetCloseDownMode { mode } => {
+                if let Err(error) = set_close_down_mode(self.client, mode) {
+                    self.write_error(error, 112);
+                }
+            }
+            Request::KillClient { resource } => {
+                if let Err(error) = kill_client(resource) {
+                    self.write_error(error, 113);
+                }
             }
             Request::NoOperation => {}
+            Request::Extension {
+                major_opcode,
+                minor_opcode,
+                data,
+            } => {
+                let request = ExtensionRequest {
+                    client: self.client,
+                    endianness: self.endianness.clone(),
+                    sequence_number: self.sequence_number,
+                    minor_opcode,
+                    data: &data,
+                };
+                let result = match extension_by_opcode(major_opcode) {
+                    Some(extension) => (extension.handler)(&request),
+                    None => Err(XError::new(ErrorCode::Request, 0)),
+                };
+                match result {
+                    Ok(Some(reply)) => self.stream.write_all(&reply).unwrap(),
+                    Ok(None) => {}
+                    Err(error) => self.write_extension_error(error, major_opcode, minor_opcode as u16),
+                }
+            }
             _ => todo!("response"),
         }
+        self.flush_events();
     }
 }
@@ -1,3 +1,62 @@
+use std::sync::Mutex;
+
+use crate::pixmap::{PixelData, Pixmap};
+
+pub static FRAMEBUFFER: Mutex<Pixmap> = Mutex::new(Pixmap {
+    width: 0,
+    height: 0,
+    depth: 0,
+    data: PixelData::Owned(Vec::new()),
+});
+
+/// The screen as it is when the server starts.
+pub const DEFAULT_SCREEN: Screen = Screen {
+    root_window: 0x100,
+    default_colormap: 1,
+    white_pixel: 1,
+    black_pixel: 0,
+    current_input_masks: 0,
+    width_px: 1920,
+    height_px: 1080,
+    width_mm: 192,
+    height_mm: 108,
+    min_installed_maps: 1,
+    max_installed_maps: 1,
+    root_visual: 1,
+    backing_stores: 0,
+    save_unders: 0,
+    root_depth: 1,
+    num_depths: 1,
+};
+
+/// The screen's current size in pixels and millimetres, which RANDR can change.
+#[derive(Clone, Copy, Debug)]
+pub struct ScreenSize {
+    pub width: u16,
+    pub height: u16,
+    pub mm_width: u16,
+    pub mm_height: u16,
+}
+
+pub static SCREEN_SIZE: Mutex<ScreenSize> = Mutex::new(ScreenSize {
+    width: DEFAULT_SCREEN.width_px,
+    height: DEFAULT_SCREEN.height_px,
+    mm_width: DEFAULT_SCREEN.width_mm,
+    mm_height: DEFAULT_SCREEN.height_mm,
+});
+
+/// The only visual, TrueColor with the root window's depth.
+pub static DEFAULT_VISUAL: Visual = Visual {
+    visual_id: 1,
+    class: 4,
+    bits_per_rgb_val: 32,
+    colormap_entries: 256,
+    red_mask: 0xFF,
+    green_mask: 0xFF00,
+    blue_mask: 0xFF0000,
+    pad0: 0,
+};
+
 #[repr(C)]
 #[derive(Clone, Copy, Default, Debug)]
 pub struct Screen {
@@ -0,0 +1,155 @@
+use std::{collections::BTreeMap, sync::Mutex};
+
+use crate::{
+    atom::atom_exists,
+    client::queue_event,
+    error::{ErrorCode, XError},
+    event::Event,
+    time,
+    window::{get_window, Window},
+    xfixes::{
+        selection_notify, SELECTION_CLIENT_CLOSE_NOTIFY, SELECTION_WINDOW_DESTROY_NOTIFY,
+        SET_SELECTION_OWNER_NOTIFY,
+    },
+};
+
+/// Selection ownership by selection atom. Lock after `WINDOWS`.
+pub static SELECTIONS: Mutex<BTreeMap<u32, Selection>> = Mutex::new(BTreeMap::new());
+
+#[derive(Clone, Copy, Debug)]
+pub struct Selection {
+    /// The owner window, or 0 if the selection has no owner.
+    pub window: u32,
+    pub client: u32,
+    pub last_change_time: u32,
+}
+
+fn check_atom(atom: u32) -> Result<(), XError> {
+    match atom_exists(atom) {
+        true => Ok(()),
+        false => Err(XError::new(ErrorCode::Atom, atom)),
+    }
+}
+
+/// Implements SetSelectionOwner. Requests with a time earlier than the last change or
+/// later than the server time are ignored.
+pub fn set_selection_owner(
+    windows: &BTreeMap<u32, Window>,
+    client: u32,
+    owner: u32,
+    selection: u32,
+    time: u32,
+) -> Result<(), XError> {
+    if owner != 0 {
+        get_window(windows, owner)?;
+    }
+    check_atom(selection)?;
+    let mut selections = SELECTIONS.lock().unwrap();
+    let previous = selections.get(&selection).copied();
+    let Some(time) = time::check(time, previous.map_or(0, |previous| previous.last_change_time)) else {
+        return Ok(());
+    };
+    if let Some(previous) = previous.filter(|previous| previous.window != 0) {
+        if owner == 0 || previous.client != client {
+            queue_event(
+                previous.client,
+                Event::SelectionClear {
+                    time,
+                    owner: previous.window,
+                    selection,
+                },
+            );
+        }
+    }
+    selections.insert(
+        selection,
+        Selection {
+            window: owner,
+            client: if owner == 0 { 0 } else { client },
+            last_change_time: time,
+        },
+    );
+    selection_notify(windows, SET_SELECTION_OWNER_NOTIFY, selection, owner, time);
+    Ok(())
+}
+
+/// Implements GetSelectionOwner.
+pub fn get_selection_owner(selection: u32) -> Result<u32, XError> {
+    check_atom(selection)?;
+    Ok(SELECTIONS
+        .lock()
+        .unwrap()
+        .get(&selection)
+        .map_or(0, |selection| selection.window))
+}
+
+/// Implements ConvertSelection: asks the owner to convert the selection, or tells the
+/// requesting client there is nothing to convert.
+pub fn convert_selection(
+    windows: &BTreeMap<u32, Window>,
+    client: u32,
+    requestor: u32,
+    selection: u32,
+    target: u32,
+    property: u32,
+    time: u32,
+) -> Result<(), XError> {
+    get_window(windows, requestor)?;
+    check_atom(selection)?;
+    check_atom(target)?;
+    if property != 0 {
+        check_atom(property)?;
+    }
+    let owner = SELECTIONS
+        .lock()
+        .unwrap()
+        .get(&selection)
+        .copied()
+        .filter(|owner| owner.window != 0);
+    match owner {
+        Some(owner) => queue_event(
+            owner.client,
+            Event::SelectionRequest {
+                time,
+                owner: owner.window,
+                requestor,
+                selection,
+                target,
+                property,
+            },
+        ),
+        None => queue_event(
+            client,
+            Event::SelectionNotify {
+                time,
+                requestor,
+                selection,
+                target,
+                property: 0,
+            },
+        ),
+    }
+    Ok(())
+}
+
+/// Gives up the selections owned by a disconnecting client.
+pub fn release_client_selections(windows: &BTreeMap<u32, Window>, client: u32) {
+    for (atom, selection) in SELECTIONS.lock().unwrap().iter_mut() {
+        if selection.window != 0 && selection.client == client {
+            selection.window = 0;
+            selection.client = 0;
+            selection_notify(windows, SELECTION_CLIENT_CLOSE_NOTIFY, *atom, 0, selection.last_change_time);
+        }
+    }
+}
+
+/// Gives up selections owned by a destroyed window, keeping their last-change times.
+pub fn release_window_selections(windows: &BTreeMap<u32, Window>, window: u32) {
+    for (atom, selection) in SELECTIONS.lock().unwrap().iter_mut() {
+        if selection.window == window {
+            selection.window = 0;
+            selection.client = 0;
+            selection_notify(windows, SELECTION_WINDOW_DESTROY_NOTIFY, *atom, 0, selection.last_change_time);
+        }
+    }
+}
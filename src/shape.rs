@@ -0,0 +1,445 @@
+//! The SHAPE extension, version 1.1: non-rectangular bounding, clip and input shapes.
+//! Shapes are kept relative to the window's origin and combined with the default
+//! rectangular shapes whenever the window tree is validated.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::{
+    client::queue_event,
+    error::{ErrorCode, XError},
+    event::Event,
+    extension::ExtensionRequest,
+    pixmap::bitmap_region,
+    region::{Rectangle, Region},
+    time,
+    window::{get_window, validate, Window, WINDOWS},
+};
+
+pub static SHAPE_NAME: &str = "SHAPE";
+pub const SHAPE_MAJOR_VERSION: u16 = 1;
+pub const SHAPE_MINOR_VERSION: u16 = 1;
+
+pub const SHAPE_BOUNDING: u8 = 0;
+pub const SHAPE_CLIP: u8 = 1;
+pub const SHAPE_INPUT: u8 = 2;
+
+pub const SHAPE_SET: u8 = 0;
+pub const SHAPE_UNION: u8 = 1;
+pub const SHAPE_INTERSECT: u8 = 2;
+pub const SHAPE_SUBTRACT: u8 = 3;
+pub const SHAPE_INVERT: u8 = 4;
+
+/// The YXBanded ordering, the strictest a client can claim for its rectangles.
+const YX_BANDED: u8 = 3;
+
+/// The shapes a client set on a window, `None` meaning the default rectangular shape.
+#[derive(Clone, Debug, Default)]
+pub struct Shape {
+    pub bounding: Option<Region>,
+    pub clip: Option<Region>,
+    pub input: Option<Region>,
+    /// Clients that selected ShapeNotify events on the window.
+    pub selected: BTreeSet<u32>,
+}
+
+impl Shape {
+    fn get(&self, kind: u8) -> &Option<Region> {
+        match kind {
+            SHAPE_BOUNDING => &self.bounding,
+            SHAPE_CLIP => &self.clip,
+            _ => &self.input,
+        }
+    }
+
+    fn get_mut(&mut self, kind: u8) -> &mut Option<Region> {
+        match kind {
+            SHAPE_BOUNDING => &mut self.bounding,
+            SHAPE_CLIP => &mut self.clip,
+            _ => &mut self.input,
+        }
+    }
+}
+
+impl Window {
+    /// The default shape of `kind`, relative to the window's origin: the window and its
+    /// border for the bounding and input shapes, the interior for the clip shape.
+    fn default_shape(&self, kind: u8) -> Rectangle {
+        let border_width = self.border_width as i32;
+        match kind {
+            SHAPE_CLIP => Rectangle::new(0, 0, self.width as i32, self.height as i32),
+            _ => Rectangle::new(
+                -border_width,
+                -border_width,
+                self.width as i32 + 2 * border_width,
+                self.height as i32 + 2 * border_width,
+            ),
+        }
+    }
+
+    /// The shape of `kind` as the client set it, or the default shape.
+    pub fn shape_or_default(&self, kind: u8) -> Region {
+        match self.shape.get(kind) {
+            Some(region) => region.clone(),
+            None => Region::from_rectangle(self.default_shape(kind)),
+        }
+    }
+
+    /// The extents of the client's shape of `kind`, or the default shape.
+    fn shape_extents(&self, kind: u8) -> Rectangle {
+        match self.shape.get(kind) {
+            Some(region) => region.extents(),
+            None => self.default_shape(kind),
+        }
+    }
+
+    /// The effective shape of `kind`: the client's shape cut down to the default shape,
+    /// with the clip and input shapes also limited to the bounding shape. The result is
+    /// in screen coordinates.
+    fn effective_shape(&self, kind: u8) -> Region {
+        let mut region = Region::from_rectangle(self.default_shape(SHAPE_BOUNDING));
+        if let Some(bounding) = &self.shape.bounding {
+            region = region.intersect(bounding);
+        }
+        if kind != SHAPE_BOUNDING {
+            region = region.intersect_rectangle(&self.default_shape(kind));
+            if let Some(shape) = self.shape.get(kind) {
+                region = region.intersect(shape);
+            }
+        }
+        region.translate(self.screen_rectangle.x, self.screen_rectangle.y)
+    }
+
+    /// The part of the screen the window and its border cover, before clipping.
+    pub fn bounding_region(&self) -> Region {
+        self.effective_shape(SHAPE_BOUNDING)
+    }
+
+    /// The part of the window's interior that the window and its children may draw to.
+    pub fn clip_region(&self) -> Region {
+        self.effective_shape(SHAPE_CLIP)
+    }
+
+    /// Whether the root coordinates (`x`, `y`) fall within the window's input shape.
+    pub fn accepts_input_at(&self, x: i32, y: i32) -> bool {
+        self.effective_shape(SHAPE_INPUT).contains(x, y)
+    }
+}
+
+pub fn region_from_rectangles(rectangles: &[Rectangle]) -> Region {
+    rectangles
+        .iter()
+        .fold(Region::new(), |region, r| region.union(&Region::from_rectangle(*r)))
+}
+
+fn check_kind(kind: u8) -> Result<(), XError> {
+    if kind > SHAPE_INPUT {
+        return Err(XError::new(ErrorCode::Value, kind as u32));
+    }
+    Ok(())
+}
+
+/// Applies `operation` to the window's shape of `kind` with `source`, given relative to
+/// the window's origin. A `None` source removes the shape when setting and is otherwise
+/// ignored.
+pub fn combine(
+    windows: &mut BTreeMap<u32, Window>,
+    id: u32,
+    operation: u8,
+    kind: u8,
+    source: Option<Region>,
+) -> Result<(), XError> {
+    check_kind(kind)?;
+    if operation > SHAPE_INVERT {
+        return Err(XError::new(ErrorCode::Value, operation as u32));
+    }
+    let window = windows.get_mut(&id).ok_or(XError::new(ErrorCode::Window, id))?;
+    let result = match (operation, source) {
+        (SHAPE_SET, source) => source,
+        (_, None) => return Ok(()),
+        (operation, Some(source)) => {
+            let destination = window.shape_or_default(kind);
+            Some(match operation {
+                SHAPE_UNION => destination.union(&source),
+                SHAPE_INTERSECT => destination.intersect(&source),
+                SHAPE_SUBTRACT => destination.subtract(&source),
+                _ => source.subtract(&destination),
+            })
+        }
+    };
+    *window.shape.get_mut(kind) = result;
+    shape_changed(windows, id, kind);
+    Ok(())
+}
+
+/// Revalidates the tree after a shape change and sends ShapeNotify.
+fn shape_changed(windows: &mut BTreeMap<u32, Window>, id: u32, kind: u8) {
+    validate(windows);
+    let window = &windows[&id];
+    let extents = window.shape_extents(kind);
+    let event = Event::ShapeNotify {
+        kind,
+        window: id,
+        x: extents.x as i16,
+        y: extents.y as i16,
+        width: extents.width as u16,
+        height: extents.height as u16,
+        time: time::now(),
+        shaped: window.shape.get(kind).is_some(),
+    };
+    for client in &window.shape.selected {
+        queue_event(*client, event.clone());
+    }
+}
+
+#[derive(Debug)]
+pub enum ShapeRequest {
+    QueryVersion,
+    Rectangles {
+        operation: u8,
+        kind: u8,
+        ordering: u8,
+        window: u32,
+        x_offset: i16,
+        y_offset: i16,
+        rectangles: Vec<Rectangle>,
+    },
+    Mask {
+        operation: u8,
+        kind: u8,
+        window: u32,
+        x_offset: i16,
+        y_offset: i16,
+        bitmap: u32,
+    },
+    Combine {
+        operation: u8,
+        kind: u8,
+        source_kind: u8,
+        window: u32,
+        x_offset: i16,
+        y_offset: i16,
+        source_window: u32,
+    },
+    Offset {
+        kind: u8,
+        window: u32,
+        x_offset: i16,
+        y_offset: i16,
+    },
+    QueryExtents {
+        window: u32,
+    },
+    SelectInput {
+        window: u32,
+        enable: bool,
+    },
+    InputSelected {
+        window: u32,
+    },
+    GetRectangles {
+        window: u32,
+        kind: u8,
+    },
+}
+
+fn read_shape_request(request: &ExtensionRequest) -> Result<ShapeRequest, XError> {
+    let data = request.data;
+    let minimum_length = match request.minor_opcode {
+        0 => 0,
+        1 | 4 => 12,
+        2 | 3 => 16,
+        5 | 7 => 4,
+        6 | 8 => 8,
+        minor_opcode => return Err(XError::new(ErrorCode::Request, minor_opcode as u32)),
+    };
+    if data.len() < minimum_length {
+        return Err(XError::new(ErrorCode::Length, 0));
+    }
+    Ok(match request.minor_opcode {
+        0 => ShapeRequest::QueryVersion,
+        1 => ShapeRequest::Rectangles {
+            operation: data[0],
+            kind: data[1],
+            ordering: data[2],
+            window: request.card32(&data[4..]),
+            x_offset: request.int16(&data[8..]),
+            y_offset: request.int16(&data[10..]),
+            rectangles: data[12..]
+                .chunks_exact(8)
+                .map(|r| {
+                    Rectangle::new(
+                        request.int16(r) as i32,
+                        request.int16(&r[2..]) as i32,
+                        request.card16(&r[4..]) as i32,
+                        request.card16(&r[6..]) as i32,
+                    )
+                })
+                .collect(),
+        },
+        2 => ShapeRequest::Mask {
+            operation: data[0],
+            kind: data[1],
+            window: request.card32(&data[4..]),
+            x_offset: request.int16(&data[8..]),
+            y_offset: request.int16(&data[10..]),
+            bitmap: request.card32(&data[12..]),
+        },
+        3 => ShapeRequest::Combine {
+            operation: data[0],
+            kind: data[1],
+            source_kind: data[2],
+            window: request.card32(&data[4..]),
+            x_offset: request.int16(&data[8..]),
+            y_offset: request.int16(&data[10..]),
+            source_window: request.card32(&data[12..]),
+        },
+        4 => ShapeRequest::Offset {
+            kind: data[0],
+            window: request.card32(&data[4..]),
+            x_offset: request.int16(&data[8..]),
+            y_offset: request.int16(&data[10..]),
+        },
+        5 => ShapeRequest::QueryExtents {
+            window: request.card32(data),
+        },
+        6 => ShapeRequest::SelectInput {
+            window: request.card32(data),
+            enable: data[4] != 0,
+        },
+        7 => ShapeRequest::InputSelected {
+            window: request.card32(data),
+        },
+        _ => ShapeRequest::GetRectangles {
+            window: request.card32(data),
+            kind: data[4],
+        },
+    })
+}
+
+pub fn handle_shape_request(request: &ExtensionRequest) -> Result<Option<Vec<u8>>, XError> {
+    let shape_request = read_shape_request(request)?;
+    let mut windows = WINDOWS.lock().unwrap();
+    match shape_request {
+        ShapeRequest::QueryVersion => {
+            let mut body = request.to_bytes_16(SHAPE_MAJOR_VERSION).to_vec();
+            body.extend(request.to_bytes_16(SHAPE_MINOR_VERSION));
+            Ok(Some(request.reply(0, body)))
+        }
+        ShapeRequest::Rectangles {
+            operation,
+            kind,
+            ordering,
+            window,
+            x_offset,
+            y_offset,
+            rectangles,
+        } => {
+            if ordering > YX_BANDED {
+                return Err(XError::new(ErrorCode::Value, ordering as u32));
+            }
+            get_window(&windows, window)?;
+            let source = region_from_rectangles(&rectangles).translate(x_offset as i32, y_offset as i32);
+            combine(&mut windows, window, operation, kind, Some(source))?;
+            Ok(None)
+        }
+        ShapeRequest::Mask {
+            operation,
+            kind,
+            window,
+            x_offset,
+            y_offset,
+            bitmap,
+        } => {
+            get_window(&windows, window)?;
+            let source = match bitmap {
+                0 => None,
+                bitmap => Some(bitmap_region(bitmap)?.translate(x_offset as i32, y_offset as i32)),
+            };
+            combine(&mut windows, window, operation, kind, source)?;
+            Ok(None)
+        }
+        ShapeRequest::Combine {
+            operation,
+            kind,
+            source_kind,
+            window,
+            x_offset,
+            y_offset,
+            source_window,
+        } => {
+            get_window(&windows, window)?;
+            check_kind(source_kind)?;
+            let source = get_window(&windows, source_window)?
+                .shape_or_default(source_kind)
+                .translate(x_offset as i32, y_offset as i32);
+            combine(&mut windows, window, operation, kind, Some(source))?;
+            Ok(None)
+        }
+        ShapeRequest::Offset {
+            kind,
+            window,
+            x_offset,
+            y_offset,
+        } => {
+            check_kind(kind)?;
+            let shape = windows
+                .get_mut(&window)
+                .ok_or(XError::new(ErrorCode::Window, window))?
+                .shape
+                .get_mut(kind);
+            if let Some(region) = shape {
+                *region = region.translate(x_offset as i32, y_offset as i32);
+            }
+            shape_changed(&mut windows, window, kind);
+            Ok(None)
+        }
+        ShapeRequest::QueryExtents { window } => {
+            let window = get_window(&windows, window)?;
+            let mut body = vec![
+                window.shape.bounding.is_some() as u8,
+                window.shape.clip.is_some() as u8,
+                0,
+                0,
+            ];
+            for kind in [SHAPE_BOUNDING, SHAPE_CLIP] {
+                let extents = window.shape_extents(kind);
+                body.extend(request.to_bytes_16(extents.x as u16));
+                body.extend(request.to_bytes_16(extents.y as u16));
+                body.extend(request.to_bytes_16(extents.width as u16));
+                body.extend(request.to_bytes_16(extents.height as u16));
+            }
+            Ok(Some(request.reply(0, body)))
+        }
+        ShapeRequest::SelectInput { window, enable } => {
+            let selected = &mut windows
+                .get_mut(&window)
+                .ok_or(XError::new(ErrorCode::Window, window))?
+                .shape
+                .selected;
+            if enable {
+                selected.insert(request.client);
+            } else {
+                selected.remove(&request.client);
+            }
+            Ok(None)
+        }
+        ShapeRequest::InputSelected { window } => {
+            let enabled = get_window(&windows, window)?.shape.selected.contains(&request.client);
+            Ok(Some(request.reply(enabled as u8, vec![])))
+        }
+        ShapeRequest::GetRectangles { window, kind } => {
+            check_kind(kind)?;
+            let region = get_window(&windows, window)?.shape_or_default(kind);
+            let mut body = request.to_bytes_32(region.rectangles.len() as u32).to_vec();
+            body.resize(24, 0);
+            for r in &region.rectangles {
+                body.extend(request.to_bytes_16(r.x as u16));
+                body.extend(request.to_bytes_16(r.y as u16));
+                body.extend(request.to_bytes_16(r.width as u16));
+                body.extend(request.to_bytes_16(r.height as u16));
+            }
+            // The rectangles of a region never overlap but aren't banded.
+            Ok(Some(request.reply(0, body)))
+        }
+    }
+}
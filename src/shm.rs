@@ -0,0 +1,546 @@
+//! The MIT-SHM extension, version 1.2: images and pixmaps in memory shared with the
+//! client, either SysV segments or file descriptors passed over the Unix socket.
+
+use std::{
+    collections::BTreeMap,
+    ffi::{c_char, c_void},
+    fs::File,
+    io, mem,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    ptr, slice,
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    client::{client_credentials, queue_event, send_fd, take_received_fd},
+    error::{ErrorCode, XError},
+    event::Event,
+    extension::{find_extension, ExtensionRequest},
+    image::{get_image, image_size, put_image, Image, Z_PIXMAP},
+    pixmap::{Pixmap, DEFAULT_PIXMAP_FORMATS, PIXMAPS},
+    region::Rectangle,
+    unix::Credentials,
+    window::WINDOWS,
+};
+
+pub static SHM_NAME: &str = "MIT-SHM";
+pub const SHM_MAJOR_VERSION: u16 = 1;
+pub const SHM_MINOR_VERSION: u16 = 2;
+
+/// BadShmSeg, relative to the extension's first error.
+const BAD_SHM_SEG: u8 = 0;
+
+/// The minor opcode of ShmPutImage, reported in ShmCompletion events.
+pub const SHM_PUT_IMAGE: u16 = 3;
+
+const IPC_STAT: i32 = 2;
+const SHM_RDONLY: i32 = 0o10000;
+const PROT_READ: i32 = 1;
+const PROT_WRITE: i32 = 2;
+const MAP_SHARED: i32 = 1;
+const MAP_ANONYMOUS: i32 = 0x20;
+const MFD_CLOEXEC: u32 = 1;
+const EACCES: i32 = 13;
+
+#[repr(C)]
+struct IpcPerm {
+    key: i32,
+    uid: u32,
+    gid: u32,
+    cuid: u32,
+    cgid: u32,
+    mode: u32,
+    seq: u16,
+    pad: u16,
+    unused: [u64; 2],
+}
+
+#[repr(C)]
+struct ShmidDs {
+    perm: IpcPerm,
+    segment_size: usize,
+    attach_time: i64,
+    detach_time: i64,
+    change_time: i64,
+    creator_pid: i32,
+    last_pid: i32,
+    attach_count: u64,
+    unused: [u64; 2],
+}
+
+extern "C" {
+    fn shmat(shmid: i32, address: *const c_void, flags: i32) -> *mut c_void;
+    fn shmdt(address: *const c_void) -> i32;
+    fn shmctl(shmid: i32, command: i32, status: *mut ShmidDs) -> i32;
+    fn mmap(address: *mut c_void, len: usize, prot: i32, flags: i32, fd: i32, offset: i64) -> *mut c_void;
+    fn munmap(address: *mut c_void, len: usize) -> i32;
+    fn memfd_create(name: *const c_char, flags: u32) -> i32;
+    fn geteuid() -> u32;
+    fn getegid() -> u32;
+}
+
+/// Memory shared with a client, an attached SysV segment or a mapped file, or between
+/// the pixmaps that name a redirected window's backing pixmap. It stays mapped until the
+/// segment is detached and every pixmap using it is freed.
+pub struct Mapping {
+    address: *mut u8,
+    size: usize,
+    sysv: bool,
+}
+
+// The memory is only reached through the locks on `SEGMENTS` and `PIXMAPS`.
+unsafe impl Send for Mapping {}
+unsafe impl Sync for Mapping {}
+
+impl Mapping {
+    pub fn address(&self) -> *mut u8 {
+        self.address
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Maps `size` bytes of zeroed memory that only the server uses.
+    pub fn anonymous(size: usize) -> Option<Mapping> {
+        if size == 0 {
+            return None;
+        }
+        let flags = MAP_SHARED | MAP_ANONYMOUS;
+        let address = unsafe { mmap(ptr::null_mut(), size, PROT_READ | PROT_WRITE, flags, -1, 0) };
+        if address as isize == -1 {
+            return None;
+        }
+        Some(Mapping {
+            address: address.cast(),
+            size,
+            sysv: false,
+        })
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.address, self.size) }
+    }
+
+    /// Copies `bytes` to `offset`. The caller checks they fit and that the mapping is
+    /// writable.
+    fn write(&self, offset: usize, bytes: &[u8]) {
+        unsafe { ptr::copy_nonoverlapping(bytes.as_ptr(), self.address.add(offset), bytes.len()) };
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe {
+            if self.sysv {
+                shmdt(self.address.cast());
+            } else {
+                munmap(self.address.cast(), self.size);
+            }
+        }
+    }
+}
+
+/// An attached segment, named by the client's ShmSeg id.
+pub struct Segment {
+    pub mapping: Arc<Mapping>,
+    pub read_only: bool,
+}
+
+pub static SEGMENTS: Mutex<BTreeMap<u32, Segment>> = Mutex::new(BTreeMap::new());
+
+#[derive(Debug)]
+pub enum ShmRequest {
+    QueryVersion,
+    Attach {
+        shmseg: u32,
+        shmid: u32,
+        read_only: bool,
+    },
+    Detach {
+        shmseg: u32,
+    },
+    PutImage {
+        drawable: u32,
+        gc: u32,
+        total_width: u16,
+        total_height: u16,
+        src_x: u16,
+        src_y: u16,
+        src_width: u16,
+        src_height: u16,
+        dst_x: i16,
+        dst_y: i16,
+        depth: u8,
+        format: u8,
+        send_event: bool,
+        shmseg: u32,
+        offset: u32,
+    },
+    GetImage {
+        drawable: u32,
+        x: i16,
+        y: i16,
+        width: u16,
+        height: u16,
+        plane_mask: u32,
+        format: u8,
+        shmseg: u32,
+        offset: u32,
+    },
+    CreatePixmap {
+        pid: u32,
+        drawable: u32,
+        width: u16,
+        height: u16,
+        depth: u8,
+        shmseg: u32,
+        offset: u32,
+    },
+    AttachFd {
+        shmseg: u32,
+        read_only: bool,
+    },
+    CreateSegment {
+        shmseg: u32,
+        size: u32,
+        read_only: bool,
+    },
+}
+
+fn read_shm_request(request: &ExtensionRequest) -> Result<ShmRequest, XError> {
+    let data = request.data;
+    let minimum_length = match request.minor_opcode {
+        0 => 0,
+        1 => 12,
+        2 => 4,
+        3 => 36,
+        4 => 28,
+        5 => 24,
+        6 => 8,
+        7 => 12,
+        minor_opcode => return Err(XError::new(ErrorCode::Request, minor_opcode as u32)),
+    };
+    if data.len() < minimum_length {
+        return Err(XError::new(ErrorCode::Length, 0));
+    }
+    Ok(match request.minor_opcode {
+        0 => ShmRequest::QueryVersion,
+        1 => ShmRequest::Attach {
+            shmseg: request.card32(data),
+            shmid: request.card32(&data[4..]),
+            read_only: data[8] != 0,
+        },
+        2 => ShmRequest::Detach {
+            shmseg: request.card32(data),
+        },
+        3 => ShmRequest::PutImage {
+            drawable: request.card32(data),
+            gc: request.card32(&data[4..]),
+            total_width: request.card16(&data[8..]),
+            total_height: request.card16(&data[10..]),
+            src_x: request.card16(&data[12..]),
+            src_y: request.card16(&data[14..]),
+            src_width: request.card16(&data[16..]),
+            src_height: request.card16(&data[18..]),
+            dst_x: request.int16(&data[20..]),
+            dst_y: request.int16(&data[22..]),
+            depth: data[24],
+            format: data[25],
+            send_event: data[26] != 0,
+            shmseg: request.card32(&data[28..]),
+            offset: request.card32(&data[32..]),
+        },
+        4 => ShmRequest::GetImage {
+            drawable: request.card32(data),
+            x: request.int16(&data[4..]),
+            y: request.int16(&data[6..]),
+            width: request.card16(&data[8..]),
+            height: request.card16(&data[10..]),
+            plane_mask: request.card32(&data[12..]),
+            format: data[16],
+            shmseg: request.card32(&data[20..]),
+            offset: request.card32(&data[24..]),
+        },
+        5 => ShmRequest::CreatePixmap {
+            pid: request.card32(data),
+            drawable: request.card32(&data[4..]),
+            width: request.card16(&data[8..]),
+            height: request.card16(&data[10..]),
+            depth: data[12],
+            shmseg: request.card32(&data[16..]),
+            offset: request.card32(&data[20..]),
+        },
+        6 => ShmRequest::AttachFd {
+            shmseg: request.card32(data),
+            read_only: data[4] != 0,
+        },
+        _ => ShmRequest::CreateSegment {
+            shmseg: request.card32(data),
+            size: request.card32(&data[4..]),
+            read_only: data[8] != 0,
+        },
+    })
+}
+
+pub fn handle_shm_request(request: &ExtensionRequest) -> Result<Option<Vec<u8>>, XError> {
+    let shm_request = read_shm_request(request)?;
+    match shm_request {
+        ShmRequest::QueryVersion => {
+            let (uid, gid) = unsafe { (geteuid(), getegid()) };
+            let mut body = request.to_bytes_16(SHM_MAJOR_VERSION).to_vec();
+            body.extend(request.to_bytes_16(SHM_MINOR_VERSION));
+            body.extend(request.to_bytes_16(uid as u16));
+            body.extend(request.to_bytes_16(gid as u16));
+            body.push(Z_PIXMAP);
+            // Shared pixmaps are supported.
+            Ok(Some(request.reply(1, body)))
+        }
+        ShmRequest::Attach {
+            shmseg,
+            shmid,
+            read_only,
+        } => {
+            check_segment_id(shmseg)?;
+            let mapping = attach_sysv(client_credentials(request.client), shmid, read_only)?;
+            add_segment(shmseg, mapping, read_only);
+            Ok(None)
+        }
+        ShmRequest::Detach { shmseg } => {
+            SEGMENTS.lock().unwrap().remove(&shmseg).ok_or(bad_shm_seg(shmseg))?;
+            Ok(None)
+        }
+        ShmRequest::PutImage {
+            drawable,
+            total_width,
+            total_height,
+            src_x,
+            src_y,
+            src_width,
+            src_height,
+            dst_x,
+            dst_y,
+            depth,
+            format,
+            send_event,
+            shmseg,
+            offset,
+            ..
+        } => {
+            let (mapping, _) = get_segment(shmseg)?;
+            if src_x as u32 + src_width as u32 > total_width as u32 {
+                return Err(XError::new(ErrorCode::Value, src_width as u32));
+            }
+            if src_y as u32 + src_height as u32 > total_height as u32 {
+                return Err(XError::new(ErrorCode::Value, src_height as u32));
+            }
+            let len = image_size(format, depth, total_width as usize, total_height as usize);
+            let data = mapping
+                .bytes()
+                .get(offset as usize..offset as usize + len)
+                .ok_or(XError::new(ErrorCode::Value, offset))?;
+            let image = Image {
+                format,
+                depth,
+                width: total_width,
+                height: total_height,
+                left_pad: 0,
+                data,
+            };
+            let source = Rectangle::new(src_x as i32, src_y as i32, src_width as i32, src_height as i32);
+            put_image(&WINDOWS.lock().unwrap(), drawable, &image, source, dst_x, dst_y)?;
+            if send_event {
+                let major_event = find_extension(SHM_NAME).map_or(0, |e| e.major_opcode);
+                let event = Event::ShmCompletion {
+                    drawable,
+                    minor_event: SHM_PUT_IMAGE,
+                    major_event,
+                    shmseg,
+                    offset,
+                };
+                queue_event(request.client, event);
+            }
+            Ok(None)
+        }
+        ShmRequest::GetImage {
+            drawable,
+            x,
+            y,
+            width,
+            height,
+            plane_mask,
+            format,
+            shmseg,
+            offset,
+        } => {
+            let (mapping, read_only) = get_segment(shmseg)?;
+            if read_only {
+                return Err(XError::new(ErrorCode::Access, shmseg));
+            }
+            let rectangle = Rectangle::new(x as i32, y as i32, width as i32, height as i32);
+            let image = get_image(&WINDOWS.lock().unwrap(), drawable, rectangle, format, plane_mask)?;
+            if offset as usize + image.data.len() > mapping.size() {
+                return Err(XError::new(ErrorCode::Value, offset));
+            }
+            mapping.write(offset as usize, &image.data);
+            let mut body = request.to_bytes_32(image.visual).to_vec();
+            body.extend(request.to_bytes_32(image.data.len() as u32));
+            Ok(Some(request.reply(image.depth, body)))
+        }
+        ShmRequest::CreatePixmap {
+            pid,
+            drawable,
+            width,
+            height,
+            depth,
+            shmseg,
+            offset,
+        } => {
+            let (mapping, read_only) = get_segment(shmseg)?;
+            let windows = WINDOWS.lock().unwrap();
+            let mut pixmaps = PIXMAPS.lock().unwrap();
+            if windows.contains_key(&pid) || pixmaps.contains_key(&pid) {
+                return Err(XError::new(ErrorCode::IDChoice, pid));
+            }
+            if !windows.contains_key(&drawable) && !pixmaps.contains_key(&drawable) {
+                return Err(XError::new(ErrorCode::Drawable, drawable));
+            }
+            if width == 0 || height == 0 {
+                return Err(XError::new(ErrorCode::Value, 0));
+            }
+            if !DEFAULT_PIXMAP_FORMATS.iter().any(|f| f.depth == depth) {
+                return Err(XError::new(ErrorCode::Value, depth as u32));
+            }
+            // The server draws into shared pixmaps, which a read-only mapping can't take.
+            if read_only {
+                return Err(XError::new(ErrorCode::Access, shmseg));
+            }
+            let len = image_size(Z_PIXMAP, depth, width as usize, height as usize);
+            if offset % 4 != 0 || offset as usize + len > mapping.size() {
+                return Err(XError::new(ErrorCode::Value, offset));
+            }
+            pixmaps.insert(pid, Pixmap::shared(width, height, depth, mapping, offset as usize));
+            Ok(None)
+        }
+        ShmRequest::AttachFd { shmseg, read_only } => {
+            let fd = take_received_fd(request.client).ok_or(XError::new(ErrorCode::Match, shmseg))?;
+            check_segment_id(shmseg)?;
+            let size = File::from(fd.try_clone().map_err(|_| XError::new(ErrorCode::Alloc, shmseg))?)
+                .metadata()
+                .map_err(|_| XError::new(ErrorCode::Access, shmseg))?
+                .len();
+            let mapping = map_fd(&fd, size as usize, read_only)?;
+            add_segment(shmseg, mapping, read_only);
+            Ok(None)
+        }
+        ShmRequest::CreateSegment {
+            shmseg,
+            size,
+            read_only,
+        } => {
+            check_segment_id(shmseg)?;
+            if size == 0 {
+                return Err(XError::new(ErrorCode::Value, size));
+            }
+            let fd = unsafe { memfd_create(c"xaugh-shm".as_ptr(), MFD_CLOEXEC) };
+            if fd < 0 {
+                return Err(XError::new(ErrorCode::Alloc, shmseg));
+            }
+            let file = unsafe { File::from_raw_fd(fd) };
+            file.set_len(size as u64)
+                .map_err(|_| XError::new(ErrorCode::Alloc, shmseg))?;
+            let fd = OwnedFd::from(file);
+            // The server keeps a writable mapping; `read_only` only limits what the
+            // client's requests may do with the segment.
+            let mapping = map_fd(&fd, size as usize, false)?;
+            if !send_fd(request.client, fd) {
+                return Err(XError::new(ErrorCode::Alloc, shmseg));
+            }
+            add_segment(shmseg, mapping, read_only);
+            Ok(Some(request.reply(1, vec![])))
+        }
+    }
+}
+
+fn bad_shm_seg(shmseg: u32) -> XError {
+    let first_error = find_extension(SHM_NAME).map_or(0, |e| e.first_error);
+    XError::extension(first_error + BAD_SHM_SEG, shmseg)
+}
+
+fn check_segment_id(shmseg: u32) -> Result<(), XError> {
+    if SEGMENTS.lock().unwrap().contains_key(&shmseg) {
+        return Err(XError::new(ErrorCode::IDChoice, shmseg));
+    }
+    Ok(())
+}
+
+fn add_segment(shmseg: u32, mapping: Mapping, read_only: bool) {
+    let segment = Segment {
+        mapping: Arc::new(mapping),
+        read_only,
+    };
+    SEGMENTS.lock().unwrap().insert(shmseg, segment);
+}
+
+fn get_segment(shmseg: u32) -> Result<(Arc<Mapping>, bool), XError> {
+    SEGMENTS
+        .lock()
+        .unwrap()
+        .get(&shmseg)
+        .map(|segment| (segment.mapping.clone(), segment.read_only))
+        .ok_or(bad_shm_seg(shmseg))
+}
+
+/// Whether a client with `credentials` may attach a segment with permissions `perm`.
+/// Clients without credentials, which connected over TCP, only get what the segment
+/// grants to everyone.
+fn permitted(perm: &IpcPerm, credentials: Option<Credentials>, read_only: bool) -> bool {
+    let wanted = if read_only { 0o4 } else { 0o6 };
+    let granted = match credentials {
+        Some(credentials) if credentials.uid == 0 => return true,
+        Some(credentials) if credentials.uid == perm.uid || credentials.uid == perm.cuid => perm.mode >> 6,
+        Some(credentials) if credentials.gid == perm.gid || credentials.gid == perm.cgid => perm.mode >> 3,
+        _ => perm.mode,
+    };
+    granted & wanted == wanted
+}
+
+fn attach_sysv(credentials: Option<Credentials>, shmid: u32, read_only: bool) -> Result<Mapping, XError> {
+    let mut status: ShmidDs = unsafe { mem::zeroed() };
+    if unsafe { shmctl(shmid as i32, IPC_STAT, &mut status) } < 0 {
+        let code = match io::Error::last_os_error().raw_os_error() {
+            Some(EACCES) => ErrorCode::Access,
+            _ => ErrorCode::Value,
+        };
+        return Err(XError::new(code, shmid));
+    }
+    if !permitted(&status.perm, credentials, read_only) {
+        return Err(XError::new(ErrorCode::Access, shmid));
+    }
+    let flags = if read_only { SHM_RDONLY } else { 0 };
+    let address = unsafe { shmat(shmid as i32, ptr::null(), flags) };
+    if address as isize == -1 {
+        return Err(XError::new(ErrorCode::Access, shmid));
+    }
+    Ok(Mapping {
+        address: address.cast(),
+        size: status.segment_size,
+        sysv: true,
+    })
+}
+
+fn map_fd(fd: &OwnedFd, size: usize, read_only: bool) -> Result<Mapping, XError> {
+    if size == 0 {
+        return Err(XError::new(ErrorCode::Value, 0));
+    }
+    let prot = if read_only { PROT_READ } else { PROT_READ | PROT_WRITE };
+    let address = unsafe { mmap(ptr::null_mut(), size, prot, MAP_SHARED, fd.as_raw_fd(), 0) };
+    if address as isize == -1 {
+        return Err(XError::new(ErrorCode::Access, 0));
+    }
+    Ok(Mapping {
+        address: address.cast(),
+        size,
+        sysv: false,
+    })
+}
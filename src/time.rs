@@ -0,0 +1,101 @@
+use std::{
+    cmp::Ordering,
+    sync::{Mutex, OnceLock},
+    time::Instant,
+};
+
+pub const CURRENT_TIME: u32 = 0;
+
+/// The instant the real clock started.
+static START: OnceLock<Instant> = OnceLock::new();
+
+/// The current time of the virtual clock, if the server runs on one.
+static VIRTUAL_CLOCK: Mutex<Option<u32>> = Mutex::new(None);
+
+/// Starts the real clock. Called at startup so server time counts from then.
+pub fn init_time() {
+    START.get_or_init(Instant::now);
+}
+
+/// Switches to a virtual clock that only moves when `advance` is called, so tests
+/// get reproducible timestamps. Switching again keeps the virtual time.
+pub fn use_virtual_clock() {
+    VIRTUAL_CLOCK.lock().unwrap().get_or_insert(1);
+}
+
+/// Moves the virtual clock forward by `milliseconds`. Returns false if the server
+/// runs on the real clock.
+pub fn advance(milliseconds: u32) -> bool {
+    match VIRTUAL_CLOCK.lock().unwrap().as_mut() {
+        Some(time) => {
+            *time = skip_current_time(time.wrapping_add(milliseconds));
+            true
+        }
+        None => false,
+    }
+}
+
+fn skip_current_time(time: u32) -> u32 {
+    time.max(1)
+}
+
+/// Server time in milliseconds since startup, wrapping at 32 bits. It starts at 1 so
+/// that it compares later than the initial last-change times, and never equals
+/// CurrentTime.
+pub fn now() -> u32 {
+    if let Some(time) = *VIRTUAL_CLOCK.lock().unwrap() {
+        return time;
+    }
+    let elapsed = START.get_or_init(Instant::now).elapsed().as_millis() as u32;
+    skip_current_time(elapsed.wrapping_add(1))
+}
+
+/// Compares two timestamps, treating the half of the 32-bit range after `b` as later.
+pub fn compare(a: u32, b: u32) -> Ordering {
+    if a == b {
+        Ordering::Equal
+    } else if a.wrapping_sub(b) < 0x8000_0000 {
+        Ordering::Greater
+    } else {
+        Ordering::Less
+    }
+}
+
+/// Applies the protocol's timestamp rules to a request time guarding state last
+/// changed at `last`: CurrentTime stands for the current server time, and a time
+/// later than the server time or earlier than `last` makes the request have no effect
+/// (returns None).
+pub fn check(time: u32, last: u32) -> Option<u32> {
+    let now = now();
+    let time = if time == CURRENT_TIME { now } else { time };
+    (compare(time, now) != Ordering::Greater && compare(time, last) != Ordering::Less).then_some(time)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compare_wraps_around() {
+        assert_eq!(compare(5, 5), Ordering::Equal);
+        assert_eq!(compare(6, 5), Ordering::Greater);
+        assert_eq!(compare(5, 6), Ordering::Less);
+        // Just after wrapping is later than just before it.
+        assert_eq!(compare(2, u32::MAX - 2), Ordering::Greater);
+        assert_eq!(compare(u32::MAX - 2, 2), Ordering::Less);
+        // Half the range ahead counts as earlier.
+        assert_eq!(compare(5 + 0x7fff_ffff, 5), Ordering::Greater);
+        assert_eq!(compare(5 + 0x8000_0000, 5), Ordering::Less);
+    }
+
+    #[test]
+    fn check_rejects_future_and_stale_times() {
+        use_virtual_clock();
+        advance(1000);
+        let now = now();
+        assert!(check(CURRENT_TIME, now).is_some_and(|time| compare(time, now) != Ordering::Less));
+        assert_eq!(check(now, now - 10), Some(now));
+        assert_eq!(check(now - 20, now - 10), None);
+        assert_eq!(check(now.wrapping_add(100_000), 0), None);
+    }
+}
@@ -0,0 +1,136 @@
+//! Touches on the touchscreen, reported through XInput only. Each touch is delivered to
+//! one window for its whole life: the grab window of an XInput pointer grab that
+//! selected touch events, or else the first window from the point the touch began
+//! towards the root where a client selected them.
+
+use std::collections::BTreeMap;
+
+use crate::{
+    client::queue_event,
+    event::Event,
+    input::{relative_to, template, window_at, DeviceEvent, InputState},
+    screen::DEFAULT_SCREEN,
+    window::Window,
+    xinput::{
+        deliver_raw_event, XIDeviceEvent, XIModifiers, XIRawEvent, VALUATOR_X, VALUATOR_Y,
+        VIRTUAL_CORE_POINTER, VIRTUAL_TOUCHSCREEN, XI_RAW_TOUCH_BEGIN, XI_TOUCH_BEGIN, XI_TOUCH_END,
+        XI_TOUCH_UPDATE,
+    },
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TouchPhase {
+    Begin,
+    Update,
+    End,
+}
+
+#[derive(Clone, Debug)]
+pub struct Touch {
+    /// The injector's name for the touch, which later touches may reuse.
+    pub slot: u32,
+    /// The touch ID reported to clients.
+    pub id: u32,
+    pub window: u32,
+    /// The clients the touch is reported to, with the device each selected it for.
+    pub recipients: Vec<(u32, u16)>,
+}
+
+impl InputState {
+    /// Finds the window a touch beginning over `source` belongs to, and who gets it.
+    fn touch_owner(&self, windows: &BTreeMap<u32, Window>, source: u32) -> Option<(u32, Vec<(u32, u16)>)> {
+        if let Some(grab) = &self.pointer_grab.grab {
+            let selected = grab.xi2_mask.is_some_and(|mask| mask & 1 << XI_TOUCH_BEGIN != 0);
+            return selected.then(|| (grab.window, vec![(grab.client, VIRTUAL_CORE_POINTER)]));
+        }
+        let mut window = source;
+        loop {
+            let w = &windows[&window];
+            let mut recipients = vec![];
+            for device in [VIRTUAL_TOUCHSCREEN, VIRTUAL_CORE_POINTER] {
+                let clients = w.xi_clients(device, XI_TOUCH_BEGIN);
+                recipients.extend(clients.into_iter().map(|client| (client, device)));
+            }
+            if !recipients.is_empty() {
+                return Some((window, recipients));
+            }
+            if w.parent == 0 {
+                return None;
+            }
+            window = w.parent;
+        }
+    }
+
+    /// Begins, moves or ends the touch in `slot` at the given root coordinates,
+    /// clamped to the screen.
+    pub fn process_touch(
+        &mut self,
+        windows: &BTreeMap<u32, Window>,
+        slot: u32,
+        phase: TouchPhase,
+        x: i32,
+        y: i32,
+    ) {
+        let bounds = windows[&DEFAULT_SCREEN.root_window].screen_rectangle;
+        let x = x.clamp(bounds.x, bounds.x + bounds.width.max(1) - 1);
+        let y = y.clamp(bounds.y, bounds.y + bounds.height.max(1) - 1);
+        let source = window_at(windows, x, y);
+        let index = match (phase, self.touches.iter().position(|touch| touch.slot == slot)) {
+            (TouchPhase::Begin, None) => {
+                let (window, recipients) = self.touch_owner(windows, source).unwrap_or_default();
+                self.touches.push(Touch {
+                    slot,
+                    id: self.next_touch_id,
+                    window,
+                    recipients,
+                });
+                self.next_touch_id = self.next_touch_id.wrapping_add(1).max(1);
+                self.touches.len() - 1
+            }
+            (TouchPhase::Update | TouchPhase::End, Some(index)) => index,
+            // A touch can't begin twice, or move or end before it began.
+            _ => return,
+        };
+        let evtype = match phase {
+            TouchPhase::Begin => XI_TOUCH_BEGIN,
+            TouchPhase::Update => XI_TOUCH_UPDATE,
+            TouchPhase::End => XI_TOUCH_END,
+        };
+        let touch = &self.touches[index];
+        let template = DeviceEvent {
+            root_x: x as i16,
+            root_y: y as i16,
+            ..template(self, 0, self.state())
+        };
+        let valuators = vec![(VALUATOR_X, x as f64), (VALUATOR_Y, y as f64)];
+        let raw = XIRawEvent {
+            evtype: evtype - XI_TOUCH_BEGIN + XI_RAW_TOUCH_BEGIN,
+            deviceid: VIRTUAL_TOUCHSCREEN,
+            sourceid: VIRTUAL_TOUCHSCREEN,
+            time: template.time,
+            detail: touch.id,
+            flags: 0,
+            valuators: valuators.clone(),
+        };
+        deliver_raw_event(windows, VIRTUAL_CORE_POINTER, raw);
+        if windows.contains_key(&touch.window) {
+            let device_event = relative_to(windows, touch.window, Some(source), template);
+            for (client, deviceid) in &touch.recipients {
+                let event = XIDeviceEvent {
+                    evtype,
+                    deviceid: *deviceid,
+                    sourceid: VIRTUAL_TOUCHSCREEN,
+                    detail: touch.id,
+                    flags: 0,
+                    device_event,
+                    modifiers: XIModifiers::current(device_event.state),
+                    valuators: valuators.clone(),
+                };
+                queue_event(*client, Event::XIDevice(event));
+            }
+        }
+        if phase == TouchPhase::End {
+            self.touches.remove(index);
+        }
+    }
+}
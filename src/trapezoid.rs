@@ -0,0 +1,162 @@
+//! Rasterizing RENDER trapezoids and triangles into coverage masks. Like pixman,
+//! anti-aliased masks sample each pixel on a grid of 17 columns by 15 rows, which gives
+//! exactly 255 levels, and sharp (depth 1) masks sample the pixel center only.
+
+use crate::region::Rectangle;
+
+/// A line through two points, for the sides of trapezoids.
+#[derive(Clone, Copy, Debug)]
+pub struct Line {
+    pub p1: (f64, f64),
+    pub p2: (f64, f64),
+}
+
+impl Line {
+    fn x_at(&self, y: f64) -> f64 {
+        self.p1.0 + (y - self.p1.1) * (self.p2.0 - self.p1.0) / (self.p2.1 - self.p1.1)
+    }
+}
+
+/// The area between `top` and `bottom` bounded by two lines.
+#[derive(Clone, Copy, Debug)]
+pub struct Trapezoid {
+    pub top: f64,
+    pub bottom: f64,
+    pub left: Line,
+    pub right: Line,
+}
+
+impl Trapezoid {
+    pub fn translate(&self, dx: f64, dy: f64) -> Trapezoid {
+        let translate = |(x, y): (f64, f64)| (x + dx, y + dy);
+        Trapezoid {
+            top: self.top + dy,
+            bottom: self.bottom + dy,
+            left: Line {
+                p1: translate(self.left.p1),
+                p2: translate(self.left.p2),
+            },
+            right: Line {
+                p1: translate(self.right.p1),
+                p2: translate(self.right.p2),
+            },
+        }
+    }
+
+    /// Trapezoids with horizontal sides or no height cover nothing.
+    fn is_valid(&self) -> bool {
+        self.left.p1.1 != self.left.p2.1 && self.right.p1.1 != self.right.p2.1 && self.bottom > self.top
+    }
+
+    /// The pixels within `clip` the trapezoid touches. Sides extended to the top and
+    /// bottom can land far outside the i32 range, so the edges are clamped to the clip
+    /// before they are converted.
+    fn bounds(&self, clip: &Rectangle) -> Option<Rectangle> {
+        let xs = [
+            self.left.x_at(self.top),
+            self.left.x_at(self.bottom),
+            self.right.x_at(self.top),
+            self.right.x_at(self.bottom),
+        ];
+        let (clip_x1, clip_x2) = (clip.x as f64, (clip.x + clip.width) as f64);
+        let (clip_y1, clip_y2) = (clip.y as f64, (clip.y + clip.height) as f64);
+        let x1 = xs.iter().copied().fold(f64::INFINITY, f64::min).floor();
+        let x2 = xs.iter().copied().fold(f64::NEG_INFINITY, f64::max).ceil();
+        let x1 = x1.clamp(clip_x1, clip_x2) as i32;
+        let x2 = x2.clamp(clip_x1, clip_x2) as i32;
+        let y1 = self.top.floor().clamp(clip_y1, clip_y2) as i32;
+        let y2 = self.bottom.ceil().clamp(clip_y1, clip_y2) as i32;
+        Rectangle::new(x1, y1, x2 - x1, y2 - y1).intersect(clip)
+    }
+}
+
+/// Splits a triangle into the trapezoids above and below its middle vertex.
+pub fn triangle_trapezoids(points: [(f64, f64); 3]) -> Vec<Trapezoid> {
+    let mut points = points;
+    points.sort_by(|a, b| a.1.total_cmp(&b.1));
+    let [top, middle, bottom] = points;
+    let long = Line { p1: top, p2: bottom };
+    let upper = Line { p1: top, p2: middle };
+    let lower = Line { p1: middle, p2: bottom };
+    // The long side is on the left if it passes left of the middle vertex.
+    let long_is_left = long.p1.1 != long.p2.1 && long.x_at(middle.1) < middle.0;
+    let trapezoid = |top: f64, bottom: f64, side: Line| {
+        let (left, right) = if long_is_left { (long, side) } else { (side, long) };
+        Trapezoid {
+            top,
+            bottom,
+            left,
+            right,
+        }
+    };
+    vec![trapezoid(top.1, middle.1, upper), trapezoid(middle.1, bottom.1, lower)]
+}
+
+/// Coverage of a set of trapezoids over `extents`, one alpha value per pixel.
+pub struct Coverage {
+    pub extents: Rectangle,
+    pub alpha: Vec<u8>,
+}
+
+impl Coverage {
+    pub fn get(&self, x: i32, y: i32) -> u8 {
+        let row = (y - self.extents.y) as usize * self.extents.width as usize;
+        self.alpha[row + (x - self.extents.x) as usize]
+    }
+}
+
+/// Rasterizes the parts of `trapezoids` within `clip`, adding up their coverage.
+/// `sharp` samples only pixel centers, for depth 1 masks.
+pub fn rasterize(trapezoids: &[Trapezoid], clip: &Rectangle, sharp: bool) -> Coverage {
+    let trapezoids: Vec<(&Trapezoid, Rectangle)> = trapezoids
+        .iter()
+        .filter(|t| t.is_valid())
+        .filter_map(|t| Some((t, t.bounds(clip)?)))
+        .collect();
+    let extents = trapezoids
+        .iter()
+        .map(|(_, bounds)| *bounds)
+        .reduce(|a, b| {
+            let (x1, y1) = (a.x.min(b.x), a.y.min(b.y));
+            let (x2, y2) = ((a.x + a.width).max(b.x + b.width), (a.y + a.height).max(b.y + b.height));
+            Rectangle::new(x1, y1, x2 - x1, y2 - y1)
+        })
+        .unwrap_or_default();
+    let mut alpha = vec![0u8; extents.width as usize * extents.height as usize];
+    let (columns, rows) = if sharp { (1, 1) } else { (17, 15) };
+    // Each sample is worth this much alpha, 255 for sharp masks and 1 otherwise.
+    let weight = (255 / (columns * rows)) as u8;
+    for (trapezoid, bounds) in trapezoids {
+        let mut coverage = vec![0u8; bounds.width as usize * bounds.height as usize];
+        for y in bounds.y..bounds.y + bounds.height {
+            for row in 0..rows {
+                let sample_y = y as f64 + (2 * row + 1) as f64 / (2 * rows) as f64;
+                if sample_y < trapezoid.top || sample_y >= trapezoid.bottom {
+                    continue;
+                }
+                let (left, right) = (trapezoid.left.x_at(sample_y), trapezoid.right.x_at(sample_y));
+                let x1 = (left.floor() as i32).max(bounds.x);
+                let x2 = (right.ceil() as i32).min(bounds.x + bounds.width);
+                for x in x1..x2 {
+                    // Samples lie at x + (column + 1/2) / columns; count those in [left, right).
+                    let first_column = |edge: f64| ((edge - x as f64) * columns as f64 - 0.5).ceil();
+                    let start = first_column(left).clamp(0.0, columns as f64);
+                    let end = first_column(right).clamp(0.0, columns as f64);
+                    if end > start {
+                        let index = (y - bounds.y) as usize * bounds.width as usize + (x - bounds.x) as usize;
+                        coverage[index] += (end - start) as u8 * weight;
+                    }
+                }
+            }
+        }
+        for y in 0..bounds.height {
+            for x in 0..bounds.width {
+                let value = coverage[y as usize * bounds.width as usize + x as usize];
+                let index = (bounds.y + y - extents.y) as usize * extents.width as usize
+                    + (bounds.x + x - extents.x) as usize;
+                alpha[index] = alpha[index].saturating_add(value);
+            }
+        }
+    }
+    Coverage { extents, alpha }
+}
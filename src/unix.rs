@@ -0,0 +1,208 @@
+//! The local transport. Clients connecting through the Unix socket can pass file
+//! descriptors along with their requests (SCM_RIGHTS), and the kernel tells us who
+//! they are, which MIT-SHM uses to check access to shared memory segments.
+
+use std::{
+    ffi::c_void,
+    io::{self, Read, Write},
+    mem::size_of,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd},
+        unix::net::{UnixListener, UnixStream},
+    },
+    ptr,
+    time::Duration,
+};
+
+use crate::client::CLIENTS;
+
+/// The socket for display :1, matching the TCP port 6001.
+pub static UNIX_SOCKET: &str = "/tmp/.X11-unix/X1";
+
+/// The most file descriptors accepted with a single read.
+const MAX_FDS: usize = 16;
+
+const SOL_SOCKET: i32 = 1;
+const SCM_RIGHTS: i32 = 1;
+const SO_PEERCRED: i32 = 17;
+const MSG_CMSG_CLOEXEC: i32 = 0x4000_0000;
+
+#[repr(C)]
+struct IoVec {
+    base: *mut c_void,
+    len: usize,
+}
+
+#[repr(C)]
+struct MsgHdr {
+    name: *mut c_void,
+    name_len: u32,
+    iov: *mut IoVec,
+    iov_len: usize,
+    control: *mut c_void,
+    control_len: usize,
+    flags: i32,
+}
+
+#[repr(C)]
+struct CmsgHdr {
+    len: usize,
+    level: i32,
+    kind: i32,
+}
+
+const CMSG_HEADER: usize = size_of::<CmsgHdr>();
+const CONTROL_LEN: usize = CMSG_HEADER + MAX_FDS * size_of::<i32>();
+
+/// Ancillary data, aligned like the `cmsghdr`s it holds.
+#[repr(C, align(8))]
+struct ControlBuffer([u8; CONTROL_LEN]);
+
+/// The process, user and group of a connected peer.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Credentials {
+    pub pid: i32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+extern "C" {
+    fn recvmsg(fd: i32, message: *mut MsgHdr, flags: i32) -> isize;
+    fn sendmsg(fd: i32, message: *const MsgHdr, flags: i32) -> isize;
+    fn getsockopt(fd: i32, level: i32, name: i32, value: *mut c_void, len: *mut u32) -> i32;
+}
+
+/// Binds the client socket, replacing one left behind by an earlier server.
+pub fn bind_unix_socket(path: &str) -> io::Result<UnixListener> {
+    if let Some(directory) = std::path::Path::new(path).parent() {
+        std::fs::create_dir_all(directory)?;
+    }
+    let _ = std::fs::remove_file(path);
+    UnixListener::bind(path)
+}
+
+pub fn peer_credentials(stream: &UnixStream) -> Option<Credentials> {
+    let mut credentials = Credentials::default();
+    let mut len = size_of::<Credentials>() as u32;
+    let result = unsafe {
+        getsockopt(
+            stream.as_raw_fd(),
+            SOL_SOCKET,
+            SO_PEERCRED,
+            ptr::addr_of_mut!(credentials).cast(),
+            &mut len,
+        )
+    };
+    (result == 0).then_some(credentials)
+}
+
+/// A client connection over the Unix socket. File descriptors that arrive with the
+/// client's bytes are queued on its `Client`, and those queued for it go out with the
+/// next write.
+pub struct UnixTransport {
+    stream: UnixStream,
+    /// Set once the connection is established; descriptors sent before that are closed.
+    pub client: u32,
+}
+
+impl UnixTransport {
+    pub fn new(stream: UnixStream) -> UnixTransport {
+        UnixTransport { stream, client: 0 }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_read_timeout(timeout)
+    }
+}
+
+impl Read for UnixTransport {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let mut iov = IoVec {
+            base: buffer.as_mut_ptr().cast(),
+            len: buffer.len(),
+        };
+        let mut control = ControlBuffer([0; CONTROL_LEN]);
+        let mut message = MsgHdr {
+            name: ptr::null_mut(),
+            name_len: 0,
+            iov: &mut iov,
+            iov_len: 1,
+            control: control.0.as_mut_ptr().cast(),
+            control_len: CONTROL_LEN,
+            flags: 0,
+        };
+        let read = unsafe { recvmsg(self.stream.as_raw_fd(), &mut message, MSG_CMSG_CLOEXEC) };
+        if read < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut fds = vec![];
+        let mut offset = 0;
+        while offset + CMSG_HEADER <= message.control_len {
+            let header = unsafe { ptr::read_unaligned(control.0[offset..].as_ptr().cast::<CmsgHdr>()) };
+            if header.len < CMSG_HEADER || offset + header.len > message.control_len {
+                break;
+            }
+            if header.level == SOL_SOCKET && header.kind == SCM_RIGHTS {
+                for bytes in control.0[offset + CMSG_HEADER..offset + header.len].chunks_exact(4) {
+                    let fd = i32::from_ne_bytes(bytes.try_into().unwrap());
+                    fds.push(unsafe { OwnedFd::from_raw_fd(fd) });
+                }
+            }
+            offset += header.len.next_multiple_of(size_of::<usize>());
+        }
+        if !fds.is_empty() {
+            if let Some(client) = CLIENTS.lock().unwrap().get_mut(&self.client) {
+                client.received_fds.extend(fds);
+            }
+        }
+        Ok(read as usize)
+    }
+}
+
+impl Write for UnixTransport {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        let fds = match CLIENTS.lock().unwrap().get_mut(&self.client) {
+            Some(client) => std::mem::take(&mut client.outgoing_fds),
+            None => vec![],
+        };
+        if fds.is_empty() {
+            return self.stream.write(bytes);
+        }
+        let mut iov = IoVec {
+            base: bytes.as_ptr() as *mut c_void,
+            len: bytes.len(),
+        };
+        let count = fds.len().min(MAX_FDS);
+        let mut control = ControlBuffer([0; CONTROL_LEN]);
+        let header = CmsgHdr {
+            len: CMSG_HEADER + count * size_of::<i32>(),
+            level: SOL_SOCKET,
+            kind: SCM_RIGHTS,
+        };
+        unsafe { ptr::write_unaligned(control.0.as_mut_ptr().cast::<CmsgHdr>(), header) };
+        for (index, fd) in fds.iter().take(count).enumerate() {
+            let start = CMSG_HEADER + index * size_of::<i32>();
+            control.0[start..start + 4].copy_from_slice(&fd.as_raw_fd().to_ne_bytes());
+        }
+        let message = MsgHdr {
+            name: ptr::null_mut(),
+            name_len: 0,
+            iov: &mut iov,
+            iov_len: 1,
+            control: control.0.as_mut_ptr().cast(),
+            control_len: (CMSG_HEADER + count * size_of::<i32>()).next_multiple_of(size_of::<usize>()),
+            flags: 0,
+        };
+        // The client holds its own copies once the message is sent, so ours close on drop.
+        let written = unsafe { sendmsg(self.stream.as_raw_fd(), &message, 0) };
+        if written < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(written as usize)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
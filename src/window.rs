@@ -0,0 +1,1115 @@
+use std::{
+    collections::BTreeMap,
+    sync::Mutex,
+};
+
+use crate::{
+    client::{remove_from_save_sets, resource_owner, SaveSetMode},
+    composite::{
+        composited_into, redirect_mode, resize_overlay, update_backing_pixmaps, OVERLAY_WINDOW,
+        REDIRECT_AUTOMATIC,
+    },
+    cursor::check_cursor,
+    damage::{damage_surface, free_drawable_damages},
+    error::{ErrorCode, XError},
+    event::{
+        deliver_event, deliver_structure_event, Event, BUTTON_PRESS_MASK, EXPOSURE_MASK,
+        RESIZE_REDIRECT_MASK, STRUCTURE_NOTIFY_MASK, SUBSTRUCTURE_NOTIFY_MASK, SUBSTRUCTURE_REDIRECT_MASK,
+    },
+    input::{process_pending, INPUT},
+    picture::Drawables,
+    pixmap::{Pixmap, PIXMAPS},
+    region::{Rectangle, Region},
+    request::{ConfigureValues, WindowAttributes},
+    screen::{DEFAULT_SCREEN, FRAMEBUFFER},
+    selection::release_window_selections,
+    shape::Shape,
+};
+
+pub static WINDOWS: Mutex<BTreeMap<u32, Window>> = Mutex::new(BTreeMap::new());
+
+pub const COPY_FROM_PARENT: u16 = 0;
+pub const INPUT_OUTPUT: u16 = 1;
+pub const INPUT_ONLY: u16 = 2;
+
+pub const STACK_ABOVE: u32 = 0;
+pub const STACK_BELOW: u32 = 1;
+pub const STACK_TOP_IF: u32 = 2;
+pub const STACK_BOTTOM_IF: u32 = 3;
+pub const STACK_OPPOSITE: u32 = 4;
+
+/// The background of a window. Pixmaps are copied when set, so the client may free
+/// theirs immediately afterwards.
+#[derive(Clone, Debug)]
+pub enum Background {
+    None,
+    ParentRelative,
+    Pixel(u32),
+    Pixmap(Pixmap),
+}
+
+#[derive(Clone, Debug)]
+pub enum Border {
+    Pixel(u32),
+    Pixmap(Pixmap),
+}
+
+#[derive(Clone, Debug)]
+pub struct Window {
+    pub id: u32,
+    pub parent: u32,
+    /// Children in stacking order, bottom-most first.
+    pub children: Vec<u32>,
+    pub x: i16,
+    pub y: i16,
+    pub width: u16,
+    pub height: u16,
+    pub border_width: u16,
+    pub class: u16,
+    pub depth: u8,
+    pub visual: u32,
+    pub background: Background,
+    pub border: Border,
+    pub attributes: WindowAttributes,
+    pub mapped: bool,
+    /// Event masks selected on this window, keyed by client.
+    pub event_masks: BTreeMap<u32, u32>,
+    /// Interior of the window in screen coordinates.
+    pub screen_rectangle: Rectangle,
+    /// Visible part of the interior in screen coordinates, not counting children.
+    pub clip: Region,
+    /// Visible part of the border in screen coordinates.
+    pub border_clip: Region,
+    pub shape: Shape,
+    /// RANDR event masks selected on this window, keyed by client.
+    pub randr_masks: BTreeMap<u32, u16>,
+    /// XInputExtension event masks selected on this window, keyed by client and device.
+    pub xi_masks: BTreeMap<(u32, u16), u32>,
+    /// XFIXES selection event masks selected on this window, keyed by client and
+    /// selection.
+    pub selection_masks: BTreeMap<(u32, u32), u32>,
+    /// XFIXES cursor event masks selected on this window, keyed by client.
+    pub cursor_masks: BTreeMap<u32, u32>,
+    /// COMPOSITE redirections of the window and of its children, keyed by client, with
+    /// their update modes.
+    pub redirects: BTreeMap<u32, u8>,
+    pub subwindow_redirects: BTreeMap<u32, u8>,
+    /// The redirected window whose backing pixmap the window draws to, itself if it is
+    /// redirected, or 0 for the framebuffer.
+    pub surface: u32,
+    /// The part of the window and its border that its parent and siblings leave visible,
+    /// in screen coordinates. The clips of a redirected window ignore its parent and
+    /// siblings, so this is where it shows in its parent's surface.
+    pub outer_clip: Region,
+    /// Whether the server paints the redirected window into its parent's surface, which
+    /// it does for automatic redirection.
+    pub composited: bool,
+}
+
+impl Window {
+    pub fn new(id: u32, parent: u32) -> Window {
+        Window {
+            id,
+            parent,
+            children: vec![],
+            x: 0,
+            y: 0,
+            width: 0,
+            height: 0,
+            border_width: 0,
+            class: INPUT_OUTPUT,
+            depth: 0,
+            visual: 0,
+            background: Background::None,
+            border: Border::Pixel(0),
+            attributes: WindowAttributes::default(),
+            mapped: false,
+            event_masks: BTreeMap::new(),
+            screen_rectangle: Rectangle::default(),
+            clip: Region::new(),
+            border_clip: Region::new(),
+            shape: Shape::default(),
+            randr_masks: BTreeMap::new(),
+            xi_masks: BTreeMap::new(),
+            selection_masks: BTreeMap::new(),
+            cursor_masks: BTreeMap::new(),
+            redirects: BTreeMap::new(),
+            subwindow_redirects: BTreeMap::new(),
+            surface: 0,
+            outer_clip: Region::new(),
+            composited: false,
+        }
+    }
+
+    pub fn override_redirect(&self) -> bool {
+        self.attributes.override_redirect != 0
+    }
+
+    /// The window including its border, relative to the parent's origin.
+    pub fn outer_rectangle(&self) -> Rectangle {
+        Rectangle::new(
+            self.x as i32,
+            self.y as i32,
+            self.width as i32 + 2 * self.border_width as i32,
+            self.height as i32 + 2 * self.border_width as i32,
+        )
+    }
+}
+
+pub fn init_windows() {
+    let screen = DEFAULT_SCREEN;
+    let mut root = Window::new(screen.root_window, 0);
+    root.width = screen.width_px;
+    root.height = screen.height_px;
+    root.depth = screen.root_depth;
+    root.visual = screen.root_visual;
+    root.background = Background::Pixel(screen.black_pixel);
+    root.mapped = true;
+    *FRAMEBUFFER.lock().unwrap() = Pixmap::new(screen.width_px, screen.height_px, screen.root_depth);
+    let mut windows = WINDOWS.lock().unwrap();
+    windows.insert(root.id, root);
+    validate(&mut windows);
+}
+
+/// Resizes the root window and the framebuffer, keeping what's on screen, and tells
+/// the root's clients with ConfigureNotify. The pointer stays on the screen.
+pub fn resize_root(windows: &mut BTreeMap<u32, Window>, width: u16, height: u16) {
+    let root = windows.get_mut(&DEFAULT_SCREEN.root_window).unwrap();
+    root.width = width;
+    root.height = height;
+    let mut framebuffer = FRAMEBUFFER.lock().unwrap();
+    let mut resized = Pixmap::new(width, height, framebuffer.depth);
+    if let Some(kept) = framebuffer.bounds().intersect(&resized.bounds()) {
+        for y in 0..kept.height {
+            for x in 0..kept.width {
+                resized.set(x, y, framebuffer.get(x, y));
+            }
+        }
+    }
+    *framebuffer = resized;
+    drop(framebuffer);
+    resize_overlay(windows, width, height);
+    let mut input = INPUT.lock().unwrap();
+    input.pointer_x = input.pointer_x.min(width as i16 - 1);
+    input.pointer_y = input.pointer_y.min(height as i16 - 1);
+    drop(input);
+    let root = DEFAULT_SCREEN.root_window;
+    deliver_structure_event(windows, root, |event| Event::ConfigureNotify {
+        event,
+        window: root,
+        above_sibling: 0,
+        x: 0,
+        y: 0,
+        width,
+        height,
+        border_width: 0,
+        override_redirect: false,
+    });
+    validate(windows);
+}
+
+pub fn get_window(windows: &BTreeMap<u32, Window>, id: u32) -> Result<&Window, XError> {
+    windows.get(&id).ok_or(XError::new(ErrorCode::Window, id))
+}
+
+/// Returns true if `id` is `ancestor` or one of its descendants.
+pub fn is_inferior_or_self(windows: &BTreeMap<u32, Window>, mut id: u32, ancestor: u32) -> bool {
+    loop {
+        if id == ancestor {
+            return true;
+        }
+        match windows.get(&id) {
+            Some(window) if window.parent != 0 => id = window.parent,
+            _ => return false,
+        }
+    }
+}
+
+/// Returns true if `ancestor` is a proper ancestor of `id`.
+pub fn is_parent(windows: &BTreeMap<u32, Window>, ancestor: u32, id: u32) -> bool {
+    id != ancestor && is_inferior_or_self(windows, id, ancestor)
+}
+
+/// Returns the closest window that is `a` or an ancestor of it and also `b` or an
+/// ancestor of `b`.
+pub fn common_ancestor(windows: &BTreeMap<u32, Window>, a: u32, b: u32) -> u32 {
+    let mut ancestor = a;
+    while !is_inferior_or_self(windows, b, ancestor) {
+        ancestor = windows[&ancestor].parent;
+    }
+    ancestor
+}
+
+/// Returns the child of `ancestor` that is `id` or an ancestor of it, or 0 if `id` is
+/// not a proper inferior of `ancestor`.
+pub fn child_towards(windows: &BTreeMap<u32, Window>, ancestor: u32, id: u32) -> u32 {
+    if !is_parent(windows, ancestor, id) {
+        return 0;
+    }
+    let mut child = id;
+    while windows[&child].parent != ancestor {
+        child = windows[&child].parent;
+    }
+    child
+}
+
+/// Implements TranslateCoordinates, returning the mapped child of `dst` that contains
+/// the translated point (or 0) along with the point in `dst` coordinates.
+pub fn translate_coordinates(
+    windows: &BTreeMap<u32, Window>,
+    src: u32,
+    dst: u32,
+    x: i16,
+    y: i16,
+) -> Result<(u32, i16, i16), XError> {
+    let src = get_window(windows, src)?;
+    let dst = get_window(windows, dst)?;
+    let x = x as i32 + src.screen_rectangle.x - dst.screen_rectangle.x;
+    let y = y as i32 + src.screen_rectangle.y - dst.screen_rectangle.y;
+    let child = dst
+        .children
+        .iter()
+        .rev()
+        .copied()
+        .find(|child| {
+            let window = &windows[child];
+            window.mapped && window.outer_rectangle().contains(x, y)
+        })
+        .unwrap_or(0);
+    Ok((child, x as i16, y as i16))
+}
+
+/// The screen position of the top-left corner of `surface`'s backing pixmap, the outer
+/// corner of the redirected window.
+pub fn surface_origin(windows: &BTreeMap<u32, Window>, surface: u32) -> (i32, i32) {
+    match windows.get(&surface) {
+        Some(window) => {
+            let border_width = window.border_width as i32;
+            (window.screen_rectangle.x - border_width, window.screen_rectangle.y - border_width)
+        }
+        None => (0, 0),
+    }
+}
+
+/// The part of its surface that drawing to `window` or its inferiors can change, in
+/// screen coordinates: its visible interior and border and those of its descendants
+/// that draw to the same surface.
+pub fn visible_region(windows: &BTreeMap<u32, Window>, window: &Window) -> Region {
+    let mut region = window.clip.union(&window.border_clip);
+    for child in window.children.iter().map(|child| &windows[child]) {
+        if child.surface == window.surface {
+            region = region.union(&visible_region(windows, child));
+        }
+    }
+    region
+}
+
+/// Stacks `id` above its siblings, but below the COMPOSITE overlay window, which stays
+/// on top of the root's children.
+fn stack_on_top(children: &mut Vec<u32>, id: u32) {
+    match children.iter().position(|c| *c == OVERLAY_WINDOW) {
+        Some(index) => children.insert(index, id),
+        None => children.push(id),
+    }
+}
+
+pub fn is_viewable(windows: &BTreeMap<u32, Window>, mut id: u32) -> bool {
+    loop {
+        match windows.get(&id) {
+            Some(window) if window.mapped => {
+                if window.parent == 0 {
+                    return true;
+                }
+                id = window.parent;
+            }
+            _ => return false,
+        }
+    }
+}
+
+pub fn create_window(
+    windows: &mut BTreeMap<u32, Window>,
+    client: u32,
+    mut window: Window,
+    value_mask: u32,
+    values: &WindowAttributes,
+) -> Result<(), XError> {
+    if windows.contains_key(&window.id) || PIXMAPS.lock().unwrap().contains_key(&window.id) {
+        return Err(XError::new(ErrorCode::IDChoice, window.id));
+    }
+    let parent = get_window(windows, window.parent)?;
+    if window.width == 0 || window.height == 0 {
+        return Err(XError::new(ErrorCode::Value, 0));
+    }
+    if window.class == COPY_FROM_PARENT {
+        window.class = parent.class;
+    }
+    match window.class {
+        INPUT_OUTPUT => {
+            if parent.class == INPUT_ONLY {
+                return Err(XError::new(ErrorCode::Match, window.id));
+            }
+            if window.depth == 0 {
+                window.depth = parent.depth;
+            }
+        }
+        INPUT_ONLY => {
+            if window.depth != 0 || window.border_width != 0 {
+                return Err(XError::new(ErrorCode::Match, window.id));
+            }
+        }
+        class => return Err(XError::new(ErrorCode::Value, class as u32)),
+    }
+    if window.visual == 0 {
+        window.visual = parent.visual;
+    }
+    window.border = parent.border.clone();
+    window.attributes.colormap = parent.attributes.colormap;
+
+    let id = window.id;
+    let parent_id = window.parent;
+    windows.insert(id, window);
+    stack_on_top(&mut windows.get_mut(&parent_id).unwrap().children, id);
+    if let Err(error) = apply_attributes(windows, client, id, value_mask, values) {
+        windows.remove(&id);
+        windows.get_mut(&parent_id).unwrap().children.retain(|c| *c != id);
+        return Err(error);
+    }
+
+    let window = &windows[&id];
+    let event = Event::CreateNotify {
+        parent: parent_id,
+        window: id,
+        x: window.x,
+        y: window.y,
+        width: window.width,
+        height: window.height,
+        border_width: window.border_width,
+        override_redirect: window.override_redirect(),
+    };
+    deliver_event(windows, parent_id, SUBSTRUCTURE_NOTIFY_MASK, event);
+    validate(windows);
+    Ok(())
+}
+
+pub fn change_window_attributes(
+    windows: &mut BTreeMap<u32, Window>,
+    client: u32,
+    id: u32,
+    value_mask: u32,
+    values: &WindowAttributes,
+) -> Result<(), XError> {
+    get_window(windows, id)?;
+    apply_attributes(windows, client, id, value_mask, values)?;
+    if value_mask & 0b1100 != 0 {
+        let border_clip = windows[&id].border_clip.clone();
+        paint_border(&mut Drawables::lock(windows), id, &border_clip);
+    }
+    if value_mask & (1 << 14) != 0 {
+        INPUT.lock().unwrap().update_cursor(windows);
+    }
+    Ok(())
+}
+
+fn apply_attributes(
+    windows: &mut BTreeMap<u32, Window>,
+    client: u32,
+    id: u32,
+    value_mask: u32,
+    values: &WindowAttributes,
+) -> Result<(), XError> {
+    let parent = windows.get(&windows[&id].parent);
+    let parent_border = parent.map(|parent| parent.border.clone());
+    let parent_depth = parent.map(|parent| parent.depth);
+    let pixmaps = PIXMAPS.lock().unwrap();
+    let window = windows.get_mut(&id).unwrap();
+    if window.class == INPUT_ONLY && value_mask & 0b1111 != 0 {
+        return Err(XError::new(ErrorCode::Match, id));
+    }
+    // Only one client at a time may select each of these.
+    let exclusive = values.event_mask
+        & (BUTTON_PRESS_MASK | SUBSTRUCTURE_REDIRECT_MASK | RESIZE_REDIRECT_MASK);
+    if value_mask & (1 << 11) != 0
+        && window
+            .event_masks
+            .iter()
+            .any(|(other, selected)| *other != client && selected & exclusive != 0)
+    {
+        return Err(XError::new(ErrorCode::Access, id));
+    }
+    let depth = window.depth;
+    let lookup_pixmap = |pixmap: u32| -> Result<Pixmap, XError> {
+        let pixmap = pixmaps
+            .get(&pixmap)
+            .ok_or(XError::new(ErrorCode::Pixmap, pixmap))?;
+        if pixmap.depth != depth {
+            return Err(XError::new(ErrorCode::Match, id));
+        }
+        Ok(pixmap.clone())
+    };
+    let mut background = window.background.clone();
+    let mut border = window.border.clone();
+    if value_mask & (1 << 0) != 0 {
+        background = match values.background_pixmap {
+            0 | 1 if window.parent == 0 => Background::Pixel(DEFAULT_SCREEN.black_pixel),
+            0 => Background::None,
+            1 if parent_depth != Some(depth) => return Err(XError::new(ErrorCode::Match, id)),
+            1 => Background::ParentRelative,
+            pixmap => Background::Pixmap(lookup_pixmap(pixmap)?),
+        };
+    }
+    if value_mask & (1 << 1) != 0 {
+        background = Background::Pixel(values.background_pixel);
+    }
+    if value_mask & (1 << 2) != 0 {
+        border = match values.border_pixmap {
+            0 => parent_border.unwrap_or(Border::Pixel(DEFAULT_SCREEN.black_pixel)),
+            pixmap => Border::Pixmap(lookup_pixmap(pixmap)?),
+        };
+    }
+    if value_mask & (1 << 3) != 0 {
+        border = Border::Pixel(values.border_pixel);
+    }
+    if value_mask & (1 << 14) != 0 {
+        check_cursor(values.cursor)?;
+    }
+    window.background = background;
+    window.border = border;
+    if value_mask & (1 << 11) != 0 {
+        if values.event_mask == 0 {
+            window.event_masks.remove(&client);
+        } else {
+            window.event_masks.insert(client, values.event_mask);
+        }
+    }
+    window.attributes.merge(value_mask, values);
+    Ok(())
+}
+
+pub fn map_window(windows: &mut BTreeMap<u32, Window>, id: u32) -> Result<(), XError> {
+    let window = get_window(windows, id)?;
+    if window.mapped {
+        return Ok(());
+    }
+    let override_redirect = window.override_redirect();
+    windows.get_mut(&id).unwrap().mapped = true;
+    deliver_structure_event(windows, id, |event| Event::MapNotify {
+        event,
+        window: id,
+        override_redirect,
+    });
+    validate(windows);
+    Ok(())
+}
+
+pub fn map_subwindows(windows: &mut BTreeMap<u32, Window>, id: u32) -> Result<(), XError> {
+    let children = get_window(windows, id)?.children.clone();
+    for child in children.into_iter().rev() {
+        let window = &windows[&child];
+        if window.mapped {
+            continue;
+        }
+        let override_redirect = window.override_redirect();
+        windows.get_mut(&child).unwrap().mapped = true;
+        deliver_structure_event(windows, child, |event| Event::MapNotify {
+            event,
+            window: child,
+            override_redirect,
+        });
+    }
+    validate(windows);
+    Ok(())
+}
+
+fn unmap(windows: &mut BTreeMap<u32, Window>, id: u32, from_configure: bool) -> bool {
+    let window = &windows[&id];
+    if !window.mapped || window.parent == 0 {
+        return false;
+    }
+    windows.get_mut(&id).unwrap().mapped = false;
+    deliver_structure_event(windows, id, |event| Event::UnmapNotify {
+        event,
+        window: id,
+        from_configure,
+    });
+    // Release grabs and move the focus while the window and its ancestors still exist.
+    let mut input = INPUT.lock().unwrap();
+    input.release_unviewable_grabs(windows);
+    input.revert_unviewable_focus(windows);
+    input.update_pointer_window(windows);
+    true
+}
+
+pub fn unmap_window(windows: &mut BTreeMap<u32, Window>, id: u32) -> Result<(), XError> {
+    get_window(windows, id)?;
+    if unmap(windows, id, false) {
+        validate(windows);
+    }
+    Ok(())
+}
+
+pub fn unmap_subwindows(windows: &mut BTreeMap<u32, Window>, id: u32) -> Result<(), XError> {
+    let children = get_window(windows, id)?.children.clone();
+    let mut changed = false;
+    for child in children {
+        changed |= unmap(windows, child, false);
+    }
+    if changed {
+        validate(windows);
+    }
+    Ok(())
+}
+
+fn destroy(windows: &mut BTreeMap<u32, Window>, id: u32) {
+    for child in windows[&id].children.clone() {
+        destroy(windows, child);
+    }
+    deliver_structure_event(windows, id, |event| Event::DestroyNotify { event, window: id });
+    let window = windows.remove(&id).unwrap();
+    release_window_selections(windows, id);
+    free_drawable_damages(id);
+    remove_from_save_sets(id);
+    if let Some(parent) = windows.get_mut(&window.parent) {
+        parent.children.retain(|c| *c != id);
+    }
+}
+
+pub fn destroy_window(windows: &mut BTreeMap<u32, Window>, id: u32) -> Result<(), XError> {
+    if get_window(windows, id)?.parent == 0 {
+        return Ok(());
+    }
+    unmap(windows, id, false);
+    destroy(windows, id);
+    validate(windows);
+    Ok(())
+}
+
+/// Destroys the windows created by `client`, as part of freeing its resources.
+pub fn destroy_client_windows(windows: &mut BTreeMap<u32, Window>, client: u32) {
+    let owned: Vec<u32> = windows
+        .keys()
+        .filter(|id| resource_owner(**id) == client)
+        .copied()
+        .collect();
+    let mut destroyed = false;
+    for id in owned {
+        if windows.contains_key(&id) {
+            unmap(windows, id, false);
+            destroy(windows, id);
+            destroyed = true;
+        }
+    }
+    if destroyed {
+        validate(windows);
+    }
+}
+
+pub fn destroy_subwindows(windows: &mut BTreeMap<u32, Window>, id: u32) -> Result<(), XError> {
+    let children = get_window(windows, id)?.children.clone();
+    for child in children.into_iter().rev() {
+        unmap(windows, child, false);
+        destroy(windows, child);
+    }
+    validate(windows);
+    Ok(())
+}
+
+/// Moves `id` to the top of `parent`'s children at `x`, `y`, unmapping it first and
+/// mapping it again afterwards if it was mapped.
+fn reparent(windows: &mut BTreeMap<u32, Window>, id: u32, parent: u32, x: i16, y: i16) {
+    let mapped = unmap(windows, id, false);
+    let window = &windows[&id];
+    let old_parent = window.parent;
+    let override_redirect = window.override_redirect();
+    let event = |event| Event::ReparentNotify {
+        event,
+        window: id,
+        parent,
+        x,
+        y,
+        override_redirect,
+    };
+    deliver_event(windows, id, STRUCTURE_NOTIFY_MASK, event(id));
+    deliver_event(windows, old_parent, SUBSTRUCTURE_NOTIFY_MASK, event(old_parent));
+    if parent != old_parent {
+        deliver_event(windows, parent, SUBSTRUCTURE_NOTIFY_MASK, event(parent));
+    }
+    windows.get_mut(&old_parent).unwrap().children.retain(|c| *c != id);
+    stack_on_top(&mut windows.get_mut(&parent).unwrap().children, id);
+    let window = windows.get_mut(&id).unwrap();
+    window.parent = parent;
+    window.x = x;
+    window.y = y;
+    if mapped {
+        window.mapped = true;
+        deliver_structure_event(windows, id, |event| Event::MapNotify {
+            event,
+            window: id,
+            override_redirect,
+        });
+    }
+}
+
+pub fn reparent_window(
+    windows: &mut BTreeMap<u32, Window>,
+    id: u32,
+    parent: u32,
+    x: i16,
+    y: i16,
+) -> Result<(), XError> {
+    let window = get_window(windows, id)?;
+    let new_parent = get_window(windows, parent)?;
+    if window.parent == 0
+        || is_inferior_or_self(windows, parent, id)
+        || (window.class == INPUT_OUTPUT && new_parent.class == INPUT_ONLY)
+        || (matches!(window.background, Background::ParentRelative) && window.depth != new_parent.depth)
+    {
+        return Err(XError::new(ErrorCode::Match, id));
+    }
+    reparent(windows, id, parent, x, y);
+    validate(windows);
+    Ok(())
+}
+
+/// Rescues the save-set windows of `client` that are inside windows it created, moving
+/// them to the closest ancestor outside them or to the root. Save-set windows are
+/// mapped, or unmapped before being moved if the client asked for that instead.
+pub fn restore_save_set(
+    windows: &mut BTreeMap<u32, Window>,
+    client: u32,
+    save_set: &BTreeMap<u32, SaveSetMode>,
+) {
+    let root = DEFAULT_SCREEN.root_window;
+    let mut changed = false;
+    for (&id, mode) in save_set {
+        if !windows.contains_key(&id) {
+            continue;
+        }
+        let mut outermost = None;
+        let mut ancestor = windows[&id].parent;
+        while ancestor != 0 {
+            if resource_owner(ancestor) == client {
+                outermost = Some(ancestor);
+            }
+            ancestor = windows[&ancestor].parent;
+        }
+        let new_parent = match mode.to_root {
+            true => Some(root).filter(|_| id != root && windows[&id].parent != root),
+            false => outermost.map(|outermost| windows[&outermost].parent),
+        };
+        if let Some(parent) = new_parent {
+            if mode.unmap {
+                unmap(windows, id, false);
+            }
+            // Keep the window where it is on screen.
+            let origin = windows[&parent].screen_rectangle;
+            let window = &windows[&id];
+            let x = window.screen_rectangle.x - window.border_width as i32 - origin.x;
+            let y = window.screen_rectangle.y - window.border_width as i32 - origin.y;
+            reparent(windows, id, parent, x as i16, y as i16);
+            changed = true;
+        }
+        if mode.unmap {
+            continue;
+        }
+        let window = windows.get_mut(&id).unwrap();
+        if !window.mapped {
+            window.mapped = true;
+            let override_redirect = window.override_redirect();
+            deliver_structure_event(windows, id, |event| Event::MapNotify {
+                event,
+                window: id,
+                override_redirect,
+            });
+            changed = true;
+        }
+    }
+    if changed {
+        validate(windows);
+    }
+}
+
+/// Returns true if `upper` is stacked above `lower` and their outer areas overlap.
+fn occludes(windows: &BTreeMap<u32, Window>, upper: u32, lower: u32) -> bool {
+    let (upper_window, lower_window) = (&windows[&upper], &windows[&lower]);
+    let siblings = &windows[&upper_window.parent].children;
+    let position = |id| siblings.iter().position(|c| *c == id);
+    upper_window.mapped
+        && lower_window.mapped
+        && position(upper) > position(lower)
+        && upper_window
+            .outer_rectangle()
+            .intersect(&lower_window.outer_rectangle())
+            .is_some()
+}
+
+fn restack(windows: &mut BTreeMap<u32, Window>, id: u32, sibling: Option<u32>, stack_mode: u32) {
+    if id == OVERLAY_WINDOW {
+        return;
+    }
+    let parent = windows[&id].parent;
+    let others: Vec<u32> = match sibling {
+        Some(sibling) => vec![sibling],
+        None => windows[&parent]
+            .children
+            .iter()
+            .copied()
+            .filter(|c| *c != id)
+            .collect(),
+    };
+    let any_occludes_window = others.iter().any(|other| occludes(windows, *other, id));
+    let window_occludes_any = others.iter().any(|other| occludes(windows, id, *other));
+    let children = &mut windows.get_mut(&parent).unwrap().children;
+    let to_top = |children: &mut Vec<u32>| {
+        children.retain(|c| *c != id);
+        stack_on_top(children, id);
+    };
+    let to_bottom = |children: &mut Vec<u32>| {
+        children.retain(|c| *c != id);
+        children.insert(0, id);
+    };
+    match (stack_mode, sibling) {
+        (STACK_ABOVE, Some(sibling)) if sibling != OVERLAY_WINDOW => {
+            children.retain(|c| *c != id);
+            let index = children.iter().position(|c| *c == sibling).unwrap();
+            children.insert(index + 1, id);
+        }
+        (STACK_ABOVE, _) => to_top(children),
+        (STACK_BELOW, Some(sibling)) => {
+            children.retain(|c| *c != id);
+            let index = children.iter().position(|c| *c == sibling).unwrap();
+            children.insert(index, id);
+        }
+        (STACK_BELOW, None) => to_bottom(children),
+        (STACK_TOP_IF, _) if any_occludes_window => to_top(children),
+        (STACK_BOTTOM_IF, _) if window_occludes_any => to_bottom(children),
+        (STACK_OPPOSITE, _) if any_occludes_window => to_top(children),
+        (STACK_OPPOSITE, _) if window_occludes_any => to_bottom(children),
+        _ => {}
+    }
+}
+
+pub fn configure_window(
+    windows: &mut BTreeMap<u32, Window>,
+    id: u32,
+    value_mask: u16,
+    values: &ConfigureValues,
+) -> Result<(), XError> {
+    let window = get_window(windows, id)?;
+    if window.parent == 0 {
+        return Ok(());
+    }
+    let sibling = (value_mask & (1 << 5) != 0).then_some(values.sibling);
+    if let Some(sibling) = sibling {
+        if value_mask & (1 << 6) == 0 || sibling == id {
+            return Err(XError::new(ErrorCode::Match, sibling));
+        }
+        match windows.get(&sibling) {
+            Some(s) if s.parent == window.parent => {}
+            Some(_) => return Err(XError::new(ErrorCode::Match, sibling)),
+            None => return Err(XError::new(ErrorCode::Window, sibling)),
+        }
+    }
+    if value_mask & (1 << 2) != 0 && values.width as u16 == 0
+        || value_mask & (1 << 3) != 0 && values.height as u16 == 0
+    {
+        return Err(XError::new(ErrorCode::Value, 0));
+    }
+    if value_mask & (1 << 6) != 0 && values.stack_mode > STACK_OPPOSITE {
+        return Err(XError::new(ErrorCode::Value, values.stack_mode));
+    }
+    if window.class == INPUT_ONLY && value_mask & (1 << 4) != 0 && values.border_width != 0 {
+        return Err(XError::new(ErrorCode::Match, id));
+    }
+
+    let window = windows.get_mut(&id).unwrap();
+    if value_mask & (1 << 0) != 0 {
+        window.x = values.x as i16;
+    }
+    if value_mask & (1 << 1) != 0 {
+        window.y = values.y as i16;
+    }
+    if value_mask & (1 << 2) != 0 {
+        window.width = values.width as u16;
+    }
+    if value_mask & (1 << 3) != 0 {
+        window.height = values.height as u16;
+    }
+    if value_mask & (1 << 4) != 0 {
+        window.border_width = values.border_width as u16;
+    }
+    if value_mask & (1 << 6) != 0 {
+        restack(windows, id, sibling, values.stack_mode);
+    }
+
+    let window = &windows[&id];
+    let siblings = &windows[&window.parent].children;
+    let index = siblings.iter().position(|c| *c == id).unwrap();
+    let above_sibling = if index == 0 { 0 } else { siblings[index - 1] };
+    let (x, y, width, height, border_width) = (
+        window.x,
+        window.y,
+        window.width,
+        window.height,
+        window.border_width,
+    );
+    let override_redirect = window.override_redirect();
+    deliver_structure_event(windows, id, |event| Event::ConfigureNotify {
+        event,
+        window: id,
+        above_sibling,
+        x,
+        y,
+        width,
+        height,
+        border_width,
+        override_redirect,
+    });
+    validate(windows);
+    Ok(())
+}
+
+/// Places `id` and its descendants on the screen and works out the surface each draws
+/// to, `surface` being the parent's.
+fn update_screen_rectangles(
+    windows: &mut BTreeMap<u32, Window>,
+    id: u32,
+    origin_x: i32,
+    origin_y: i32,
+    surface: u32,
+) {
+    let surface = match redirect_mode(windows, id) {
+        Some(_) => id,
+        None => surface,
+    };
+    let window = windows.get_mut(&id).unwrap();
+    let border_width = window.border_width as i32;
+    window.screen_rectangle = Rectangle::new(
+        origin_x + window.x as i32 + border_width,
+        origin_y + window.y as i32 + border_width,
+        window.width as i32,
+        window.height as i32,
+    );
+    window.surface = surface;
+    let (x, y) = (window.screen_rectangle.x, window.screen_rectangle.y);
+    for child in window.children.clone() {
+        update_screen_rectangles(windows, child, x, y, surface);
+    }
+}
+
+fn compute_clips(windows: &mut BTreeMap<u32, Window>, id: u32, available: Region) {
+    let mut remaining = available;
+    for child in windows[&id].children.clone().into_iter().rev() {
+        let window = &windows[&child];
+        if !window.mapped {
+            continue;
+        }
+        let inner = window.screen_rectangle;
+        let bounding = window.bounding_region();
+        let interior = window.clip_region();
+        let mut visible = remaining.intersect(&bounding);
+        if window.surface == child {
+            // A redirected window is drawn whole to its own pixmap and leaves its parent
+            // and siblings drawing underneath it.
+            let composited = redirect_mode(windows, child) == Some(REDIRECT_AUTOMATIC);
+            let window = windows.get_mut(&child).unwrap();
+            window.outer_clip = visible;
+            window.composited = composited;
+            visible = bounding;
+            window.border_clip = visible.subtract_rectangle(&inner);
+        } else if window.class != INPUT_ONLY {
+            remaining = remaining.subtract(&bounding);
+            let window = windows.get_mut(&child).unwrap();
+            window.outer_clip = visible.clone();
+            window.border_clip = visible.subtract_rectangle(&inner);
+        }
+        compute_clips(windows, child, visible.intersect(&interior));
+    }
+    let window = windows.get_mut(&id).unwrap();
+    if window.class != INPUT_ONLY {
+        window.clip = remaining;
+    }
+}
+
+/// A window's surface and its clips and interior relative to the surface's origin,
+/// which stay the same when a redirected window moves.
+fn surface_state(windows: &BTreeMap<u32, Window>, window: &Window) -> (u32, Region, Region, Rectangle) {
+    let (x, y) = surface_origin(windows, window.surface);
+    (
+        window.surface,
+        window.clip.translate(-x, -y),
+        window.border_clip.translate(-x, -y),
+        window.screen_rectangle.translate(-x, -y),
+    )
+}
+
+/// Where each automatically redirected window shows, by window: the surface it is
+/// composited into and its clip there, relative to that surface's origin.
+fn composited_clips(windows: &BTreeMap<u32, Window>) -> BTreeMap<u32, (u32, Region)> {
+    windows
+        .keys()
+        .filter_map(|id| {
+            let (surface, clip) = composited_into(windows, *id)?;
+            let (x, y) = surface_origin(windows, surface);
+            Some((*id, (surface, clip.translate(-x, -y))))
+        })
+        .collect()
+}
+
+/// Recomputes the clip of every window after a change to the tree, then paints and
+/// sends Expose events for everything that became visible, including what
+/// automatically redirected windows no longer cover.
+pub fn validate(windows: &mut BTreeMap<u32, Window>) {
+    let old: BTreeMap<u32, (u32, Region, Region, Rectangle)> =
+        windows.values().map(|w| (w.id, surface_state(windows, w))).collect();
+    let old_composited = composited_clips(windows);
+    for window in windows.values_mut() {
+        window.clip = Region::new();
+        window.border_clip = Region::new();
+        window.outer_clip = Region::new();
+        window.composited = false;
+    }
+    let root = DEFAULT_SCREEN.root_window;
+    update_screen_rectangles(windows, root, 0, 0, 0);
+    let root_rectangle = windows[&root].screen_rectangle;
+    compute_clips(windows, root, Region::from_rectangle(root_rectangle));
+    let fresh = update_backing_pixmaps(windows);
+    let composited = composited_clips(windows);
+    // What automatically redirected windows no longer cover still shows their pixels.
+    let mut uncovered: BTreeMap<u32, Region> = BTreeMap::new();
+    for (id, (surface, old_clip)) in &old_composited {
+        let clip = composited.get(id).filter(|(s, _)| s == surface).map(|(_, clip)| clip);
+        let lost = clip.map_or(old_clip.clone(), |clip| old_clip.subtract(clip));
+        let entry = uncovered.entry(*surface).or_default();
+        *entry = entry.union(&lost);
+    }
+
+    let windows: &BTreeMap<u32, Window> = windows;
+    let mut drawables = Drawables::lock(windows);
+    for window in windows.values() {
+        let (old_surface, old_clip, old_border_clip, old_rectangle) =
+            old.get(&window.id).cloned().unwrap_or_default();
+        let (surface, clip, border_clip, rectangle) = surface_state(windows, window);
+        let moved = (old_surface, old_rectangle) != (surface, rectangle) || fresh.contains(&surface);
+        let (mut exposed, mut border_exposed) = if moved {
+            (clip.clone(), border_clip.clone())
+        } else {
+            (clip.subtract(&old_clip), border_clip.subtract(&old_border_clip))
+        };
+        if let Some(uncovered) = uncovered.get(&surface) {
+            exposed = exposed.union(&clip.intersect(uncovered));
+            border_exposed = border_exposed.union(&border_clip.intersect(uncovered));
+        }
+        let (x, y) = surface_origin(windows, surface);
+        let (exposed, border_exposed) = (exposed.translate(x, y), border_exposed.translate(x, y));
+        paint_border(&mut drawables, window.id, &border_exposed);
+        paint_background(&mut drawables, window.id, &exposed);
+        send_exposures(windows, window.id, &exposed);
+    }
+    // Shows automatically redirected windows where they newly appear in their parents.
+    for (id, (surface, clip)) in &composited {
+        if fresh.contains(id) || old_composited.get(id) != Some(&(*surface, clip.clone())) {
+            let (x, y) = surface_origin(windows, *surface);
+            damage_surface(&mut drawables, *surface, &clip.translate(x, y));
+        }
+    }
+    drop(drawables);
+    let mut input = INPUT.lock().unwrap();
+    input.release_unviewable_grabs(windows);
+    input.update_pointer_window(windows);
+    process_pending(windows, &mut input);
+}
+
+/// Paints `region` (in screen coordinates) of the window's border to its surface.
+pub fn paint_border(drawables: &mut Drawables, id: u32, region: &Region) {
+    let windows = drawables.windows;
+    let window = &windows[&id];
+    let (origin_x, origin_y) = (window.screen_rectangle.x, window.screen_rectangle.y);
+    let Some((pixels, surface_x, surface_y)) = drawables.surface(window.surface) else {
+        return;
+    };
+    for rectangle in &region.rectangles {
+        let rectangle = rectangle.translate(-surface_x, -surface_y);
+        match &window.border {
+            Border::Pixel(pixel) => pixels.fill_rectangle(&rectangle, *pixel),
+            Border::Pixmap(tile) => {
+                pixels.tile_rectangle(&rectangle, tile, origin_x - surface_x, origin_y - surface_y)
+            }
+        }
+    }
+    damage_surface(drawables, window.surface, region);
+}
+
+/// Fills `region` (in screen coordinates) with the window's background, following
+/// ParentRelative up the tree. Windows with background None are left untouched.
+pub fn paint_background(drawables: &mut Drawables, id: u32, region: &Region) {
+    let windows = drawables.windows;
+    let surface = windows[&id].surface;
+    let mut window = &windows[&id];
+    while let Background::ParentRelative = window.background {
+        window = &windows[&window.parent];
+    }
+    let (origin_x, origin_y) = (window.screen_rectangle.x, window.screen_rectangle.y);
+    let Some((pixels, surface_x, surface_y)) = drawables.surface(surface) else {
+        return;
+    };
+    for rectangle in &region.rectangles {
+        let rectangle = rectangle.translate(-surface_x, -surface_y);
+        match &window.background {
+            Background::Pixel(pixel) => pixels.fill_rectangle(&rectangle, *pixel),
+            Background::Pixmap(tile) => {
+                pixels.tile_rectangle(&rectangle, tile, origin_x - surface_x, origin_y - surface_y)
+            }
+            Background::None | Background::ParentRelative => return,
+        }
+    }
+    damage_surface(drawables, surface, region);
+}
+
+/// Sends Expose events for `region`, given in screen coordinates.
+pub fn send_exposures(windows: &BTreeMap<u32, Window>, id: u32, region: &Region) {
+    let window = &windows[&id];
+    if window.class == INPUT_ONLY {
+        return;
+    }
+    let (origin_x, origin_y) = (window.screen_rectangle.x, window.screen_rectangle.y);
+    let count = region.rectangles.len();
+    for (index, rectangle) in region.rectangles.iter().enumerate() {
+        let event = Event::Expose {
+            window: id,
+            x: (rectangle.x - origin_x) as u16,
+            y: (rectangle.y - origin_y) as u16,
+            width: rectangle.width as u16,
+            height: rectangle.height as u16,
+            count: (count - index - 1) as u16,
+        };
+        deliver_event(windows, id, EXPOSURE_MASK, event);
+    }
+}
+
+/// Implements ClearArea; `rectangle` is relative to the window and a zero width or
+/// height extends to the window's edge.
+pub fn clear_area(
+    windows: &BTreeMap<u32, Window>,
+    id: u32,
+    mut rectangle: Rectangle,
+    exposures: bool,
+) -> Result<(), XError> {
+    let window = get_window(windows, id)?;
+    if window.class == INPUT_ONLY {
+        return Err(XError::new(ErrorCode::Match, id));
+    }
+    if rectangle.width == 0 {
+        rectangle.width = window.width as i32 - rectangle.x;
+    }
+    if rectangle.height == 0 {
+        rectangle.height = window.height as i32 - rectangle.y;
+    }
+    let rectangle = rectangle.translate(window.screen_rectangle.x, window.screen_rectangle.y);
+    let region = window.clip.intersect_rectangle(&rectangle);
+    paint_background(&mut Drawables::lock(windows), id, &region);
+    if exposures {
+        send_exposures(windows, id, &region);
+    }
+    Ok(())
+}
@@ -0,0 +1,915 @@
+//! The XFIXES extension, version 5.0: server-side regions, selection and cursor change
+//! events, cursor images and names, XFIXES save-set modes, hiding the cursor and
+//! pointer barriers. GCs keep no clip in this server, so the GC region requests only
+//! check their region.
+
+use std::{collections::BTreeMap, sync::Mutex};
+
+use crate::{
+    atom::{atom_exists, get_atom, ATOMS},
+    client::{change_save_set, queue_event, resource_owner, SaveSetMode},
+    cursor::{get_cursor, Cursor, CURSORS},
+    error::{ErrorCode, XError},
+    event::Event,
+    extension::{find_extension, ExtensionRequest},
+    input::INPUT,
+    picture::PICTURES,
+    pixmap::bitmap_region,
+    region::{Rectangle, Region},
+    render::{bad_picture, read_rectangles},
+    shape::{combine, region_from_rectangles, SHAPE_BOUNDING, SHAPE_CLIP, SHAPE_SET},
+    time,
+    window::{get_window, Window, WINDOWS},
+    xinput::{check_master_device, VIRTUAL_CORE_POINTER},
+};
+
+pub static XFIXES_NAME: &str = "XFIXES";
+pub const XFIXES_MAJOR_VERSION: u32 = 5;
+pub const XFIXES_MINOR_VERSION: u32 = 0;
+
+/// Errors relative to the extension's first error.
+const BAD_REGION: u8 = 0;
+
+/// Events relative to the extension's first event.
+pub const SELECTION_NOTIFY: u8 = 0;
+pub const CURSOR_NOTIFY: u8 = 1;
+
+/// The subtypes of selection events, each selected by the mask bit of the same number.
+pub const SET_SELECTION_OWNER_NOTIFY: u8 = 0;
+pub const SELECTION_WINDOW_DESTROY_NOTIFY: u8 = 1;
+pub const SELECTION_CLIENT_CLOSE_NOTIFY: u8 = 2;
+const SELECTION_EVENT_MASK: u32 = 0b111;
+
+pub const DISPLAY_CURSOR_NOTIFY: u8 = 0;
+const DISPLAY_CURSOR_NOTIFY_MASK: u32 = 1;
+
+/// The minor opcodes of the region operations that share a request layout.
+const UNION_REGION: u8 = 13;
+const INTERSECT_REGION: u8 = 14;
+
+const SAVE_SET_NEAREST: u8 = 0;
+const SAVE_SET_ROOT: u8 = 1;
+const SAVE_SET_MAP: u8 = 0;
+const SAVE_SET_UNMAP: u8 = 1;
+
+/// The directions a pointer barrier lets the pointer cross in.
+pub const BARRIER_POSITIVE_X: u32 = 1 << 0;
+pub const BARRIER_POSITIVE_Y: u32 = 1 << 1;
+pub const BARRIER_NEGATIVE_X: u32 = 1 << 2;
+pub const BARRIER_NEGATIVE_Y: u32 = 1 << 3;
+
+/// Regions by id. Lock after `WINDOWS` and `PICTURES`.
+pub static REGIONS: Mutex<BTreeMap<u32, Region>> = Mutex::new(BTreeMap::new());
+
+/// Pointer barriers by id. Lock after `INPUT`.
+pub static BARRIERS: Mutex<BTreeMap<u32, Barrier>> = Mutex::new(BTreeMap::new());
+
+/// The clients that hid the cursor, with the window they named. Cursors are never
+/// drawn, so this only decides which ShowCursor requests match.
+static CURSOR_HIDDEN: Mutex<BTreeMap<u32, u32>> = Mutex::new(BTreeMap::new());
+
+/// A horizontal or vertical line the pointer can't cross, except in the directions
+/// allowed. Vertical barriers stop the pointer between x1 - 1 and x1, and horizontal
+/// barriers between y1 - 1 and y1.
+#[derive(Clone, Debug)]
+pub struct Barrier {
+    pub window: u32,
+    pub x1: i32,
+    pub y1: i32,
+    pub x2: i32,
+    pub y2: i32,
+    pub directions: u32,
+    /// The master devices the barrier stops, or every master if empty.
+    pub devices: Vec<u16>,
+}
+
+impl Barrier {
+    fn stops_core_pointer(&self) -> bool {
+        self.devices.is_empty() || self.devices.contains(&VIRTUAL_CORE_POINTER)
+    }
+
+    /// How far along the motion from `from` to `to` the barrier stops the pointer, as a
+    /// fraction of the motion, or `None` if it lets the pointer through.
+    fn blocks(&self, from: (i32, i32), to: (i32, i32)) -> Option<f64> {
+        // Work along the axis the barrier is across, then check the other axis.
+        let ((p0, p1), (q0, q1), line, (low, high), (positive, negative)) = match self.x1 == self.x2 {
+            true => (
+                (from.0, to.0),
+                (from.1, to.1),
+                self.x1,
+                (self.y1, self.y2),
+                (BARRIER_POSITIVE_X, BARRIER_NEGATIVE_X),
+            ),
+            false => (
+                (from.1, to.1),
+                (from.0, to.0),
+                self.y1,
+                (self.x1, self.x2),
+                (BARRIER_POSITIVE_Y, BARRIER_NEGATIVE_Y),
+            ),
+        };
+        let crosses = match p1 > p0 {
+            true => p0 < line && p1 >= line && self.directions & positive == 0,
+            false => p0 >= line && p1 < line && self.directions & negative == 0,
+        };
+        if !crosses {
+            return None;
+        }
+        let fraction = (line - p0) as f64 / (p1 - p0) as f64;
+        let q = q0 as f64 + fraction * (q1 - q0) as f64;
+        (low as f64 <= q && q <= high as f64).then_some(fraction)
+    }
+}
+
+/// Stops core pointer motion from `from` to `to` at the barriers in its way, returning
+/// where the pointer ends up. After a barrier stops one axis the pointer keeps moving
+/// along the other, which another barrier may stop.
+pub fn apply_barriers(from: (i32, i32), mut to: (i32, i32)) -> (i32, i32) {
+    let barriers = BARRIERS.lock().unwrap();
+    let mut hit = vec![];
+    loop {
+        let nearest = barriers
+            .iter()
+            .filter(|(id, barrier)| !hit.contains(*id) && barrier.stops_core_pointer())
+            .filter_map(|(id, barrier)| barrier.blocks(from, to).map(|fraction| (fraction, id, barrier)))
+            .min_by(|a, b| a.0.total_cmp(&b.0));
+        let Some((_, id, barrier)) = nearest else {
+            return to;
+        };
+        hit.push(*id);
+        if barrier.x1 == barrier.x2 {
+            to.0 = if to.0 > from.0 { barrier.x1 - 1 } else { barrier.x1 };
+        } else {
+            to.1 = if to.1 > from.1 { barrier.y1 - 1 } else { barrier.y1 };
+        }
+    }
+}
+
+fn xfixes_error(offset: u8, bad_value: u32) -> XError {
+    let first_error = find_extension(XFIXES_NAME).map_or(0, |e| e.first_error);
+    XError::extension(first_error + offset, bad_value)
+}
+
+fn bad_region(region: u32) -> XError {
+    xfixes_error(BAD_REGION, region)
+}
+
+pub fn get_region(regions: &BTreeMap<u32, Region>, id: u32) -> Result<&Region, XError> {
+    regions.get(&id).ok_or(bad_region(id))
+}
+
+/// Sends XFIXES selection events of `subtype` about `selection`, whose owner is now
+/// `owner`, to the clients that selected them.
+pub fn selection_notify(
+    windows: &BTreeMap<u32, Window>,
+    subtype: u8,
+    selection: u32,
+    owner: u32,
+    selection_timestamp: u32,
+) {
+    let timestamp = time::now();
+    for window in windows.values() {
+        for ((client, selected), mask) in &window.selection_masks {
+            if *selected == selection && mask & 1 << subtype != 0 {
+                let event = Event::XFixesSelectionNotify {
+                    subtype,
+                    window: window.id,
+                    owner,
+                    selection,
+                    timestamp,
+                    selection_timestamp,
+                };
+                queue_event(*client, event);
+            }
+        }
+    }
+}
+
+/// Tells the clients that selected cursor events that the pointer shows another cursor.
+pub fn cursor_notify(windows: &BTreeMap<u32, Window>, cursor_serial: u32, name: u32) {
+    let timestamp = time::now();
+    for window in windows.values() {
+        for (client, mask) in &window.cursor_masks {
+            if mask & DISPLAY_CURSOR_NOTIFY_MASK != 0 {
+                let event = Event::XFixesCursorNotify {
+                    subtype: DISPLAY_CURSOR_NOTIFY,
+                    window: window.id,
+                    cursor_serial,
+                    timestamp,
+                    name,
+                };
+                queue_event(*client, event);
+            }
+        }
+    }
+}
+
+/// Frees the regions and barriers of a client whose resources are destroyed, and shows
+/// the cursor if it hid it.
+pub fn free_client_xfixes_resources(client: u32) {
+    REGIONS.lock().unwrap().retain(|id, _| resource_owner(*id) != client);
+    BARRIERS.lock().unwrap().retain(|id, _| resource_owner(*id) != client);
+    CURSOR_HIDDEN.lock().unwrap().remove(&client);
+}
+
+#[derive(Debug)]
+pub enum XFixesRequest {
+    QueryVersion {
+        major_version: u32,
+        minor_version: u32,
+    },
+    ChangeSaveSet {
+        mode: u8,
+        target: u8,
+        map: u8,
+        window: u32,
+    },
+    SelectSelectionInput {
+        window: u32,
+        selection: u32,
+        event_mask: u32,
+    },
+    SelectCursorInput {
+        window: u32,
+        event_mask: u32,
+    },
+    GetCursorImage,
+    CreateRegion {
+        region: u32,
+        rectangles: Vec<Rectangle>,
+    },
+    CreateRegionFromBitmap {
+        region: u32,
+        bitmap: u32,
+    },
+    CreateRegionFromWindow {
+        region: u32,
+        window: u32,
+        kind: u8,
+    },
+    CreateRegionFromGC {
+        region: u32,
+        gc: u32,
+    },
+    CreateRegionFromPicture {
+        region: u32,
+        picture: u32,
+    },
+    DestroyRegion {
+        region: u32,
+    },
+    SetRegion {
+        region: u32,
+        rectangles: Vec<Rectangle>,
+    },
+    CopyRegion {
+        source: u32,
+        destination: u32,
+    },
+    /// UnionRegion, IntersectRegion and SubtractRegion, told apart by the minor opcode.
+    CombineRegion {
+        operation: u8,
+        source1: u32,
+        source2: u32,
+        destination: u32,
+    },
+    InvertRegion {
+        source: u32,
+        bounds: Rectangle,
+        destination: u32,
+    },
+    TranslateRegion {
+        region: u32,
+        dx: i16,
+        dy: i16,
+    },
+    RegionExtents {
+        source: u32,
+        destination: u32,
+    },
+    FetchRegion {
+        region: u32,
+    },
+    SetGCClipRegion {
+        gc: u32,
+        region: u32,
+        x_origin: i16,
+        y_origin: i16,
+    },
+    SetWindowShapeRegion {
+        window: u32,
+        kind: u8,
+        x_offset: i16,
+        y_offset: i16,
+        region: u32,
+    },
+    SetPictureClipRegion {
+        picture: u32,
+        region: u32,
+        x_origin: i16,
+        y_origin: i16,
+    },
+    SetCursorName {
+        cursor: u32,
+        name: String,
+    },
+    GetCursorName {
+        cursor: u32,
+    },
+    GetCursorImageAndName,
+    ChangeCursor {
+        source: u32,
+        destination: u32,
+    },
+    ChangeCursorByName {
+        source: u32,
+        name: String,
+    },
+    ExpandRegion {
+        source: u32,
+        destination: u32,
+        left: u16,
+        right: u16,
+        top: u16,
+        bottom: u16,
+    },
+    HideCursor {
+        window: u32,
+    },
+    ShowCursor {
+        window: u32,
+    },
+    CreatePointerBarrier {
+        barrier: u32,
+        window: u32,
+        x1: i16,
+        y1: i16,
+        x2: i16,
+        y2: i16,
+        directions: u32,
+        devices: Vec<u16>,
+    },
+    DeletePointerBarrier {
+        barrier: u32,
+    },
+}
+
+fn read_xfixes_request(request: &ExtensionRequest) -> Result<XFixesRequest, XError> {
+    let data = request.data;
+    let minimum_length = match request.minor_opcode {
+        4 | 25 => 0,
+        5 | 10 | 11 | 19 | 24 | 29 | 30 | 32 => 4,
+        0 | 1 | 3 | 6 | 8 | 9 | 12 | 17 | 18 | 23 | 26 | 27 => 8,
+        2 | 7 | 13..=15 | 20 | 22 => 12,
+        16 | 21 | 28 => 16,
+        31 => 24,
+        minor_opcode => return Err(XError::new(ErrorCode::Request, minor_opcode as u32)),
+    };
+    if data.len() < minimum_length {
+        return Err(XError::new(ErrorCode::Length, 0));
+    }
+    let name = |bytes: &[u8]| -> Result<String, XError> {
+        let length = request.card16(bytes) as usize;
+        let name = data.get(8..8 + length).ok_or(XError::new(ErrorCode::Length, 0))?;
+        Ok(String::from_utf8_lossy(name).to_string())
+    };
+    Ok(match request.minor_opcode {
+        0 => XFixesRequest::QueryVersion {
+            major_version: request.card32(data),
+            minor_version: request.card32(&data[4..]),
+        },
+        1 => XFixesRequest::ChangeSaveSet {
+            mode: data[0],
+            target: data[1],
+            map: data[2],
+            window: request.card32(&data[4..]),
+        },
+        2 => XFixesRequest::SelectSelectionInput {
+            window: request.card32(data),
+            selection: request.card32(&data[4..]),
+            event_mask: request.card32(&data[8..]),
+        },
+        3 => XFixesRequest::SelectCursorInput {
+            window: request.card32(data),
+            event_mask: request.card32(&data[4..]),
+        },
+        4 => XFixesRequest::GetCursorImage,
+        5 => XFixesRequest::CreateRegion {
+            region: request.card32(data),
+            rectangles: read_rectangles(request, &data[4..]),
+        },
+        6 => XFixesRequest::CreateRegionFromBitmap {
+            region: request.card32(data),
+            bitmap: request.card32(&data[4..]),
+        },
+        7 => XFixesRequest::CreateRegionFromWindow {
+            region: request.card32(data),
+            window: request.card32(&data[4..]),
+            kind: data[8],
+        },
+        8 => XFixesRequest::CreateRegionFromGC {
+            region: request.card32(data),
+            gc: request.card32(&data[4..]),
+        },
+        9 => XFixesRequest::CreateRegionFromPicture {
+            region: request.card32(data),
+            picture: request.card32(&data[4..]),
+        },
+        10 => XFixesRequest::DestroyRegion {
+            region: request.card32(data),
+        },
+        11 => XFixesRequest::SetRegion {
+            region: request.card32(data),
+            rectangles: read_rectangles(request, &data[4..]),
+        },
+        12 => XFixesRequest::CopyRegion {
+            source: request.card32(data),
+            destination: request.card32(&data[4..]),
+        },
+        13..=15 => XFixesRequest::CombineRegion {
+            operation: request.minor_opcode,
+            source1: request.card32(data),
+            source2: request.card32(&data[4..]),
+            destination: request.card32(&data[8..]),
+        },
+        16 => XFixesRequest::InvertRegion {
+            source: request.card32(data),
+            bounds: read_rectangles(request, &data[4..12])[0],
+            destination: request.card32(&data[12..]),
+        },
+        17 => XFixesRequest::TranslateRegion {
+            region: request.card32(data),
+            dx: request.int16(&data[4..]),
+            dy: request.int16(&data[6..]),
+        },
+        18 => XFixesRequest::RegionExtents {
+            source: request.card32(data),
+            destination: request.card32(&data[4..]),
+        },
+        19 => XFixesRequest::FetchRegion {
+            region: request.card32(data),
+        },
+        20 => XFixesRequest::SetGCClipRegion {
+            gc: request.card32(data),
+            region: request.card32(&data[4..]),
+            x_origin: request.int16(&data[8..]),
+            y_origin: request.int16(&data[10..]),
+        },
+        21 => XFixesRequest::SetWindowShapeRegion {
+            window: request.card32(data),
+            kind: data[4],
+            x_offset: request.int16(&data[8..]),
+            y_offset: request.int16(&data[10..]),
+            region: request.card32(&data[12..]),
+        },
+        22 => XFixesRequest::SetPictureClipRegion {
+            picture: request.card32(data),
+            region: request.card32(&data[4..]),
+            x_origin: request.int16(&data[8..]),
+            y_origin: request.int16(&data[10..]),
+        },
+        23 => XFixesRequest::SetCursorName {
+            cursor: request.card32(data),
+            name: name(&data[4..])?,
+        },
+        24 => XFixesRequest::GetCursorName {
+            cursor: request.card32(data),
+        },
+        25 => XFixesRequest::GetCursorImageAndName,
+        26 => XFixesRequest::ChangeCursor {
+            source: request.card32(data),
+            destination: request.card32(&data[4..]),
+        },
+        27 => XFixesRequest::ChangeCursorByName {
+            source: request.card32(data),
+            name: name(&data[4..])?,
+        },
+        28 => XFixesRequest::ExpandRegion {
+            source: request.card32(data),
+            destination: request.card32(&data[4..]),
+            left: request.card16(&data[8..]),
+            right: request.card16(&data[10..]),
+            top: request.card16(&data[12..]),
+            bottom: request.card16(&data[14..]),
+        },
+        29 => XFixesRequest::HideCursor {
+            window: request.card32(data),
+        },
+        30 => XFixesRequest::ShowCursor {
+            window: request.card32(data),
+        },
+        31 => {
+            let count = request.card16(&data[22..]) as usize;
+            let devices = data
+                .get(24..24 + 2 * count)
+                .ok_or(XError::new(ErrorCode::Length, 0))?;
+            XFixesRequest::CreatePointerBarrier {
+                barrier: request.card32(data),
+                window: request.card32(&data[4..]),
+                x1: request.int16(&data[8..]),
+                y1: request.int16(&data[10..]),
+                x2: request.int16(&data[12..]),
+                y2: request.int16(&data[14..]),
+                directions: request.card32(&data[16..]),
+                devices: devices.chunks(2).map(|device| request.card16(device)).collect(),
+            }
+        }
+        _ => XFixesRequest::DeletePointerBarrier {
+            barrier: request.card32(data),
+        },
+    })
+}
+
+/// Adds a region, refusing ids already in use.
+pub fn create_region(id: u32, region: Region) -> Result<(), XError> {
+    let mut regions = REGIONS.lock().unwrap();
+    if regions.contains_key(&id) {
+        return Err(XError::new(ErrorCode::IDChoice, id));
+    }
+    regions.insert(id, region);
+    Ok(())
+}
+
+/// Replaces the region `destination` with the result of `operation` on the regions.
+fn set_region(
+    destination: u32,
+    operation: impl FnOnce(&BTreeMap<u32, Region>) -> Result<Region, XError>,
+) -> Result<(), XError> {
+    let mut regions = REGIONS.lock().unwrap();
+    get_region(&regions, destination)?;
+    let region = operation(&regions)?;
+    regions.insert(destination, region);
+    Ok(())
+}
+
+fn cursor_image_bytes(request: &ExtensionRequest, x: i16, y: i16, cursor: &Cursor) -> Vec<u8> {
+    let mut body = vec![];
+    for value in [x as u16, y as u16, cursor.width, cursor.height, cursor.x_hot, cursor.y_hot] {
+        body.extend(request.to_bytes_16(value));
+    }
+    body.extend(request.to_bytes_32(cursor.serial));
+    body
+}
+
+fn atom_name(atom: u32) -> String {
+    ATOMS.lock().unwrap().get(&atom).cloned().unwrap_or_default()
+}
+
+/// The cursor the pointer shows and the pointer's position, for GetCursorImage.
+fn displayed_cursor() -> (i16, i16, Cursor) {
+    let windows = WINDOWS.lock().unwrap();
+    let input = INPUT.lock().unwrap();
+    let cursors = CURSORS.lock().unwrap();
+    let cursor = match input.displayed_cursor(&windows, &cursors) {
+        0 => Cursor::default_cursor(),
+        id => cursors[&id].clone(),
+    };
+    (input.pointer_x, input.pointer_y, cursor)
+}
+
+/// Gives every cursor `matches` picks the image, serial and name of `source`, as if
+/// everything showing one of them showed `source` instead.
+fn replace_cursors(source: u32, matches: impl Fn(u32, &Cursor) -> bool) -> Result<(), XError> {
+    let windows = WINDOWS.lock().unwrap();
+    let mut input = INPUT.lock().unwrap();
+    let mut cursors = CURSORS.lock().unwrap();
+    let source_cursor = get_cursor(&cursors, source)?.clone();
+    for (id, cursor) in cursors.iter_mut() {
+        if *id != source && matches(*id, cursor) {
+            *cursor = Cursor {
+                freed: cursor.freed,
+                ..source_cursor.clone()
+            };
+        }
+    }
+    drop(cursors);
+    input.update_cursor(&windows);
+    Ok(())
+}
+
+pub fn handle_xfixes_request(request: &ExtensionRequest) -> Result<Option<Vec<u8>>, XError> {
+    let xfixes_request = read_xfixes_request(request)?;
+    match xfixes_request {
+        XFixesRequest::QueryVersion { .. } => {
+            let mut body = request.to_bytes_32(XFIXES_MAJOR_VERSION).to_vec();
+            body.extend(request.to_bytes_32(XFIXES_MINOR_VERSION));
+            Ok(Some(request.reply(0, body)))
+        }
+        XFixesRequest::ChangeSaveSet {
+            mode,
+            target,
+            map,
+            window,
+        } => {
+            if !matches!(target, SAVE_SET_NEAREST | SAVE_SET_ROOT) {
+                return Err(XError::new(ErrorCode::Value, target as u32));
+            }
+            if !matches!(map, SAVE_SET_MAP | SAVE_SET_UNMAP) {
+                return Err(XError::new(ErrorCode::Value, map as u32));
+            }
+            let save_set_mode = SaveSetMode {
+                to_root: target == SAVE_SET_ROOT,
+                unmap: map == SAVE_SET_UNMAP,
+            };
+            change_save_set(&WINDOWS.lock().unwrap(), request.client, mode, window, save_set_mode)?;
+            Ok(None)
+        }
+        XFixesRequest::SelectSelectionInput {
+            window,
+            selection,
+            event_mask,
+        } => {
+            let mut windows = WINDOWS.lock().unwrap();
+            get_window(&windows, window)?;
+            if !atom_exists(selection) {
+                return Err(XError::new(ErrorCode::Atom, selection));
+            }
+            if event_mask & !SELECTION_EVENT_MASK != 0 {
+                return Err(XError::new(ErrorCode::Value, event_mask));
+            }
+            let masks = &mut windows.get_mut(&window).unwrap().selection_masks;
+            match event_mask {
+                0 => masks.remove(&(request.client, selection)),
+                event_mask => masks.insert((request.client, selection), event_mask),
+            };
+            Ok(None)
+        }
+        XFixesRequest::SelectCursorInput { window, event_mask } => {
+            let mut windows = WINDOWS.lock().unwrap();
+            get_window(&windows, window)?;
+            if event_mask & !DISPLAY_CURSOR_NOTIFY_MASK != 0 {
+                return Err(XError::new(ErrorCode::Value, event_mask));
+            }
+            let masks = &mut windows.get_mut(&window).unwrap().cursor_masks;
+            match event_mask {
+                0 => masks.remove(&request.client),
+                event_mask => masks.insert(request.client, event_mask),
+            };
+            Ok(None)
+        }
+        XFixesRequest::GetCursorImage => {
+            let (x, y, cursor) = displayed_cursor();
+            let mut body = cursor_image_bytes(request, x, y, &cursor);
+            body.resize(24, 0);
+            for pixel in &cursor.pixels {
+                body.extend(request.to_bytes_32(*pixel));
+            }
+            Ok(Some(request.reply(0, body)))
+        }
+        XFixesRequest::CreateRegion { region, rectangles } => {
+            create_region(region, region_from_rectangles(&rectangles))?;
+            Ok(None)
+        }
+        XFixesRequest::CreateRegionFromBitmap { region, bitmap } => {
+            create_region(region, bitmap_region(bitmap)?)?;
+            Ok(None)
+        }
+        XFixesRequest::CreateRegionFromWindow { region, window, kind } => {
+            if !matches!(kind, SHAPE_BOUNDING | SHAPE_CLIP) {
+                return Err(XError::new(ErrorCode::Value, kind as u32));
+            }
+            let shape = get_window(&WINDOWS.lock().unwrap(), window)?.shape_or_default(kind);
+            create_region(region, shape)?;
+            Ok(None)
+        }
+        XFixesRequest::CreateRegionFromGC { gc, .. } => Err(XError::new(ErrorCode::Match, gc)),
+        XFixesRequest::CreateRegionFromPicture { region, picture } => {
+            let pictures = PICTURES.lock().unwrap();
+            let picture = pictures.get(&picture).ok_or(bad_picture(picture))?;
+            let clip = picture.clip.clone().ok_or(XError::new(ErrorCode::Match, 0))?;
+            create_region(region, clip)?;
+            Ok(None)
+        }
+        XFixesRequest::DestroyRegion { region } => {
+            REGIONS.lock().unwrap().remove(&region).ok_or(bad_region(region))?;
+            Ok(None)
+        }
+        XFixesRequest::SetRegion { region, rectangles } => {
+            set_region(region, |_| Ok(region_from_rectangles(&rectangles)))?;
+            Ok(None)
+        }
+        XFixesRequest::CopyRegion { source, destination } => {
+            set_region(destination, |regions| get_region(regions, source).cloned())?;
+            Ok(None)
+        }
+        XFixesRequest::CombineRegion {
+            operation,
+            source1,
+            source2,
+            destination,
+        } => {
+            set_region(destination, |regions| {
+                let (source1, source2) = (get_region(regions, source1)?, get_region(regions, source2)?);
+                Ok(match operation {
+                    UNION_REGION => source1.union(source2),
+                    INTERSECT_REGION => source1.intersect(source2),
+                    _ => source1.subtract(source2),
+                })
+            })?;
+            Ok(None)
+        }
+        XFixesRequest::InvertRegion {
+            source,
+            bounds,
+            destination,
+        } => {
+            set_region(destination, |regions| {
+                Ok(Region::from_rectangle(bounds).subtract(get_region(regions, source)?))
+            })?;
+            Ok(None)
+        }
+        XFixesRequest::TranslateRegion { region, dx, dy } => {
+            set_region(region, |regions| {
+                Ok(get_region(regions, region)?.translate(dx as i32, dy as i32))
+            })?;
+            Ok(None)
+        }
+        XFixesRequest::RegionExtents { source, destination } => {
+            set_region(destination, |regions| {
+                Ok(Region::from_rectangle(get_region(regions, source)?.extents()))
+            })?;
+            Ok(None)
+        }
+        XFixesRequest::FetchRegion { region } => {
+            let regions = REGIONS.lock().unwrap();
+            let region = get_region(&regions, region)?;
+            let extents = region.extents();
+            let mut body = vec![];
+            for r in std::iter::once(&extents).chain(&region.rectangles) {
+                body.extend(request.to_bytes_16(r.x as u16));
+                body.extend(request.to_bytes_16(r.y as u16));
+                body.extend(request.to_bytes_16(r.width as u16));
+                body.extend(request.to_bytes_16(r.height as u16));
+                // The extents are followed by padding up to the rectangles.
+                if body.len() == 8 {
+                    body.resize(24, 0);
+                }
+            }
+            Ok(Some(request.reply(0, body)))
+        }
+        XFixesRequest::SetGCClipRegion { region, .. } => {
+            if region != 0 {
+                get_region(&REGIONS.lock().unwrap(), region)?;
+            }
+            Ok(None)
+        }
+        XFixesRequest::SetWindowShapeRegion {
+            window,
+            kind,
+            x_offset,
+            y_offset,
+            region,
+        } => {
+            let mut windows = WINDOWS.lock().unwrap();
+            get_window(&windows, window)?;
+            let source = match region {
+                0 => None,
+                region => Some(
+                    get_region(&REGIONS.lock().unwrap(), region)?.translate(x_offset as i32, y_offset as i32),
+                ),
+            };
+            combine(&mut windows, window, SHAPE_SET, kind, source)?;
+            Ok(None)
+        }
+        XFixesRequest::SetPictureClipRegion {
+            picture,
+            region,
+            x_origin,
+            y_origin,
+        } => {
+            let mut pictures = PICTURES.lock().unwrap();
+            let picture = pictures
+                .get_mut(&picture)
+                .filter(|p| p.fill.is_none())
+                .ok_or(bad_picture(picture))?;
+            picture.clip = match region {
+                0 => None,
+                region => Some(get_region(&REGIONS.lock().unwrap(), region)?.clone()),
+            };
+            picture.clip_origin = (x_origin, y_origin);
+            Ok(None)
+        }
+        XFixesRequest::SetCursorName { cursor, name } => {
+            let atom = get_atom(false, name);
+            let mut cursors = CURSORS.lock().unwrap();
+            get_cursor(&cursors, cursor)?;
+            cursors.get_mut(&cursor).unwrap().name = atom;
+            Ok(None)
+        }
+        XFixesRequest::GetCursorName { cursor } => {
+            let atom = get_cursor(&CURSORS.lock().unwrap(), cursor)?.name;
+            let name = atom_name(atom);
+            let mut body = request.to_bytes_32(atom).to_vec();
+            body.extend(request.to_bytes_16(name.len() as u16));
+            body.resize(24, 0);
+            body.extend(name.as_bytes());
+            Ok(Some(request.reply(0, body)))
+        }
+        XFixesRequest::GetCursorImageAndName => {
+            let (x, y, cursor) = displayed_cursor();
+            let name = atom_name(cursor.name);
+            let mut body = cursor_image_bytes(request, x, y, &cursor);
+            body.extend(request.to_bytes_32(cursor.name));
+            body.extend(request.to_bytes_16(name.len() as u16));
+            body.resize(24, 0);
+            for pixel in &cursor.pixels {
+                body.extend(request.to_bytes_32(*pixel));
+            }
+            body.extend(name.as_bytes());
+            Ok(Some(request.reply(0, body)))
+        }
+        XFixesRequest::ChangeCursor { source, destination } => {
+            get_cursor(&CURSORS.lock().unwrap(), destination)?;
+            replace_cursors(source, |id, _| id == destination)?;
+            Ok(None)
+        }
+        XFixesRequest::ChangeCursorByName { source, name } => {
+            let atom = get_atom(true, name);
+            replace_cursors(source, |_, cursor| atom != 0 && cursor.name == atom)?;
+            Ok(None)
+        }
+        XFixesRequest::ExpandRegion {
+            source,
+            destination,
+            left,
+            right,
+            top,
+            bottom,
+        } => {
+            let (left, right, top, bottom) = (left as i32, right as i32, top as i32, bottom as i32);
+            set_region(destination, |regions| {
+                let rectangles: Vec<Rectangle> = get_region(regions, source)?
+                    .rectangles
+                    .iter()
+                    .map(|r| {
+                        let (width, height) = (r.width + left + right, r.height + top + bottom);
+                        Rectangle::new(r.x - left, r.y - top, width, height)
+                    })
+                    .collect();
+                Ok(region_from_rectangles(&rectangles))
+            })?;
+            Ok(None)
+        }
+        XFixesRequest::HideCursor { window } => {
+            get_window(&WINDOWS.lock().unwrap(), window)?;
+            CURSOR_HIDDEN.lock().unwrap().entry(request.client).or_insert(window);
+            Ok(None)
+        }
+        XFixesRequest::ShowCursor { window } => {
+            let windows = WINDOWS.lock().unwrap();
+            get_window(&windows, window)?;
+            // Hiding ends with the window it was asked for.
+            let hidden = CURSOR_HIDDEN.lock().unwrap().remove(&request.client);
+            if !hidden.is_some_and(|hidden| windows.contains_key(&hidden)) {
+                return Err(XError::new(ErrorCode::Match, window));
+            }
+            Ok(None)
+        }
+        XFixesRequest::CreatePointerBarrier {
+            barrier,
+            window,
+            x1,
+            y1,
+            x2,
+            y2,
+            directions,
+            devices,
+        } => {
+            get_window(&WINDOWS.lock().unwrap(), window)?;
+            // Barriers are horizontal or vertical lines, not points.
+            if (x1 != x2 && y1 != y2) || (x1 == x2 && y1 == y2) {
+                return Err(XError::new(ErrorCode::Value, 0));
+            }
+            for device in &devices {
+                check_master_device(*device)?;
+            }
+            let mut barriers = BARRIERS.lock().unwrap();
+            if barriers.contains_key(&barrier) {
+                return Err(XError::new(ErrorCode::IDChoice, barrier));
+            }
+            let (x1, y1, x2, y2) = (x1 as i32, y1 as i32, x2 as i32, y2 as i32);
+            barriers.insert(
+                barrier,
+                Barrier {
+                    window,
+                    x1: x1.min(x2),
+                    y1: y1.min(y2),
+                    x2: x1.max(x2),
+                    y2: y1.max(y2),
+                    directions,
+                    devices,
+                },
+            );
+            Ok(None)
+        }
+        XFixesRequest::DeletePointerBarrier { barrier } => {
+            // XFIXES defines no error for barriers, so an unknown one is a bad value.
+            BARRIERS
+                .lock()
+                .unwrap()
+                .remove(&barrier)
+                .ok_or(XError::new(ErrorCode::Value, barrier))?;
+            Ok(None)
+        }
+    }
+}
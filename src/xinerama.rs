@@ -0,0 +1,114 @@
+//! The XINERAMA extension and the PanoramiX requests it grew out of. There is only
+//! one X screen; the Xinerama screens are the RANDR monitors laid out on it, so they
+//! follow every change clients make through RANDR.
+
+use crate::{
+    error::{ErrorCode, XError},
+    extension::ExtensionRequest,
+    randr::{Monitor, RANDR},
+    window::{get_window, WINDOWS},
+};
+
+pub static XINERAMA_NAME: &str = "XINERAMA";
+pub const XINERAMA_MAJOR_VERSION: u16 = 1;
+pub const XINERAMA_MINOR_VERSION: u16 = 1;
+
+#[derive(Debug)]
+pub enum XineramaRequest {
+    QueryVersion { major_version: u8, minor_version: u8 },
+    GetState { window: u32 },
+    GetScreenCount { window: u32 },
+    GetScreenSize { window: u32, screen: u32 },
+    IsActive,
+    QueryScreens,
+}
+
+fn read_xinerama_request(request: &ExtensionRequest) -> Result<XineramaRequest, XError> {
+    let data = request.data;
+    let minimum_length = match request.minor_opcode {
+        0..=2 => 4,
+        3 => 8,
+        4 | 5 => 0,
+        minor_opcode => return Err(XError::new(ErrorCode::Request, minor_opcode as u32)),
+    };
+    if data.len() < minimum_length {
+        return Err(XError::new(ErrorCode::Length, 0));
+    }
+    Ok(match request.minor_opcode {
+        0 => XineramaRequest::QueryVersion {
+            major_version: data[0],
+            minor_version: data[1],
+        },
+        1 => XineramaRequest::GetState {
+            window: request.card32(data),
+        },
+        2 => XineramaRequest::GetScreenCount {
+            window: request.card32(data),
+        },
+        3 => XineramaRequest::GetScreenSize {
+            window: request.card32(data),
+            screen: request.card32(&data[4..]),
+        },
+        4 => XineramaRequest::IsActive,
+        _ => XineramaRequest::QueryScreens,
+    })
+}
+
+/// The Xinerama screens: the active monitors, primary first. Xinerama is inactive when
+/// no output is enabled.
+fn xinerama_screens() -> Vec<Monitor> {
+    RANDR.lock().unwrap().list_monitors(true)
+}
+
+fn check_window(window: u32) -> Result<(), XError> {
+    get_window(&WINDOWS.lock().unwrap(), window).map(|_| ())
+}
+
+pub fn handle_xinerama_request(request: &ExtensionRequest) -> Result<Option<Vec<u8>>, XError> {
+    let xinerama_request = read_xinerama_request(request)?;
+    match xinerama_request {
+        XineramaRequest::QueryVersion { .. } => {
+            let mut body = request.to_bytes_16(XINERAMA_MAJOR_VERSION).to_vec();
+            body.extend(request.to_bytes_16(XINERAMA_MINOR_VERSION));
+            Ok(Some(request.reply(0, body)))
+        }
+        XineramaRequest::GetState { window } => {
+            check_window(window)?;
+            let active = !xinerama_screens().is_empty();
+            Ok(Some(request.reply(active as u8, request.to_bytes_32(window).to_vec())))
+        }
+        XineramaRequest::GetScreenCount { window } => {
+            check_window(window)?;
+            let count = xinerama_screens().len() as u8;
+            Ok(Some(request.reply(count, request.to_bytes_32(window).to_vec())))
+        }
+        XineramaRequest::GetScreenSize { window, screen } => {
+            check_window(window)?;
+            let screens = xinerama_screens();
+            let monitor = screens
+                .get(screen as usize)
+                .ok_or(XError::new(ErrorCode::Match, screen))?;
+            let mut body = request.to_bytes_32(monitor.width as u32).to_vec();
+            body.extend(request.to_bytes_32(monitor.height as u32));
+            body.extend(request.to_bytes_32(window));
+            body.extend(request.to_bytes_32(screen));
+            Ok(Some(request.reply(0, body)))
+        }
+        XineramaRequest::IsActive => {
+            let active = !xinerama_screens().is_empty();
+            Ok(Some(request.reply(0, request.to_bytes_32(active as u32).to_vec())))
+        }
+        XineramaRequest::QueryScreens => {
+            let screens = xinerama_screens();
+            let mut body = request.to_bytes_32(screens.len() as u32).to_vec();
+            body.resize(24, 0);
+            for monitor in screens {
+                body.extend(request.to_bytes_16(monitor.x as u16));
+                body.extend(request.to_bytes_16(monitor.y as u16));
+                body.extend(request.to_bytes_16(monitor.width));
+                body.extend(request.to_bytes_16(monitor.height));
+            }
+            Ok(Some(request.reply(0, body)))
+        }
+    }
+}
@@ -0,0 +1,1061 @@
+//! The X Input Extension, version 2.2. The device hierarchy is fixed: the core pointer
+//! and keyboard are the master devices, each with one slave that all injected pointer
+//! and key input comes from, plus a touchscreen slave attached to the master pointer.
+//! Events of the 2.x protocol are generic events; the events of version 1 are not
+//! supported, though their event codes are reserved so libXi keeps out of the core
+//! range. Touches are not emulated as pointer events.
+
+use std::collections::BTreeMap;
+
+use crate::{
+    atom::get_atom,
+    client::queue_event,
+    cursor::check_cursor,
+    error::{ErrorCode, XError},
+    event::Event,
+    extension::{find_extension, ExtensionRequest},
+    focus::REVERT_TO_PARENT,
+    grab::{
+        check_grab_arguments, check_grab_key, check_modifiers, Device, Grab, GrabKind, PassiveGrab,
+        ANY_MODIFIER, ASYNC_BOTH, ASYNC_KEYBOARD, ASYNC_POINTER, REPLAY_KEYBOARD, REPLAY_POINTER,
+        SYNC_BOTH, SYNC_KEYBOARD, SYNC_POINTER,
+    },
+    input::{dispatch, process_pending, DeviceEvent, InputState, INPUT},
+    region::Rectangle,
+    screen::{DEFAULT_SCREEN, SCREEN_SIZE},
+    window::{child_towards, get_window, Window, WINDOWS},
+    xkb::XKB,
+};
+
+pub static XINPUT_NAME: &str = "XInputExtension";
+pub const XINPUT_MAJOR_VERSION: u16 = 2;
+pub const XINPUT_MINOR_VERSION: u16 = 2;
+
+/// Error codes, relative to the extension's first error.
+const BAD_DEVICE: u8 = 0;
+
+/// Device IDs that select events for several devices at once.
+pub const ALL_DEVICES: u16 = 0;
+pub const ALL_MASTER_DEVICES: u16 = 1;
+
+pub const VIRTUAL_CORE_POINTER: u16 = 2;
+pub const VIRTUAL_CORE_KEYBOARD: u16 = 3;
+pub const VIRTUAL_POINTER: u16 = 4;
+pub const VIRTUAL_KEYBOARD: u16 = 5;
+pub const VIRTUAL_TOUCHSCREEN: u16 = 6;
+
+pub const XI_KEY_PRESS: u16 = 2;
+pub const XI_KEY_RELEASE: u16 = 3;
+pub const XI_BUTTON_PRESS: u16 = 4;
+pub const XI_BUTTON_RELEASE: u16 = 5;
+pub const XI_MOTION: u16 = 6;
+pub const XI_ENTER: u16 = 7;
+pub const XI_LEAVE: u16 = 8;
+pub const XI_RAW_KEY_PRESS: u16 = 13;
+pub const XI_RAW_MOTION: u16 = 17;
+pub const XI_TOUCH_BEGIN: u16 = 18;
+pub const XI_TOUCH_UPDATE: u16 = 19;
+pub const XI_TOUCH_END: u16 = 20;
+pub const XI_RAW_TOUCH_BEGIN: u16 = 22;
+pub const XI_RAW_TOUCH_END: u16 = 24;
+
+/// Raw events, which may only be selected on the root window.
+const RAW_EVENTS: u32 = 0x1f << XI_RAW_KEY_PRESS | 0x7 << XI_RAW_TOUCH_BEGIN;
+/// Touch events, which must be selected together.
+const TOUCH_EVENTS: u32 = 1 << XI_TOUCH_BEGIN | 1 << XI_TOUCH_UPDATE | 1 << XI_TOUCH_END;
+
+/// Set on key events of a held key repeating.
+pub const XI_KEY_REPEAT: u32 = 1 << 16;
+/// Set on button events emulated from smooth scrolling.
+pub const XI_POINTER_EMULATED: u32 = 1 << 16;
+
+/// The modifiers value that passive grabs use for any modifiers.
+const XI_ANY_MODIFIER: u32 = 1 << 31;
+
+const GRAB_TYPE_BUTTON: u8 = 0;
+const GRAB_TYPE_KEYCODE: u8 = 1;
+
+const MASTER_POINTER: u16 = 1;
+const MASTER_KEYBOARD: u16 = 2;
+const SLAVE_POINTER: u16 = 3;
+const SLAVE_KEYBOARD: u16 = 4;
+
+const KEY_CLASS: u16 = 0;
+const BUTTON_CLASS: u16 = 1;
+const VALUATOR_CLASS: u16 = 2;
+const SCROLL_CLASS: u16 = 3;
+const TOUCH_CLASS: u16 = 8;
+
+const SCROLL_TYPE_VERTICAL: u16 = 1;
+const SCROLL_TYPE_HORIZONTAL: u16 = 2;
+const DIRECT_TOUCH: u8 = 1;
+const MAX_TOUCHES: u8 = 10;
+
+/// Valuators of the pointer devices: relative motion, then the scroll axes.
+pub const VALUATOR_X: u16 = 0;
+pub const VALUATOR_Y: u16 = 1;
+pub const VALUATOR_HORIZONTAL_SCROLL: u16 = 2;
+pub const VALUATOR_VERTICAL_SCROLL: u16 = 3;
+
+const BUTTON_LABELS: [&str; 7] = [
+    "Button Left",
+    "Button Middle",
+    "Button Right",
+    "Button Wheel Up",
+    "Button Wheel Down",
+    "Button Horiz Wheel Left",
+    "Button Horiz Wheel Right",
+];
+
+/// A device of the hierarchy: its ID, name, use and the device it is attached to.
+struct DeviceDescription {
+    id: u16,
+    name: &'static str,
+    device_use: u16,
+    attachment: u16,
+}
+
+const DEVICES: [DeviceDescription; 5] = [
+    DeviceDescription {
+        id: VIRTUAL_CORE_POINTER,
+        name: "Virtual core pointer",
+        device_use: MASTER_POINTER,
+        attachment: VIRTUAL_CORE_KEYBOARD,
+    },
+    DeviceDescription {
+        id: VIRTUAL_CORE_KEYBOARD,
+        name: "Virtual core keyboard",
+        device_use: MASTER_KEYBOARD,
+        attachment: VIRTUAL_CORE_POINTER,
+    },
+    DeviceDescription {
+        id: VIRTUAL_POINTER,
+        name: "xaugh pointer",
+        device_use: SLAVE_POINTER,
+        attachment: VIRTUAL_CORE_POINTER,
+    },
+    DeviceDescription {
+        id: VIRTUAL_KEYBOARD,
+        name: "xaugh keyboard",
+        device_use: SLAVE_KEYBOARD,
+        attachment: VIRTUAL_CORE_KEYBOARD,
+    },
+    DeviceDescription {
+        id: VIRTUAL_TOUCHSCREEN,
+        name: "xaugh touchscreen",
+        device_use: SLAVE_POINTER,
+        attachment: VIRTUAL_CORE_POINTER,
+    },
+];
+
+fn xi_error(offset: u8, bad_value: u32) -> XError {
+    let first_error = find_extension(XINPUT_NAME).map_or(0, |e| e.first_error);
+    XError::extension(first_error + offset, bad_value)
+}
+
+fn is_master(device: u16) -> bool {
+    matches!(device, VIRTUAL_CORE_POINTER | VIRTUAL_CORE_KEYBOARD)
+}
+
+/// Whether a selection made for `selected` covers events reported for `device`.
+fn selects_device(selected: u16, device: u16) -> bool {
+    match selected {
+        ALL_DEVICES => true,
+        ALL_MASTER_DEVICES => is_master(device),
+        selected => selected == device,
+    }
+}
+
+/// Whether two selections cover a device in common.
+fn devices_overlap(a: u16, b: u16) -> bool {
+    a == b || [(a, b), (b, a)].iter().any(|(all, device)| match *all {
+        ALL_DEVICES => true,
+        ALL_MASTER_DEVICES => is_master(*device),
+        _ => false,
+    })
+}
+
+fn check_device(device: u16) -> Result<(), XError> {
+    match DEVICES.iter().any(|d| d.id == device) {
+        true => Ok(()),
+        false => Err(xi_error(BAD_DEVICE, device as u32)),
+    }
+}
+
+/// Checks that `device` is a master device.
+pub fn check_master_device(device: u16) -> Result<(), XError> {
+    match is_master(device) {
+        true => Ok(()),
+        false => Err(xi_error(BAD_DEVICE, device as u32)),
+    }
+}
+
+/// The core device whose grabs and focus an XInput device shares.
+fn core_device(device: u16) -> Result<Device, XError> {
+    match device {
+        VIRTUAL_CORE_POINTER | VIRTUAL_POINTER | VIRTUAL_TOUCHSCREEN => Ok(Device::Pointer),
+        VIRTUAL_CORE_KEYBOARD | VIRTUAL_KEYBOARD => Ok(Device::Keyboard),
+        device => Err(xi_error(BAD_DEVICE, device as u32)),
+    }
+}
+
+impl Window {
+    /// The XInput events `client` selected on this window for `device`.
+    pub fn xi_mask(&self, client: u32, device: u16) -> u32 {
+        self.xi_masks
+            .iter()
+            .filter(|((selecting, selected), _)| *selecting == client && selects_device(*selected, device))
+            .fold(0, |mask, (_, selected)| mask | selected)
+    }
+
+    /// The clients that selected the XInput event type `xi_type` on this window for
+    /// `device`.
+    pub fn xi_clients(&self, device: u16, xi_type: u16) -> Vec<u32> {
+        let mut clients: Vec<u32> = self
+            .xi_masks
+            .iter()
+            .filter(|((_, selected), mask)| selects_device(*selected, device) && *mask & 1 << xi_type != 0)
+            .map(|((client, _), _)| *client)
+            .collect();
+        clients.dedup();
+        clients
+    }
+}
+
+/// Converts a value to the 32.32 fixed-point format, as its integral and fractional
+/// parts.
+pub fn fp3232(value: f64) -> (i32, u32) {
+    let integral = value.floor();
+    (integral as i32, ((value - integral) * 4294967296.0) as u32)
+}
+
+/// The modifier and group state reported by XInput events: base, latched, locked and
+/// effective.
+#[derive(Clone, Copy, Debug)]
+pub struct XIModifiers {
+    pub mods: [u32; 4],
+    pub group: [u8; 4],
+}
+
+impl XIModifiers {
+    /// The current keyboard state, with the effective modifiers and group taken from
+    /// the core `state` of the event.
+    pub fn current(state: u16) -> XIModifiers {
+        let keyboard = XKB.lock().unwrap().state;
+        XIModifiers {
+            mods: [
+                keyboard.base_mods as u32,
+                keyboard.latched_mods as u32,
+                keyboard.locked_mods as u32,
+                state as u32 & 0xff,
+            ],
+            group: [
+                keyboard.base_group as u8,
+                keyboard.latched_group as u8,
+                keyboard.locked_group,
+                (state >> 13) as u8 & 3,
+            ],
+        }
+    }
+}
+
+/// A key, button, motion or touch event. The window fields and coordinates come from
+/// the core event; the buttons held come from its `state`.
+#[derive(Clone, Debug)]
+pub struct XIDeviceEvent {
+    pub evtype: u16,
+    pub deviceid: u16,
+    pub sourceid: u16,
+    /// The keycode, button or touch ID.
+    pub detail: u32,
+    pub flags: u32,
+    pub device_event: DeviceEvent,
+    pub modifiers: XIModifiers,
+    /// Valuator numbers and values, in increasing valuator order.
+    pub valuators: Vec<(u16, f64)>,
+}
+
+/// The device data behind an event, before pointer acceleration, grabs or delivery.
+#[derive(Clone, Debug)]
+pub struct XIRawEvent {
+    pub evtype: u16,
+    pub deviceid: u16,
+    pub sourceid: u16,
+    pub time: u32,
+    pub detail: u32,
+    pub flags: u32,
+    pub valuators: Vec<(u16, f64)>,
+}
+
+/// XI_Enter or XI_Leave for the master pointer.
+#[derive(Clone, Debug)]
+pub struct XICrossingEvent {
+    pub evtype: u16,
+    pub mode: u8,
+    pub focus: bool,
+    pub device_event: DeviceEvent,
+    pub modifiers: XIModifiers,
+}
+
+/// Sends a raw event to the clients that selected it on the root window, for its
+/// source device and for `master`.
+pub fn deliver_raw_event(windows: &BTreeMap<u32, Window>, master: u16, event: XIRawEvent) {
+    let root = &windows[&DEFAULT_SCREEN.root_window];
+    for deviceid in [event.sourceid, master] {
+        for client in root.xi_clients(deviceid, event.evtype) {
+            queue_event(
+                client,
+                Event::XIRaw(XIRawEvent {
+                    deviceid,
+                    ..event.clone()
+                }),
+            );
+        }
+    }
+}
+
+/// Reads an XInput event mask of `length` four-byte units. Events this server doesn't
+/// know set bits past the first unit.
+fn read_mask(request: &ExtensionRequest, data: &[u8], length: usize) -> u32 {
+    let mut units = data.chunks_exact(4).take(length).map(|unit| request.card32(unit));
+    let mask = units.next().unwrap_or(0);
+    match units.any(|unit| unit != 0) {
+        true => u32::MAX,
+        false => mask,
+    }
+}
+
+#[derive(Debug)]
+pub enum XIRequest {
+    GetExtensionVersion,
+    QueryPointer {
+        window: u32,
+        deviceid: u16,
+    },
+    WarpPointer {
+        src_window: u32,
+        dst_window: u32,
+        src: Rectangle,
+        dst_x: i32,
+        dst_y: i32,
+        deviceid: u16,
+    },
+    ChangeCursor {
+        window: u32,
+        cursor: u32,
+        deviceid: u16,
+    },
+    SetClientPointer {
+        window: u32,
+        deviceid: u16,
+    },
+    GetClientPointer {
+        window: u32,
+    },
+    SelectEvents {
+        window: u32,
+        masks: Vec<(u16, u32)>,
+    },
+    QueryVersion {
+        major_version: u16,
+        minor_version: u16,
+    },
+    QueryDevice {
+        deviceid: u16,
+    },
+    SetFocus {
+        focus: u32,
+        time: u32,
+        deviceid: u16,
+    },
+    GetFocus {
+        deviceid: u16,
+    },
+    GrabDevice {
+        window: u32,
+        time: u32,
+        cursor: u32,
+        deviceid: u16,
+        grab_mode: u8,
+        paired_device_mode: u8,
+        owner_events: bool,
+        mask: u32,
+    },
+    UngrabDevice {
+        time: u32,
+        deviceid: u16,
+    },
+    AllowEvents {
+        time: u32,
+        deviceid: u16,
+        event_mode: u8,
+    },
+    PassiveGrabDevice {
+        time: u32,
+        window: u32,
+        cursor: u32,
+        detail: u32,
+        deviceid: u16,
+        grab_type: u8,
+        grab_mode: u8,
+        paired_device_mode: u8,
+        owner_events: bool,
+        mask: u32,
+        modifiers: Vec<u32>,
+    },
+    PassiveUngrabDevice {
+        window: u32,
+        detail: u32,
+        deviceid: u16,
+        grab_type: u8,
+        modifiers: Vec<u32>,
+    },
+    ListProperties {
+        deviceid: u16,
+    },
+    GetProperty {
+        deviceid: u16,
+    },
+    GetSelectedEvents {
+        window: u32,
+    },
+}
+
+fn read_xi_request(request: &ExtensionRequest) -> Result<XIRequest, XError> {
+    let data = request.data;
+    let minimum_length = match request.minor_opcode {
+        1 | 45 | 47 | 48 | 50 | 56 | 60 => 4,
+        40 | 44 | 46 | 52 | 53 => 8,
+        42 | 49 => 12,
+        55 => 16,
+        51 | 59 => 20,
+        54 => 28,
+        41 => 32,
+        minor_opcode => return Err(XError::new(ErrorCode::Request, minor_opcode as u32)),
+    };
+    if data.len() < minimum_length {
+        return Err(XError::new(ErrorCode::Length, 0));
+    }
+    let card32s = |bytes: &[u8], count: usize| -> Result<Vec<u32>, XError> {
+        let bytes = bytes.get(..count * 4).ok_or(XError::new(ErrorCode::Length, 0))?;
+        Ok(bytes.chunks(4).map(|chunk| request.card32(chunk)).collect())
+    };
+    Ok(match request.minor_opcode {
+        1 => XIRequest::GetExtensionVersion,
+        40 => XIRequest::QueryPointer {
+            window: request.card32(data),
+            deviceid: request.card16(&data[4..]),
+        },
+        41 => XIRequest::WarpPointer {
+            src_window: request.card32(data),
+            dst_window: request.card32(&data[4..]),
+            src: Rectangle::new(
+                request.card32(&data[8..]) as i32 >> 16,
+                request.card32(&data[12..]) as i32 >> 16,
+                request.card16(&data[16..]) as i32,
+                request.card16(&data[18..]) as i32,
+            ),
+            dst_x: request.card32(&data[20..]) as i32 >> 16,
+            dst_y: request.card32(&data[24..]) as i32 >> 16,
+            deviceid: request.card16(&data[28..]),
+        },
+        42 => XIRequest::ChangeCursor {
+            window: request.card32(data),
+            cursor: request.card32(&data[4..]),
+            deviceid: request.card16(&data[8..]),
+        },
+        44 => XIRequest::SetClientPointer {
+            window: request.card32(data),
+            deviceid: request.card16(&data[4..]),
+        },
+        45 => XIRequest::GetClientPointer {
+            window: request.card32(data),
+        },
+        46 => {
+            let num_masks = request.card16(&data[4..]);
+            let mut masks = vec![];
+            let mut rest = &data[8..];
+            for _ in 0..num_masks {
+                if rest.len() < 4 {
+                    return Err(XError::new(ErrorCode::Length, 0));
+                }
+                let mask_len = request.card16(&rest[2..]) as usize;
+                let mask = rest.get(4..4 + mask_len * 4).ok_or(XError::new(ErrorCode::Length, 0))?;
+                masks.push((request.card16(rest), read_mask(request, mask, mask_len)));
+                rest = &rest[4 + mask_len * 4..];
+            }
+            XIRequest::SelectEvents {
+                window: request.card32(data),
+                masks,
+            }
+        }
+        47 => XIRequest::QueryVersion {
+            major_version: request.card16(data),
+            minor_version: request.card16(&data[2..]),
+        },
+        48 => XIRequest::QueryDevice {
+            deviceid: request.card16(data),
+        },
+        49 => XIRequest::SetFocus {
+            focus: request.card32(data),
+            time: request.card32(&data[4..]),
+            deviceid: request.card16(&data[8..]),
+        },
+        50 => XIRequest::GetFocus {
+            deviceid: request.card16(data),
+        },
+        51 => {
+            let mask_len = request.card16(&data[18..]) as usize;
+            let mask = data.get(20..20 + mask_len * 4).ok_or(XError::new(ErrorCode::Length, 0))?;
+            XIRequest::GrabDevice {
+                window: request.card32(data),
+                time: request.card32(&data[4..]),
+                cursor: request.card32(&data[8..]),
+                deviceid: request.card16(&data[12..]),
+                grab_mode: data[14],
+                paired_device_mode: data[15],
+                owner_events: data[16] != 0,
+                mask: read_mask(request, mask, mask_len),
+            }
+        }
+        52 => XIRequest::UngrabDevice {
+            time: request.card32(data),
+            deviceid: request.card16(&data[4..]),
+        },
+        53 => XIRequest::AllowEvents {
+            time: request.card32(data),
+            deviceid: request.card16(&data[4..]),
+            event_mode: data[6],
+        },
+        54 => {
+            let num_modifiers = request.card16(&data[18..]) as usize;
+            let mask_len = request.card16(&data[20..]) as usize;
+            let mask = data.get(28..28 + mask_len * 4).ok_or(XError::new(ErrorCode::Length, 0))?;
+            XIRequest::PassiveGrabDevice {
+                time: request.card32(data),
+                window: request.card32(&data[4..]),
+                cursor: request.card32(&data[8..]),
+                detail: request.card32(&data[12..]),
+                deviceid: request.card16(&data[16..]),
+                grab_type: data[22],
+                grab_mode: data[23],
+                paired_device_mode: data[24],
+                owner_events: data[25] != 0,
+                mask: read_mask(request, mask, mask_len),
+                modifiers: card32s(&data[28 + mask_len * 4..], num_modifiers)?,
+            }
+        }
+        55 => XIRequest::PassiveUngrabDevice {
+            window: request.card32(data),
+            detail: request.card32(&data[4..]),
+            deviceid: request.card16(&data[8..]),
+            grab_type: data[12],
+            modifiers: card32s(&data[16..], request.card16(&data[10..]) as usize)?,
+        },
+        56 => XIRequest::ListProperties {
+            deviceid: request.card16(data),
+        },
+        59 => XIRequest::GetProperty {
+            deviceid: request.card16(data),
+        },
+        _ => XIRequest::GetSelectedEvents {
+            window: request.card32(data),
+        },
+    })
+}
+
+/// Implements XISelectEvents for `client`. Raw events can only be selected on the root
+/// window, touch events only all together, and only one client at a time may select
+/// touch events for a device on a window.
+fn select_events(
+    windows: &mut BTreeMap<u32, Window>,
+    client: u32,
+    window: u32,
+    masks: &[(u16, u32)],
+) -> Result<(), XError> {
+    let w = get_window(windows, window)?;
+    for (deviceid, mask) in masks {
+        if !matches!(*deviceid, ALL_DEVICES | ALL_MASTER_DEVICES) {
+            check_device(*deviceid)?;
+        }
+        let unknown = !((1 << (XI_RAW_TOUCH_END + 1)) - 1);
+        let touch = mask & TOUCH_EVENTS;
+        if mask & unknown != 0
+            || (mask & RAW_EVENTS != 0 && window != DEFAULT_SCREEN.root_window)
+            || (touch != 0 && touch != TOUCH_EVENTS)
+        {
+            return Err(XError::new(ErrorCode::Value, *mask));
+        }
+        let taken = w.xi_masks.iter().any(|((other, selected), other_mask)| {
+            *other != client && devices_overlap(*selected, *deviceid) && touch & other_mask != 0
+        });
+        if taken {
+            return Err(XError::new(ErrorCode::Access, window));
+        }
+    }
+    let w = windows.get_mut(&window).unwrap();
+    for (deviceid, mask) in masks {
+        match mask {
+            0 => w.xi_masks.remove(&(client, *deviceid)),
+            mask => w.xi_masks.insert((client, *deviceid), *mask),
+        };
+    }
+    Ok(())
+}
+
+fn class(request: &ExtensionRequest, class_type: u16, sourceid: u16, mut body: Vec<u8>) -> Vec<u8> {
+    let mut bytes = request.to_bytes_16(class_type).to_vec();
+    bytes.extend([0, 0]);
+    bytes.extend(request.to_bytes_16(sourceid));
+    bytes.append(&mut body);
+    bytes.resize(bytes.len().next_multiple_of(4), 0);
+    let length = request.to_bytes_16(bytes.len() as u16 / 4);
+    bytes[2..4].copy_from_slice(&length);
+    bytes
+}
+
+fn fp3232_bytes(request: &ExtensionRequest, value: f64) -> Vec<u8> {
+    let (integral, fraction) = fp3232(value);
+    let mut bytes = request.to_bytes_32(integral as u32).to_vec();
+    bytes.extend(request.to_bytes_32(fraction));
+    bytes
+}
+
+/// A valuator: its number, label, range, current value and whether it is absolute.
+fn valuator_class(
+    request: &ExtensionRequest,
+    sourceid: u16,
+    number: u16,
+    label: &str,
+    range: (f64, f64),
+    value: f64,
+    absolute: bool,
+) -> Vec<u8> {
+    let mut body = request.to_bytes_16(number).to_vec();
+    body.extend(request.to_bytes_32(get_atom(false, label.to_string())));
+    body.extend(fp3232_bytes(request, range.0));
+    body.extend(fp3232_bytes(request, range.1));
+    body.extend(fp3232_bytes(request, value));
+    // Resolution, then the mode.
+    body.extend(request.to_bytes_32(1));
+    body.push(absolute as u8);
+    class(request, VALUATOR_CLASS, sourceid, body)
+}
+
+fn scroll_class(request: &ExtensionRequest, sourceid: u16, number: u16, scroll_type: u16) -> Vec<u8> {
+    let mut body = request.to_bytes_16(number).to_vec();
+    body.extend(request.to_bytes_16(scroll_type));
+    body.extend([0, 0]);
+    // No flags: neither NoEmulation nor Preferred.
+    body.extend(request.to_bytes_32(0));
+    body.extend(fp3232_bytes(request, 1.0));
+    class(request, SCROLL_CLASS, sourceid, body)
+}
+
+/// The classes describing what `device` can do.
+fn device_classes(request: &ExtensionRequest, input: &InputState, device: u16) -> Vec<Vec<u8>> {
+    match device {
+        VIRTUAL_CORE_KEYBOARD | VIRTUAL_KEYBOARD => {
+            let mut body = request.to_bytes_16(248).to_vec();
+            for keycode in 8..=255 {
+                body.extend(request.to_bytes_32(keycode));
+            }
+            vec![class(request, KEY_CLASS, VIRTUAL_KEYBOARD, body)]
+        }
+        VIRTUAL_CORE_POINTER | VIRTUAL_POINTER => {
+            let mut buttons = request.to_bytes_16(BUTTON_LABELS.len() as u16).to_vec();
+            buttons.extend(request.to_bytes_32((input.buttons as u32 >> 8 & 0x1f) << 1));
+            for label in BUTTON_LABELS {
+                buttons.extend(request.to_bytes_32(get_atom(false, label.to_string())));
+            }
+            let source = VIRTUAL_POINTER;
+            let relative = (-1.0, -1.0);
+            let (x, y) = (input.pointer_x as f64, input.pointer_y as f64);
+            let (scroll_x, scroll_y) = (input.scroll.0 as f64, input.scroll.1 as f64);
+            vec![
+                class(request, BUTTON_CLASS, source, buttons),
+                valuator_class(request, source, VALUATOR_X, "Rel X", relative, x, false),
+                valuator_class(request, source, VALUATOR_Y, "Rel Y", relative, y, false),
+                valuator_class(
+                    request,
+                    source,
+                    VALUATOR_HORIZONTAL_SCROLL,
+                    "Rel Horiz Scroll",
+                    relative,
+                    scroll_x,
+                    false,
+                ),
+                valuator_class(
+                    request,
+                    source,
+                    VALUATOR_VERTICAL_SCROLL,
+                    "Rel Vert Scroll",
+                    relative,
+                    scroll_y,
+                    false,
+                ),
+                scroll_class(request, source, VALUATOR_HORIZONTAL_SCROLL, SCROLL_TYPE_HORIZONTAL),
+                scroll_class(request, source, VALUATOR_VERTICAL_SCROLL, SCROLL_TYPE_VERTICAL),
+            ]
+        }
+        _ => {
+            let size = *SCREEN_SIZE.lock().unwrap();
+            let width = (0.0, size.width as f64 - 1.0);
+            let height = (0.0, size.height as f64 - 1.0);
+            let source = VIRTUAL_TOUCHSCREEN;
+            vec![
+                valuator_class(request, source, VALUATOR_X, "Abs MT Position X", width, 0.0, true),
+                valuator_class(request, source, VALUATOR_Y, "Abs MT Position Y", height, 0.0, true),
+                class(request, TOUCH_CLASS, source, vec![DIRECT_TOUCH, MAX_TOUCHES]),
+            ]
+        }
+    }
+}
+
+fn device_info(request: &ExtensionRequest, input: &InputState, device: &DeviceDescription) -> Vec<u8> {
+    let classes = device_classes(request, input, device.id);
+    let mut bytes = vec![];
+    for value in [device.id, device.device_use, device.attachment, classes.len() as u16] {
+        bytes.extend(request.to_bytes_16(value));
+    }
+    bytes.extend(request.to_bytes_16(device.name.len() as u16));
+    // Enabled.
+    bytes.extend([1, 0]);
+    bytes.extend(device.name.as_bytes());
+    bytes.resize(bytes.len().next_multiple_of(4), 0);
+    bytes.extend(classes.concat());
+    bytes
+}
+
+/// Maps an XIAllowEvents mode to the core AllowEvents mode it amounts to for `device`.
+/// The touch modes have nothing to do, as touches are never grabbed passively.
+fn core_allow_mode(device: Device, event_mode: u8) -> Result<Option<u8>, XError> {
+    let pointer = device == Device::Pointer;
+    Ok(match event_mode {
+        0 => Some(if pointer { ASYNC_POINTER } else { ASYNC_KEYBOARD }),
+        1 => Some(if pointer { SYNC_POINTER } else { SYNC_KEYBOARD }),
+        2 => Some(if pointer { REPLAY_POINTER } else { REPLAY_KEYBOARD }),
+        3 => Some(if pointer { ASYNC_KEYBOARD } else { ASYNC_POINTER }),
+        4 => Some(ASYNC_BOTH),
+        5 => Some(SYNC_BOTH),
+        6 | 7 => None,
+        event_mode => return Err(XError::new(ErrorCode::Value, event_mode as u32)),
+    })
+}
+
+/// Checks that a passive grab of `grab_type` can be made on `deviceid` for `detail`,
+/// returning the core device it applies to.
+fn check_passive_grab(deviceid: u16, grab_type: u8, detail: u32) -> Result<Device, XError> {
+    let device = core_device(deviceid)?;
+    let expected = match grab_type {
+        GRAB_TYPE_BUTTON => Device::Pointer,
+        GRAB_TYPE_KEYCODE => Device::Keyboard,
+        grab_type => return Err(XError::new(ErrorCode::Value, grab_type as u32)),
+    };
+    if device != expected {
+        return Err(XError::new(ErrorCode::Match, deviceid as u32));
+    }
+    let detail = u8::try_from(detail).map_err(|_| XError::new(ErrorCode::Value, detail))?;
+    if device == Device::Keyboard {
+        check_grab_key(detail)?;
+    }
+    Ok(device)
+}
+
+/// Converts passive grab modifiers to the core modifiers value.
+fn core_modifiers(modifiers: u32) -> Result<u16, XError> {
+    if modifiers == XI_ANY_MODIFIER {
+        return Ok(ANY_MODIFIER);
+    }
+    let core = u16::try_from(modifiers).map_err(|_| XError::new(ErrorCode::Value, modifiers))?;
+    check_modifiers(core)?;
+    Ok(core)
+}
+
+pub fn handle_xi_request(request: &ExtensionRequest) -> Result<Option<Vec<u8>>, XError> {
+    let xi_request = read_xi_request(request)?;
+    // Replies repeat the minor opcode.
+    let reply_type = request.minor_opcode;
+    match xi_request {
+        XIRequest::GetExtensionVersion => {
+            let mut body = request.to_bytes_16(XINPUT_MAJOR_VERSION).to_vec();
+            body.extend(request.to_bytes_16(XINPUT_MINOR_VERSION));
+            body.push(1);
+            Ok(Some(request.reply(reply_type, body)))
+        }
+        XIRequest::QueryPointer { window, deviceid } => {
+            core_device(deviceid)?;
+            let windows = WINDOWS.lock().unwrap();
+            let mut input = INPUT.lock().unwrap();
+            let w = get_window(&windows, window)?;
+            input.stop_motion_hint(&windows, request.client);
+            let state = input.state();
+            let child = child_towards(&windows, window, input.pointer_window);
+            let mut body = request.to_bytes_32(DEFAULT_SCREEN.root_window).to_vec();
+            body.extend(request.to_bytes_32(child));
+            for value in [
+                input.pointer_x as i32,
+                input.pointer_y as i32,
+                input.pointer_x as i32 - w.screen_rectangle.x,
+                input.pointer_y as i32 - w.screen_rectangle.y,
+            ] {
+                body.extend(request.to_bytes_32((value << 16) as u32));
+            }
+            // Same screen, then one unit of buttons.
+            body.extend([1, 0]);
+            body.extend(request.to_bytes_16(1));
+            let modifiers = XIModifiers::current(state);
+            for mods in modifiers.mods {
+                body.extend(request.to_bytes_32(mods));
+            }
+            body.extend(modifiers.group);
+            body.extend(request.to_bytes_32((state as u32 >> 8 & 0x1f) << 1));
+            Ok(Some(request.reply(reply_type, body)))
+        }
+        XIRequest::WarpPointer {
+            src_window,
+            dst_window,
+            src,
+            dst_x,
+            dst_y,
+            deviceid,
+        } => {
+            if core_device(deviceid)? != Device::Pointer {
+                return Err(xi_error(BAD_DEVICE, deviceid as u32));
+            }
+            let windows = WINDOWS.lock().unwrap();
+            let mut input = INPUT.lock().unwrap();
+            input.warp_pointer(&windows, src_window, src, dst_window, dst_x as i16, dst_y as i16)?;
+            Ok(None)
+        }
+        XIRequest::ChangeCursor {
+            window,
+            cursor,
+            deviceid,
+        } => {
+            if core_device(deviceid)? != Device::Pointer {
+                return Err(xi_error(BAD_DEVICE, deviceid as u32));
+            }
+            // With one master pointer, this is the window's core cursor.
+            let mut windows = WINDOWS.lock().unwrap();
+            get_window(&windows, window)?;
+            check_cursor(cursor)?;
+            windows.get_mut(&window).unwrap().attributes.cursor = cursor;
+            INPUT.lock().unwrap().update_cursor(&windows);
+            Ok(None)
+        }
+        XIRequest::SetClientPointer { deviceid, .. } => {
+            // There is only one master pointer to choose.
+            if deviceid != VIRTUAL_CORE_POINTER {
+                return Err(xi_error(BAD_DEVICE, deviceid as u32));
+            }
+            Ok(None)
+        }
+        XIRequest::GetClientPointer { .. } => {
+            let mut body = vec![1, 0];
+            body.extend(request.to_bytes_16(VIRTUAL_CORE_POINTER));
+            Ok(Some(request.reply(reply_type, body)))
+        }
+        XIRequest::SelectEvents { window, masks } => {
+            let mut windows = WINDOWS.lock().unwrap();
+            select_events(&mut windows, request.client, window, &masks)?;
+            Ok(None)
+        }
+        XIRequest::QueryVersion {
+            major_version,
+            minor_version,
+        } => {
+            let (major_version, minor_version) =
+                (major_version, minor_version).min((XINPUT_MAJOR_VERSION, XINPUT_MINOR_VERSION));
+            let mut body = request.to_bytes_16(major_version).to_vec();
+            body.extend(request.to_bytes_16(minor_version));
+            Ok(Some(request.reply(reply_type, body)))
+        }
+        XIRequest::QueryDevice { deviceid } => {
+            if deviceid > ALL_MASTER_DEVICES {
+                check_device(deviceid)?;
+            }
+            let input = INPUT.lock().unwrap();
+            let infos: Vec<Vec<u8>> = DEVICES
+                .iter()
+                .filter(|device| selects_device(deviceid, device.id))
+                .map(|device| device_info(request, &input, device))
+                .collect();
+            let mut body = request.to_bytes_16(infos.len() as u16).to_vec();
+            body.resize(24, 0);
+            body.extend(infos.concat());
+            Ok(Some(request.reply(reply_type, body)))
+        }
+        XIRequest::SetFocus { focus, time, deviceid } => {
+            if core_device(deviceid)? != Device::Keyboard {
+                return Err(xi_error(BAD_DEVICE, deviceid as u32));
+            }
+            let windows = WINDOWS.lock().unwrap();
+            INPUT.lock().unwrap().set_focus(&windows, focus, REVERT_TO_PARENT, time)?;
+            Ok(None)
+        }
+        XIRequest::GetFocus { deviceid } => {
+            if core_device(deviceid)? != Device::Keyboard {
+                return Err(xi_error(BAD_DEVICE, deviceid as u32));
+            }
+            let focus = INPUT.lock().unwrap().focus.id();
+            Ok(Some(request.reply(reply_type, request.to_bytes_32(focus).to_vec())))
+        }
+        XIRequest::GrabDevice {
+            window,
+            time,
+            cursor,
+            deviceid,
+            grab_mode,
+            paired_device_mode,
+            owner_events,
+            mask,
+        } => {
+            let device = core_device(deviceid)?;
+            let windows = WINDOWS.lock().unwrap();
+            check_grab_arguments(&windows, window, 0, grab_mode, paired_device_mode)?;
+            let (pointer_mode, keyboard_mode) = match device {
+                Device::Pointer => (grab_mode, paired_device_mode),
+                Device::Keyboard => (paired_device_mode, grab_mode),
+            };
+            let grab = Grab {
+                client: request.client,
+                window,
+                owner_events,
+                event_mask: 0,
+                pointer_mode,
+                keyboard_mode,
+                confine_to: 0,
+                cursor,
+                time,
+                kind: GrabKind::Active,
+                xi2_mask: Some(mask),
+            };
+            let status = INPUT.lock().unwrap().grab_device(&windows, device, grab);
+            Ok(Some(request.reply(reply_type, vec![status])))
+        }
+        XIRequest::UngrabDevice { time, deviceid } => {
+            let device = core_device(deviceid)?;
+            let windows = WINDOWS.lock().unwrap();
+            let mut input = INPUT.lock().unwrap();
+            input.ungrab_device(&windows, device, request.client, time);
+            process_pending(&windows, &mut input);
+            Ok(None)
+        }
+        XIRequest::AllowEvents {
+            time,
+            deviceid,
+            event_mode,
+        } => {
+            let Some(mode) = core_allow_mode(core_device(deviceid)?, event_mode)? else {
+                return Ok(None);
+            };
+            let windows = WINDOWS.lock().unwrap();
+            let mut input = INPUT.lock().unwrap();
+            let result = input.allow_events(&windows, request.client, mode, time);
+            if let Ok(Some((raw, event, grab_window))) = result {
+                dispatch(&windows, &mut input, raw, event, Some(grab_window));
+            }
+            process_pending(&windows, &mut input);
+            result.map(|_| None)
+        }
+        XIRequest::PassiveGrabDevice {
+            window,
+            cursor,
+            detail,
+            deviceid,
+            grab_type,
+            grab_mode,
+            paired_device_mode,
+            owner_events,
+            mask,
+            modifiers,
+            ..
+        } => {
+            let device = check_passive_grab(deviceid, grab_type, detail)?;
+            let windows = WINDOWS.lock().unwrap();
+            check_grab_arguments(&windows, window, 0, grab_mode, paired_device_mode)?;
+            let core = modifiers
+                .iter()
+                .map(|modifiers| core_modifiers(*modifiers))
+                .collect::<Result<Vec<u16>, XError>>()?;
+            let (pointer_mode, keyboard_mode) = match device {
+                Device::Pointer => (grab_mode, paired_device_mode),
+                Device::Keyboard => (paired_device_mode, grab_mode),
+            };
+            let mut input = INPUT.lock().unwrap();
+            let mut failed = vec![];
+            for (modifiers, core) in modifiers.into_iter().zip(core) {
+                let passive = PassiveGrab {
+                    device,
+                    client: request.client,
+                    window,
+                    detail: detail as u8,
+                    modifiers: core,
+                    owner_events,
+                    event_mask: 0,
+                    pointer_mode,
+                    keyboard_mode,
+                    confine_to: 0,
+                    cursor,
+                    xi2_mask: Some(mask),
+                };
+                if input.grab_passive(passive).is_err() {
+                    failed.push(modifiers);
+                }
+            }
+            let mut body = request.to_bytes_16(failed.len() as u16).to_vec();
+            body.resize(24, 0);
+            for modifiers in failed {
+                body.extend(request.to_bytes_32(modifiers));
+                // AlreadyGrabbed.
+                body.extend([1, 0, 0, 0]);
+            }
+            Ok(Some(request.reply(reply_type, body)))
+        }
+        XIRequest::PassiveUngrabDevice {
+            window,
+            detail,
+            deviceid,
+            grab_type,
+            modifiers,
+        } => {
+            let device = check_passive_grab(deviceid, grab_type, detail)?;
+            let windows = WINDOWS.lock().unwrap();
+            get_window(&windows, window)?;
+            let mut input = INPUT.lock().unwrap();
+            for modifiers in modifiers {
+                let modifiers = core_modifiers(modifiers)?;
+                input.ungrab_passive(device, request.client, window, detail as u8, modifiers, true);
+            }
+            Ok(None)
+        }
+        XIRequest::ListProperties { deviceid } => {
+            check_device(deviceid)?;
+            Ok(Some(request.reply(reply_type, request.to_bytes_16(0).to_vec())))
+        }
+        XIRequest::GetProperty { deviceid } => {
+            // No device has properties, so every property reads as None.
+            check_device(deviceid)?;
+            Ok(Some(request.reply(reply_type, vec![])))
+        }
+        XIRequest::GetSelectedEvents { window } => {
+            let windows = WINDOWS.lock().unwrap();
+            let w = get_window(&windows, window)?;
+            let masks: Vec<(u16, u32)> = w
+                .xi_masks
+                .iter()
+                .filter(|((client, _), _)| *client == request.client)
+                .map(|((_, deviceid), mask)| (*deviceid, *mask))
+                .collect();
+            let mut body = request.to_bytes_16(masks.len() as u16).to_vec();
+            body.resize(24, 0);
+            for (deviceid, mask) in masks {
+                body.extend(request.to_bytes_16(deviceid));
+                body.extend(request.to_bytes_16(1));
+                body.extend(request.to_bytes_32(mask));
+            }
+            Ok(Some(request.reply(reply_type, body)))
+        }
+    }
+}
@@ -0,0 +1,1131 @@
+//! The X Keyboard Extension, for the core keyboard only. The keymap is compiled from
+//! an XKB text keymap when the server starts and the core keyboard mapping is derived
+//! from it; core requests that change the mapping are carried back into it. Key
+//! actions drive the modifier and group state, with latches behaving like sets.
+
+use std::{collections::BTreeMap, sync::Mutex};
+
+use crate::{
+    atom::get_atom,
+    client::CLIENTS,
+    error::{ErrorCode, XError},
+    event::Event,
+    extension::{find_extension, ExtensionRequest},
+    input::INPUT,
+    keyboard::{init_keyboard, Keymap, MAPPING_KEYBOARD, MAPPING_MODIFIER, MAX_KEYCODE, MIN_KEYCODE},
+    time,
+    xkbcomp::{
+        Action, ModDef, XkbKeymap, IM_USE_BASE, IM_USE_COMPAT, IM_USE_EFFECTIVE, IM_USE_LATCHED,
+        IM_USE_LOCKED, NUM_INDICATORS, NUM_VIRTUAL_MODS, SA_GROUP_ABSOLUTE, SA_LATCH_GROUP,
+        SA_LATCH_MODS, SA_LOCK_GROUP, SA_LOCK_MODS, SA_LOCK_NO_LOCK, SA_LOCK_NO_UNLOCK, SA_SET_GROUP,
+        SA_SET_MODS,
+    },
+};
+
+pub static XKB_NAME: &str = "XKEYBOARD";
+pub const XKB_MAJOR_VERSION: u16 = 1;
+pub const XKB_MINOR_VERSION: u16 = 0;
+
+/// Error codes, relative to the extension's first error.
+const BAD_KEYBOARD: u8 = 0;
+
+/// The device specifier naming the core keyboard, and the ID it is reported with.
+pub const USE_CORE_KBD: u16 = 0x100;
+pub const CORE_KEYBOARD_ID: u8 = 3;
+
+pub const NEW_KEYBOARD_NOTIFY: u8 = 0;
+pub const MAP_NOTIFY: u8 = 1;
+pub const STATE_NOTIFY: u8 = 2;
+const NUM_EVENT_TYPES: usize = 12;
+
+/// Bytes in each of the affect and details masks SelectEvents takes for an event type.
+/// MapNotify's are in the fixed part of the request.
+const EVENT_DETAILS_SIZE: [usize; NUM_EVENT_TYPES] = [2, 0, 2, 4, 4, 4, 2, 1, 1, 1, 2, 2];
+
+pub const NKN_KEYCODES: u16 = 1 << 0;
+
+/// Parts of the keymap, in GetMap and MapNotify.
+pub const MAP_KEY_TYPES: u16 = 1 << 0;
+pub const MAP_KEY_SYMS: u16 = 1 << 1;
+pub const MAP_MODIFIER_MAP: u16 = 1 << 2;
+pub const MAP_EXPLICIT_COMPONENTS: u16 = 1 << 3;
+pub const MAP_KEY_ACTIONS: u16 = 1 << 4;
+pub const MAP_KEY_BEHAVIORS: u16 = 1 << 5;
+pub const MAP_VIRTUAL_MODS: u16 = 1 << 6;
+pub const MAP_VIRTUAL_MOD_MAP: u16 = 1 << 7;
+const MAP_ALL_PARTS: u16 = (1 << 8) - 1;
+
+/// Parts of the keyboard state, in StateNotify. The first eight follow the order of
+/// `Xkb::summary`.
+const STATE_COMPAT_STATE: u16 = 1 << 8;
+const STATE_GRAB_MODS: u16 = 1 << 9;
+const STATE_COMPAT_GRAB_MODS: u16 = 1 << 10;
+const STATE_LOOKUP_MODS: u16 = 1 << 11;
+const STATE_COMPAT_LOOKUP_MODS: u16 = 1 << 12;
+
+/// Names GetNames can return.
+const NAME_KEYCODES: u32 = 1 << 0;
+const NAME_GEOMETRY: u32 = 1 << 1;
+const NAME_SYMBOLS: u32 = 1 << 2;
+const NAME_PHYS_SYMBOLS: u32 = 1 << 3;
+const NAME_TYPES: u32 = 1 << 4;
+const NAME_COMPAT: u32 = 1 << 5;
+const NAME_KEY_TYPE_NAMES: u32 = 1 << 6;
+const NAME_KT_LEVEL_NAMES: u32 = 1 << 7;
+const NAME_INDICATOR_NAMES: u32 = 1 << 8;
+const NAME_KEY_NAMES: u32 = 1 << 9;
+const NAME_KEY_ALIASES: u32 = 1 << 10;
+const NAME_VIRTUAL_MOD_NAMES: u32 = 1 << 11;
+const NAME_GROUP_NAMES: u32 = 1 << 12;
+const NAME_RG_NAMES: u32 = 1 << 13;
+const NAME_ALL: u32 = (1 << 14) - 1;
+
+pub const CONTROL_REPEAT_KEYS: u32 = 1 << 0;
+pub const CONTROL_AUDIBLE_BELL: u32 = 1 << 9;
+
+/// Per-client flags. Event state always comes from XKB, so only detectable auto-repeat
+/// changes anything.
+pub const PCF_DETECTABLE_AUTO_REPEAT: u32 = 1 << 0;
+const PCF_GRABS_USE_XKB_STATE: u32 = 1 << 1;
+const PCF_LOOKUP_STATE_WHEN_GRABBED: u32 = 1 << 3;
+const PCF_SEND_EVENT_USES_XKB_STATE: u32 = 1 << 4;
+const PCF_SUPPORTED: u32 = PCF_DETECTABLE_AUTO_REPEAT
+    | PCF_GRABS_USE_XKB_STATE
+    | PCF_LOOKUP_STATE_WHEN_GRABBED
+    | PCF_SEND_EVENT_USES_XKB_STATE;
+
+/// The virtual modifier index reported for interpretations without one.
+const NO_MODIFIER: u8 = 0xff;
+
+/// The XKB keymap, keyboard state and controls. Lock after `INPUT` and `KEYMAP`.
+pub static XKB: Mutex<Xkb> = Mutex::new(Xkb {
+    keymap: XkbKeymap::EMPTY,
+    state: KeyboardState {
+        base_mods: 0,
+        latched_mods: 0,
+        locked_mods: 0,
+        base_group: 0,
+        latched_group: 0,
+        locked_group: 0,
+    },
+    held: BTreeMap::new(),
+    repeat_delay: 660,
+    repeat_interval: 40,
+    enabled_controls: CONTROL_REPEAT_KEYS | CONTROL_AUDIBLE_BELL,
+});
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct KeyboardState {
+    pub base_mods: u8,
+    pub latched_mods: u8,
+    pub locked_mods: u8,
+    pub base_group: i16,
+    pub latched_group: i16,
+    pub locked_group: u8,
+}
+
+#[derive(Debug)]
+pub struct Xkb {
+    pub keymap: XkbKeymap,
+    pub state: KeyboardState,
+    /// The action each held key performed when pressed, with its modifiers resolved,
+    /// and for LockMods the modifiers that were already locked.
+    held: BTreeMap<u8, (Action, u8)>,
+    pub repeat_delay: u16,
+    pub repeat_interval: u16,
+    pub enabled_controls: u32,
+}
+
+impl Xkb {
+    /// Installs a new keymap, resetting the keyboard state.
+    pub fn set_keymap(&mut self, keymap: XkbKeymap) {
+        self.keymap = keymap;
+        self.state = KeyboardState::default();
+        self.held.clear();
+    }
+
+    /// The effective modifiers.
+    pub fn mods(&self) -> u8 {
+        self.state.base_mods | self.state.latched_mods | self.state.locked_mods
+    }
+
+    /// The effective group, wrapped into the keymap's groups.
+    pub fn group(&self) -> u8 {
+        let group = self.state.base_group + self.state.latched_group + self.state.locked_group as i16;
+        group.rem_euclid(self.keymap.num_groups() as i16) as u8
+    }
+
+    /// The modifier and group bits of the `state` field of core events.
+    pub fn core_state(&self) -> u16 {
+        self.mods() as u16 | (self.group() as u16) << 13
+    }
+
+    /// Effective, base, latched and locked modifiers, then the same for the group.
+    fn summary(&self) -> [i16; 8] {
+        let state = &self.state;
+        [
+            self.mods() as i16,
+            state.base_mods as i16,
+            state.latched_mods as i16,
+            state.locked_mods as i16,
+            self.group() as i16,
+            state.base_group,
+            state.latched_group,
+            state.locked_group as i16,
+        ]
+    }
+
+    /// The StateNotify change bits between an earlier `summary` and now.
+    fn changes(&self, old: [i16; 8]) -> u16 {
+        let new = self.summary();
+        let mut changed = (0..8).filter(|i| old[*i] != new[*i]).fold(0, |changed, i| changed | 1 << i);
+        if changed & (1 << 0 | 1 << 4) != 0 {
+            changed |= STATE_COMPAT_STATE
+                | STATE_GRAB_MODS
+                | STATE_COMPAT_GRAB_MODS
+                | STATE_LOOKUP_MODS
+                | STATE_COMPAT_LOOKUP_MODS;
+        }
+        changed
+    }
+
+    /// Performs the action bound to `keycode` when it is pressed, or undoes it when it
+    /// is released.
+    fn apply_key(&mut self, keycode: u8, pressed: bool) {
+        let num_groups = self.keymap.num_groups() as i16;
+        if pressed {
+            let mut action = self.keymap.key_action(keycode, self.group(), self.mods());
+            action.mods = ModDef {
+                real_mods: self.keymap.resolve(action.mods),
+                vmods: 0,
+            };
+            let mut locked = 0;
+            match action.kind {
+                SA_LOCK_MODS => {
+                    locked = self.state.locked_mods & action.mods.real_mods;
+                    if action.flags & SA_LOCK_NO_LOCK == 0 {
+                        self.state.locked_mods |= action.mods.real_mods;
+                    }
+                }
+                SA_LOCK_GROUP => {
+                    let group = match action.flags & SA_GROUP_ABSOLUTE {
+                        0 => self.state.locked_group as i16 + action.group as i16,
+                        _ => action.group as i16,
+                    };
+                    self.state.locked_group = group.rem_euclid(num_groups) as u8;
+                }
+                _ => {}
+            }
+            self.held.insert(keycode, (action, locked));
+        } else {
+            let Some((action, locked)) = self.held.remove(&keycode) else {
+                return;
+            };
+            // A lock key unlocks when released if its modifiers were locked before it
+            // was pressed.
+            if action.kind == SA_LOCK_MODS && action.flags & SA_LOCK_NO_UNLOCK == 0 {
+                self.state.locked_mods &= !locked;
+            }
+        }
+        self.state.base_mods = 0;
+        self.state.base_group = 0;
+        for (action, _) in self.held.values() {
+            match action.kind {
+                SA_SET_MODS | SA_LATCH_MODS | SA_LOCK_MODS => self.state.base_mods |= action.mods.real_mods,
+                SA_SET_GROUP | SA_LATCH_GROUP if action.flags & SA_GROUP_ABSOLUTE != 0 => {
+                    self.state.base_group = action.group as i16;
+                }
+                SA_SET_GROUP | SA_LATCH_GROUP => self.state.base_group += action.group as i16,
+                _ => {}
+            }
+        }
+    }
+
+    /// The indicators lit by the keyboard state, one bit per indicator.
+    pub fn indicator_state(&self) -> u32 {
+        let mut lit = 0;
+        for (index, map) in self.keymap.indicators.iter().enumerate() {
+            let which_mods = [
+                (IM_USE_BASE, self.state.base_mods),
+                (IM_USE_LATCHED, self.state.latched_mods),
+                (IM_USE_LOCKED, self.state.locked_mods),
+                (IM_USE_EFFECTIVE, self.mods()),
+                (IM_USE_COMPAT, self.mods()),
+            ];
+            let mods = which_mods
+                .iter()
+                .filter(|(which, _)| map.which_mods & which != 0)
+                .fold(0, |mods, (_, state)| mods | state);
+            let num_groups = self.keymap.num_groups() as i16;
+            let which_groups = [
+                (IM_USE_BASE, self.state.base_group.rem_euclid(num_groups) as u8),
+                (IM_USE_LATCHED, self.state.latched_group.rem_euclid(num_groups) as u8),
+                (IM_USE_LOCKED, self.state.locked_group),
+                (IM_USE_EFFECTIVE, self.group()),
+                (IM_USE_COMPAT, self.group()),
+            ];
+            let groups = which_groups
+                .iter()
+                .filter(|(which, _)| map.which_groups & which != 0)
+                .fold(0, |groups, (_, group)| groups | 1 << group);
+            if mods & self.keymap.resolve(map.mods) != 0
+                || map.groups & groups != 0
+                || map.ctrls & self.enabled_controls != 0
+            {
+                lit |= 1 << index;
+            }
+        }
+        lit
+    }
+
+    /// Whether holding `keycode` makes it repeat.
+    pub fn key_repeats(&self, keycode: u8) -> bool {
+        self.enabled_controls & CONTROL_REPEAT_KEYS != 0 && self.keymap.keys[keycode as usize].repeat
+    }
+
+    /// One bit per keycode, set for keys that repeat when held.
+    pub fn per_key_repeat(&self) -> [u8; 32] {
+        let mut repeat = [0; 32];
+        for (keycode, key) in self.keymap.keys.iter().enumerate() {
+            if key.repeat {
+                repeat[keycode / 8] |= 1 << (keycode % 8);
+            }
+        }
+        repeat
+    }
+
+    fn map_notify(&self, changed: u16, first_key: u8, n_keys: u8) -> Event {
+        let types = changed & MAP_KEY_TYPES != 0;
+        Event::XkbMapNotify {
+            time: time::now(),
+            changed,
+            min_key_code: self.keymap.min_keycode,
+            max_key_code: self.keymap.max_keycode,
+            first_type: 0,
+            n_types: if types { self.keymap.types.len() as u8 } else { 0 },
+            first_key,
+            n_keys,
+            virtual_mods: if changed & MAP_VIRTUAL_MODS != 0 { self.keymap.vmod_mask() } else { 0 },
+        }
+    }
+
+    /// MapNotify for the whole keymap.
+    fn full_map_notify(&self) -> Event {
+        let n_keys = self.keymap.max_keycode - self.keymap.min_keycode + 1;
+        self.map_notify(MAP_ALL_PARTS & !MAP_KEY_BEHAVIORS, self.keymap.min_keycode, n_keys)
+    }
+}
+
+/// Queues `event` for every client that selected one of the `detail` bits for
+/// `event_type`.
+fn deliver_xkb_event(event_type: u8, detail: u16, event: Event) {
+    for client in CLIENTS.lock().unwrap().values_mut() {
+        if client.xkb_events[event_type as usize] & detail as u32 != 0 {
+            client.events.push_back(event.clone());
+        }
+    }
+}
+
+/// Updates the keyboard state for a key press or release, sending StateNotify if it
+/// changed. `buttons` is the pointer button state reported alongside.
+pub fn process_key(keycode: u8, pressed: bool, buttons: u16) {
+    let mut xkb = XKB.lock().unwrap();
+    let old = xkb.summary();
+    xkb.apply_key(keycode, pressed);
+    let changed = xkb.changes(old);
+    if changed == 0 {
+        return;
+    }
+    let event = Event::XkbStateNotify {
+        time: time::now(),
+        mods: xkb.mods(),
+        base_mods: xkb.state.base_mods,
+        latched_mods: xkb.state.latched_mods,
+        locked_mods: xkb.state.locked_mods,
+        group: xkb.group(),
+        base_group: xkb.state.base_group,
+        latched_group: xkb.state.latched_group,
+        locked_group: xkb.state.locked_group,
+        ptr_btn_state: buttons,
+        changed,
+        keycode,
+        event_type: if pressed { 2 } else { 3 },
+    };
+    drop(xkb);
+    deliver_xkb_event(STATE_NOTIFY, changed, event);
+}
+
+/// Whether holding `keycode` makes it repeat.
+pub fn key_repeats(keycode: u8) -> bool {
+    XKB.lock().unwrap().key_repeats(keycode)
+}
+
+/// Carries a core ChangeKeyboardMapping of `count` keys over to the XKB keymap.
+pub fn core_keysyms_changed(keymap: &Keymap, first_keycode: u8, count: u8) {
+    let mut xkb = XKB.lock().unwrap();
+    // The range can end at keycode 255, so step by offset rather than to an end keycode.
+    for offset in 0..count {
+        let keycode = first_keycode + offset;
+        let columns = keymap.get_keysyms(keycode, 1).unwrap_or_default();
+        xkb.keymap.set_core_keysyms(keycode, &columns);
+    }
+    xkb.keymap.apply_compat();
+    let changed =
+        MAP_KEY_SYMS | MAP_KEY_ACTIONS | MAP_EXPLICIT_COMPONENTS | MAP_VIRTUAL_MOD_MAP | MAP_VIRTUAL_MODS;
+    let event = xkb.map_notify(changed, first_keycode, count);
+    drop(xkb);
+    deliver_xkb_event(MAP_NOTIFY, changed, event);
+}
+
+/// Carries a core SetModifierMapping over to the XKB keymap.
+pub fn core_modifiers_changed(keymap: &Keymap) {
+    let mut xkb = XKB.lock().unwrap();
+    for (keycode, key) in xkb.keymap.keys.iter_mut().enumerate() {
+        key.modmap = keymap.modifier_mask(keycode as u8) as u8;
+    }
+    xkb.keymap.apply_compat();
+    let changed = MAP_MODIFIER_MAP | MAP_KEY_ACTIONS | MAP_VIRTUAL_MOD_MAP | MAP_VIRTUAL_MODS;
+    let (first_key, n_keys) = (xkb.keymap.min_keycode, xkb.keymap.max_keycode - xkb.keymap.min_keycode + 1);
+    let event = xkb.map_notify(changed, first_key, n_keys);
+    drop(xkb);
+    deliver_xkb_event(MAP_NOTIFY, changed, event);
+}
+
+/// Replaces the keymap while the server runs, telling XKB clients with
+/// NewKeyboardNotify and MapNotify and core clients with MappingNotify.
+pub fn replace_keymap(text: &str) -> Result<(), String> {
+    let (old_min, old_max) = {
+        let xkb = XKB.lock().unwrap();
+        (xkb.keymap.min_keycode, xkb.keymap.max_keycode)
+    };
+    init_keyboard(text)?;
+    let xkb = XKB.lock().unwrap();
+    let new_keyboard = Event::XkbNewKeyboardNotify {
+        time: time::now(),
+        min_key_code: xkb.keymap.min_keycode,
+        max_key_code: xkb.keymap.max_keycode,
+        old_min_key_code: old_min,
+        old_max_key_code: old_max,
+        changed: NKN_KEYCODES,
+    };
+    let map = xkb.full_map_notify();
+    drop(xkb);
+    deliver_xkb_event(NEW_KEYBOARD_NOTIFY, NKN_KEYCODES, new_keyboard);
+    deliver_xkb_event(MAP_NOTIFY, MAP_ALL_PARTS, map);
+    crate::client::broadcast_event(Event::MappingNotify {
+        request: MAPPING_KEYBOARD,
+        first_keycode: MIN_KEYCODE,
+        count: MAX_KEYCODE - MIN_KEYCODE + 1,
+    });
+    crate::client::broadcast_event(Event::MappingNotify {
+        request: MAPPING_MODIFIER,
+        first_keycode: 0,
+        count: 0,
+    });
+    Ok(())
+}
+
+#[derive(Debug)]
+pub enum XkbRequest {
+    UseExtension {
+        wanted_major: u16,
+        wanted_minor: u16,
+    },
+    SelectEvents {
+        device_spec: u16,
+        affect_which: u16,
+        clear: u16,
+        select_all: u16,
+        affect_map: u16,
+        map: u16,
+        details: Vec<u8>,
+    },
+    GetState {
+        device_spec: u16,
+    },
+    GetControls {
+        device_spec: u16,
+    },
+    GetMap {
+        device_spec: u16,
+        full: u16,
+        partial: u16,
+        first_type: u8,
+        n_types: u8,
+        /// First keycode and count for key symbols, actions, behaviors, explicit
+        /// components, modifier map and virtual modifier map.
+        key_ranges: [(u8, u8); 6],
+        virtual_mods: u16,
+    },
+    GetCompatMap {
+        device_spec: u16,
+        groups: u8,
+        get_all_si: bool,
+        first_si: u16,
+        n_si: u16,
+    },
+    GetIndicatorMap {
+        device_spec: u16,
+        which: u32,
+    },
+    GetNames {
+        device_spec: u16,
+        which: u32,
+    },
+    PerClientFlags {
+        device_spec: u16,
+        change: u32,
+        value: u32,
+    },
+    GetDeviceInfo {
+        device_spec: u16,
+        wanted: u16,
+    },
+}
+
+fn read_xkb_request(request: &ExtensionRequest) -> Result<XkbRequest, XError> {
+    let data = request.data;
+    let minimum_length = match request.minor_opcode {
+        0 | 4 | 6 => 4,
+        10 | 13 | 17 => 8,
+        1 | 24 => 12,
+        8 | 21 => 24,
+        minor_opcode => return Err(XError::new(ErrorCode::Request, minor_opcode as u32)),
+    };
+    if data.len() < minimum_length {
+        return Err(XError::new(ErrorCode::Length, 0));
+    }
+    let device_spec = request.card16(data);
+    Ok(match request.minor_opcode {
+        0 => XkbRequest::UseExtension {
+            wanted_major: request.card16(data),
+            wanted_minor: request.card16(&data[2..]),
+        },
+        1 => XkbRequest::SelectEvents {
+            device_spec,
+            affect_which: request.card16(&data[2..]),
+            clear: request.card16(&data[4..]),
+            select_all: request.card16(&data[6..]),
+            affect_map: request.card16(&data[8..]),
+            map: request.card16(&data[10..]),
+            details: data[12..].to_vec(),
+        },
+        4 => XkbRequest::GetState { device_spec },
+        6 => XkbRequest::GetControls { device_spec },
+        8 => XkbRequest::GetMap {
+            device_spec,
+            full: request.card16(&data[2..]),
+            partial: request.card16(&data[4..]),
+            first_type: data[6],
+            n_types: data[7],
+            key_ranges: [
+                (data[8], data[9]),
+                (data[10], data[11]),
+                (data[12], data[13]),
+                (data[16], data[17]),
+                (data[18], data[19]),
+                (data[20], data[21]),
+            ],
+            virtual_mods: request.card16(&data[14..]),
+        },
+        10 => XkbRequest::GetCompatMap {
+            device_spec,
+            groups: data[2],
+            get_all_si: data[3] != 0,
+            first_si: request.card16(&data[4..]),
+            n_si: request.card16(&data[6..]),
+        },
+        13 => XkbRequest::GetIndicatorMap {
+            device_spec,
+            which: request.card32(&data[4..]),
+        },
+        17 => XkbRequest::GetNames {
+            device_spec,
+            which: request.card32(&data[4..]),
+        },
+        21 => XkbRequest::PerClientFlags {
+            device_spec,
+            change: request.card32(&data[4..]),
+            value: request.card32(&data[8..]),
+        },
+        _ => XkbRequest::GetDeviceInfo {
+            device_spec,
+            wanted: request.card16(&data[2..]),
+        },
+    })
+}
+
+fn xkb_error(offset: u8, bad_value: u32) -> XError {
+    let first_error = find_extension(XKB_NAME).map_or(0, |e| e.first_error);
+    XError::extension(first_error + offset, bad_value)
+}
+
+/// Only the core keyboard exists.
+fn check_device(device_spec: u16) -> Result<(), XError> {
+    if device_spec != USE_CORE_KBD && device_spec != CORE_KEYBOARD_ID as u16 {
+        return Err(xkb_error(BAD_KEYBOARD, device_spec as u32));
+    }
+    Ok(())
+}
+
+fn name_atom(name: &str) -> u32 {
+    match name {
+        "" => 0,
+        name => get_atom(false, name.to_string()),
+    }
+}
+
+/// Pads `bytes` to a multiple of four.
+fn pad(bytes: &mut Vec<u8>) {
+    bytes.resize(bytes.len().next_multiple_of(4), 0);
+}
+
+fn mod_def_bytes(request: &ExtensionRequest, keymap: &XkbKeymap, mods: ModDef) -> Vec<u8> {
+    let mut bytes = vec![keymap.resolve(mods), mods.real_mods];
+    bytes.extend(request.to_bytes_16(mods.vmods));
+    bytes
+}
+
+fn action_bytes(keymap: &XkbKeymap, action: &Action) -> [u8; 8] {
+    match action.kind {
+        SA_SET_MODS | SA_LATCH_MODS | SA_LOCK_MODS => {
+            let vmods = action.mods.vmods.to_be_bytes();
+            let mask = keymap.resolve(action.mods);
+            [action.kind, action.flags, mask, action.mods.real_mods, vmods[0], vmods[1], 0, 0]
+        }
+        SA_SET_GROUP | SA_LATCH_GROUP | SA_LOCK_GROUP => {
+            [action.kind, action.flags, action.group as u8, 0, 0, 0, 0, 0]
+        }
+        _ => [0; 8],
+    }
+}
+
+/// Implements SelectEvents. Each event type has an affect and a details mask, except
+/// that MapNotify's are in the fixed part of the request.
+fn select_events(
+    request: &ExtensionRequest,
+    affect_which: u16,
+    clear: u16,
+    select_all: u16,
+    (affect_map, map): (u16, u16),
+    details: &[u8],
+) -> Result<(), XError> {
+    let mut selected =
+        CLIENTS.lock().unwrap().get(&request.client).map_or([0; NUM_EVENT_TYPES], |c| c.xkb_events);
+    let mut offset = 0;
+    for (event_type, size) in EVENT_DETAILS_SIZE.iter().enumerate() {
+        let bit = 1 << event_type;
+        if affect_which & bit == 0 {
+            continue;
+        }
+        let (affect, detail) = if clear & bit != 0 {
+            (u32::MAX, 0)
+        } else if select_all & bit != 0 {
+            (u32::MAX, u32::MAX)
+        } else if event_type == MAP_NOTIFY as usize {
+            (affect_map as u32, map as u32)
+        } else {
+            let Some(masks) = details.get(offset..offset + size * 2) else {
+                return Err(XError::new(ErrorCode::Length, 0));
+            };
+            offset += size * 2;
+            match size {
+                1 => (masks[0] as u32, masks[1] as u32),
+                2 => (request.card16(masks) as u32, request.card16(&masks[2..]) as u32),
+                _ => (request.card32(masks), request.card32(&masks[4..])),
+            }
+        };
+        selected[event_type] = selected[event_type] & !affect | detail & affect;
+    }
+    if let Some(client) = CLIENTS.lock().unwrap().get_mut(&request.client) {
+        client.xkb_events = selected;
+    }
+    Ok(())
+}
+
+fn get_state(request: &ExtensionRequest) -> Vec<u8> {
+    let buttons = INPUT.lock().unwrap().buttons;
+    let xkb = XKB.lock().unwrap();
+    let mods = xkb.mods();
+    let state = &xkb.state;
+    let mut body = vec![
+        mods,
+        state.base_mods,
+        state.latched_mods,
+        state.locked_mods,
+        xkb.group(),
+        state.locked_group,
+    ];
+    body.extend(request.to_bytes_16(state.base_group as u16));
+    body.extend(request.to_bytes_16(state.latched_group as u16));
+    // The compatibility, grab and lookup states are all the effective modifiers.
+    body.extend([mods, mods, mods, mods, mods, 0]);
+    body.extend(request.to_bytes_16(buttons));
+    request.reply(CORE_KEYBOARD_ID, body)
+}
+
+fn get_controls(request: &ExtensionRequest) -> Vec<u8> {
+    let xkb = XKB.lock().unwrap();
+    let mut body = vec![0, xkb.keymap.num_groups(), 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    body.extend(request.to_bytes_16(xkb.repeat_delay));
+    body.extend(request.to_bytes_16(xkb.repeat_interval));
+    // Slow keys, bounce keys, mouse keys and AccessX settings, none of which are
+    // supported.
+    body.resize(48, 0);
+    body.extend(request.to_bytes_32(xkb.enabled_controls));
+    body.extend(xkb.per_key_repeat());
+    request.reply(CORE_KEYBOARD_ID, body)
+}
+
+/// Implements GetMap. Parts in `full` cover the whole keymap and parts in `partial` the
+/// requested types and keys.
+fn get_map(
+    request: &ExtensionRequest,
+    full: u16,
+    partial: u16,
+    (first_type, n_types): (u8, u8),
+    key_ranges: [(u8, u8); 6],
+    virtual_mods: u16,
+) -> Result<Vec<u8>, XError> {
+    let xkb = XKB.lock().unwrap();
+    let keymap = &xkb.keymap;
+    let (min, max) = (keymap.min_keycode, keymap.max_keycode);
+    let key_parts = [
+        MAP_KEY_SYMS,
+        MAP_KEY_ACTIONS,
+        MAP_KEY_BEHAVIORS,
+        MAP_EXPLICIT_COMPONENTS,
+        MAP_MODIFIER_MAP,
+        MAP_VIRTUAL_MOD_MAP,
+    ];
+    let mut ranges = [None; 6];
+    for (index, part) in key_parts.iter().enumerate() {
+        let (first, n) = key_ranges[index];
+        ranges[index] = if full & part != 0 {
+            Some((min, max - min + 1))
+        } else if partial & part != 0 {
+            if first < min || first as usize + n as usize > max as usize + 1 {
+                return Err(XError::new(ErrorCode::Value, first as u32));
+            }
+            Some((first, n))
+        } else {
+            None
+        };
+    }
+    let types = if full & MAP_KEY_TYPES != 0 {
+        Some((0, keymap.types.len() as u8))
+    } else if partial & MAP_KEY_TYPES != 0 {
+        if first_type as usize + n_types as usize > keymap.types.len() {
+            return Err(XError::new(ErrorCode::Value, first_type as u32));
+        }
+        Some((first_type, n_types))
+    } else {
+        None
+    };
+    let virtual_mods = match (full & MAP_VIRTUAL_MODS, partial & MAP_VIRTUAL_MODS) {
+        (0, 0) => None,
+        (0, _) => Some(virtual_mods),
+        _ => Some(keymap.vmod_mask()),
+    };
+    let keys = |range: Option<(u8, u8)>| {
+        let (first, n) = range.unwrap_or((0, 0));
+        (first as usize..first as usize + n as usize).map(|keycode| (keycode as u8, &keymap.keys[keycode]))
+    };
+
+    let mut lists = vec![];
+    if let Some((first, n)) = types {
+        for key_type in &keymap.types[first as usize..first as usize + n as usize] {
+            let has_preserve = key_type.entries.iter().any(|entry| entry.preserve != ModDef::default());
+            lists.extend(mod_def_bytes(request, keymap, key_type.mods));
+            lists.extend([key_type.num_levels, key_type.entries.len() as u8, has_preserve as u8, 0]);
+            for entry in &key_type.entries {
+                let mods = mod_def_bytes(request, keymap, entry.mods);
+                lists.extend([keymap.entry_active(entry) as u8, mods[0], entry.level, mods[1]]);
+                lists.extend(&mods[2..]);
+                lists.extend([0, 0]);
+            }
+            if has_preserve {
+                for entry in &key_type.entries {
+                    lists.extend(mod_def_bytes(request, keymap, entry.preserve));
+                }
+            }
+        }
+    }
+    let mut total_syms = 0;
+    for (_, key) in keys(ranges[0]) {
+        let width = key.width();
+        let mut kt_index = [0; 4];
+        for (index, group) in key.groups.iter().enumerate() {
+            kt_index[index] = group.key_type as u8;
+        }
+        lists.extend(kt_index);
+        lists.extend([key.groups.len() as u8, width as u8]);
+        lists.extend(request.to_bytes_16((width * key.groups.len()) as u16));
+        for group in &key.groups {
+            for level in 0..width {
+                lists.extend(request.to_bytes_32(group.syms.get(level).copied().unwrap_or(0)));
+            }
+        }
+        total_syms += width * key.groups.len();
+    }
+    let mut total_actions = 0;
+    let mut actions = vec![];
+    for (_, key) in keys(ranges[1]) {
+        if !key.has_actions() {
+            lists.push(0);
+            continue;
+        }
+        let width = key.width();
+        lists.push((width * key.groups.len()) as u8);
+        for group in &key.groups {
+            for level in 0..width {
+                let action = group.actions.get(level).copied().unwrap_or_default();
+                actions.extend(action_bytes(keymap, &action));
+            }
+        }
+        total_actions += width * key.groups.len();
+    }
+    if ranges[1].is_some() {
+        pad(&mut lists);
+        lists.append(&mut actions);
+    }
+    // No key has a behavior, so that list is always empty.
+    if let Some(mask) = virtual_mods {
+        lists.extend((0..NUM_VIRTUAL_MODS).filter(|i| mask & 1 << i != 0).map(|i| keymap.vmods[i]));
+        pad(&mut lists);
+    }
+    let explicit: Vec<(u8, u8)> = keys(ranges[3])
+        .filter(|(_, key)| key.explicit != 0)
+        .map(|(keycode, key)| (keycode, key.explicit))
+        .collect();
+    if ranges[3].is_some() {
+        lists.extend(explicit.iter().flat_map(|(keycode, explicit)| [*keycode, *explicit]));
+        pad(&mut lists);
+    }
+    let modmap: Vec<(u8, u8)> = keys(ranges[4])
+        .filter(|(_, key)| key.modmap != 0)
+        .map(|(keycode, key)| (keycode, key.modmap))
+        .collect();
+    if ranges[4].is_some() {
+        lists.extend(modmap.iter().flat_map(|(keycode, mods)| [*keycode, *mods]));
+        pad(&mut lists);
+    }
+    let vmodmap: Vec<(u8, u16)> = keys(ranges[5])
+        .filter(|(_, key)| key.vmodmap != 0)
+        .map(|(keycode, key)| (keycode, key.vmodmap))
+        .collect();
+    for (keycode, vmods) in &vmodmap {
+        lists.extend([*keycode, 0]);
+        lists.extend(request.to_bytes_16(*vmods));
+    }
+
+    let present = types.map_or(0, |_| MAP_KEY_TYPES)
+        | key_parts
+            .iter()
+            .zip(ranges)
+            .filter(|(_, range)| range.is_some())
+            .fold(0, |present, (part, _)| present | part)
+        | virtual_mods.map_or(0, |_| MAP_VIRTUAL_MODS);
+    let (first_type, n_types) = types.unwrap_or_default();
+    let range = |index: usize| ranges[index].unwrap_or_default();
+    let mut body = vec![0, 0, min, max];
+    body.extend(request.to_bytes_16(present));
+    body.extend([first_type, n_types, keymap.types.len() as u8, range(0).0]);
+    body.extend(request.to_bytes_16(total_syms as u16));
+    body.extend([range(0).1, range(1).0]);
+    body.extend(request.to_bytes_16(total_actions as u16));
+    body.extend([range(1).1, range(2).0, range(2).1, 0]);
+    body.extend([range(3).0, range(3).1, explicit.len() as u8]);
+    body.extend([range(4).0, range(4).1, modmap.len() as u8]);
+    body.extend([range(5).0, range(5).1, vmodmap.len() as u8, 0]);
+    body.extend(request.to_bytes_16(virtual_mods.unwrap_or(0)));
+    body.append(&mut lists);
+    Ok(request.reply(CORE_KEYBOARD_ID, body))
+}
+
+fn get_compat_map(
+    request: &ExtensionRequest,
+    groups: u8,
+    get_all_si: bool,
+    first_si: u16,
+    n_si: u16,
+) -> Result<Vec<u8>, XError> {
+    let xkb = XKB.lock().unwrap();
+    let keymap = &xkb.keymap;
+    let total = keymap.interprets.len();
+    let (first_si, n_si) = match get_all_si {
+        true => (0, total),
+        false if first_si as usize + n_si as usize <= total => (first_si as usize, n_si as usize),
+        false => return Err(XError::new(ErrorCode::Value, first_si as u32)),
+    };
+    let groups = groups & 0x0f;
+    let mut body = vec![groups, 0];
+    body.extend(request.to_bytes_16(first_si as u16));
+    body.extend(request.to_bytes_16(n_si as u16));
+    body.extend(request.to_bytes_16(total as u16));
+    body.resize(24, 0);
+    for interpret in &keymap.interprets[first_si..first_si + n_si] {
+        body.extend(request.to_bytes_32(interpret.keysym));
+        body.extend([
+            interpret.mods,
+            interpret.match_op,
+            interpret.virtual_mod.unwrap_or(NO_MODIFIER),
+            interpret.flags,
+        ]);
+        body.extend(action_bytes(keymap, &interpret.action));
+    }
+    // Groups don't map to compatibility modifiers.
+    body.resize(body.len() + groups.count_ones() as usize * 4, 0);
+    Ok(request.reply(CORE_KEYBOARD_ID, body))
+}
+
+fn get_indicator_map(request: &ExtensionRequest, which: u32) -> Vec<u8> {
+    let xkb = XKB.lock().unwrap();
+    let keymap = &xkb.keymap;
+    let real_indicators = (0..NUM_INDICATORS)
+        .filter(|i| keymap.indicator_names[*i].is_some())
+        .fold(0u32, |mask, i| mask | 1 << i);
+    let mut body = request.to_bytes_32(which).to_vec();
+    body.extend(request.to_bytes_32(real_indicators));
+    body.push(NUM_INDICATORS as u8);
+    body.resize(24, 0);
+    for (index, map) in keymap.indicators.iter().enumerate() {
+        if which & 1 << index != 0 {
+            body.extend([map.flags, map.which_groups, map.groups, map.which_mods]);
+            body.extend(mod_def_bytes(request, keymap, map.mods));
+            body.extend(request.to_bytes_32(map.ctrls));
+        }
+    }
+    request.reply(CORE_KEYBOARD_ID, body)
+}
+
+fn key_name_bytes(name: &str) -> [u8; 4] {
+    let mut bytes = [0; 4];
+    for (byte, c) in bytes.iter_mut().zip(name.bytes()) {
+        *byte = c;
+    }
+    bytes
+}
+
+fn get_names(request: &ExtensionRequest, which: u32) -> Vec<u8> {
+    let xkb = XKB.lock().unwrap();
+    let keymap = &xkb.keymap;
+    let which = which & NAME_ALL & !NAME_RG_NAMES;
+    let indicators = (0..NUM_INDICATORS)
+        .filter(|i| keymap.indicator_names[*i].is_some())
+        .fold(0u32, |mask, i| mask | 1 << i);
+    let group_names = (0..keymap.group_names.len())
+        .filter(|i| !keymap.group_names[*i].is_empty())
+        .fold(0u8, |mask, i| mask | 1 << i);
+    let levels: usize = keymap.types.iter().map(|key_type| key_type.num_levels as usize).sum();
+    let (first_key, n_keys) = (keymap.min_keycode, keymap.max_keycode - keymap.min_keycode + 1);
+
+    let mut values = vec![];
+    let atom = |values: &mut Vec<u8>, name: &str| values.extend(request.to_bytes_32(name_atom(name)));
+    for (bit, name) in [
+        (NAME_KEYCODES, &keymap.keycodes_name),
+        (NAME_GEOMETRY, &keymap.geometry_name),
+        (NAME_SYMBOLS, &keymap.symbols_name),
+        (NAME_PHYS_SYMBOLS, &keymap.symbols_name),
+        (NAME_TYPES, &keymap.types_name),
+        (NAME_COMPAT, &keymap.compat_name),
+    ] {
+        if which & bit != 0 {
+            atom(&mut values, name);
+        }
+    }
+    if which & NAME_KEY_TYPE_NAMES != 0 {
+        for key_type in &keymap.types {
+            atom(&mut values, &key_type.name);
+        }
+    }
+    if which & NAME_KT_LEVEL_NAMES != 0 {
+        values.extend(keymap.types.iter().map(|key_type| key_type.num_levels));
+        pad(&mut values);
+        for key_type in &keymap.types {
+            for level in 0..key_type.num_levels as usize {
+                atom(&mut values, key_type.level_names.get(level).map_or("", |name| name));
+            }
+        }
+    }
+    if which & NAME_INDICATOR_NAMES != 0 {
+        for name in keymap.indicator_names.iter().flatten() {
+            atom(&mut values, name);
+        }
+    }
+    if which & NAME_VIRTUAL_MOD_NAMES != 0 {
+        for name in &keymap.vmod_names {
+            atom(&mut values, name);
+        }
+    }
+    if which & NAME_GROUP_NAMES != 0 {
+        for name in keymap.group_names.iter().filter(|name| !name.is_empty()) {
+            atom(&mut values, name);
+        }
+    }
+    if which & NAME_KEY_NAMES != 0 {
+        for key in &keymap.keys[first_key as usize..first_key as usize + n_keys as usize] {
+            values.extend(key_name_bytes(&key.name));
+        }
+    }
+    if which & NAME_KEY_ALIASES != 0 {
+        for (alias, real) in &keymap.aliases {
+            values.extend(key_name_bytes(real));
+            values.extend(key_name_bytes(alias));
+        }
+    }
+
+    let mut body = request.to_bytes_32(which).to_vec();
+    body.extend([keymap.min_keycode, keymap.max_keycode, keymap.types.len() as u8, group_names]);
+    body.extend(request.to_bytes_16(keymap.vmod_mask()));
+    body.extend([first_key, n_keys]);
+    body.extend(request.to_bytes_32(indicators));
+    body.extend([0, keymap.aliases.len() as u8]);
+    body.extend(request.to_bytes_16(levels as u16));
+    body.resize(24, 0);
+    body.append(&mut values);
+    request.reply(CORE_KEYBOARD_ID, body)
+}
+
+fn per_client_flags(request: &ExtensionRequest, change: u32, value: u32) -> Vec<u8> {
+    let mut clients = CLIENTS.lock().unwrap();
+    let flags = match clients.get_mut(&request.client) {
+        Some(client) => {
+            let change = change & PCF_SUPPORTED;
+            client.xkb_flags = client.xkb_flags & !change | value & change;
+            client.xkb_flags
+        }
+        None => 0,
+    };
+    drop(clients);
+    let mut body = request.to_bytes_32(PCF_SUPPORTED).to_vec();
+    body.extend(request.to_bytes_32(flags));
+    // Controls aren't reset when the client exits.
+    body.extend(request.to_bytes_32(0));
+    body.extend(request.to_bytes_32(0));
+    request.reply(CORE_KEYBOARD_ID, body)
+}
+
+/// Implements GetDeviceInfo for the core keyboard, which has no buttons or LED
+/// feedbacks to describe beyond its name.
+fn get_device_info(request: &ExtensionRequest, wanted: u16) -> Vec<u8> {
+    let name = "Virtual core keyboard";
+    let mut body = request.to_bytes_16(0).to_vec();
+    body.extend(request.to_bytes_16(0));
+    body.extend(request.to_bytes_16(wanted));
+    body.extend(request.to_bytes_16(0));
+    // No buttons; the device has its own state.
+    body.extend([0, 0, 0, 0, 0, 1]);
+    body.extend(request.to_bytes_16(0));
+    body.extend(request.to_bytes_16(0));
+    body.extend([0, 0]);
+    body.extend(request.to_bytes_32(get_atom(false, "KEYBOARD".to_string())));
+    body.extend(request.to_bytes_16(name.len() as u16));
+    body.extend(name.bytes());
+    request.reply(CORE_KEYBOARD_ID, body)
+}
+
+pub fn handle_xkb_request(request: &ExtensionRequest) -> Result<Option<Vec<u8>>, XError> {
+    let xkb_request = read_xkb_request(request)?;
+    // Every other request needs the client to have agreed on a version first.
+    let initialized = CLIENTS.lock().unwrap().get(&request.client).is_some_and(|c| c.xkb_initialized);
+    if !initialized && !matches!(xkb_request, XkbRequest::UseExtension { .. }) {
+        return Err(XError::new(ErrorCode::Access, 0));
+    }
+    match xkb_request {
+        XkbRequest::UseExtension { wanted_major, .. } => {
+            let supported = wanted_major == XKB_MAJOR_VERSION;
+            if let Some(client) = CLIENTS.lock().unwrap().get_mut(&request.client) {
+                client.xkb_initialized |= supported;
+            }
+            let mut body = request.to_bytes_16(XKB_MAJOR_VERSION).to_vec();
+            body.extend(request.to_bytes_16(XKB_MINOR_VERSION));
+            Ok(Some(request.reply(supported as u8, body)))
+        }
+        XkbRequest::SelectEvents {
+            device_spec,
+            affect_which,
+            clear,
+            select_all,
+            affect_map,
+            map,
+            details,
+        } => {
+            check_device(device_spec)?;
+            select_events(request, affect_which, clear, select_all, (affect_map, map), &details)?;
+            Ok(None)
+        }
+        XkbRequest::GetState { device_spec } => {
+            check_device(device_spec)?;
+            Ok(Some(get_state(request)))
+        }
+        XkbRequest::GetControls { device_spec } => {
+            check_device(device_spec)?;
+            Ok(Some(get_controls(request)))
+        }
+        XkbRequest::GetMap {
+            device_spec,
+            full,
+            partial,
+            first_type,
+            n_types,
+            key_ranges,
+            virtual_mods,
+        } => {
+            check_device(device_spec)?;
+            let reply = get_map(request, full, partial, (first_type, n_types), key_ranges, virtual_mods)?;
+            Ok(Some(reply))
+        }
+        XkbRequest::GetCompatMap {
+            device_spec,
+            groups,
+            get_all_si,
+            first_si,
+            n_si,
+        } => {
+            check_device(device_spec)?;
+            Ok(Some(get_compat_map(request, groups, get_all_si, first_si, n_si)?))
+        }
+        XkbRequest::GetIndicatorMap { device_spec, which } => {
+            check_device(device_spec)?;
+            Ok(Some(get_indicator_map(request, which)))
+        }
+        XkbRequest::GetNames { device_spec, which } => {
+            check_device(device_spec)?;
+            Ok(Some(get_names(request, which)))
+        }
+        XkbRequest::PerClientFlags {
+            device_spec,
+            change,
+            value,
+        } => {
+            check_device(device_spec)?;
+            Ok(Some(per_client_flags(request, change, value)))
+        }
+        XkbRequest::GetDeviceInfo { device_spec, wanted } => {
+            check_device(device_spec)?;
+            Ok(Some(get_device_info(request, wanted)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keyboard::{init_keyboard, DEFAULT_KEYMAP, KEYMAP, MAX_KEYCODE, MIN_KEYCODE};
+
+    #[test]
+    fn core_keysyms_reach_the_last_keycode() {
+        init_keyboard(DEFAULT_KEYMAP).unwrap();
+        let mut keymap = KEYMAP.lock().unwrap();
+        let per = keymap.keysyms_per_keycode;
+        let mut keysyms = vec![0; (MAX_KEYCODE - MIN_KEYCODE + 1) as usize * per as usize];
+        let last = keysyms.len() - per as usize;
+        keysyms[last] = 0x61;
+        let count = keymap.change_keysyms(MIN_KEYCODE, per, &keysyms).unwrap();
+        assert_eq!(count, 248);
+        core_keysyms_changed(&keymap, MIN_KEYCODE, count);
+        let xkb = XKB.lock().unwrap();
+        assert_eq!(xkb.keymap.core_keymap().keysym(MAX_KEYCODE, 0), 0x61);
+    }
+}
@@ -0,0 +1,1584 @@
+//! Compiling XKB text keymaps, the format `xkbcomp -xkb` and libxkbcommon write, into
+//! the tables the XKB extension reports. Keymaps must be complete: `include`
+//! statements aren't resolved, and keycodes above 255 are dropped. Actions other than
+//! the modifier and group actions compile to NoAction.
+
+use crate::{
+    keyboard::{Keymap, MIN_KEYCODE, NO_SYMBOL},
+    keysym::keysym_from_name,
+};
+
+pub const NUM_VIRTUAL_MODS: usize = 16;
+pub const NUM_INDICATORS: usize = 32;
+pub const MAX_GROUPS: usize = 4;
+
+pub const SA_NO_ACTION: u8 = 0;
+pub const SA_SET_MODS: u8 = 1;
+pub const SA_LATCH_MODS: u8 = 2;
+pub const SA_LOCK_MODS: u8 = 3;
+pub const SA_SET_GROUP: u8 = 4;
+pub const SA_LATCH_GROUP: u8 = 5;
+pub const SA_LOCK_GROUP: u8 = 6;
+
+/// Action flags. Modifier and group actions share the first two.
+pub const SA_CLEAR_LOCKS: u8 = 1 << 0;
+pub const SA_LATCH_TO_LOCK: u8 = 1 << 1;
+pub const SA_LOCK_NO_LOCK: u8 = 1 << 0;
+pub const SA_LOCK_NO_UNLOCK: u8 = 1 << 1;
+pub const SA_USE_MOD_MAP_MODS: u8 = 1 << 2;
+pub const SA_GROUP_ABSOLUTE: u8 = 1 << 2;
+
+/// How a symbol interpretation matches the modifiers bound to a key.
+pub const SI_NONE_OF: u8 = 0;
+pub const SI_ANY_OF_OR_NONE: u8 = 1;
+pub const SI_ANY_OF: u8 = 2;
+pub const SI_ALL_OF: u8 = 3;
+pub const SI_EXACTLY: u8 = 4;
+pub const SI_LEVEL_ONE_ONLY: u8 = 1 << 7;
+pub const SI_OP_MASK: u8 = 0x7f;
+
+pub const SI_AUTO_REPEAT: u8 = 1 << 0;
+pub const SI_LOCKING_KEY: u8 = 1 << 1;
+
+/// Parts of a key set explicitly rather than through the compatibility map.
+pub const EXPLICIT_KEY_TYPE_1: u8 = 1 << 0;
+pub const EXPLICIT_INTERPRET: u8 = 1 << 4;
+pub const EXPLICIT_AUTO_REPEAT: u8 = 1 << 5;
+pub const EXPLICIT_VMODMAP: u8 = 1 << 7;
+
+pub const IM_NO_EXPLICIT: u8 = 1 << 7;
+pub const IM_NO_AUTOMATIC: u8 = 1 << 6;
+pub const IM_LED_DRIVES_KB: u8 = 1 << 5;
+
+/// Which parts of the keyboard state an indicator follows.
+pub const IM_USE_BASE: u8 = 1 << 0;
+pub const IM_USE_LATCHED: u8 = 1 << 1;
+pub const IM_USE_LOCKED: u8 = 1 << 2;
+pub const IM_USE_EFFECTIVE: u8 = 1 << 3;
+pub const IM_USE_COMPAT: u8 = 1 << 4;
+
+/// The types every keymap starts with, in this order.
+const CANONICAL_TYPES: [&str; 4] = ["ONE_LEVEL", "TWO_LEVEL", "ALPHABETIC", "KEYPAD"];
+
+const REAL_MOD_NAMES: [&str; 8] = ["Shift", "Lock", "Control", "Mod1", "Mod2", "Mod3", "Mod4", "Mod5"];
+
+const CONTROL_NAMES: [(&str, u32); 13] = [
+    ("RepeatKeys", 1 << 0),
+    ("SlowKeys", 1 << 1),
+    ("BounceKeys", 1 << 2),
+    ("StickyKeys", 1 << 3),
+    ("MouseKeys", 1 << 4),
+    ("MouseKeysAccel", 1 << 5),
+    ("AccessXKeys", 1 << 6),
+    ("AccessXTimeout", 1 << 7),
+    ("AccessXFeedback", 1 << 8),
+    ("AudibleBell", 1 << 9),
+    ("Overlay1", 1 << 10),
+    ("Overlay2", 1 << 11),
+    ("IgnoreGroupLock", 1 << 12),
+];
+
+/// Real and virtual modifiers; virtual modifiers stand for whichever real modifiers
+/// the keys carrying them are bound to.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ModDef {
+    pub real_mods: u8,
+    pub vmods: u16,
+}
+
+/// A key action. `mods` is used by the modifier actions and `group` by the group actions.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Action {
+    pub kind: u8,
+    pub flags: u8,
+    pub mods: ModDef,
+    pub group: i8,
+}
+
+#[derive(Clone, Debug)]
+pub struct KeyTypeEntry {
+    pub mods: ModDef,
+    /// Zero-based.
+    pub level: u8,
+    /// Modifiers left for the client to interpret.
+    pub preserve: ModDef,
+}
+
+#[derive(Clone, Debug)]
+pub struct KeyType {
+    pub name: String,
+    pub mods: ModDef,
+    pub num_levels: u8,
+    pub entries: Vec<KeyTypeEntry>,
+    /// Names of the first levels; missing names are empty.
+    pub level_names: Vec<String>,
+}
+
+/// A symbol interpretation from the compatibility map, assigning actions to keys by
+/// the keysyms and modifiers they have.
+#[derive(Clone, Debug)]
+pub struct Interpret {
+    /// `NO_SYMBOL` matches any keysym.
+    pub keysym: u32,
+    pub match_op: u8,
+    pub mods: u8,
+    pub virtual_mod: Option<u8>,
+    pub flags: u8,
+    pub action: Action,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct IndicatorMap {
+    pub flags: u8,
+    pub which_groups: u8,
+    pub groups: u8,
+    pub which_mods: u8,
+    pub mods: ModDef,
+    pub ctrls: u32,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct KeyGroup {
+    /// Index into the keymap's types.
+    pub key_type: usize,
+    /// One keysym per level of the type.
+    pub syms: Vec<u32>,
+    /// One action per level.
+    pub actions: Vec<Action>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Key {
+    pub name: String,
+    pub groups: Vec<KeyGroup>,
+    pub explicit: u8,
+    /// The real modifiers the key is bound to, as in the core modifier mapping.
+    pub modmap: u8,
+    pub vmodmap: u16,
+    pub repeat: bool,
+}
+
+impl Key {
+    pub fn has_actions(&self) -> bool {
+        self.groups
+            .iter()
+            .any(|group| group.actions.iter().any(|action| action.kind != SA_NO_ACTION))
+    }
+
+    /// The number of keysyms in each group, the widest type's levels.
+    pub fn width(&self) -> usize {
+        self.groups.iter().map(|group| group.syms.len()).max().unwrap_or(0)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct XkbKeymap {
+    pub min_keycode: u8,
+    pub max_keycode: u8,
+    pub keycodes_name: String,
+    pub types_name: String,
+    pub compat_name: String,
+    pub symbols_name: String,
+    pub geometry_name: String,
+    /// Indexed by keycode, 256 entries once compiled.
+    pub keys: Vec<Key>,
+    /// Alias and real key names.
+    pub aliases: Vec<(String, String)>,
+    pub types: Vec<KeyType>,
+    pub vmod_names: Vec<String>,
+    /// The real modifiers each virtual modifier is bound to.
+    pub vmods: [u8; NUM_VIRTUAL_MODS],
+    pub interprets: Vec<Interpret>,
+    pub indicator_names: [Option<String>; NUM_INDICATORS],
+    pub indicators: [IndicatorMap; NUM_INDICATORS],
+    pub group_names: Vec<String>,
+}
+
+impl XkbKeymap {
+    pub const EMPTY: XkbKeymap = XkbKeymap {
+        min_keycode: 8,
+        max_keycode: 255,
+        keycodes_name: String::new(),
+        types_name: String::new(),
+        compat_name: String::new(),
+        symbols_name: String::new(),
+        geometry_name: String::new(),
+        keys: Vec::new(),
+        aliases: Vec::new(),
+        types: Vec::new(),
+        vmod_names: Vec::new(),
+        vmods: [0; NUM_VIRTUAL_MODS],
+        interprets: Vec::new(),
+        indicator_names: [const { None }; NUM_INDICATORS],
+        indicators: [const {
+            IndicatorMap {
+                flags: 0,
+                which_groups: 0,
+                groups: 0,
+                which_mods: 0,
+                mods: ModDef { real_mods: 0, vmods: 0 },
+                ctrls: 0,
+            }
+        }; NUM_INDICATORS],
+        group_names: Vec::new(),
+    };
+
+    /// The real modifiers `mods` stands for.
+    pub fn resolve(&self, mods: ModDef) -> u8 {
+        (0..NUM_VIRTUAL_MODS)
+            .filter(|i| mods.vmods & 1 << i != 0)
+            .fold(mods.real_mods, |real, i| real | self.vmods[i])
+    }
+
+    /// The number of groups of the key with the most.
+    pub fn num_groups(&self) -> u8 {
+        self.keys.iter().map(|key| key.groups.len()).max().unwrap_or(0).max(1) as u8
+    }
+
+    /// Bits set for every virtual modifier that has a name.
+    pub fn vmod_mask(&self) -> u16 {
+        ((1u32 << self.vmod_names.len()) - 1) as u16
+    }
+
+    /// Whether a map entry applies: entries using virtual modifiers that aren't bound
+    /// to any real modifier don't.
+    pub fn entry_active(&self, entry: &KeyTypeEntry) -> bool {
+        let vmods = self.resolve(ModDef {
+            real_mods: 0,
+            vmods: entry.mods.vmods,
+        });
+        entry.mods.vmods == 0 || vmods != 0
+    }
+
+    /// The group of `keycode` used in effective group `group`, wrapping groups the key
+    /// doesn't have.
+    fn key_group(&self, keycode: u8, group: u8) -> Option<&KeyGroup> {
+        let groups = &self.keys.get(keycode as usize)?.groups;
+        groups.get(group as usize % groups.len().max(1))
+    }
+
+    /// The level of `group` chosen by the modifiers in `mods`.
+    fn level(&self, group: &KeyGroup, mods: u8) -> usize {
+        let key_type = &self.types[group.key_type];
+        let mods = mods & self.resolve(key_type.mods);
+        key_type
+            .entries
+            .iter()
+            .find(|entry| self.entry_active(entry) && self.resolve(entry.mods) == mods)
+            .map_or(0, |entry| entry.level as usize)
+    }
+
+    /// The action `keycode` performs in `group` with `mods` down.
+    pub fn key_action(&self, keycode: u8, group: u8, mods: u8) -> Action {
+        let Some(key_group) = self.key_group(keycode, group) else {
+            return Action::default();
+        };
+        let level = self.level(key_group, mods);
+        key_group.actions.get(level).copied().unwrap_or_default()
+    }
+
+    /// Derives the core keyboard mapping. Keys with a second group or more than two
+    /// levels are laid out as the core protocol expects: the first two levels of
+    /// groups 1 and 2, then the remaining levels of group 1.
+    pub fn core_keymap(&self) -> Keymap {
+        let columns: Vec<Vec<u32>> = self.keys.iter().map(core_columns).collect();
+        let per = columns.iter().map(|syms| syms.len()).max().unwrap_or(0).max(2);
+        let mut keysyms = vec![];
+        for syms in &columns[MIN_KEYCODE as usize..] {
+            keysyms.extend(syms.iter().copied().chain(std::iter::repeat(NO_SYMBOL)).take(per));
+        }
+        let mut modifiers: Vec<Vec<u8>> = vec![vec![]; 8];
+        for (keycode, key) in self.keys.iter().enumerate() {
+            for (index, keycodes) in modifiers.iter_mut().enumerate() {
+                if key.modmap & 1 << index != 0 {
+                    keycodes.push(keycode as u8);
+                }
+            }
+        }
+        let keycodes_per_modifier = modifiers.iter().map(Vec::len).max().unwrap_or(0);
+        Keymap {
+            keysyms_per_keycode: per as u8,
+            keysyms,
+            keycodes_per_modifier: keycodes_per_modifier as u8,
+            modifiers: modifiers
+                .into_iter()
+                .flat_map(|keycodes| {
+                    keycodes.into_iter().chain(std::iter::repeat(0)).take(keycodes_per_modifier)
+                })
+                .collect(),
+        }
+    }
+
+    /// Takes the keysyms of `keycode` from a core keyboard mapping entry, choosing key
+    /// types the way xkbcomp does for keys without one.
+    pub fn set_core_keysyms(&mut self, keycode: u8, columns: &[u32]) {
+        let column = |i: usize| columns.get(i).copied().unwrap_or(NO_SYMBOL);
+        let mut group1: Vec<u32> = [column(0), column(1)]
+            .into_iter()
+            .chain(columns.iter().skip(4).copied())
+            .collect();
+        let mut group2 = vec![column(2), column(3)];
+        for syms in [&mut group1, &mut group2] {
+            while syms.len() > 1 && syms.last() == Some(&NO_SYMBOL) {
+                syms.pop();
+            }
+        }
+        let mut groups = vec![group1];
+        if group2 != [NO_SYMBOL] && group2[..] != groups[0][..group2.len().min(groups[0].len())] {
+            groups.push(group2);
+        }
+        if groups.len() == 1 && groups[0] == [NO_SYMBOL] {
+            groups.clear();
+        }
+        let groups = groups
+            .into_iter()
+            .map(|syms| {
+                let key_type = self.automatic_type(&syms);
+                self.make_group(key_type, syms)
+            })
+            .collect();
+        let key = &mut self.keys[keycode as usize];
+        key.groups = groups;
+        key.explicit = 0;
+    }
+
+    /// Builds a group of type `key_type` with `syms` padded or cut to its levels.
+    fn make_group(&self, key_type: usize, mut syms: Vec<u32>) -> KeyGroup {
+        let levels = self.types[key_type].num_levels as usize;
+        syms.resize(levels, NO_SYMBOL);
+        KeyGroup {
+            key_type,
+            syms,
+            actions: vec![Action::default(); levels],
+        }
+    }
+
+    fn find_type(&self, name: &str) -> Option<usize> {
+        self.types.iter().position(|key_type| key_type.name == name)
+    }
+
+    /// Picks a type for a group without one, like xkbcomp's FindAutomaticType.
+    fn automatic_type(&self, syms: &[u32]) -> usize {
+        let sym = |i: usize| syms.get(i).copied().unwrap_or(NO_SYMBOL);
+        let name = match syms.len() {
+            0 | 1 => "ONE_LEVEL",
+            2 if is_alphabetic_pair(sym(0), sym(1)) => "ALPHABETIC",
+            2 if is_keypad(sym(0)) || is_keypad(sym(1)) => "KEYPAD",
+            2 => "TWO_LEVEL",
+            _ if is_alphabetic_pair(sym(0), sym(1)) && is_alphabetic_pair(sym(2), sym(3)) => {
+                "FOUR_LEVEL_ALPHABETIC"
+            }
+            _ if is_alphabetic_pair(sym(0), sym(1)) => "FOUR_LEVEL_SEMIALPHABETIC",
+            _ if is_keypad(sym(0)) || is_keypad(sym(1)) => "FOUR_LEVEL_KEYPAD",
+            _ => "FOUR_LEVEL",
+        };
+        self.find_type(name)
+            .or_else(|| self.types.iter().position(|key_type| key_type.num_levels as usize >= syms.len()))
+            .unwrap_or(0)
+    }
+
+    /// Assigns actions, virtual modifiers and repeat to keys from the symbol
+    /// interpretations, except where the keymap set them explicitly, and then binds the
+    /// virtual modifiers to real ones.
+    pub fn apply_compat(&mut self) {
+        for keycode in 0..self.keys.len() {
+            let key = &self.keys[keycode];
+            if key.explicit & EXPLICIT_INTERPRET != 0 {
+                continue;
+            }
+            let modmap = key.modmap;
+            let mut vmodmap = 0;
+            let mut repeat = None;
+            let mut actions = vec![];
+            for (group_index, group) in key.groups.iter().enumerate() {
+                let mut group_actions = vec![];
+                for (level, sym) in group.syms.iter().enumerate() {
+                    let interpret = self.find_interpret(*sym, level, modmap);
+                    let mut action = interpret.map_or(Action::default(), |interpret| interpret.action);
+                    if let Some(interpret) = interpret {
+                        if level == 0 || interpret.match_op & SI_LEVEL_ONE_ONLY == 0 {
+                            if let Some(vmod) = interpret.virtual_mod {
+                                vmodmap |= 1 << vmod;
+                            }
+                        }
+                        if group_index == 0 && level == 0 {
+                            repeat = Some(interpret.flags & SI_AUTO_REPEAT != 0);
+                        }
+                    }
+                    let uses_mods = matches!(action.kind, SA_SET_MODS | SA_LATCH_MODS | SA_LOCK_MODS);
+                    if uses_mods && action.flags & SA_USE_MOD_MAP_MODS != 0 {
+                        action.mods = ModDef {
+                            real_mods: modmap,
+                            vmods: 0,
+                        };
+                    }
+                    group_actions.push(action);
+                }
+                actions.push(group_actions);
+            }
+            let key = &mut self.keys[keycode];
+            for (group, group_actions) in key.groups.iter_mut().zip(actions) {
+                group.actions = group_actions;
+            }
+            if key.explicit & EXPLICIT_VMODMAP == 0 {
+                key.vmodmap = vmodmap;
+            }
+            if let (Some(repeat), 0) = (repeat, key.explicit & EXPLICIT_AUTO_REPEAT) {
+                key.repeat = repeat;
+            }
+        }
+        self.vmods = [0; NUM_VIRTUAL_MODS];
+        for key in &self.keys {
+            for (vmod, real_mods) in self.vmods.iter_mut().enumerate() {
+                if key.vmodmap & 1 << vmod != 0 {
+                    *real_mods |= key.modmap;
+                }
+            }
+        }
+    }
+
+    fn find_interpret(&self, sym: u32, level: usize, modmap: u8) -> Option<&Interpret> {
+        if sym == NO_SYMBOL {
+            return None;
+        }
+        self.interprets.iter().find(|interpret| {
+            let mods = match interpret.match_op & SI_LEVEL_ONE_ONLY {
+                0 => modmap,
+                _ if level == 0 => modmap,
+                _ => 0,
+            };
+            let matches = match interpret.match_op & SI_OP_MASK {
+                SI_NONE_OF => interpret.mods & mods == 0,
+                SI_ANY_OF_OR_NONE => mods == 0 || interpret.mods & mods != 0,
+                SI_ANY_OF => interpret.mods & mods != 0,
+                SI_ALL_OF => interpret.mods & mods == interpret.mods,
+                SI_EXACTLY => interpret.mods == mods,
+                _ => false,
+            };
+            (interpret.keysym == NO_SYMBOL || interpret.keysym == sym) && matches
+        })
+    }
+}
+
+/// The core keysyms of a key, with trailing NoSymbols left off.
+fn core_columns(key: &Key) -> Vec<u32> {
+    let level = |group: usize, level: usize| {
+        key.groups
+            .get(group)
+            .and_then(|group| group.syms.get(level))
+            .copied()
+            .unwrap_or(NO_SYMBOL)
+    };
+    let mut columns = match (key.groups.len(), key.width()) {
+        (0, _) => vec![],
+        (1, width) if width <= 2 => key.groups[0].syms.clone(),
+        (groups, width) => {
+            let group2 = if groups > 1 { 1 } else { 0 };
+            let mut columns = vec![level(0, 0), level(0, 1), level(group2, 0), level(group2, 1)];
+            columns.extend((2..width).map(|i| level(0, i)));
+            columns
+        }
+    };
+    while columns.last() == Some(&NO_SYMBOL) {
+        columns.pop();
+    }
+    columns
+}
+
+/// Lowercase and uppercase keysyms of a Latin-1 or Unicode character.
+fn keysym_case(keysym: u32) -> (u32, u32) {
+    let (offset, code_point) = match keysym {
+        0x20..=0xff => (0, keysym),
+        0x1000100..=0x110ffff => (0x1000000, keysym - 0x1000000),
+        _ => return (keysym, keysym),
+    };
+    let Some(c) = char::from_u32(code_point) else {
+        return (keysym, keysym);
+    };
+    let single = |mut chars: std::char::ToLowercase| -> Option<char> {
+        let c = chars.next()?;
+        chars.next().is_none().then_some(c)
+    };
+    let lower = single(c.to_lowercase()).unwrap_or(c);
+    let mut upper_chars = c.to_uppercase();
+    let upper = match (upper_chars.next(), upper_chars.next()) {
+        (Some(upper), None) => upper,
+        _ => c,
+    };
+    // ÿ and µ have uppercase forms outside Latin-1, which keysyms don't pair them with.
+    let keysym_of = |c: char| match c as u32 {
+        cp if offset == 0 && cp > 0xff => keysym,
+        cp if cp <= 0xff && offset == 0 => cp,
+        cp => cp + offset,
+    };
+    (keysym_of(lower), keysym_of(upper))
+}
+
+fn is_alphabetic_pair(lower: u32, upper: u32) -> bool {
+    let (lowercase, uppercase) = keysym_case(lower);
+    lower != upper && lowercase == lower && uppercase == upper
+}
+
+fn is_keypad(keysym: u32) -> bool {
+    (0xff80..=0xffbd).contains(&keysym) || (0x11000000..=0x1100ffff).contains(&keysym)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Ident(String),
+    Str(String),
+    KeyName(String),
+    Number(i64),
+    Punct(char),
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = vec![];
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {}
+            '#' => {
+                chars.by_ref().find(|c| *c == '\n');
+            }
+            '/' if chars.peek() == Some(&'/') => {
+                chars.by_ref().find(|c| *c == '\n');
+            }
+            '"' => {
+                let mut string = String::new();
+                loop {
+                    match chars.next().ok_or("unterminated string")? {
+                        '"' => break,
+                        '\\' => match chars.next().ok_or("unterminated string")? {
+                            'n' => string.push('\n'),
+                            't' => string.push('\t'),
+                            c => string.push(c),
+                        },
+                        c => string.push(c),
+                    }
+                }
+                tokens.push(Token::Str(string));
+            }
+            '<' => {
+                let name: String = chars.by_ref().take_while(|c| *c != '>').collect();
+                tokens.push(Token::KeyName(name));
+            }
+            c if c.is_ascii_digit() => {
+                let mut word = c.to_string();
+                while let Some(c) = chars.next_if(|c| c.is_ascii_alphanumeric()) {
+                    word.push(c);
+                }
+                let number = match word.strip_prefix("0x").or_else(|| word.strip_prefix("0X")) {
+                    Some(hex) => i64::from_str_radix(hex, 16),
+                    None => word.parse(),
+                };
+                tokens.push(Token::Number(number.map_err(|_| format!("invalid number {word}"))?));
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let mut word = c.to_string();
+                while let Some(c) = chars.next_if(|c| c.is_alphanumeric() || *c == '_') {
+                    word.push(c);
+                }
+                tokens.push(Token::Ident(word));
+            }
+            '{' | '}' | '[' | ']' | '(' | ')' | ';' | ',' | '=' | '+' | '-' | '!' | '~' | '.' => {
+                tokens.push(Token::Punct(c))
+            }
+            c => return Err(format!("unexpected character {c:?}")),
+        }
+    }
+    Ok(tokens)
+}
+
+#[derive(Clone, Debug)]
+enum Expr {
+    Ident(String),
+    Str(String),
+    KeyName(String),
+    Number(i64),
+    Negate(Box<Expr>),
+    Not(Box<Expr>),
+    /// `a+b+c`, as in modifier masks.
+    Sum(Vec<Expr>),
+    List(Vec<Expr>),
+    Call(String, Vec<Expr>),
+    /// `field[index]`.
+    Index(String, Box<Expr>),
+    /// `b` of `a.b`, for statements such as `interpret.repeat = true;`.
+    Field(String),
+    Assign(Box<Expr>, Box<Expr>),
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<&Token> {
+        self.tokens.get(self.position + offset)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(&Token::Punct(c)) {
+            self.position += 1;
+            return true;
+        }
+        false
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), String> {
+        match self.next() {
+            Some(Token::Punct(p)) if p == c => Ok(()),
+            other => Err(format!("expected {c:?}, found {other:?}")),
+        }
+    }
+
+    /// Takes `word` if it starts a statement rather than naming a field being set.
+    fn keyword(&mut self, word: &str) -> bool {
+        let is_word = matches!(self.peek(), Some(Token::Ident(w)) if w.eq_ignore_ascii_case(word));
+        let is_field = matches!(self.peek_at(1), Some(Token::Punct('=' | '.' | '[')));
+        if is_word && !is_field {
+            self.position += 1;
+        }
+        is_word && !is_field
+    }
+
+    /// Skips a statement we don't support, including any braces it has.
+    fn skip_statement(&mut self) -> Result<(), String> {
+        let mut depth = 0;
+        loop {
+            match self.next().ok_or("unexpected end of keymap")? {
+                Token::Punct('{' | '[' | '(') => depth += 1,
+                Token::Punct('}' | ']' | ')') if depth > 0 => depth -= 1,
+                Token::Punct('}') => return Err("unbalanced braces".to_string()),
+                Token::Punct(';') if depth == 0 => return Ok(()),
+                _ => {}
+            }
+        }
+    }
+
+    fn expression(&mut self) -> Result<Expr, String> {
+        let mut terms = vec![self.unary()?];
+        while self.eat('+') {
+            terms.push(self.unary()?);
+        }
+        Ok(match terms.len() {
+            1 => terms.pop().unwrap(),
+            _ => Expr::Sum(terms),
+        })
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        if self.eat('-') {
+            return Ok(Expr::Negate(Box::new(self.unary()?)));
+        }
+        if self.eat('+') {
+            return self.unary();
+        }
+        if self.eat('!') || self.eat('~') {
+            return Ok(Expr::Not(Box::new(self.unary()?)));
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Expr, String> {
+        match self.next().ok_or("unexpected end of keymap")? {
+            Token::Number(number) => Ok(Expr::Number(number)),
+            Token::Str(string) => Ok(Expr::Str(string)),
+            Token::KeyName(name) => Ok(Expr::KeyName(name)),
+            Token::Punct('[') => Ok(Expr::List(self.items(']')?)),
+            Token::Punct('{') => Ok(Expr::List(self.items('}')?)),
+            Token::Punct('(') => {
+                let expr = self.expression()?;
+                self.expect(')')?;
+                Ok(expr)
+            }
+            Token::Ident(name) => {
+                if self.eat('(') {
+                    return Ok(Expr::Call(name, self.items(')')?));
+                }
+                if self.eat('[') {
+                    let index = self.expression()?;
+                    self.expect(']')?;
+                    return Ok(Expr::Index(name, Box::new(index)));
+                }
+                if self.eat('.') {
+                    let Some(Token::Ident(field)) = self.next() else {
+                        return Err(format!("expected a field of {name}"));
+                    };
+                    if self.eat('[') {
+                        let index = self.expression()?;
+                        self.expect(']')?;
+                        return Ok(Expr::Index(format!("{name}.{field}"), Box::new(index)));
+                    }
+                    return Ok(Expr::Field(field));
+                }
+                Ok(Expr::Ident(name))
+            }
+            token => Err(format!("unexpected {token:?}")),
+        }
+    }
+
+    /// Parses comma-separated items, each an expression or an assignment, up to `close`.
+    fn items(&mut self, close: char) -> Result<Vec<Expr>, String> {
+        let mut items = vec![];
+        while !self.eat(close) {
+            let item = self.expression()?;
+            if self.eat('=') {
+                items.push(Expr::Assign(Box::new(item), Box::new(self.expression()?)));
+            } else {
+                items.push(item);
+            }
+            if !self.eat(',') {
+                self.expect(close)?;
+                break;
+            }
+        }
+        Ok(items)
+    }
+
+    /// Parses `{ field = value; ... };`. A bare `field;` sets it to true and `!field;`
+    /// to false.
+    fn block(&mut self) -> Result<Vec<(Expr, Expr)>, String> {
+        self.expect('{')?;
+        let mut statements = vec![];
+        while !self.eat('}') {
+            let field = self.expression()?;
+            let statement = if self.eat('=') {
+                (field, self.expression()?)
+            } else {
+                match field {
+                    Expr::Not(field) => (*field, Expr::Ident("false".to_string())),
+                    field => (field, Expr::Ident("true".to_string())),
+                }
+            };
+            statements.push(statement);
+            self.expect(';')?;
+        }
+        self.eat(';');
+        Ok(statements)
+    }
+
+    /// Parses `field = value;` for statements of a section that aren't keywords.
+    fn assignment(&mut self) -> Result<Option<(Expr, Expr)>, String> {
+        let start = self.position;
+        let field = self.expression()?;
+        if !self.eat('=') {
+            self.position = start;
+            self.skip_statement()?;
+            return Ok(None);
+        }
+        let value = self.expression()?;
+        self.expect(';')?;
+        Ok(Some((field, value)))
+    }
+}
+
+/// One group of a `key` statement: its explicit type, keysyms and actions.
+#[derive(Clone, Default)]
+struct GroupSymbols {
+    type_name: Option<String>,
+    syms: Vec<u32>,
+    actions: Option<Vec<Action>>,
+}
+
+/// Symbols of one `key` statement before types are chosen.
+#[derive(Default)]
+struct KeySymbols {
+    groups: Vec<GroupSymbols>,
+    default_type: Option<String>,
+    repeat: Option<bool>,
+    vmodmap: Option<u16>,
+}
+
+/// Compiles a keymap. Unknown keysyms become NoSymbol and unsupported statements are
+/// skipped, as xkbcomp does with a warning; syntax errors and references to undefined
+/// types fail.
+pub fn compile_keymap(text: &str) -> Result<XkbKeymap, String> {
+    let mut parser = Parser {
+        tokens: tokenize(text)?,
+        position: 0,
+    };
+    let mut keymap = XkbKeymap::EMPTY;
+    keymap.keys = vec![Key::default(); 256];
+    let mut symbols = vec![];
+    let mut modifier_maps = vec![];
+    let wrapped = parser.keyword("xkb_keymap");
+    if wrapped {
+        if let Some(Token::Str(_)) = parser.peek() {
+            parser.next();
+        }
+        parser.expect('{')?;
+    }
+    while let Some(token) = parser.next() {
+        let section = match token {
+            Token::Punct('}') if wrapped => {
+                parser.eat(';');
+                break;
+            }
+            Token::Ident(section) => section.to_ascii_lowercase(),
+            token => return Err(format!("expected a keymap section, found {token:?}")),
+        };
+        let name = match parser.peek() {
+            Some(Token::Str(name)) => {
+                let name = name.clone();
+                parser.next();
+                name
+            }
+            _ => String::new(),
+        };
+        parser.expect('{')?;
+        match section.as_str() {
+            "xkb_keycodes" => {
+                keymap.keycodes_name = name;
+                compile_keycodes(&mut parser, &mut keymap)?;
+            }
+            "xkb_types" => {
+                keymap.types_name = name;
+                compile_types(&mut parser, &mut keymap)?;
+            }
+            "xkb_compatibility" | "xkb_compatibility_map" | "xkb_compat" => {
+                keymap.compat_name = name;
+                compile_compat(&mut parser, &mut keymap)?;
+            }
+            "xkb_symbols" => {
+                keymap.symbols_name = name;
+                compile_symbols(&mut parser, &mut keymap, &mut symbols, &mut modifier_maps)?;
+            }
+            "xkb_geometry" => {
+                keymap.geometry_name = name;
+                parser.position -= 1;
+                parser.skip_statement()?;
+                continue;
+            }
+            section => return Err(format!("unknown keymap section {section}")),
+        }
+        parser.eat(';');
+    }
+    add_canonical_types(&mut keymap);
+    for (keycode, key_symbols) in symbols {
+        let key = build_key(&keymap, key_symbols)?;
+        let name = std::mem::take(&mut keymap.keys[keycode as usize].name);
+        keymap.keys[keycode as usize] = Key { name, ..key };
+    }
+    for (modifier, item) in modifier_maps {
+        let keycode = match item {
+            Expr::KeyName(name) => find_key(&keymap, &name),
+            item => keysym_of(&item).and_then(|keysym| {
+                let key = keymap.keys.iter().position(|key| {
+                    key.groups.iter().any(|group| group.syms.contains(&keysym))
+                });
+                key.map(|keycode| keycode as u8)
+            }),
+        };
+        if let Some(keycode) = keycode {
+            keymap.keys[keycode as usize].modmap |= 1 << modifier;
+        }
+    }
+    keymap.interprets.sort_by_key(|interpret| {
+        let rank = match interpret.match_op & SI_OP_MASK {
+            SI_EXACTLY => 0,
+            SI_ALL_OF => 1,
+            SI_NONE_OF => 2,
+            SI_ANY_OF => 3,
+            _ => 4,
+        };
+        (interpret.keysym == NO_SYMBOL, rank)
+    });
+    keymap.apply_compat();
+    Ok(keymap)
+}
+
+fn find_key(keymap: &XkbKeymap, name: &str) -> Option<u8> {
+    let real = keymap
+        .aliases
+        .iter()
+        .find(|(alias, _)| alias == name)
+        .map_or(name, |(_, real)| real);
+    let keycode = keymap.keys.iter().position(|key| key.name == real)?;
+    Some(keycode as u8)
+}
+
+fn compile_keycodes(parser: &mut Parser, keymap: &mut XkbKeymap) -> Result<(), String> {
+    while !parser.eat('}') {
+        if parser.keyword("indicator") {
+            let Some((Expr::Number(index), Expr::Str(name))) = parser.assignment()? else {
+                continue;
+            };
+            if (1..=NUM_INDICATORS as i64).contains(&index) {
+                keymap.indicator_names[index as usize - 1] = Some(name);
+            }
+            continue;
+        }
+        if parser.keyword("alias") {
+            if let Some((Expr::KeyName(alias), Expr::KeyName(real))) = parser.assignment()? {
+                keymap.aliases.push((alias, real));
+            }
+            continue;
+        }
+        if parser.keyword("virtual") {
+            parser.skip_statement()?;
+            continue;
+        }
+        match parser.assignment()? {
+            Some((Expr::KeyName(name), Expr::Number(keycode)))
+                if (8..=255).contains(&keycode) && !keymap.keys.iter().any(|key| key.name == name) =>
+            {
+                keymap.keys[keycode as usize].name = name;
+            }
+            Some((Expr::Ident(field), Expr::Number(keycode))) => match field.as_str() {
+                "minimum" => keymap.min_keycode = keycode.clamp(8, 255) as u8,
+                "maximum" => keymap.max_keycode = keycode.clamp(8, 255) as u8,
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+    keymap.aliases.retain(|(_, real)| keymap.keys.iter().any(|key| key.name == *real));
+    Ok(())
+}
+
+fn compile_virtual_modifiers(parser: &mut Parser, keymap: &mut XkbKeymap) -> Result<(), String> {
+    loop {
+        let Some(Token::Ident(name)) = parser.next() else {
+            return Err("expected a virtual modifier name".to_string());
+        };
+        if !keymap.vmod_names.contains(&name) && keymap.vmod_names.len() < NUM_VIRTUAL_MODS {
+            keymap.vmod_names.push(name);
+        }
+        if parser.eat('=') {
+            parser.expression()?;
+        }
+        if !parser.eat(',') {
+            return parser.expect(';');
+        }
+    }
+}
+
+/// Evaluates a modifier mask such as `Shift+Lock+NumLock`, `none` or `all`.
+fn mods_of(keymap: &XkbKeymap, expr: &Expr) -> Result<ModDef, String> {
+    match expr {
+        Expr::Sum(terms) => terms.iter().try_fold(ModDef::default(), |mods, term| {
+            let term = mods_of(keymap, term)?;
+            Ok(ModDef {
+                real_mods: mods.real_mods | term.real_mods,
+                vmods: mods.vmods | term.vmods,
+            })
+        }),
+        Expr::Number(mask) => Ok(ModDef {
+            real_mods: *mask as u8,
+            vmods: 0,
+        }),
+        Expr::Ident(name) => {
+            if name.eq_ignore_ascii_case("none") {
+                return Ok(ModDef::default());
+            }
+            if name.eq_ignore_ascii_case("all") {
+                return Ok(ModDef {
+                    real_mods: 0xff,
+                    vmods: keymap.vmod_mask(),
+                });
+            }
+            if let Some(index) = REAL_MOD_NAMES.iter().position(|n| n.eq_ignore_ascii_case(name)) {
+                return Ok(ModDef {
+                    real_mods: 1 << index,
+                    vmods: 0,
+                });
+            }
+            if let Some(index) = keymap.vmod_names.iter().position(|n| n == name) {
+                return Ok(ModDef {
+                    real_mods: 0,
+                    vmods: 1 << index,
+                });
+            }
+            Err(format!("unknown modifier {name}"))
+        }
+        expr => Err(format!("expected modifiers, found {expr:?}")),
+    }
+}
+
+/// Evaluates a level or group number: `2`, `Level2` or `Group2`, counting from 1.
+fn index_of(expr: &Expr, prefix: &str) -> Result<usize, String> {
+    let index = match expr {
+        Expr::Number(index) => *index,
+        Expr::Ident(name)
+            if name.len() > prefix.len() && name[..prefix.len()].eq_ignore_ascii_case(prefix) =>
+        {
+            name[prefix.len()..].parse().map_err(|_| format!("invalid {prefix} {name}"))?
+        }
+        expr => return Err(format!("expected a {prefix}, found {expr:?}")),
+    };
+    if index < 1 {
+        return Err(format!("invalid {prefix} {index}"));
+    }
+    Ok(index as usize)
+}
+
+fn bool_of(expr: &Expr) -> Result<bool, String> {
+    match expr {
+        Expr::Ident(value) => match value.to_ascii_lowercase().as_str() {
+            "true" | "yes" | "on" => Ok(true),
+            "false" | "no" | "off" => Ok(false),
+            _ => Err(format!("expected true or false, found {value}")),
+        },
+        Expr::Not(expr) => Ok(!bool_of(expr)?),
+        expr => Err(format!("expected true or false, found {expr:?}")),
+    }
+}
+
+fn compile_types(parser: &mut Parser, keymap: &mut XkbKeymap) -> Result<(), String> {
+    while !parser.eat('}') {
+        if parser.keyword("virtual_modifiers") {
+            compile_virtual_modifiers(parser, keymap)?;
+            continue;
+        }
+        if !parser.keyword("type") {
+            parser.skip_statement()?;
+            continue;
+        }
+        let Some(Token::Str(name)) = parser.next() else {
+            return Err("expected a type name".to_string());
+        };
+        let mut key_type = KeyType {
+            name,
+            mods: ModDef::default(),
+            num_levels: 1,
+            entries: vec![],
+            level_names: vec![],
+        };
+        for (field, value) in parser.block()? {
+            match field {
+                Expr::Ident(field) if field.eq_ignore_ascii_case("modifiers") => {
+                    key_type.mods = mods_of(keymap, &value)?;
+                }
+                Expr::Index(field, index) if field.eq_ignore_ascii_case("map") => {
+                    let mods = mods_of(keymap, &index)?;
+                    let level = index_of(&value, "level")?;
+                    key_type.entries.retain(|entry| entry.mods != mods);
+                    key_type.entries.push(KeyTypeEntry {
+                        mods,
+                        level: level as u8 - 1,
+                        preserve: ModDef::default(),
+                    });
+                }
+                Expr::Index(field, index) if field.eq_ignore_ascii_case("preserve") => {
+                    let mods = mods_of(keymap, &index)?;
+                    let preserve = mods_of(keymap, &value)?;
+                    if let Some(entry) = key_type.entries.iter_mut().find(|entry| entry.mods == mods) {
+                        entry.preserve = preserve;
+                    }
+                }
+                Expr::Index(field, index) if field.eq_ignore_ascii_case("level_name") => {
+                    let level = index_of(&index, "level")?;
+                    let Expr::Str(name) = value else {
+                        return Err("expected a level name".to_string());
+                    };
+                    if key_type.level_names.len() < level {
+                        key_type.level_names.resize(level, String::new());
+                    }
+                    key_type.level_names[level - 1] = name;
+                }
+                _ => {}
+            }
+        }
+        let highest = key_type.entries.iter().map(|entry| entry.level + 1).max().unwrap_or(1);
+        key_type.num_levels = highest.max(key_type.level_names.len() as u8);
+        for entry in &mut key_type.entries {
+            entry.mods.real_mods &= key_type.mods.real_mods;
+            entry.mods.vmods &= key_type.mods.vmods;
+        }
+        keymap.types.retain(|existing| existing.name != key_type.name);
+        keymap.types.push(key_type);
+    }
+    Ok(())
+}
+
+/// Puts the four canonical types first, adding any the keymap lacks.
+fn add_canonical_types(keymap: &mut XkbKeymap) {
+    let shift = ModDef {
+        real_mods: 1,
+        vmods: 0,
+    };
+    let lock = ModDef {
+        real_mods: 2,
+        vmods: 0,
+    };
+    for (index, name) in CANONICAL_TYPES.iter().enumerate() {
+        let key_type = match keymap.find_type(name) {
+            Some(position) => keymap.types.remove(position),
+            None => {
+                let entry = |mods| KeyTypeEntry {
+                    mods,
+                    level: 1,
+                    preserve: ModDef::default(),
+                };
+                let (mods, entries) = match index {
+                    0 => (ModDef::default(), vec![]),
+                    1 | 3 => (shift, vec![entry(shift)]),
+                    _ => (
+                        ModDef {
+                            real_mods: 3,
+                            vmods: 0,
+                        },
+                        vec![entry(shift), entry(lock)],
+                    ),
+                };
+                KeyType {
+                    name: name.to_string(),
+                    mods,
+                    num_levels: if index == 0 { 1 } else { 2 },
+                    entries,
+                    level_names: vec![],
+                }
+            }
+        };
+        keymap.types.insert(index, key_type);
+    }
+}
+
+fn action_of(keymap: &XkbKeymap, expr: &Expr) -> Result<Action, String> {
+    let Expr::Call(name, args) = expr else {
+        return match expr {
+            Expr::Ident(name) if name.eq_ignore_ascii_case("NoAction") => Ok(Action::default()),
+            expr => Err(format!("expected an action, found {expr:?}")),
+        };
+    };
+    let kind = match name.to_ascii_lowercase().as_str() {
+        "setmods" => SA_SET_MODS,
+        "latchmods" => SA_LATCH_MODS,
+        "lockmods" => SA_LOCK_MODS,
+        "setgroup" => SA_SET_GROUP,
+        "latchgroup" => SA_LATCH_GROUP,
+        "lockgroup" => SA_LOCK_GROUP,
+        _ => return Ok(Action::default()),
+    };
+    let mut action = Action {
+        kind,
+        ..Action::default()
+    };
+    for arg in args {
+        let (field, value) = match arg {
+            Expr::Assign(field, value) => (&**field, (**value).clone()),
+            Expr::Not(field) => (&**field, Expr::Ident("false".to_string())),
+            field => (field, Expr::Ident("true".to_string())),
+        };
+        let Expr::Ident(field) = field else {
+            continue;
+        };
+        let mut set_flag = |flag: u8| -> Result<(), String> {
+            match bool_of(&value)? {
+                true => action.flags |= flag,
+                false => action.flags &= !flag,
+            }
+            Ok(())
+        };
+        match field.to_ascii_lowercase().as_str() {
+            "modifiers" | "mods" => match &value {
+                Expr::Ident(mods) if mods.eq_ignore_ascii_case("modmapmods") => {
+                    action.flags |= SA_USE_MOD_MAP_MODS;
+                }
+                value => action.mods = mods_of(keymap, value)?,
+            },
+            "clearlocks" => set_flag(SA_CLEAR_LOCKS)?,
+            "latchtolock" => set_flag(SA_LATCH_TO_LOCK)?,
+            "affect" if kind == SA_LOCK_MODS => {
+                let Expr::Ident(affect) = &value else {
+                    continue;
+                };
+                action.flags &= !(SA_LOCK_NO_LOCK | SA_LOCK_NO_UNLOCK);
+                action.flags |= match affect.to_ascii_lowercase().as_str() {
+                    "lock" => SA_LOCK_NO_UNLOCK,
+                    "unlock" => SA_LOCK_NO_LOCK,
+                    "neither" => SA_LOCK_NO_LOCK | SA_LOCK_NO_UNLOCK,
+                    _ => 0,
+                };
+            }
+            "group" => {
+                let (relative, value) = match &value {
+                    Expr::Negate(value) => (Some(-1), &**value),
+                    Expr::Sum(terms) if terms.len() == 1 => (Some(1), &terms[0]),
+                    value => (None, value),
+                };
+                let group = index_of(value, "group")? as i8;
+                match relative {
+                    Some(sign) => action.group = sign * group,
+                    None => {
+                        action.group = group - 1;
+                        action.flags |= SA_GROUP_ABSOLUTE;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(action)
+}
+
+/// Evaluates a keysym: a name, a digit or a number.
+fn keysym_of(expr: &Expr) -> Option<u32> {
+    match expr {
+        Expr::Ident(name) if name == "NoSymbol" => Some(NO_SYMBOL),
+        Expr::Ident(name) => {
+            let keysym = keysym_from_name(name);
+            if keysym.is_none() {
+                eprintln!("keymap: unknown keysym {name}");
+            }
+            keysym
+        }
+        Expr::Number(digit @ 0..=9) => Some(0x30 + *digit as u32),
+        Expr::Number(keysym) => Some(*keysym as u32),
+        // Keymaps from libxkbcommon may give a level several keysyms; keep the first.
+        Expr::List(keysyms) => keysyms.first().and_then(keysym_of),
+        _ => None,
+    }
+}
+
+fn compile_compat(parser: &mut Parser, keymap: &mut XkbKeymap) -> Result<(), String> {
+    let mut default = Interpret {
+        keysym: NO_SYMBOL,
+        match_op: SI_ANY_OF_OR_NONE,
+        mods: 0xff,
+        virtual_mod: None,
+        flags: 0,
+        action: Action::default(),
+    };
+    while !parser.eat('}') {
+        if parser.keyword("virtual_modifiers") {
+            compile_virtual_modifiers(parser, keymap)?;
+        } else if parser.keyword("interpret") {
+            let head = parser.expression()?;
+            let mut interpret = default.clone();
+            let (sym, condition) = match head {
+                Expr::Sum(mut terms) if terms.len() == 2 => {
+                    let condition = terms.pop();
+                    (terms.pop().unwrap(), condition)
+                }
+                head => (head, None),
+            };
+            interpret.keysym = match sym {
+                Expr::Ident(name) if name.eq_ignore_ascii_case("any") => NO_SYMBOL,
+                sym => keysym_of(&sym).unwrap_or(NO_SYMBOL),
+            };
+            if let Some(condition) = condition {
+                let (op, mods) = match condition {
+                    Expr::Call(op, args) => (op, args.first().cloned()),
+                    condition => ("exactly".to_string(), Some(condition)),
+                };
+                interpret.match_op = match op.to_ascii_lowercase().as_str() {
+                    "noneof" => SI_NONE_OF,
+                    "anyofornone" => SI_ANY_OF_OR_NONE,
+                    "anyof" => SI_ANY_OF,
+                    "allof" => SI_ALL_OF,
+                    "exactly" => SI_EXACTLY,
+                    op => return Err(format!("unknown match operation {op}")),
+                } | default.match_op & SI_LEVEL_ONE_ONLY;
+                interpret.mods = match mods {
+                    Some(mods) => mods_of(keymap, &mods)?.real_mods,
+                    None => 0,
+                };
+            }
+            for (field, value) in parser.block()? {
+                if let Expr::Ident(field) = field {
+                    set_interpret_field(keymap, &mut interpret, &field, &value)?;
+                }
+            }
+            keymap.interprets.push(interpret);
+        } else if parser.keyword("indicator") {
+            let Some(Token::Str(name)) = parser.next() else {
+                return Err("expected an indicator name".to_string());
+            };
+            let index = match keymap.indicator_names.iter().position(|n| n.as_deref() == Some(&*name)) {
+                Some(index) => index,
+                None => {
+                    let Some(free) = keymap.indicator_names.iter().position(Option::is_none) else {
+                        parser.block()?;
+                        continue;
+                    };
+                    keymap.indicator_names[free] = Some(name);
+                    free
+                }
+            };
+            let mut map = IndicatorMap::default();
+            for (field, value) in parser.block()? {
+                if let Expr::Ident(field) = field {
+                    set_indicator_field(keymap, &mut map, &field, &value)?;
+                }
+            }
+            if map.mods != ModDef::default() && map.which_mods == 0 {
+                map.which_mods = IM_USE_EFFECTIVE;
+            }
+            if map.groups != 0 && map.which_groups == 0 {
+                map.which_groups = IM_USE_EFFECTIVE;
+            }
+            keymap.indicators[index] = map;
+        } else if let Some(Token::Ident(word)) = parser.peek() {
+            if word.eq_ignore_ascii_case("interpret") && parser.peek_at(1) == Some(&Token::Punct('.')) {
+                if let Some((Expr::Field(field), value)) = parser.assignment()? {
+                    set_interpret_field(keymap, &mut default, &field, &value)?;
+                }
+            } else {
+                parser.skip_statement()?;
+            }
+        } else {
+            parser.skip_statement()?;
+        }
+    }
+    Ok(())
+}
+
+fn set_interpret_field(
+    keymap: &XkbKeymap,
+    interpret: &mut Interpret,
+    field: &str,
+    value: &Expr,
+) -> Result<(), String> {
+    match field.to_ascii_lowercase().as_str() {
+        "action" => interpret.action = action_of(keymap, value)?,
+        "virtualmodifier" | "virtualmod" => {
+            let Expr::Ident(name) = value else {
+                return Err("expected a virtual modifier".to_string());
+            };
+            interpret.virtual_mod = keymap
+                .vmod_names
+                .iter()
+                .position(|n| n == name)
+                .map(|index| index as u8);
+        }
+        "usemodmapmods" | "usemodmap" => {
+            let level_one = matches!(value, Expr::Ident(v) if v.eq_ignore_ascii_case("level1")
+                || v.eq_ignore_ascii_case("levelone"));
+            match level_one {
+                true => interpret.match_op |= SI_LEVEL_ONE_ONLY,
+                false => interpret.match_op &= !SI_LEVEL_ONE_ONLY,
+            }
+        }
+        "repeat" => match bool_of(value)? {
+            true => interpret.flags |= SI_AUTO_REPEAT,
+            false => interpret.flags &= !SI_AUTO_REPEAT,
+        },
+        "locking" => match bool_of(value)? {
+            true => interpret.flags |= SI_LOCKING_KEY,
+            false => interpret.flags &= !SI_LOCKING_KEY,
+        },
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Evaluates `which...State` values such as `locked` or `base+latched`.
+fn which_state_of(value: &Expr) -> Result<u8, String> {
+    match value {
+        Expr::Sum(terms) => terms.iter().try_fold(0, |which, term| Ok(which | which_state_of(term)?)),
+        Expr::Ident(name) => match name.to_ascii_lowercase().as_str() {
+            "none" => Ok(0),
+            "base" => Ok(IM_USE_BASE),
+            "latched" => Ok(IM_USE_LATCHED),
+            "locked" => Ok(IM_USE_LOCKED),
+            "effective" => Ok(IM_USE_EFFECTIVE),
+            "compat" => Ok(IM_USE_COMPAT),
+            "any" => Ok(IM_USE_BASE | IM_USE_LATCHED | IM_USE_LOCKED | IM_USE_EFFECTIVE | IM_USE_COMPAT),
+            name => Err(format!("unknown state component {name}")),
+        },
+        value => Err(format!("expected a state component, found {value:?}")),
+    }
+}
+
+fn set_indicator_field(
+    keymap: &XkbKeymap,
+    map: &mut IndicatorMap,
+    field: &str,
+    value: &Expr,
+) -> Result<(), String> {
+    let mut set_flag = |flag: u8, on: bool| match on {
+        true => map.flags |= flag,
+        false => map.flags &= !flag,
+    };
+    match field.to_ascii_lowercase().as_str() {
+        "modifiers" | "mods" => map.mods = mods_of(keymap, value)?,
+        "whichmodstate" | "whichmodifierstate" => map.which_mods = which_state_of(value)?,
+        "groups" => {
+            map.groups = match value {
+                Expr::Number(groups) => *groups as u8,
+                value => 1 << (index_of(value, "group")? - 1),
+            }
+        }
+        "whichgroupstate" => map.which_groups = which_state_of(value)?,
+        "controls" | "ctrls" => {
+            let names = match value {
+                Expr::Sum(terms) => terms.clone(),
+                value => vec![value.clone()],
+            };
+            for name in names {
+                if let Expr::Ident(name) = name {
+                    let control = CONTROL_NAMES.iter().find(|(n, _)| n.eq_ignore_ascii_case(&name));
+                    map.ctrls |= control.map_or(0, |(_, mask)| *mask);
+                }
+            }
+        }
+        "allowexplicit" => set_flag(IM_NO_EXPLICIT, !bool_of(value)?),
+        "driveskeyboard" | "driveskbd" | "ledsdrivekbd" => set_flag(IM_LED_DRIVES_KB, bool_of(value)?),
+        "indexed" => {}
+        _ => {}
+    }
+    Ok(())
+}
+
+fn compile_symbols(
+    parser: &mut Parser,
+    keymap: &mut XkbKeymap,
+    symbols: &mut Vec<(u8, KeySymbols)>,
+    modifier_maps: &mut Vec<(usize, Expr)>,
+) -> Result<(), String> {
+    while !parser.eat('}') {
+        if parser.keyword("key") {
+            let Some(Token::KeyName(name)) = parser.next() else {
+                return Err("expected a key name".to_string());
+            };
+            parser.expect('{')?;
+            let items = parser.items('}')?;
+            parser.eat(';');
+            let Some(keycode) = find_key(keymap, &name) else {
+                continue;
+            };
+            let key_symbols = key_symbols_of(keymap, items)?;
+            symbols.retain(|(existing, _)| *existing != keycode);
+            symbols.push((keycode, key_symbols));
+        } else if parser.keyword("modifier_map") || parser.keyword("modmap") {
+            let Some(Token::Ident(modifier)) = parser.next() else {
+                return Err("expected a modifier".to_string());
+            };
+            let Some(modifier) = REAL_MOD_NAMES.iter().position(|n| n.eq_ignore_ascii_case(&modifier)) else {
+                return Err(format!("modifier_map needs a real modifier, not {modifier}"));
+            };
+            parser.expect('{')?;
+            for item in parser.items('}')? {
+                modifier_maps.push((modifier, item));
+            }
+            parser.eat(';');
+        } else {
+            match parser.assignment()? {
+                Some((Expr::Index(field, index), Expr::Str(name))) if field.eq_ignore_ascii_case("name") => {
+                    let group = index_of(&index, "group")?;
+                    if group <= MAX_GROUPS {
+                        if keymap.group_names.len() < group {
+                            keymap.group_names.resize(group, String::new());
+                        }
+                        keymap.group_names[group - 1] = name;
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(())
+}
+
+fn key_symbols_of(keymap: &XkbKeymap, items: Vec<Expr>) -> Result<KeySymbols, String> {
+    let mut key = KeySymbols::default();
+    let group = |key: &mut KeySymbols, index: usize| -> Result<usize, String> {
+        if index >= MAX_GROUPS {
+            return Err(format!("group {} is out of range", index + 1));
+        }
+        if key.groups.len() <= index {
+            key.groups.resize(index + 1, GroupSymbols::default());
+        }
+        Ok(index)
+    };
+    let mut next_group = 0;
+    for item in items {
+        match item {
+            Expr::List(syms) => {
+                let index = group(&mut key, next_group)?;
+                let syms = syms.iter().map(|sym| keysym_of(sym).unwrap_or(NO_SYMBOL));
+                key.groups[index].syms = syms.collect();
+                next_group += 1;
+            }
+            Expr::Assign(field, value) => match (*field, *value) {
+                (Expr::Ident(field), Expr::Str(name)) if field.eq_ignore_ascii_case("type") => {
+                    key.default_type = Some(name);
+                }
+                (Expr::Index(field, index), Expr::Str(name)) if field.eq_ignore_ascii_case("type") => {
+                    let index = group(&mut key, index_of(&index, "group")? - 1)?;
+                    key.groups[index].type_name = Some(name);
+                }
+                (Expr::Index(field, index), Expr::List(syms)) if field.eq_ignore_ascii_case("symbols") => {
+                    let index = group(&mut key, index_of(&index, "group")? - 1)?;
+                    let syms = syms.iter().map(|sym| keysym_of(sym).unwrap_or(NO_SYMBOL));
+                key.groups[index].syms = syms.collect();
+                    next_group = index + 1;
+                }
+                (Expr::Index(field, index), Expr::List(actions)) if field.eq_ignore_ascii_case("actions") => {
+                    let index = group(&mut key, index_of(&index, "group")? - 1)?;
+                    let actions = actions.iter().map(|action| action_of(keymap, action));
+                    key.groups[index].actions = Some(actions.collect::<Result<_, _>>()?);
+                }
+                (Expr::Ident(field), value) if field.eq_ignore_ascii_case("repeat") => {
+                    key.repeat = match value {
+                        Expr::Ident(value) if value.eq_ignore_ascii_case("default") => None,
+                        value => Some(bool_of(&value)?),
+                    };
+                }
+                (Expr::Ident(field), value)
+                    if field.eq_ignore_ascii_case("virtualmods") || field.eq_ignore_ascii_case("vmods") =>
+                {
+                    key.vmodmap = Some(mods_of(keymap, &value)?.vmods);
+                }
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+    Ok(key)
+}
+
+fn build_key(keymap: &XkbKeymap, symbols: KeySymbols) -> Result<Key, String> {
+    let mut key = Key {
+        repeat: symbols.repeat.unwrap_or(true),
+        vmodmap: symbols.vmodmap.unwrap_or(0),
+        ..Key::default()
+    };
+    if symbols.repeat.is_some() {
+        key.explicit |= EXPLICIT_AUTO_REPEAT;
+    }
+    if symbols.vmodmap.is_some() {
+        key.explicit |= EXPLICIT_VMODMAP;
+    }
+    for (index, group) in symbols.groups.into_iter().enumerate() {
+        let GroupSymbols { type_name, syms, actions } = group;
+        let key_type = match type_name.or(symbols.default_type.clone()) {
+            Some(name) => {
+                key.explicit |= EXPLICIT_KEY_TYPE_1 << index;
+                keymap.find_type(&name).ok_or(format!("unknown key type {name}"))?
+            }
+            None => {
+                let width = syms.len().max(actions.as_ref().map_or(0, Vec::len));
+                let mut padded = syms.clone();
+                padded.resize(width, NO_SYMBOL);
+                keymap.automatic_type(&padded)
+            }
+        };
+        let mut group = keymap.make_group(key_type, syms);
+        if let Some(mut actions) = actions {
+            key.explicit |= EXPLICIT_INTERPRET;
+            actions.resize(group.syms.len(), Action::default());
+            group.actions = actions;
+        }
+        key.groups.push(group);
+    }
+    // Trailing empty groups would only repeat NoSymbol.
+    while key.groups.last().is_some_and(|group| group.syms.iter().all(|sym| *sym == NO_SYMBOL)) {
+        key.groups.pop();
+    }
+    Ok(key)
+}
@@ -0,0 +1,147 @@
+use std::{thread, time::Duration};
+
+use crate::{
+    client::CLIENTS,
+    error::{ErrorCode, XError},
+    extension::ExtensionRequest,
+    input::{
+        inject_button, inject_key, inject_motion, inject_relative_motion, is_valid_button, is_valid_keycode,
+    },
+    screen::DEFAULT_SCREEN,
+    window::{get_window, WINDOWS},
+};
+
+pub static XTEST_NAME: &str = "XTEST";
+pub const XTEST_MAJOR_VERSION: u8 = 2;
+pub const XTEST_MINOR_VERSION: u16 = 2;
+
+#[derive(Debug)]
+pub enum XTestRequest {
+    GetVersion {
+        major_version: u8,
+        minor_version: u16,
+    },
+    CompareCursor {
+        window: u32,
+        cursor: u32,
+    },
+    FakeInput {
+        event_type: u8,
+        detail: u8,
+        time: u32,
+        root: u32,
+        root_x: i16,
+        root_y: i16,
+    },
+    GrabControl {
+        impervious: bool,
+    },
+}
+
+fn read_xtest_request(request: &ExtensionRequest) -> Result<XTestRequest, XError> {
+    let data = request.data;
+    let minimum_length = match request.minor_opcode {
+        0 => 4,
+        1 => 8,
+        2 => 24,
+        3 => 4,
+        minor_opcode => return Err(XError::new(ErrorCode::Request, minor_opcode as u32)),
+    };
+    if data.len() < minimum_length {
+        return Err(XError::new(ErrorCode::Length, 0));
+    }
+    Ok(match request.minor_opcode {
+        0 => XTestRequest::GetVersion {
+            major_version: data[0],
+            minor_version: request.card16(&data[2..]),
+        },
+        1 => XTestRequest::CompareCursor {
+            window: request.card32(data),
+            cursor: request.card32(&data[4..]),
+        },
+        2 => XTestRequest::FakeInput {
+            event_type: data[0],
+            detail: data[1],
+            time: request.card32(&data[4..]),
+            root: request.card32(&data[8..]),
+            root_x: request.int16(&data[20..]),
+            root_y: request.int16(&data[22..]),
+        },
+        _ => XTestRequest::GrabControl {
+            impervious: data[0] != 0,
+        },
+    })
+}
+
+pub fn handle_xtest_request(request: &ExtensionRequest) -> Result<Option<Vec<u8>>, XError> {
+    let xtest_request = read_xtest_request(request)?;
+    match xtest_request {
+        XTestRequest::GetVersion { .. } => {
+            let body = request.to_bytes_16(XTEST_MINOR_VERSION).to_vec();
+            Ok(Some(request.reply(XTEST_MAJOR_VERSION, body)))
+        }
+        XTestRequest::CompareCursor { window, cursor } => {
+            let same = get_window(&WINDOWS.lock().unwrap(), window)
+                .map(|w| cursor == 1 || cursor == w.attributes.cursor)?;
+            Ok(Some(request.reply(same as u8, vec![])))
+        }
+        XTestRequest::FakeInput {
+            event_type,
+            detail,
+            time,
+            root,
+            root_x,
+            root_y,
+        } => {
+            fake_input(event_type, detail, time, root, root_x, root_y)?;
+            Ok(None)
+        }
+        XTestRequest::GrabControl { impervious } => {
+            grab_control(request.client, impervious);
+            Ok(None)
+        }
+    }
+}
+
+/// Replays a FakeInput request through the core input path. `time` is a delay in
+/// milliseconds before the event is processed.
+pub fn fake_input(
+    event_type: u8,
+    detail: u8,
+    time: u32,
+    root: u32,
+    root_x: i16,
+    root_y: i16,
+) -> Result<(), XError> {
+    match event_type {
+        2 | 3 if !is_valid_keycode(detail) => {
+            return Err(XError::new(ErrorCode::Value, detail as u32))
+        }
+        4 | 5 if !is_valid_button(detail) => {
+            return Err(XError::new(ErrorCode::Value, detail as u32))
+        }
+        6 if root != 0 && root != DEFAULT_SCREEN.root_window => {
+            return Err(XError::new(ErrorCode::Window, root))
+        }
+        2..=6 => {}
+        _ => return Err(XError::new(ErrorCode::Value, event_type as u32)),
+    }
+    if time != 0 {
+        thread::sleep(Duration::from_millis(time as u64));
+    }
+    match event_type {
+        2 => inject_key(detail, true),
+        3 => inject_key(detail, false),
+        4 => inject_button(detail, true),
+        5 => inject_button(detail, false),
+        _ if detail != 0 => inject_relative_motion(root_x as i32, root_y as i32),
+        _ => inject_motion(root_x as i32, root_y as i32),
+    }
+    Ok(())
+}
+
+pub fn grab_control(client: u32, impervious: bool) {
+    if let Some(client) = CLIENTS.lock().unwrap().get_mut(&client) {
+        client.impervious = impervious;
+    }
+}